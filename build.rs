@@ -0,0 +1,27 @@
+// Captures build information for the `/version` endpoint: the git commit
+// and the build time, exposed to the crate as `GIT_HASH` and `BUILD_TIME`.
+// Either falls back to "unknown" when it can't be determined (e.g. building
+// from a source tarball without git).
+
+use std::process::Command;
+
+fn main() {
+    let git_hash = output("git", &["rev-parse", "--short=12", "HEAD"]);
+    let build_time = output("date", &["-u", "+%Y-%m-%dT%H:%M:%SZ"]);
+    println!("cargo:rustc-env=GIT_HASH={}", git_hash.as_deref().unwrap_or("unknown"));
+    println!("cargo:rustc-env=BUILD_TIME={}", build_time.as_deref().unwrap_or("unknown"));
+    // Only rebuild when the checked-out commit changes.
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+    println!("cargo:rerun-if-changed=build.rs");
+}
+
+// Trimmed stdout of a command, if it ran successfully.
+fn output(program: &str, args: &[&str]) -> Option<String> {
+    let out = Command::new(program).args(args).output().ok()?;
+    if !out.status.success() {
+        return None;
+    }
+    let text = String::from_utf8(out.stdout).ok()?;
+    Some(text.trim().to_string()).filter(|t| !t.is_empty())
+}
@@ -0,0 +1,77 @@
+// Admin endpoints (`ADMIN_ENABLED`), guarded by their own `ADMIN_TOKEN`
+// rather than the credentials used for proxied requests.
+//
+// `POST /admin/shutdown` starts the same graceful drain as SIGTERM and is
+// answered with 202 before the listener stops. `/admin/maintenance` reports
+// maintenance mode on `GET`, turns it on with `POST` and off with `DELETE`.
+
+use crate::auth::bearer_matches;
+use crate::error::text_response;
+use crate::maintenance::Maintenance;
+use hyper::header::{HeaderValue, ALLOW};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use std::sync::Arc;
+use tokio::sync::Notify;
+use tracing::warn;
+
+pub const SHUTDOWN_PATH: &str = "/admin/shutdown";
+pub const MAINTENANCE_PATH: &str = "/admin/maintenance";
+
+pub struct Admin {
+    token: String,
+    shutdown: Notify,
+    maintenance: Arc<Maintenance>,
+}
+
+impl Admin {
+    pub fn new(token: String, maintenance: Arc<Maintenance>) -> Admin {
+        Admin {
+            token,
+            shutdown: Notify::new(),
+            maintenance,
+        }
+    }
+
+    // Answer `req` if it's for an admin path; `None` otherwise. `Err` holds
+    // the response to a request without the admin token.
+    pub fn handle(&self, req: &Request<Body>) -> Option<Result<Response<Body>, Response<Body>>> {
+        let path = req.uri().path();
+        if path != SHUTDOWN_PATH && path != MAINTENANCE_PATH {
+            return None;
+        }
+        if !bearer_matches(req.headers(), &self.token) {
+            return Some(Err(text_response(StatusCode::UNAUTHORIZED, "Invalid admin token")));
+        }
+        if path == MAINTENANCE_PATH {
+            return Some(Ok(self.maintenance(req.method())));
+        }
+        if req.method() != Method::POST {
+            return Some(Ok(method_not_allowed("POST")));
+        }
+        warn!("shutdown requested through the admin endpoint");
+        self.shutdown.notify_one();
+        Some(Ok(text_response(StatusCode::ACCEPTED, "Shutting down")))
+    }
+
+    fn maintenance(&self, method: &Method) -> Response<Body> {
+        match *method {
+            Method::GET => {}
+            Method::POST => self.maintenance.set(true),
+            Method::DELETE => self.maintenance.set(false),
+            _ => return method_not_allowed("GET, POST, DELETE"),
+        }
+        let state = if self.maintenance.is_on() { "on" } else { "off" };
+        text_response(StatusCode::OK, format!("Maintenance mode {}", state))
+    }
+
+    // Resolves once a shutdown has been requested.
+    pub async fn shutdown_requested(&self) {
+        self.shutdown.notified().await;
+    }
+}
+
+fn method_not_allowed(allow: &'static str) -> Response<Body> {
+    let mut resp = text_response(StatusCode::METHOD_NOT_ALLOWED, "Method Not Allowed");
+    resp.headers_mut().insert(ALLOW, HeaderValue::from_static(allow));
+    resp
+}
@@ -0,0 +1,457 @@
+// Authentication for proxied requests.
+//
+// In token mode (the default) the incoming `Authorization` header must carry
+// one of the configured tokens, normally as `Bearer <token>`. In basic mode
+// it must carry `Basic <base64 user:pass>` matching the configured
+// credentials. Comparisons are constant-time. In JWT mode the Bearer token is
+// verified as a signed JWT instead; see `jwt.rs`. In introspect mode an
+// external service decides; see `introspect.rs`.
+//
+// Refusals carry a reason code in `X-Auth-Error` (`missing_header`,
+// `invalid_token`, `expired_jwt`, ...) so clients can tell the cases apart
+// without parsing the body.
+
+use crate::config::Config;
+use crate::error::text_response;
+use crate::introspect::{Introspector, Verdict};
+use crate::jwt::JwtValidator;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION, WWW_AUTHENTICATE};
+use hyper::{Body, Request, Response, StatusCode};
+use jsonwebtoken::errors::ErrorKind;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use subtle::ConstantTimeEq;
+use tracing::{debug, info};
+
+// Carries the JWT `sub` claim to the upstream. Always removed from client
+// requests in JWT mode so it can't be spoofed.
+const X_AUTH_SUBJECT: HeaderName = HeaderName::from_static("x-auth-subject");
+
+const X_AUTH_ERROR: HeaderName = HeaderName::from_static("x-auth-error");
+
+// Why credentials were refused, as reported in `X-Auth-Error`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum AuthFailure {
+    MissingHeader,
+    // Not readable as text, or missing the expected `Bearer` scheme.
+    MalformedHeader,
+    InvalidToken,
+    InvalidCredentials,
+    ExpiredJwt,
+    BadSignature,
+    // A JWT that is malformed or fails any other check (algorithm,
+    // audience, issuer, not-before).
+    InvalidJwt,
+    // The introspection service couldn't give an answer.
+    Unavailable,
+}
+
+impl AuthFailure {
+    fn as_str(self) -> &'static str {
+        match self {
+            AuthFailure::MissingHeader => "missing_header",
+            AuthFailure::MalformedHeader => "malformed_header",
+            AuthFailure::InvalidToken => "invalid_token",
+            AuthFailure::InvalidCredentials => "invalid_credentials",
+            AuthFailure::ExpiredJwt => "expired_jwt",
+            AuthFailure::BadSignature => "bad_signature",
+            AuthFailure::InvalidJwt => "invalid_jwt",
+            AuthFailure::Unavailable => "auth_unavailable",
+        }
+    }
+
+    fn of_jwt(e: &jsonwebtoken::errors::Error) -> AuthFailure {
+        match e.kind() {
+            ErrorKind::ExpiredSignature => AuthFailure::ExpiredJwt,
+            ErrorKind::InvalidSignature => AuthFailure::BadSignature,
+            _ => AuthFailure::InvalidJwt,
+        }
+    }
+}
+
+// How the token is expected to appear in the Authorization header.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthScheme {
+    // `Authorization: Bearer <token>` (the default).
+    #[default]
+    Bearer,
+    // Also accept a bare `Authorization: <token>` for older clients.
+    Raw,
+}
+
+impl FromStr for AuthScheme {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<AuthScheme, String> {
+        if s.eq_ignore_ascii_case("bearer") {
+            Ok(AuthScheme::Bearer)
+        } else if s.eq_ignore_ascii_case("raw") {
+            Ok(AuthScheme::Raw)
+        } else {
+            Err(format!("unknown auth scheme `{}` (expected `bearer` or `raw`)", s))
+        }
+    }
+}
+
+// Which kind of credentials requests must present.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthMode {
+    // A shared token (the default).
+    #[default]
+    Token,
+    // HTTP Basic username and password.
+    Basic,
+    // A signed JWT as the Bearer token.
+    Jwt,
+    // A token approved by an external introspection service.
+    Introspect,
+}
+
+impl FromStr for AuthMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<AuthMode, String> {
+        if s.eq_ignore_ascii_case("token") {
+            Ok(AuthMode::Token)
+        } else if s.eq_ignore_ascii_case("basic") {
+            Ok(AuthMode::Basic)
+        } else if s.eq_ignore_ascii_case("jwt") {
+            Ok(AuthMode::Jwt)
+        } else if s.eq_ignore_ascii_case("introspect") {
+            Ok(AuthMode::Introspect)
+        } else {
+            Err(format!(
+                "unknown auth mode `{}` (expected `token`, `basic`, `jwt` or `introspect`)",
+                s
+            ))
+        }
+    }
+}
+
+// The username and password accepted in basic mode.
+#[derive(Clone, Debug)]
+pub struct BasicCredentials {
+    pub user: String,
+    pub pass: String,
+}
+
+impl BasicCredentials {
+    // Check an `Authorization` header value of the form `Basic <base64>`.
+    // Malformed values (bad base64, no colon) are simply rejected.
+    fn accepts(&self, value: &str) -> bool {
+        let Some(encoded) = value
+            .split_once(' ')
+            .filter(|(name, _)| name.eq_ignore_ascii_case("basic"))
+            .map(|(_, encoded)| encoded.trim())
+        else {
+            return false;
+        };
+        let Ok(decoded) = BASE64.decode(encoded) else {
+            return false;
+        };
+        let Ok(decoded) = String::from_utf8(decoded) else {
+            return false;
+        };
+        match decoded.split_once(':') {
+            // Both halves are always compared, so timing doesn't reveal
+            // whether the username was right.
+            Some((user, pass)) => tokens_match(user, &self.user) & tokens_match(pass, &self.pass),
+            None => false,
+        }
+    }
+}
+
+// How a route overrides the global auth settings.
+#[derive(Clone, Debug)]
+pub enum RouteAuth {
+    // No credentials needed.
+    Public,
+    // One of these tokens is required, whatever the global mode.
+    Tokens(HashSet<String>),
+}
+
+// Auth settings shared by every request.
+#[derive(Clone, Debug)]
+pub struct AuthConfig {
+    pub mode: AuthMode,
+    pub tokens: HashSet<String>,
+    pub scheme: AuthScheme,
+    // Where credentials are read from; `Authorization` by default.
+    pub header: HeaderName,
+    // Set when `mode` is `Basic`.
+    pub basic: Option<BasicCredentials>,
+    // Set when `mode` is `Jwt`.
+    pub jwt: Option<Arc<JwtValidator>>,
+    // Set when `mode` is `Introspect`.
+    pub introspect: Option<Arc<Introspector>>,
+    // Overrides keyed by route prefix; other routes use the settings above.
+    pub routes: HashMap<String, RouteAuth>,
+    // Status and body for rejected credentials; the body replaces the usual
+    // short message when set.
+    pub deny_status: StatusCode,
+    pub deny_body: Option<String>,
+}
+
+impl AuthConfig {
+    // Build the auth settings from a validated config. Fails only if the JWT
+    // key can't be loaded.
+    pub fn from_config(config: &Config) -> Result<AuthConfig, String> {
+        let jwt = match config.jwt_key() {
+            Some(key) if config.auth_mode == AuthMode::Jwt => {
+                let validator = JwtValidator::new(
+                    key,
+                    config.jwt_algorithm,
+                    config.jwt_audience.as_deref(),
+                    config.jwt_issuer.as_deref(),
+                    config.jwt_forward_subject,
+                )
+                .map_err(|e| format!("JWT: {}", e))?;
+                Some(Arc::new(validator))
+            }
+            _ => None,
+        };
+        let introspect = match &config.introspect_url {
+            Some(url) if config.auth_mode == AuthMode::Introspect => Some(Arc::new(Introspector::new(
+                url.parse().expect("validated by Config::load"),
+                Duration::from_millis(config.introspect_timeout_ms),
+                config.introspect_cache_secs.map(Duration::from_secs),
+                StatusCode::from_u16(config.introspect_failure_status).expect("validated by Config::load"),
+            ))),
+            _ => None,
+        };
+        let routes = config
+            .routes
+            .iter()
+            .filter_map(|r| {
+                let policy = if r.public {
+                    RouteAuth::Public
+                } else if !r.auth_tokens.is_empty() {
+                    RouteAuth::Tokens(r.auth_tokens.iter().filter(|t| !t.is_empty()).cloned().collect())
+                } else {
+                    return None;
+                };
+                Some((r.prefix.clone(), policy))
+            })
+            .collect();
+        let header = HeaderName::from_bytes(config.auth_header.as_bytes())
+            .expect("validated by Config::load");
+        Ok(AuthConfig {
+            mode: config.auth_mode,
+            tokens: config.tokens(),
+            scheme: config.auth_scheme,
+            header,
+            basic: config.basic_credentials(),
+            jwt,
+            introspect,
+            routes,
+            deny_status: StatusCode::from_u16(config.auth_deny_status).expect("validated by Config::load"),
+            deny_body: config.auth_deny_body.clone(),
+        })
+    }
+}
+
+// Check a presented token against every accepted token. All entries are
+// compared (no early exit) to keep timing independent of which one matched.
+fn accepts_token(tokens: &HashSet<String>, presented: &str) -> bool {
+    tokens
+        .iter()
+        .fold(false, |found, t| found | tokens_match(presented, t))
+}
+
+// Pull the token out of an Authorization header value. The scheme name is
+// matched case-insensitively per RFC 7235; a bare token is only accepted
+// when the raw scheme is enabled.
+fn extract_token(value: &str, scheme: AuthScheme) -> Option<&str> {
+    let bearer = value
+        .split_once(' ')
+        .filter(|(name, _)| name.eq_ignore_ascii_case("bearer"))
+        .map(|(_, token)| token.trim_start());
+    match (bearer, scheme) {
+        (Some(token), _) => Some(token),
+        (None, AuthScheme::Raw) => Some(value),
+        (None, AuthScheme::Bearer) => None,
+    }
+}
+
+// Whether `headers` carry `Authorization: Bearer <expected>`.
+pub fn bearer_matches(headers: &HeaderMap, expected: &str) -> bool {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| extract_token(v, AuthScheme::Bearer))
+        .is_some_and(|token| tokens_match(token, expected))
+}
+
+// Compare two tokens without short-circuiting on the first differing byte,
+// so response timing does not reveal how much of a guess was correct.
+fn tokens_match(presented: &str, expected: &str) -> bool {
+    presented.as_bytes().ct_eq(expected.as_bytes()).into()
+}
+
+// Simple auth middleware – checks the Authorization header against the
+// configured token, Basic credentials, or JWT key. `route` is the prefix of
+// the matched route, whose own settings take precedence.
+pub async fn authorize(
+    req: Request<Body>,
+    auth: &AuthConfig,
+    route: Option<&str>,
+) -> Result<Request<Body>, Response<Body>> {
+    let route_auth = route.and_then(|prefix| auth.routes.get(prefix));
+    if let Some(RouteAuth::Public) = route_auth {
+        return Ok(req);
+    }
+
+    // Extract the header value. A custom header (e.g. `X-Api-Key`) usually
+    // carries the bare token, so it's accepted with or without `Bearer`.
+    let custom_header = auth.header != AUTHORIZATION;
+    let Some(value) = req.headers().get(&auth.header) else {
+        let message = if custom_header { "Missing auth header" } else { "Missing Authorization header" };
+        return Err(unauthorized(auth, message, AuthFailure::MissingHeader));
+    };
+    let value = value.to_str().ok();
+    let scheme = if custom_header { AuthScheme::Raw } else { auth.scheme };
+    if let Some(RouteAuth::Tokens(tokens)) = route_auth {
+        return match value.and_then(|v| extract_token(v, scheme)) {
+            Some(token) if accepts_token(tokens, token) => Ok(req),
+            Some(_) => Err(deny(auth, "Invalid auth token", AuthFailure::InvalidToken)),
+            None => Err(deny(auth, "Invalid auth token", AuthFailure::MalformedHeader)),
+        };
+    }
+    match auth.mode {
+        AuthMode::Token => match value.and_then(|v| extract_token(v, scheme)) {
+            Some(token) if accepts_token(&auth.tokens, token) => Ok(req),
+            Some(_) => Err(unauthorized(auth, "Invalid auth token", AuthFailure::InvalidToken)),
+            None => Err(unauthorized(auth, "Invalid auth token", AuthFailure::MalformedHeader)),
+        },
+        AuthMode::Basic => {
+            let creds = auth.basic.as_ref();
+            match value {
+                Some(v) if creds.is_some_and(|c| c.accepts(v)) => Ok(req),
+                Some(_) => Err(unauthorized(auth, "Invalid credentials", AuthFailure::InvalidCredentials)),
+                None => Err(unauthorized(auth, "Invalid credentials", AuthFailure::MalformedHeader)),
+            }
+        }
+        AuthMode::Jwt => {
+            let jwt_scheme = if custom_header { AuthScheme::Raw } else { AuthScheme::Bearer };
+            let token = value.and_then(|v| extract_token(v, jwt_scheme));
+            let claims = match (token, auth.jwt.as_deref()) {
+                (Some(token), Some(validator)) => validator.validate(token).map_err(|e| {
+                    debug!(error = %e, "JWT rejected");
+                    AuthFailure::of_jwt(&e)
+                }),
+                (Some(_), None) => Err(AuthFailure::InvalidToken),
+                (None, _) => Err(AuthFailure::MalformedHeader),
+            };
+            let claims = match claims {
+                Ok(claims) => claims,
+                Err(failure) => return Err(unauthorized(auth, "Invalid auth token", failure)),
+            };
+            info!(sub = claims.sub.as_deref(), "JWT accepted");
+            let mut req = req;
+            req.headers_mut().remove(X_AUTH_SUBJECT);
+            let forward_subject = auth.jwt.as_ref().is_some_and(|v| v.forward_subject);
+            if let Some(sub) = claims.sub.filter(|_| forward_subject) {
+                if let Ok(sub) = HeaderValue::from_str(&sub) {
+                    req.headers_mut().insert(X_AUTH_SUBJECT, sub);
+                }
+            }
+            Ok(req)
+        }
+        AuthMode::Introspect => {
+            let Some(token) = value.and_then(|v| extract_token(v, scheme)) else {
+                return Err(unauthorized(auth, "Invalid auth token", AuthFailure::MalformedHeader));
+            };
+            let verdict = match auth.introspect.as_deref() {
+                Some(introspector) => introspector.check(token).await,
+                None => Verdict::Denied,
+            };
+            match verdict {
+                Verdict::Allowed => Ok(req),
+                Verdict::Denied => Err(unauthorized(auth, "Invalid auth token", AuthFailure::InvalidToken)),
+                Verdict::Unavailable => {
+                    let status = auth
+                        .introspect
+                        .as_ref()
+                        .map_or(StatusCode::UNAUTHORIZED, |i| i.failure_status);
+                    let mut resp = text_response(status, "Authorization service unavailable");
+                    resp.headers_mut().insert(X_AUTH_ERROR, reason(AuthFailure::Unavailable));
+                    Err(resp)
+                }
+            }
+        }
+    }
+}
+
+// The response to rejected credentials: 401 unless configured otherwise.
+// In basic mode a 401 carries a challenge so browsers prompt for
+// credentials.
+fn unauthorized(auth: &AuthConfig, message: &'static str, failure: AuthFailure) -> Response<Body> {
+    let mut resp = deny(auth, message, failure);
+    if auth.mode == AuthMode::Basic && resp.status() == StatusCode::UNAUTHORIZED {
+        resp.headers_mut().insert(
+            WWW_AUTHENTICATE,
+            HeaderValue::from_static(r#"Basic realm="proxy""#),
+        );
+    }
+    resp
+}
+
+// The configured deny status, with the configured body or else `message`.
+// The reason code is left out when the status disguises the denial as
+// something other than an auth failure (e.g. 404).
+fn deny(auth: &AuthConfig, message: &'static str, failure: AuthFailure) -> Response<Body> {
+    let mut resp = match &auth.deny_body {
+        Some(body) => text_response(auth.deny_status, body.clone()),
+        None => text_response(auth.deny_status, message),
+    };
+    if matches!(auth.deny_status, StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN) {
+        resp.headers_mut().insert(X_AUTH_ERROR, reason(failure));
+    }
+    resp
+}
+
+fn reason(failure: AuthFailure) -> HeaderValue {
+    HeaderValue::from_static(failure.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bearer_scheme_is_case_insensitive() {
+        assert_eq!(extract_token("Bearer abc", AuthScheme::Bearer), Some("abc"));
+        assert_eq!(extract_token("bearer abc", AuthScheme::Bearer), Some("abc"));
+        assert_eq!(extract_token("BEARER abc", AuthScheme::Bearer), Some("abc"));
+    }
+
+    #[test]
+    fn bare_token_needs_raw_scheme() {
+        assert_eq!(extract_token("abc", AuthScheme::Bearer), None);
+        assert_eq!(extract_token("abc", AuthScheme::Raw), Some("abc"));
+        assert_eq!(extract_token("Bearer abc", AuthScheme::Raw), Some("abc"));
+    }
+
+    #[test]
+    fn tokens_match_only_when_equal() {
+        assert!(tokens_match("secret", "secret"));
+        assert!(!tokens_match("secreT", "secret"));
+        assert!(!tokens_match("secret", "secret2"));
+        assert!(!tokens_match("", "secret"));
+    }
+
+    #[test]
+    fn any_accepted_token_matches() {
+        let tokens: HashSet<String> = ["old".to_string(), "new".to_string()].into();
+        assert!(accepts_token(&tokens, "old"));
+        assert!(accepts_token(&tokens, "new"));
+        assert!(!accepts_token(&tokens, "other"));
+    }
+}
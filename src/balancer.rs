@@ -0,0 +1,272 @@
+// Round-robin load balancing across upstream replicas.
+//
+// A `Balancer` holds the replicas behind one upstream (the default upstream
+// or a route). Each request starts at the next replica in turn; the rest
+// follow in order so callers can fail over when a replica is unreachable.
+// The counter is atomic, so one balancer is shared by all connections.
+//
+// When replicas have different weights, the first replica is instead drawn
+// at random in proportion to its weight. Replicas with weight 0 are drained:
+// they get no requests, not even on failover.
+//
+// With sticky sessions a request instead starts at the replica picked by
+// hashing its affinity key (a cookie or the client IP), so the same client
+// keeps landing on the same replica while it's reachable.
+//
+// With slow start, a replica that couldn't be reached and then answers again
+// doesn't get its full share straight away: for the ramp period it only
+// takes its round-robin turn with a probability growing linearly from zero,
+// handing the turn to the next replica otherwise. Weighted draws scale its
+// weight down the same way.
+
+use hyper::header::{HeaderMap, COOKIE};
+use hyper::Uri;
+use serde::Deserialize;
+use std::collections::hash_map::{DefaultHasher, RandomState};
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// What pins a client to a replica.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StickyKey {
+    // The client's IP address.
+    Ip,
+    // A cookie's value, falling back to the client IP when it's absent.
+    Cookie,
+}
+
+impl FromStr for StickyKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<StickyKey, String> {
+        if s.eq_ignore_ascii_case("ip") {
+            Ok(StickyKey::Ip)
+        } else if s.eq_ignore_ascii_case("cookie") {
+            Ok(StickyKey::Cookie)
+        } else {
+            Err(format!("unknown sticky session key `{}` (expected `ip` or `cookie`)", s))
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Stickiness {
+    key: StickyKey,
+    cookie: String,
+}
+
+impl Stickiness {
+    pub fn new(key: StickyKey, cookie: String) -> Stickiness {
+        Stickiness { key, cookie }
+    }
+
+    // Hash of the request's affinity key, for `Balancer::replicas`.
+    pub fn affinity(&self, headers: &HeaderMap, client_ip: IpAddr) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        match self.cookie_value(headers) {
+            Some(value) if self.key == StickyKey::Cookie => value.hash(&mut hasher),
+            _ => client_ip.hash(&mut hasher),
+        }
+        hasher.finish()
+    }
+
+    fn cookie_value<'a>(&self, headers: &'a HeaderMap) -> Option<&'a str> {
+        headers
+            .get_all(COOKIE)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(';'))
+            .filter_map(|pair| pair.trim().split_once('='))
+            .find(|(name, _)| *name == self.cookie)
+            .map(|(_, value)| value)
+    }
+}
+
+// How a replica has been doing, as far as slow start cares.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Health {
+    Up,
+    // The last request to it couldn't connect.
+    Down,
+    // Reachable again since this instant, and still ramping up.
+    Recovering(Instant),
+}
+
+#[derive(Debug)]
+pub struct Balancer {
+    replicas: Vec<Uri>,
+    weights: Vec<u32>,
+    // Indexes of the replicas with a non-zero weight, the only ones used.
+    active: Vec<usize>,
+    // Whether the active replicas' weights differ, so plain round-robin
+    // won't do.
+    weighted: bool,
+    next: AtomicUsize,
+    // Ramp period for recovered replicas; no slow start when `None`.
+    slow_start: Option<Duration>,
+    // One per replica, in the same order.
+    health: Vec<Mutex<Health>>,
+}
+
+impl Balancer {
+    // `weights` has one entry per replica, and at least one must be non-zero;
+    // config validation guarantees this.
+    pub fn new(replicas: Vec<Uri>, weights: Vec<u32>, slow_start: Option<Duration>) -> Balancer {
+        assert_eq!(replicas.len(), weights.len(), "balancer needs one weight per replica");
+        let active: Vec<usize> = (0..replicas.len()).filter(|&i| weights[i] > 0).collect();
+        assert!(!active.is_empty(), "balancer needs at least one replica with a weight");
+        let weighted = active.iter().any(|&i| weights[i] != weights[active[0]]);
+        let health = replicas.iter().map(|_| Mutex::new(Health::Up)).collect();
+        Balancer {
+            replicas,
+            weights,
+            active,
+            weighted,
+            next: AtomicUsize::new(0),
+            slow_start,
+            health,
+        }
+    }
+
+    // Every active replica, starting with the one whose turn it is (or the
+    // weighted pick), or the one `affinity` maps to for sticky sessions.
+    pub fn replicas(&self, affinity: Option<u64>) -> impl Iterator<Item = &Uri> {
+        let len = self.active.len();
+        let start = match affinity {
+            Some(hash) if self.weighted => self.pick(hash, |i| f64::from(self.weights[i])),
+            Some(hash) => (hash % len as u64) as usize,
+            None if self.weighted => self.pick(roll(), |i| f64::from(self.weights[i]) * self.share(i)),
+            None => {
+                let turn = self.next.fetch_add(1, Ordering::Relaxed) % len;
+                if len > 1 && !draw(self.share(self.active[turn]) * 100.0, roll()) {
+                    (turn + 1) % len
+                } else {
+                    turn
+                }
+            }
+        };
+        (0..len).map(move |k| &self.replicas[self.active[(start + k) % len]])
+    }
+
+    // The position in `active` that `roll` lands on when each replica takes
+    // a slice of the range as wide as its `weight`. Uniform if every weight
+    // is zero (all replicas just recovered).
+    fn pick(&self, roll: u64, weight: impl Fn(usize) -> f64) -> usize {
+        let weights: Vec<f64> = self.active.iter().map(|&i| weight(i)).collect();
+        let total: f64 = weights.iter().sum();
+        if total <= 0.0 {
+            return (roll % weights.len() as u64) as usize;
+        }
+        let mut point = roll as f64 / u64::MAX as f64 * total;
+        for (k, weight) in weights.iter().enumerate() {
+            if point < *weight {
+                return k;
+            }
+            point -= weight;
+        }
+        weights.len() - 1
+    }
+
+    // Note whether a request could connect to `replica`, for slow start.
+    pub fn record(&self, replica: &Uri, reachable: bool) {
+        let Some(ramp) = self.slow_start else {
+            return;
+        };
+        let Some(i) = self.replicas.iter().position(|r| r == replica) else {
+            return;
+        };
+        let mut health = self.health[i].lock().unwrap();
+        *health = match (*health, reachable) {
+            (_, false) => Health::Down,
+            (Health::Down, true) => Health::Recovering(Instant::now()),
+            (Health::Recovering(since), true) if since.elapsed() < ramp => Health::Recovering(since),
+            (_, true) => Health::Up,
+        };
+    }
+
+    // The fraction of its turns replica `i` takes: below 1 only while it is
+    // ramping up after recovering.
+    fn share(&self, i: usize) -> f64 {
+        let Some(ramp) = self.slow_start else {
+            return 1.0;
+        };
+        match *self.health[i].lock().unwrap() {
+            Health::Recovering(since) => (since.elapsed().as_secs_f64() / ramp.as_secs_f64()).min(1.0),
+            _ => 1.0,
+        }
+    }
+
+    // Every replica in configured order.
+    pub fn all(&self) -> &[Uri] {
+        &self.replicas
+    }
+
+    // The first configured replica that isn't drained, used where a single
+    // stable target is needed (e.g. readiness probes).
+    pub fn first(&self) -> &Uri {
+        &self.replicas[self.active[0]]
+    }
+}
+
+// A random number for weighted draws; `RandomState` is freshly keyed on
+// every call.
+pub fn roll() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+// Whether `roll` falls within `percent` (0–100) of the range.
+pub fn draw(percent: f64, roll: u64) -> bool {
+    percent >= 100.0 || (roll as f64 / u64::MAX as f64) * 100.0 < percent
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread::sleep;
+
+    const PICKS: usize = 400;
+
+    fn uris(hosts: &[&str]) -> Vec<Uri> {
+        hosts.iter().map(|host| format!("http://{}", host).parse().unwrap()).collect()
+    }
+
+    // How many of `PICKS` requests start at `replica`.
+    fn starts_at(balancer: &Balancer, replica: &Uri) -> usize {
+        (0..PICKS).filter(|_| balancer.replicas(None).next() == Some(replica)).count()
+    }
+
+    #[test]
+    fn recovered_replica_ramps_up() {
+        for weights in [vec![1, 1], vec![1, 2]] {
+            let replicas = uris(&["a", "b"]);
+            let balancer = Balancer::new(replicas.clone(), weights.clone(), Some(Duration::from_millis(200)));
+            let b = &replicas[1];
+            let full = starts_at(&balancer, b);
+
+            balancer.record(b, false);
+            balancer.record(b, true);
+            let ramping = starts_at(&balancer, b);
+            assert!(ramping < full / 3, "{:?}: {} while ramping, {} at full share", weights, ramping, full);
+
+            // Once the ramp is over it's back to its full share.
+            sleep(Duration::from_millis(250));
+            balancer.record(b, true);
+            let recovered = starts_at(&balancer, b);
+            assert!(recovered > full * 2 / 3, "{:?}: {} after ramping, {} before", weights, recovered, full);
+        }
+    }
+
+    #[test]
+    fn without_slow_start_recovery_is_immediate() {
+        let replicas = uris(&["a", "b"]);
+        let balancer = Balancer::new(replicas.clone(), vec![1, 1], None);
+        balancer.record(&replicas[1], false);
+        balancer.record(&replicas[1], true);
+        assert_eq!(starts_at(&balancer, &replicas[1]), PICKS / 2);
+    }
+}
@@ -0,0 +1,185 @@
+// Request body size and time limiting, copying, and tying resources to a
+// body's lifetime.
+//
+// The limit is enforced while the body streams to the upstream, so an
+// oversized upload is cut off as soon as it crosses the limit instead of
+// being buffered first. A declared `Content-Length` over the limit is
+// rejected up front without reading anything. Likewise a body that is still
+// arriving at its deadline is cut off there.
+
+use futures_util::StreamExt;
+use hyper::body::{Bytes, HttpBody};
+use hyper::header::CONTENT_LENGTH;
+use hyper::{Body, HeaderMap};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+// Error yielded by a limited body once it exceeds the limit.
+#[derive(Debug)]
+pub struct BodyTooLarge;
+
+impl fmt::Display for BodyTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "request body exceeds the configured limit")
+    }
+}
+
+impl std::error::Error for BodyTooLarge {}
+
+// Error yielded by a body that didn't arrive in time.
+#[derive(Debug)]
+pub struct BodyTimedOut;
+
+impl fmt::Display for BodyTimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "request body not received within the configured timeout")
+    }
+}
+
+impl std::error::Error for BodyTimedOut {}
+
+// True when the request declares a body longer than `max` bytes.
+pub fn content_length_exceeds(headers: &HeaderMap, max: u64) -> bool {
+    headers
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok())
+        .is_some_and(|len| len > max)
+}
+
+// True when the request declares a body of at most `max` bytes.
+pub fn content_length_at_most(headers: &HeaderMap, max: u64) -> bool {
+    headers
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok())
+        .is_some_and(|len| len <= max)
+}
+
+// Wrap `body` so it errors after `max` bytes. The returned flag is set when
+// that happens, letting the caller answer 413 rather than a generic 502 when
+// the resulting upstream failure surfaces.
+pub fn limit(body: Body, max: u64) -> (Body, Arc<AtomicBool>) {
+    let exceeded = Arc::new(AtomicBool::new(false));
+    let flag = exceeded.clone();
+    let mut seen: u64 = 0;
+    let stream = body.map(move |chunk| {
+        let chunk = chunk.map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)?;
+        seen += chunk.len() as u64;
+        if seen > max {
+            flag.store(true, Ordering::Relaxed);
+            return Err(Box::new(BodyTooLarge) as Box<dyn std::error::Error + Send + Sync>);
+        }
+        Ok(chunk)
+    });
+    (Body::wrap_stream(stream), exceeded)
+}
+
+// Wrap `body` so it errors if it hasn't ended `timeout` from now. The
+// returned flag is set when that happens, letting the caller answer 408
+// rather than a generic 502, as with `limit`.
+pub fn deadline(body: Body, timeout: Duration) -> (Body, Arc<AtomicBool>) {
+    let expired = Arc::new(AtomicBool::new(false));
+    let flag = expired.clone();
+    let deadline = tokio::time::Instant::now() + timeout;
+    let stream = futures_util::stream::unfold(Some(body), move |body| {
+        let flag = flag.clone();
+        async move {
+            let mut body = body?;
+            match tokio::time::timeout_at(deadline, body.data()).await {
+                Ok(Some(chunk)) => {
+                    let chunk = chunk.map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>);
+                    Some((chunk, Some(body)))
+                }
+                Ok(None) => None,
+                Err(_) => {
+                    flag.store(true, Ordering::Relaxed);
+                    Some((Err(Box::new(BodyTimedOut) as Box<dyn std::error::Error + Send + Sync>), None))
+                }
+            }
+        }
+    });
+    (Body::wrap_stream(stream), expired)
+}
+
+// Pass `body` through unchanged while keeping a copy of it, delivered
+// through the receiver once the body has been read to the end. Bodies over
+// `max` bytes, or that fail, are not copied and the sender is dropped.
+pub fn tee(body: Body, max: u64) -> (Body, oneshot::Receiver<Bytes>) {
+    let (tx, rx) = oneshot::channel();
+    // Hyper's client stops reading once it has the declared length, so a
+    // body of known size is complete then, without waiting for its end.
+    let expected = body.size_hint().exact();
+    let copy = Some((Vec::new(), tx));
+    let stream = futures_util::stream::unfold((body, copy), move |(mut body, mut copy)| async move {
+        match body.data().await {
+            Some(Ok(chunk)) => {
+                if let Some((mut buf, tx)) = copy.take() {
+                    if (buf.len() + chunk.len()) as u64 <= max {
+                        buf.extend_from_slice(&chunk);
+                        if expected == Some(buf.len() as u64) {
+                            let _ = tx.send(Bytes::from(buf));
+                        } else {
+                            copy = Some((buf, tx));
+                        }
+                    }
+                }
+                Some((Ok(chunk), (body, copy)))
+            }
+            Some(Err(e)) => Some((Err(e), (body, None))),
+            None => {
+                if let Some((buf, tx)) = copy {
+                    let _ = tx.send(Bytes::from(buf));
+                }
+                None
+            }
+        }
+    });
+    (Body::wrap_stream(stream), rx)
+}
+
+// Hold `body` back for `delay` before reading any of it. Hyper answers a
+// client's `Expect: 100-continue` only once the body is first read, so this
+// gives the upstream time to refuse the request with a final status before
+// the client is told to start sending. The returned flag is set once reading
+// starts.
+pub fn delay_start(body: Body, delay: Duration) -> (Body, Arc<AtomicBool>) {
+    let started = Arc::new(AtomicBool::new(false));
+    let flag = started.clone();
+    let stream = futures_util::stream::once(async move {
+        tokio::time::sleep(delay).await;
+        flag.store(true, Ordering::Relaxed);
+        body
+    })
+    .flatten();
+    (Body::wrap_stream(stream), started)
+}
+
+// Keep `guard` alive until `body` has been fully relayed, or its reader has
+// gone away. A spawned task pumps data and trailers through a channel one
+// chunk at a time, so nothing extra is buffered.
+pub fn hold_until_end<G: Send + 'static>(mut body: Body, guard: G) -> Body {
+    if body.is_end_stream() {
+        return body;
+    }
+    let (mut tx, rx) = Body::channel();
+    tokio::spawn(async move {
+        let _guard = guard;
+        while let Some(chunk) = body.data().await {
+            let Ok(data) = chunk else {
+                tx.abort();
+                return;
+            };
+            if tx.send_data(data).await.is_err() {
+                return;
+            }
+        }
+        if let Ok(Some(trailers)) = body.trailers().await {
+            let _ = tx.send_trailers(trailers).await;
+        }
+    });
+    rx
+}
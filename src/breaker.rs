@@ -0,0 +1,179 @@
+// Circuit breaker in front of the upstream.
+//
+// Closed: requests flow and consecutive failures are counted. After
+// `threshold` of them in a row the breaker opens and requests are refused
+// outright for `cooldown`. Then it goes half-open and lets a single probe
+// request through: success closes it again, failure reopens it for another
+// cooldown. State lives behind one mutex, so every transition is atomic
+// across connections.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    // `probing` is set while the one trial request is in flight.
+    HalfOpen { probing: bool },
+}
+
+pub struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    state: Mutex<State>,
+}
+
+// Permission to send one request. Report how it went with `record`; a
+// permit dropped unreported (e.g. the client went away) counts as neither.
+pub struct Permit<'a> {
+    breaker: &'a CircuitBreaker,
+    probe: bool,
+    recorded: bool,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, cooldown: Duration) -> CircuitBreaker {
+        CircuitBreaker {
+            threshold: threshold.max(1),
+            cooldown,
+            state: Mutex::new(State::Closed { failures: 0 }),
+        }
+    }
+
+    // Ask to send a request; `None` means the breaker is open (or a
+    // half-open probe is already in flight) and the request should be
+    // refused.
+    pub fn acquire(&self) -> Option<Permit<'_>> {
+        let mut state = self.state.lock().unwrap();
+        let probe = match *state {
+            State::Closed { .. } => false,
+            State::Open { until } if Instant::now() < until => return None,
+            State::Open { .. } | State::HalfOpen { probing: false } => {
+                *state = State::HalfOpen { probing: true };
+                true
+            }
+            State::HalfOpen { probing: true } => return None,
+        };
+        Some(Permit {
+            breaker: self,
+            probe,
+            recorded: false,
+        })
+    }
+
+    fn on_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        if matches!(*state, State::HalfOpen { .. }) {
+            info!("upstream recovered, closing circuit breaker");
+        }
+        *state = State::Closed { failures: 0 };
+    }
+
+    fn on_failure(&self, probe: bool) {
+        let mut state = self.state.lock().unwrap();
+        let open = State::Open {
+            until: Instant::now() + self.cooldown,
+        };
+        match *state {
+            State::Closed { failures } if failures + 1 >= self.threshold => {
+                warn!(failures = failures + 1, "opening circuit breaker");
+                *state = open;
+            }
+            State::Closed { failures } => *state = State::Closed { failures: failures + 1 },
+            State::HalfOpen { .. } if probe => {
+                warn!("half-open probe failed, reopening circuit breaker");
+                *state = open;
+            }
+            // A request admitted before the breaker opened; already counted.
+            State::Open { .. } | State::HalfOpen { .. } => {}
+        }
+    }
+
+    fn on_abandoned(&self) {
+        let mut state = self.state.lock().unwrap();
+        if *state == (State::HalfOpen { probing: true }) {
+            *state = State::HalfOpen { probing: false };
+        }
+    }
+}
+
+impl Permit<'_> {
+    pub fn record(mut self, success: bool) {
+        self.recorded = true;
+        if success {
+            self.breaker.on_success();
+        } else {
+            self.breaker.on_failure(self.probe);
+        }
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        if !self.recorded && self.probe {
+            self.breaker.on_abandoned();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread::sleep;
+
+    const COOLDOWN: Duration = Duration::from_millis(20);
+
+    fn state(breaker: &CircuitBreaker) -> State {
+        *breaker.state.lock().unwrap()
+    }
+
+    fn fail(breaker: &CircuitBreaker) {
+        breaker.acquire().expect("breaker lets the request through").record(false);
+    }
+
+    #[test]
+    fn closed_open_half_open_closed() {
+        let breaker = CircuitBreaker::new(2, COOLDOWN);
+        fail(&breaker);
+        assert_eq!(state(&breaker), State::Closed { failures: 1 });
+        // A success resets the count.
+        breaker.acquire().unwrap().record(true);
+        assert_eq!(state(&breaker), State::Closed { failures: 0 });
+
+        fail(&breaker);
+        fail(&breaker);
+        assert!(matches!(state(&breaker), State::Open { .. }));
+        assert!(breaker.acquire().is_none());
+
+        // After the cooldown a single probe goes through.
+        sleep(COOLDOWN);
+        let probe = breaker.acquire().expect("probe admitted");
+        assert_eq!(state(&breaker), State::HalfOpen { probing: true });
+        assert!(breaker.acquire().is_none());
+        probe.record(true);
+        assert_eq!(state(&breaker), State::Closed { failures: 0 });
+        assert!(breaker.acquire().is_some());
+    }
+
+    #[test]
+    fn failed_probe_reopens() {
+        let breaker = CircuitBreaker::new(1, COOLDOWN);
+        fail(&breaker);
+        sleep(COOLDOWN);
+        fail(&breaker);
+        assert!(matches!(state(&breaker), State::Open { .. }));
+        assert!(breaker.acquire().is_none());
+    }
+
+    #[test]
+    fn abandoned_probe_allows_another() {
+        let breaker = CircuitBreaker::new(1, COOLDOWN);
+        fail(&breaker);
+        sleep(COOLDOWN);
+        drop(breaker.acquire().unwrap());
+        assert_eq!(state(&breaker), State::HalfOpen { probing: false });
+        assert!(breaker.acquire().is_some());
+    }
+}
@@ -0,0 +1,47 @@
+// Retry budget (`RETRY_BUDGET_RATIO`), so retries can't snowball during an
+// outage.
+//
+// Every successful upstream response deposits `ratio` of a token and every
+// retry spends a whole one, so over time retries stay within that fraction
+// of successes. A small reserve lets occasional failures be retried while
+// traffic is light, and the balance is capped so a long healthy stretch
+// can't bank an unbounded burst of retries. Once it runs dry, failures are
+// returned as they are until successes refill it.
+
+use std::sync::Mutex;
+
+// Retries available before any success has been seen.
+const RESERVE: f64 = 10.0;
+
+// Most retries that can be saved up.
+const CAP: f64 = 100.0;
+
+pub struct RetryBudget {
+    ratio: f64,
+    tokens: Mutex<f64>,
+}
+
+impl RetryBudget {
+    pub fn new(ratio: f64) -> RetryBudget {
+        RetryBudget {
+            ratio,
+            tokens: Mutex::new(RESERVE),
+        }
+    }
+
+    pub fn deposit(&self) {
+        let mut tokens = self.tokens.lock().unwrap();
+        *tokens = (*tokens + self.ratio).min(CAP);
+    }
+
+    // Spend a token on a retry; false when the budget is exhausted.
+    pub fn withdraw(&self) -> bool {
+        let mut tokens = self.tokens.lock().unwrap();
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
@@ -0,0 +1,506 @@
+// In-memory response cache for GET requests (`CACHE_MAX_ENTRIES`).
+//
+// Responses are keyed on method, path and query, plus the request values of
+// any headers the response `Vary`s on. Requests whose upstream was picked
+// per request (by a selector, or in forward-proxy mode) are also keyed on
+// its scheme and authority, so different upstreams never share entries.
+// Only responses that explicitly allow shared caching are stored:
+// `Cache-Control` must give a `s-maxage` or `max-age`, which sets the TTL,
+// and must not say `no-store`, `no-cache` or `private`. Responses setting
+// cookies are never stored. As RFC 9111 requires of shared caches, a
+// response to a request carrying credentials (`Authorization`, or the
+// configured `AUTH_HEADER`) is only stored when it's also marked `public`,
+// has `s-maxage`, or says `must-revalidate`.
+//
+// Misses stream through to the client as usual while a copy is kept; the
+// copy is only stored once the body has ended cleanly. The cache is bounded
+// by entry count and total bytes, evicting the least recently used entries.
+// Only the most recently stored variant of each key is kept.
+//
+// A client's `If-None-Match` or `If-Modified-Since` matching a fresh 200
+// entry gets a bodiless 304. A stale entry with an `ETag` or
+// `Last-Modified` is revalidated rather than dropped: the request goes
+// upstream with the entry's validators in place of the client's, and a 304
+// from the upstream refreshes the entry, whose body is then served (or a
+// 304, if the client's own validators match).
+
+use hyper::body::{Bytes, HttpBody};
+use hyper::header::{
+    HeaderMap, HeaderName, HeaderValue, AGE, AUTHORIZATION, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_LOCATION, DATE,
+    ETAG, EXPIRES, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, SET_COOKIE, TRANSFER_ENCODING, VARY,
+};
+use hyper::{Body, Method, Request, Response, StatusCode, Uri};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+pub const X_CACHE: HeaderName = HeaderName::from_static("x-cache");
+
+// Method, the per-request upstream's `scheme://authority` (empty for the
+// configured upstreams), and path and query.
+type Key = (Method, String, String);
+
+pub struct ResponseCache {
+    max_entries: usize,
+    max_bytes: usize,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    entries: HashMap<Key, Entry>,
+    // Last-use tick of every entry, oldest first.
+    recency: BTreeMap<u64, Key>,
+    tick: u64,
+    bytes: usize,
+}
+
+struct Entry {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    // Request header values the response varies on, as they were when it
+    // was stored.
+    vary: Vec<(HeaderName, Option<HeaderValue>)>,
+    stored: Instant,
+    ttl: Duration,
+    // The upstream's `Age` when the response was stored.
+    age: u64,
+    tick: u64,
+    size: usize,
+}
+
+pub enum CacheLookup {
+    // A fresh stored response, ready to send.
+    Hit(Response<Body>),
+    // Forward the request, then hand the response to `ResponseCache::store`.
+    Miss(Miss),
+    // The request can't use the cache at all.
+    Bypass,
+}
+
+// How a storable response varies and how long it stays fresh.
+struct Storable {
+    ttl: Duration,
+    age: u64,
+    vary: Vec<(HeaderName, Option<HeaderValue>)>,
+}
+
+// What `store` needs to know about the request that missed.
+pub struct Miss {
+    key: Key,
+    // The client's request headers, before any validators were swapped in.
+    headers: HeaderMap,
+    // The stale entry being revalidated, if any.
+    stale: Option<Stale>,
+    // Whether the request carried credentials.
+    authenticated: bool,
+}
+
+// A copy of a stale entry, served if the upstream says it's still valid.
+struct Stale {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl ResponseCache {
+    pub fn new(max_entries: usize, max_bytes: u64) -> ResponseCache {
+        ResponseCache {
+            max_entries,
+            max_bytes: usize::try_from(max_bytes).unwrap_or(usize::MAX),
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    // Look `req` up. `upstream` is the upstream picked for this request
+    // alone, if any; `auth_header` is where clients send credentials, in
+    // addition to `Authorization`. A stale entry that can be revalidated
+    // gets its validators put on `req` in place of the client's.
+    pub fn lookup(
+        &self,
+        req: &mut Request<Body>,
+        upstream: Option<&Uri>,
+        auth_header: &HeaderName,
+    ) -> CacheLookup {
+        if req.method() != Method::GET {
+            return CacheLookup::Bypass;
+        }
+        let directives = cache_control(req.headers());
+        if directives.no_store {
+            return CacheLookup::Bypass;
+        }
+        let key = (req.method().clone(), upstream.map_or_else(String::new, origin), path_and_query(req));
+        let mut miss = Miss {
+            key: key.clone(),
+            headers: req.headers().clone(),
+            stale: None,
+            authenticated: [&AUTHORIZATION, auth_header].iter().any(|name| req.headers().contains_key(*name)),
+        };
+        // `no-cache` asks for a fresh response, which may still be stored.
+        if directives.no_cache {
+            return CacheLookup::Miss(miss);
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        let entry = match inner.entries.get(&key) {
+            Some(entry) => entry,
+            None => return CacheLookup::Miss(miss),
+        };
+        if !entry.vary.iter().all(|(name, value)| req.headers().get(name) == value.as_ref()) {
+            return CacheLookup::Miss(miss);
+        }
+        let elapsed = entry.stored.elapsed();
+        if elapsed >= entry.ttl {
+            let etag = entry.headers.get(ETAG).cloned();
+            let last_modified = entry.headers.get(LAST_MODIFIED).cloned();
+            if etag.is_none() && last_modified.is_none() {
+                inner.remove(&key);
+                return CacheLookup::Miss(miss);
+            }
+            miss.stale = Some(Stale {
+                status: entry.status,
+                headers: entry.headers.clone(),
+                body: entry.body.clone(),
+            });
+            let headers = req.headers_mut();
+            headers.remove(IF_NONE_MATCH);
+            headers.remove(IF_MODIFIED_SINCE);
+            if let Some(etag) = etag {
+                headers.insert(IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = last_modified {
+                headers.insert(IF_MODIFIED_SINCE, last_modified);
+            }
+            return CacheLookup::Miss(miss);
+        }
+
+        let age = entry.age + elapsed.as_secs();
+        let resp = respond(req.headers(), entry.status, &entry.headers, &entry.body, age, "HIT");
+        inner.touch(&key);
+        CacheLookup::Hit(resp)
+    }
+
+    // Mark `resp` as a miss and, if it may be cached, keep a copy of its
+    // body as it streams so it can be stored once complete. A 304 to a
+    // revalidation refreshes the stale entry and serves it instead.
+    pub fn store(self: &Arc<Self>, miss: Miss, mut resp: Response<Body>) -> Response<Body> {
+        let miss = match miss.stale {
+            Some(stale) if resp.status() == StatusCode::NOT_MODIFIED => {
+                return self.refresh(miss.key, &miss.headers, miss.authenticated, stale, resp.headers());
+            }
+            _ => miss,
+        };
+        resp.headers_mut().insert(X_CACHE, HeaderValue::from_static("MISS"));
+        let storable = self.cacheable(&miss.headers, miss.authenticated, resp.status(), resp.headers());
+        let storable = match storable {
+            Some(storable) => storable,
+            None => return resp,
+        };
+        let (parts, mut body) = resp.into_parts();
+        let mut headers = parts.headers.clone();
+        headers.remove(X_CACHE);
+        let status = parts.status;
+
+        let (mut tx, rx) = Body::channel();
+        let cache = self.clone();
+        tokio::spawn(async move {
+            let mut copy = Vec::new();
+            let mut complete = true;
+            while let Some(chunk) = body.data().await {
+                let Ok(data) = chunk else {
+                    tx.abort();
+                    return;
+                };
+                if complete && copy.len() + data.len() <= cache.max_bytes {
+                    copy.extend_from_slice(&data);
+                } else {
+                    complete = false;
+                    copy = Vec::new();
+                }
+                if tx.send_data(data).await.is_err() {
+                    return;
+                }
+            }
+            // Trailers aren't stored, so a response with any isn't either.
+            match body.trailers().await {
+                Ok(None) => {}
+                Ok(Some(trailers)) => {
+                    let _ = tx.send_trailers(trailers).await;
+                    return;
+                }
+                Err(_) => return,
+            }
+            if complete {
+                cache.insert(miss.key, status, headers, Bytes::from(copy), storable);
+            }
+        });
+        Response::from_parts(parts, rx)
+    }
+
+    // Update a revalidated entry with the headers of the upstream's 304
+    // (RFC 9111 section 4.3.4) and answer the client from it.
+    fn refresh(
+        &self,
+        key: Key,
+        req: &HeaderMap,
+        authenticated: bool,
+        stale: Stale,
+        not_modified: &HeaderMap,
+    ) -> Response<Body> {
+        let mut headers = stale.headers;
+        for name in not_modified.keys() {
+            if *name != CONTENT_LENGTH && *name != TRANSFER_ENCODING {
+                headers.remove(name);
+            }
+        }
+        for (name, value) in not_modified {
+            if *name != CONTENT_LENGTH && *name != TRANSFER_ENCODING {
+                headers.append(name, value.clone());
+            }
+        }
+        let age = match self.cacheable(req, authenticated, stale.status, &headers) {
+            Some(storable) => {
+                let age = storable.age;
+                self.insert(key, stale.status, headers.clone(), stale.body.clone(), storable);
+                age
+            }
+            None => {
+                self.inner.lock().unwrap().remove(&key);
+                0
+            }
+        };
+        respond(req, stale.status, &headers, &stale.body, age, "REVALIDATED")
+    }
+
+    // How a response would be stored, or `None` if it may not be.
+    // `authenticated` says whether the request carried credentials.
+    fn cacheable(
+        &self,
+        req: &HeaderMap,
+        authenticated: bool,
+        status: StatusCode,
+        headers: &HeaderMap,
+    ) -> Option<Storable> {
+        if !matches!(status.as_u16(), 200 | 203 | 301 | 308 | 404 | 410) {
+            return None;
+        }
+        if headers.contains_key(SET_COOKIE) {
+            return None;
+        }
+        let directives = cache_control(headers);
+        if directives.no_store || directives.no_cache || directives.private {
+            return None;
+        }
+        if authenticated
+            && !(directives.public || directives.s_maxage.is_some() || directives.must_revalidate)
+        {
+            return None;
+        }
+        let max_age = directives.s_maxage.or(directives.max_age)?;
+        let age = headers
+            .get(AGE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.trim().parse::<u64>().ok())
+            .unwrap_or(0);
+        if max_age <= age {
+            return None;
+        }
+        let length = headers
+            .get(CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok());
+        if length.is_some_and(|len| len > self.max_bytes as u64) {
+            return None;
+        }
+
+        let mut vary = Vec::new();
+        for field in headers
+            .get_all(VARY)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+        {
+            let field = field.trim();
+            if field == "*" {
+                return None;
+            }
+            let name = HeaderName::from_bytes(field.as_bytes()).ok()?;
+            let value = req.get(&name).cloned();
+            vary.push((name, value));
+        }
+        Some(Storable {
+            ttl: Duration::from_secs(max_age - age),
+            age,
+            vary,
+        })
+    }
+
+    fn insert(&self, key: Key, status: StatusCode, headers: HeaderMap, body: Bytes, storable: Storable) {
+        let size = body.len()
+            + key.1.len()
+            + key.2.len()
+            + headers
+                .iter()
+                .map(|(name, value)| name.as_str().len() + value.len())
+                .sum::<usize>();
+        if size > self.max_bytes {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        inner.remove(&key);
+        while inner.entries.len() >= self.max_entries || inner.bytes + size > self.max_bytes {
+            let oldest = match inner.recency.first_key_value() {
+                Some((_, key)) => key.clone(),
+                None => break,
+            };
+            inner.remove(&oldest);
+        }
+        inner.tick += 1;
+        let tick = inner.tick;
+        inner.recency.insert(tick, key.clone());
+        inner.bytes += size;
+        inner.entries.insert(
+            key,
+            Entry {
+                status,
+                headers,
+                body,
+                vary: storable.vary,
+                stored: Instant::now(),
+                ttl: storable.ttl,
+                age: storable.age,
+                tick,
+                size,
+            },
+        );
+    }
+}
+
+impl Inner {
+    fn remove(&mut self, key: &Key) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.recency.remove(&entry.tick);
+            self.bytes -= entry.size;
+        }
+    }
+
+    // Mark `key` as the most recently used entry.
+    fn touch(&mut self, key: &Key) {
+        self.tick += 1;
+        let tick = self.tick;
+        if let Some(entry) = self.entries.get_mut(key) {
+            self.recency.remove(&entry.tick);
+            entry.tick = tick;
+            self.recency.insert(tick, key.clone());
+        }
+    }
+}
+
+#[derive(Default)]
+struct Directives {
+    no_store: bool,
+    no_cache: bool,
+    private: bool,
+    public: bool,
+    must_revalidate: bool,
+    max_age: Option<u64>,
+    s_maxage: Option<u64>,
+}
+
+fn cache_control(headers: &HeaderMap) -> Directives {
+    let mut directives = Directives::default();
+    for directive in headers
+        .get_all(CACHE_CONTROL)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+    {
+        let (name, value) = match directive.split_once('=') {
+            Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+            None => (directive.trim(), None),
+        };
+        let seconds = value.and_then(|v| v.parse::<u64>().ok());
+        match name.to_ascii_lowercase().as_str() {
+            "no-store" => directives.no_store = true,
+            "no-cache" => directives.no_cache = true,
+            "private" => directives.private = true,
+            "public" => directives.public = true,
+            "must-revalidate" => directives.must_revalidate = true,
+            "max-age" => directives.max_age = seconds,
+            "s-maxage" => directives.s_maxage = seconds,
+            _ => {}
+        }
+    }
+    directives
+}
+
+// A stored response as sent to a client with request headers `req`: a 304
+// when the client's validators show its copy is current, the full response
+// otherwise. `x_cache` says how the cache was used.
+fn respond(
+    req: &HeaderMap,
+    status: StatusCode,
+    headers: &HeaderMap,
+    body: &Bytes,
+    age: u64,
+    x_cache: &'static str,
+) -> Response<Body> {
+    let mut resp = if status == StatusCode::OK && not_modified(req, headers) {
+        let mut resp = Response::new(Body::empty());
+        *resp.status_mut() = StatusCode::NOT_MODIFIED;
+        for name in [CACHE_CONTROL, CONTENT_LOCATION, DATE, ETAG, EXPIRES, LAST_MODIFIED, VARY] {
+            for value in headers.get_all(&name) {
+                resp.headers_mut().append(name.clone(), value.clone());
+            }
+        }
+        resp
+    } else {
+        let mut resp = Response::new(Body::from(body.clone()));
+        *resp.status_mut() = status;
+        *resp.headers_mut() = headers.clone();
+        resp
+    };
+    resp.headers_mut().insert(AGE, age.into());
+    resp.headers_mut().insert(X_CACHE, HeaderValue::from_static(x_cache));
+    resp
+}
+
+// Whether the client's `If-None-Match`, or failing that its
+// `If-Modified-Since`, shows that its copy of a response with `headers` is
+// current (RFC 9110 section 13.2.2). ETags compare weakly.
+fn not_modified(req: &HeaderMap, headers: &HeaderMap) -> bool {
+    if let Some(if_none_match) = req.get(IF_NONE_MATCH).and_then(|v| v.to_str().ok()) {
+        let etag = headers.get(ETAG).and_then(|v| v.to_str().ok()).map(weak);
+        return if_none_match
+            .split(',')
+            .map(str::trim)
+            .any(|tag| tag == "*" || etag.is_some_and(|etag| weak(tag) == etag));
+    }
+    let since = req.get(IF_MODIFIED_SINCE).and_then(http_date);
+    let modified = headers.get(LAST_MODIFIED).and_then(http_date);
+    matches!((since, modified), (Some(since), Some(modified)) if modified <= since)
+}
+
+// An entity tag without its weakness marker.
+fn weak(tag: &str) -> &str {
+    tag.strip_prefix("W/").unwrap_or(tag)
+}
+
+fn http_date(value: &HeaderValue) -> Option<SystemTime> {
+    httpdate::parse_http_date(value.to_str().ok()?).ok()
+}
+
+fn origin(upstream: &Uri) -> String {
+    format!(
+        "{}://{}",
+        upstream.scheme_str().unwrap_or_default(),
+        upstream.authority().map_or("", |a| a.as_str())
+    )
+}
+
+fn path_and_query(req: &Request<Body>) -> String {
+    req.uri()
+        .path_and_query()
+        .map_or_else(|| "/".to_string(), |pq| pq.as_str().to_string())
+}
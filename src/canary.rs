@@ -0,0 +1,36 @@
+// Canary routing for the default upstream.
+//
+// A configured share of the requests bound for the default upstream goes to
+// a separate canary upstream instead, decided by a random draw per request.
+// A request carrying the canary header set to `true` always goes to the
+// canary, so it can be tested on demand.
+
+use crate::balancer::{draw, roll, Balancer};
+use hyper::header::{HeaderMap, HeaderName};
+
+pub struct Canary {
+    upstream: Balancer,
+    // Share of requests sent to the canary, 0–100.
+    percent: f64,
+    header: HeaderName,
+}
+
+impl Canary {
+    pub fn new(upstream: Balancer, percent: f64, header: HeaderName) -> Canary {
+        Canary {
+            upstream,
+            percent,
+            header,
+        }
+    }
+
+    // The canary upstream when this request should go there, otherwise
+    // `None` to keep the primary.
+    pub fn select(&self, headers: &HeaderMap) -> Option<&Balancer> {
+        let forced = headers
+            .get(&self.header)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.trim().eq_ignore_ascii_case("true"));
+        (forced || draw(self.percent, roll())).then_some(&self.upstream)
+    }
+}
@@ -0,0 +1,252 @@
+// Response compression (`ENABLE_COMPRESSION`) and decompression
+// (`ENABLE_DECOMPRESSION`).
+//
+// Upstream responses are gzip- or deflate-encoded on the fly when the client
+// accepts it, the upstream hasn't encoded them already, and the content type
+// is worth compressing. Responses with a known length under the threshold
+// are left alone. In the other direction, a gzip or deflate response is
+// decoded for a client that didn't say it accepts that encoding. Either way
+// bodies stream through the codec chunk by chunk and are never buffered
+// whole.
+
+use async_compression::tokio::bufread::{GzipDecoder, GzipEncoder, ZlibDecoder, ZlibEncoder};
+use futures_util::TryStreamExt;
+use hyper::header::{
+    HeaderMap, HeaderValue, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, ETAG, VARY,
+};
+use hyper::{Body, Response, StatusCode};
+use std::io;
+use tokio_util::io::{ReaderStream, StreamReader};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    // HTTP's `deflate` is the zlib format, not raw deflate.
+    Deflate,
+}
+
+impl Encoding {
+    fn header_value(self) -> HeaderValue {
+        match self {
+            Encoding::Gzip => HeaderValue::from_static("gzip"),
+            Encoding::Deflate => HeaderValue::from_static("deflate"),
+        }
+    }
+
+    // The encoding named by a Content-Encoding value, if it's one we handle.
+    fn from_header(value: &HeaderValue) -> Option<Encoding> {
+        let value = value.to_str().ok()?.trim();
+        if value.eq_ignore_ascii_case("gzip") || value.eq_ignore_ascii_case("x-gzip") {
+            Some(Encoding::Gzip)
+        } else if value.eq_ignore_ascii_case("deflate") {
+            Some(Encoding::Deflate)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Compression {
+    // Skip bodies whose Content-Length is below this.
+    min_bytes: u64,
+}
+
+impl Compression {
+    pub fn new(min_bytes: u64) -> Compression {
+        Compression { min_bytes }
+    }
+
+    // Compress `resp` for a client that sent `accept_encoding`, if it's
+    // worth doing.
+    pub fn apply(&self, accept_encoding: Option<&HeaderValue>, resp: &mut Response<Body>) {
+        let status = resp.status();
+        if status.is_informational()
+            || status == StatusCode::NO_CONTENT
+            || status == StatusCode::NOT_MODIFIED
+            || status == StatusCode::PARTIAL_CONTENT
+        {
+            return;
+        }
+        let headers = resp.headers();
+        if headers.contains_key(CONTENT_ENCODING) || !compressible(headers) || no_transform(headers) {
+            return;
+        }
+        // Whether or not this client gets it compressed, caches must know
+        // the representation depends on Accept-Encoding.
+        add_vary(resp.headers_mut());
+        if content_length(resp.headers()).is_some_and(|len| len < self.min_bytes) {
+            return;
+        }
+        let encoding = match negotiate(accept_encoding) {
+            Some(encoding) => encoding,
+            None => return,
+        };
+
+        let headers = resp.headers_mut();
+        headers.insert(CONTENT_ENCODING, encoding.header_value());
+        headers.remove(CONTENT_LENGTH);
+        weaken_etag(headers);
+        let body = std::mem::take(resp.body_mut());
+        *resp.body_mut() = encode(body, encoding);
+    }
+}
+
+// Decode a gzip or deflate `resp` when the client's `accept_encoding`
+// doesn't allow that encoding. Other encodings, and stacked ones, are passed
+// through as they are.
+pub fn decode_unaccepted(accept_encoding: Option<&HeaderValue>, resp: &mut Response<Body>) {
+    let mut values = resp.headers().get_all(CONTENT_ENCODING).iter();
+    let encoding = match (values.next().and_then(Encoding::from_header), values.next()) {
+        (Some(encoding), None) => encoding,
+        _ => return,
+    };
+    if quality(accept_encoding, encoding) > 0.0 {
+        return;
+    }
+    decode_as(resp, encoding);
+}
+
+// Decode a gzip or deflate `resp` whatever the client accepts. Returns
+// whether the body is now unencoded; false for other or stacked encodings.
+pub fn decode(resp: &mut Response<Body>) -> bool {
+    let mut values = resp.headers().get_all(CONTENT_ENCODING).iter();
+    match (values.next(), values.next()) {
+        (None, _) => true,
+        (Some(value), None) => match Encoding::from_header(value) {
+            Some(encoding) => {
+                decode_as(resp, encoding);
+                true
+            }
+            None => false,
+        },
+        _ => false,
+    }
+}
+
+fn decode_as(resp: &mut Response<Body>, encoding: Encoding) {
+    let headers = resp.headers_mut();
+    headers.remove(CONTENT_ENCODING);
+    headers.remove(CONTENT_LENGTH);
+    weaken_etag(headers);
+    add_vary(headers);
+    let body = std::mem::take(resp.body_mut());
+    *resp.body_mut() = decode_body(body, encoding);
+}
+
+// The preferred encoding the client accepts, gzip winning ties.
+pub fn negotiate(accept_encoding: Option<&HeaderValue>) -> Option<Encoding> {
+    let gzip = quality(accept_encoding, Encoding::Gzip);
+    let deflate = quality(accept_encoding, Encoding::Deflate);
+    if gzip > 0.0 && gzip >= deflate {
+        Some(Encoding::Gzip)
+    } else if deflate > 0.0 {
+        Some(Encoding::Deflate)
+    } else {
+        None
+    }
+}
+
+// The client's preference for `encoding`, from 0 (not acceptable) to 1. A
+// missing header accepts no encodings; `*` covers ones not listed by name.
+fn quality(accept_encoding: Option<&HeaderValue>, encoding: Encoding) -> f32 {
+    let header = match accept_encoding.and_then(|v| v.to_str().ok()) {
+        Some(header) => header,
+        None => return 0.0,
+    };
+    let (mut named, mut any) = (None, None);
+    for item in header.split(',') {
+        let mut params = item.split(';');
+        let name = params.next().unwrap_or("").trim().to_ascii_lowercase();
+        let q = params
+            .filter_map(|p| p.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        match (name.as_str(), encoding) {
+            ("gzip" | "x-gzip", Encoding::Gzip) | ("deflate", Encoding::Deflate) => named = Some(q),
+            ("*", _) => any = Some(q),
+            _ => {}
+        }
+    }
+    named.or(any).unwrap_or(0.0)
+}
+
+// Text-like content types; images, video and archives are already
+// compressed and only get bigger.
+fn compressible(headers: &HeaderMap) -> bool {
+    let content_type = match headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok()) {
+        Some(value) => value.split(';').next().unwrap_or("").trim().to_ascii_lowercase(),
+        None => return false,
+    };
+    content_type.starts_with("text/")
+        || content_type.ends_with("+json")
+        || content_type.ends_with("+xml")
+        || matches!(
+            content_type.as_str(),
+            "application/json"
+                | "application/javascript"
+                | "application/xml"
+                | "application/x-www-form-urlencoded"
+                | "application/wasm"
+                | "image/svg+xml"
+        )
+}
+
+fn no_transform(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(CACHE_CONTROL)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|directive| directive.trim().eq_ignore_ascii_case("no-transform"))
+}
+
+fn content_length(headers: &HeaderMap) -> Option<u64> {
+    headers.get(CONTENT_LENGTH)?.to_str().ok()?.parse().ok()
+}
+
+fn add_vary(headers: &mut HeaderMap) {
+    let listed = headers
+        .get_all(VARY)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|field| {
+            let field = field.trim();
+            field == "*" || field.eq_ignore_ascii_case("accept-encoding")
+        });
+    if !listed {
+        headers.append(VARY, HeaderValue::from_static("accept-encoding"));
+    }
+}
+
+// The re-encoded bytes differ, so a strong validator no longer holds.
+pub fn weaken_etag(headers: &mut HeaderMap) {
+    let weak = match headers.get(ETAG) {
+        Some(etag) if !etag.as_bytes().starts_with(b"W/") => {
+            HeaderValue::from_bytes(&[b"W/", etag.as_bytes()].concat())
+        }
+        _ => return,
+    };
+    if let Ok(weak) = weak {
+        headers.insert(ETAG, weak);
+    }
+}
+
+fn encode(body: Body, encoding: Encoding) -> Body {
+    let reader = StreamReader::new(body.map_err(io::Error::other));
+    match encoding {
+        Encoding::Gzip => Body::wrap_stream(ReaderStream::new(GzipEncoder::new(reader))),
+        Encoding::Deflate => Body::wrap_stream(ReaderStream::new(ZlibEncoder::new(reader))),
+    }
+}
+
+// A body that fails to decode ends with an error, cutting the response off
+// rather than passing on garbage.
+fn decode_body(body: Body, encoding: Encoding) -> Body {
+    let reader = StreamReader::new(body.map_err(io::Error::other));
+    match encoding {
+        Encoding::Gzip => Body::wrap_stream(ReaderStream::new(GzipDecoder::new(reader))),
+        Encoding::Deflate => Body::wrap_stream(ReaderStream::new(ZlibDecoder::new(reader))),
+    }
+}
@@ -0,0 +1,39 @@
+// Global caps on concurrent proxied requests (`MAX_CONCURRENT_REQUESTS`) and
+// on requests outstanding upstream (`MAX_OUTSTANDING_UPSTREAM`).
+//
+// Each forwarded request holds a semaphore permit from just before it's sent
+// upstream until its response body has been fully relayed, or for the
+// outstanding cap until its response headers arrive. When every permit is
+// taken, new requests either fail straight away or wait up to the
+// configured queue time for one to free up.
+
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+pub struct ConcurrencyLimit {
+    permits: Arc<Semaphore>,
+    // How long a request may wait for a permit; `None` fails fast.
+    queue_timeout: Option<Duration>,
+}
+
+impl ConcurrencyLimit {
+    pub fn new(max: usize, queue_timeout: Option<Duration>) -> ConcurrencyLimit {
+        ConcurrencyLimit {
+            permits: Arc::new(Semaphore::new(max)),
+            queue_timeout,
+        }
+    }
+
+    // A permit for one request, or `None` if none became free in time.
+    pub async fn acquire(&self) -> Option<OwnedSemaphorePermit> {
+        let permits = self.permits.clone();
+        match self.queue_timeout {
+            None => permits.try_acquire_owned().ok(),
+            Some(wait) => tokio::time::timeout(wait, permits.acquire_owned())
+                .await
+                .ok()
+                .and_then(Result::ok),
+        }
+    }
+}
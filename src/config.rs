@@ -0,0 +1,1373 @@
+// Proxy configuration.
+//
+// Settings come from an optional TOML file (given by the caller, e.g. the
+// binary's `--config path.toml`, or named in the `CONFIG_FILE` env var) and
+// are then overridden by environment variables, so deployments configured
+// purely through the environment keep working.
+// Every problem is reported as a `ConfigError` at startup.
+
+use crate::auth::{AuthMode, AuthScheme, BasicCredentials};
+use crate::balancer::StickyKey;
+use crate::proxyproto::ProxyProtocol;
+use crate::error::ErrorFormat;
+use crate::headers;
+use crate::ipfilter::CidrList;
+use crate::jwt::JwtKey;
+use crate::rules::HeaderRule;
+use hyper::header::{HeaderName, HeaderValue};
+use jsonwebtoken::Algorithm;
+use hyper::http::method::InvalidMethod;
+use hyper::http::uri::PathAndQuery;
+use hyper::{Method, StatusCode, Uri};
+use serde::{Deserialize, Deserializer};
+use std::collections::{BTreeMap, HashSet};
+use std::env;
+use std::fmt;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+#[derive(Debug)]
+pub enum ConfigError {
+    // The config file could not be read.
+    Read { path: PathBuf, source: std::io::Error },
+    // The auth token file could not be read.
+    ReadTokenFile { path: PathBuf, source: std::io::Error },
+    // The config file is not valid TOML or doesn't match the schema.
+    Parse { path: PathBuf, source: toml::de::Error },
+    // A `${VAR}` reference in the config file can't be filled in.
+    Interpolate { path: PathBuf, reason: String },
+    // An environment variable holds a value that doesn't parse.
+    InvalidEnv { name: String, value: String, reason: String },
+    // A required setting was provided neither in the file nor the environment.
+    Missing(&'static str),
+    // A setting has a value outside its allowed range.
+    Invalid(&'static str),
+    // A URL setting the proxy sends requests to is unusable.
+    InvalidUrl { setting: &'static str, reason: String },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read { path, source } => {
+                write!(f, "cannot read config file {}: {}", path.display(), source)
+            }
+            ConfigError::ReadTokenFile { path, source } => {
+                write!(f, "cannot read auth token file {}: {}", path.display(), source)
+            }
+            ConfigError::Parse { path, source } => {
+                write!(f, "invalid config file {}: {}", path.display(), source)
+            }
+            ConfigError::Interpolate { path, reason } => {
+                write!(f, "invalid config file {}: {}", path.display(), reason)
+            }
+            ConfigError::InvalidEnv { name, value, reason } => {
+                write!(f, "invalid value {:?} for {}: {}", value, name, reason)
+            }
+            ConfigError::Missing(what) => write!(f, "missing required setting: {}", what),
+            ConfigError::Invalid(msg) => write!(f, "{}", msg),
+            ConfigError::InvalidUrl { setting, reason } => write!(f, "invalid {}: {}", setting, reason),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+// A `[[routes]]` entry: requests under `prefix` go to `upstream`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteConfig {
+    pub prefix: String,
+    pub upstream: UpstreamList,
+    #[serde(default)]
+    pub strip_prefix: bool,
+    // Tokens accepted on this route instead of the global ones.
+    #[serde(default)]
+    pub auth_tokens: Vec<String>,
+    // Serve this route without any auth check.
+    #[serde(default)]
+    pub public: bool,
+    // Upstream response deadline for this route instead of
+    // `upstream_timeout_ms`.
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+}
+
+// One or more replicas of an upstream: a comma-separated string
+// (`"http://a:80,http://b:80"`) or, in the config file, an array. A replica
+// may carry a weight (`http://a:80;weight=3`, default 1); weight 0 drains it.
+#[derive(Clone, Debug)]
+pub struct UpstreamList {
+    pub uris: Vec<Uri>,
+    // One per URI, in the same order.
+    pub weights: Vec<u32>,
+}
+
+impl FromStr for UpstreamList {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<UpstreamList, String> {
+        let (uris, weights) = split_list(s)
+            .iter()
+            .map(|u| weighted_upstream(u))
+            .collect::<Result<(Vec<_>, Vec<_>), _>>()?;
+        if uris.is_empty() {
+            return Err("no upstream URIs given".to_string());
+        }
+        if weights.iter().all(|&w| w == 0) {
+            return Err(format!("{}: every replica has weight 0", s));
+        }
+        Ok(UpstreamList { uris, weights })
+    }
+}
+
+// A replica with its optional `;weight=N` suffix.
+fn weighted_upstream(s: &str) -> Result<(Uri, u32), String> {
+    let Some((uri, param)) = s.split_once(';') else {
+        return Ok((upstream_uri(s)?, 1));
+    };
+    let weight = param
+        .trim()
+        .strip_prefix("weight=")
+        .and_then(|w| w.parse().ok())
+        .ok_or_else(|| format!("{}: expected `;weight=N` after the URI", s))?;
+    Ok((upstream_uri(uri.trim())?, weight))
+}
+
+impl<'de> Deserialize<'de> for UpstreamList {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<UpstreamList, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            One(String),
+            Many(Vec<String>),
+        }
+        let joined = match Repr::deserialize(deserializer)? {
+            Repr::One(s) => s,
+            Repr::Many(v) => v.join(","),
+        };
+        joined.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+// Where the proxy listens: `host:port` for TCP, or `unix:/path` for a Unix
+// domain socket.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BindAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for BindAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<BindAddr, String> {
+        match s.strip_prefix("unix:") {
+            Some("") => Err("unix: needs a socket path".to_string()),
+            Some(path) => Ok(BindAddr::Unix(PathBuf::from(path))),
+            None => s.parse().map(BindAddr::Tcp).map_err(|e| format!("{}", e)),
+        }
+    }
+}
+
+impl fmt::Display for BindAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BindAddr::Tcp(addr) => write!(f, "{}", addr),
+            BindAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for BindAddr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<BindAddr, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+// One or more listen addresses: a comma-separated string
+// (`"0.0.0.0:8080,127.0.0.1:8081"`) or, in the config file, an array.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BindAddrs(pub Vec<BindAddr>);
+
+impl FromStr for BindAddrs {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<BindAddrs, String> {
+        let addrs = split_list(s)
+            .iter()
+            .map(|a| a.parse::<BindAddr>().map_err(|e| format!("{}: {}", a, e)))
+            .collect::<Result<Vec<_>, _>>()?;
+        if addrs.is_empty() {
+            return Err("no listen addresses given".to_string());
+        }
+        Ok(BindAddrs(addrs))
+    }
+}
+
+impl<'de> Deserialize<'de> for BindAddrs {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<BindAddrs, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            One(String),
+            Many(Vec<String>),
+        }
+        let joined = match Repr::deserialize(deserializer)? {
+            Repr::One(s) => s,
+            Repr::Many(v) => v.join(","),
+        };
+        joined.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+// Unix socket permission bits, written in octal (`"660"` or `"0o660"`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SocketMode(pub u32);
+
+impl FromStr for SocketMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SocketMode, String> {
+        let digits = s.strip_prefix("0o").unwrap_or(s);
+        match u32::from_str_radix(digits, 8) {
+            Ok(mode) if mode <= 0o777 => Ok(SocketMode(mode)),
+            _ => Err(format!("`{}` is not an octal permission mode like 660", s)),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for SocketMode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<SocketMode, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    // `token` (the default) checks `auth_token`/`auth_tokens`; `basic` checks
+    // `basic_auth_user`/`basic_auth_pass`; `jwt` verifies Bearer JWTs with
+    // `jwt_secret` or `jwt_public_key_path`.
+    pub auth_mode: AuthMode,
+    pub auth_token: Option<String>,
+    pub auth_tokens: Vec<String>,
+    // File holding the token, e.g. a mounted Docker/Kubernetes secret. Read
+    // on every load, including SIGHUP reloads, and replaces `auth_token`.
+    pub auth_token_file: Option<PathBuf>,
+    pub auth_scheme: AuthScheme,
+    // Header carrying the credentials. A header other than Authorization
+    // also accepts a bare token, whatever `auth_scheme` says.
+    pub auth_header: String,
+    // Remove the client's credentials (`auth_header`) before forwarding, so
+    // the upstream never sees them.
+    pub strip_auth_header: bool,
+    // `Authorization` value sent to upstreams instead, e.g. the upstream's
+    // own `Bearer` token.
+    pub upstream_auth_header: Option<String>,
+    // Status and body for requests with missing or invalid credentials; the
+    // body defaults to a short message naming the problem.
+    pub auth_deny_status: u16,
+    pub auth_deny_body: Option<String>,
+    pub basic_auth_user: Option<String>,
+    pub basic_auth_pass: Option<String>,
+    pub jwt_secret: Option<String>,
+    pub jwt_public_key_path: Option<PathBuf>,
+    // Defaults to HS256 with a secret and RS256 with a public key.
+    pub jwt_algorithm: Option<Algorithm>,
+    pub jwt_audience: Option<String>,
+    pub jwt_issuer: Option<String>,
+    // Forward the `sub` claim to the upstream as `X-Auth-Subject`.
+    pub jwt_forward_subject: bool,
+    // Introspect mode: tokens are approved by a 200 from `introspect_url`.
+    // Approvals are cached for `introspect_cache_secs` when set. When the
+    // service is unavailable requests get `introspect_failure_status` (401
+    // or 503).
+    pub introspect_url: Option<String>,
+    pub introspect_timeout_ms: u64,
+    pub introspect_cache_secs: Option<u64>,
+    pub introspect_failure_status: u16,
+    // Default upstream for requests that match no route.
+    pub upstream_url: Option<UpstreamList>,
+    // SRV record whose targets replace `upstream_url` when set, looked up
+    // again every `upstream_srv_refresh_secs`.
+    pub upstream_srv: Option<String>,
+    pub upstream_srv_refresh_secs: u64,
+    // Send `canary_percent` (0–100) of the default upstream's requests to
+    // `canary_url` instead; requests with `canary_header: true` always go.
+    pub canary_url: Option<UpstreamList>,
+    pub canary_percent: f64,
+    pub canary_header: String,
+    // Also send a copy of `mirror_percent` (0–100) of proxied requests to
+    // `mirror_url`, ignoring its responses. Bodies over
+    // `mirror_max_body_bytes` aren't mirrored.
+    pub mirror_url: Option<String>,
+    pub mirror_percent: f64,
+    pub mirror_max_body_bytes: u64,
+    // Path-prefix routes; only settable from the config file.
+    pub routes: Vec<RouteConfig>,
+    // Body, and its content type, of the 404 for requests matching no route
+    // when there's no default upstream to catch them.
+    pub no_route_body: Option<String>,
+    pub no_route_content_type: String,
+    // Files served in place of the proxy's own error bodies, by status code
+    // (`ERROR_PAGE_502=/srv/502.html`; `[error_pages]` in the config file).
+    pub error_pages: BTreeMap<String, PathBuf>,
+    // Prepended to the path of every request sent upstream, after any route
+    // prefix has been stripped.
+    pub upstream_path_prefix: Option<String>,
+    // Rewrites applied to every request sent upstream; file only.
+    pub request_headers: Vec<HeaderRule>,
+    // Rewrites applied to every response sent to clients; file only.
+    pub response_headers: Vec<HeaderRule>,
+    // Append the proxy to the `Via` header of forwarded requests and of the
+    // responses relayed back.
+    pub add_via_header: bool,
+    // Remove the upstream's `Server` header, which often names its software
+    // and version.
+    pub strip_server_header: bool,
+    // Accept any certificate from HTTPS upstreams (self-signed backends).
+    pub upstream_insecure_skip_verify: bool,
+    // Use HTTP/2 for every upstream connection (e.g. gRPC backends).
+    pub upstream_http2: bool,
+    // Every address is served the same way.
+    pub bind_addr: BindAddrs,
+    // Accept HTTP/2 from clients as well as HTTP/1.1.
+    pub http2: bool,
+    // Reuse HTTP/1.1 client connections for further requests; when off,
+    // every response closes its connection.
+    pub keep_alive: bool,
+    // Close an HTTP/1.1 client connection after this many requests.
+    pub max_requests_per_conn: Option<u64>,
+    // Close a client connection that sends nothing for this long while no
+    // request is being answered, whether it's new or kept alive.
+    pub client_idle_timeout_secs: Option<u64>,
+    // Expect a PROXY protocol header of this version on every accepted
+    // connection and take the client address from it.
+    pub proxy_protocol: Option<ProxyProtocol>,
+    // Refuse connections from a client IP that already has this many open.
+    pub max_conns_per_ip: Option<usize>,
+    // Permissions for a Unix socket listener; the umask applies when unset.
+    pub unix_socket_mode: Option<SocketMode>,
+    // TCP listener tuning. Nodelay and keepalive also apply to upstream
+    // connections.
+    pub reuse_addr: bool,
+    pub listen_backlog: u32,
+    pub tcp_nodelay: bool,
+    pub tcp_keepalive_secs: Option<u64>,
+    // PEM certificate chain and private key; when both are set the listener
+    // serves HTTPS.
+    pub tls_cert_path: Option<PathBuf>,
+    pub tls_key_path: Option<PathBuf>,
+    pub metrics_addr: SocketAddr,
+    // Write the access log to this file instead of stdout. It is rotated when
+    // it would grow past `access_log_max_bytes`, keeping
+    // `access_log_max_files` old copies.
+    pub access_log_file: Option<PathBuf>,
+    pub access_log_max_bytes: u64,
+    pub access_log_max_files: usize,
+    // Upstream latency above which a request counts as violating the SLO,
+    // tagged in the access log and metrics. Untagged when unset.
+    pub slo_threshold_ms: Option<u64>,
+    // Fraction (0.0–1.0) of requests without a W3C `traceparent` that get
+    // a new, sampled one; trace context handling is off when unset.
+    pub trace_sample_rate: Option<f64>,
+    pub pool_idle_timeout_secs: u64,
+    pub pool_max_idle_per_host: usize,
+    // Reuse upstream connections; when off, each request opens a new one.
+    pub upstream_keep_alive: bool,
+    pub upstream_timeout_ms: u64,
+    // Limit on establishing an upstream TCP connection; otherwise only
+    // `upstream_timeout_ms` applies.
+    pub connect_timeout_ms: Option<u64>,
+    // Hold a body sent with `Expect: 100-continue` back this long so the
+    // upstream can refuse it first; off when unset, so the client gets its
+    // 100 as soon as the request is forwarded.
+    pub expect_continue_timeout_ms: Option<u64>,
+    pub upstream_max_retries: u32,
+    pub upstream_retry_backoff_ms: u64,
+    // Retries allowed per successful upstream response, across all
+    // requests; unbudgeted when unset.
+    pub retry_budget_ratio: Option<f64>,
+    // Buffer request bodies up to this size so they can be retried.
+    pub retry_buffer_bytes: Option<u64>,
+    // Spool larger request bodies, up to this size, to a file in
+    // `spool_dir` (the system temp dir by default) so they can be retried.
+    pub max_spool_bytes: Option<u64>,
+    pub spool_dir: Option<PathBuf>,
+    // Per-client-IP token bucket; disabled unless a rate is set. The burst
+    // defaults to one second's worth of requests.
+    pub rate_limit_rps: Option<f64>,
+    pub rate_limit_burst: Option<u32>,
+    // Requests with larger bodies get 413; unlimited when unset.
+    pub max_body_bytes: Option<u64>,
+    // Requests whose body hasn't fully arrived this long after their headers
+    // get 408; no limit when unset.
+    pub request_body_timeout_ms: Option<u64>,
+    // Requests with more headers, or more header bytes (names, values and
+    // separators), get 431; Hyper's own limits apply when unset.
+    pub max_headers: Option<usize>,
+    pub max_header_bytes: Option<usize>,
+    pub health_path: String,
+    pub ready_path: String,
+    pub version_path: String,
+    pub upstream_health_path: String,
+    pub upstream_health_timeout_ms: u64,
+    pub ready_cache_secs: u64,
+    // Probe the upstream at startup and only start listening once it's
+    // ready; exit if it isn't within `wait_for_upstream_secs`.
+    pub wait_for_upstream: bool,
+    pub wait_for_upstream_secs: u64,
+    pub shutdown_grace_secs: u64,
+    // Open the circuit breaker after this many consecutive upstream failures;
+    // disabled when unset. It stays open for `cb_open_secs`.
+    pub cb_failure_threshold: Option<u32>,
+    pub cb_open_secs: u64,
+    // Replicas back from being unreachable ramp up to their full share of
+    // traffic over this long; they get it at once when unset.
+    pub slow_start_secs: Option<u64>,
+    // Browser origins allowed to call the proxy (`*` for any); CORS is off
+    // when empty. Methods and headers are sent verbatim in preflights.
+    pub cors_allowed_origins: Vec<String>,
+    pub cors_allowed_methods: String,
+    pub cors_allowed_headers: String,
+    pub cors_max_age_secs: Option<u64>,
+    // Gzip/deflate text-like responses for clients that accept it, skipping
+    // bodies known to be smaller than `compression_min_bytes`.
+    pub enable_compression: bool,
+    pub compression_min_bytes: u64,
+    // Decode gzip/deflate responses for clients that don't accept them.
+    pub enable_decompression: bool,
+    // Serve `/admin/*` endpoints, which require `admin_token`.
+    pub admin_enabled: bool,
+    pub admin_token: Option<String>,
+    // Answer every request except health, readiness, version and admin
+    // endpoints with `maintenance_status` without contacting the upstream.
+    // Toggled at runtime through SIGHUP or `/admin/maintenance`.
+    pub maintenance_mode: bool,
+    pub maintenance_status: u16,
+    pub maintenance_body: String,
+    // Plain-text or JSON bodies for the proxy's own error responses.
+    pub error_format: ErrorFormat,
+    // Pin clients to a replica by hashing their IP or the `sticky_cookie`
+    // cookie; round-robin when unset.
+    pub sticky_sessions: Option<StickyKey>,
+    pub sticky_cookie: String,
+    // Requests proxied at once; unlimited when unset. Extra requests wait up
+    // to `concurrency_queue_ms` for a slot, or get 503 at once without it.
+    pub max_concurrent_requests: Option<usize>,
+    pub concurrency_queue_ms: Option<u64>,
+    // Requests sent upstream and still waiting for response headers;
+    // unlimited when unset. Past it, new requests get 503 at once.
+    pub max_outstanding_upstream: Option<usize>,
+    // Client IP ranges let in / kept out; deny wins over allow, and an empty
+    // allow list allows everyone not denied.
+    pub ip_allow: CidrList,
+    pub ip_deny: CidrList,
+    // The proxy's public scheme and host (`https://api.example.com`).
+    // `Location` headers pointing at an upstream replica are rewritten to
+    // it; off when unset.
+    pub public_url: Option<String>,
+    // Collapse `//` and resolve `.`/`..` in request paths before routing;
+    // `preserve_trailing_slash` keeps a final `/`.
+    pub normalize_path: bool,
+    pub preserve_trailing_slash: bool,
+    // Cache GET responses in memory, holding at most this many; off when
+    // unset. Total size is bounded by `cache_max_bytes`.
+    pub cache_max_entries: Option<usize>,
+    pub cache_max_bytes: u64,
+    // Methods clients may use (`GET,HEAD,OPTIONS`); others get 405. All are
+    // allowed when empty.
+    pub allowed_methods: Vec<String>,
+    // Refuse TRACE and TRACK, which echo requests back (cross-site
+    // tracing), even when `allowed_methods` lists them or is empty.
+    pub block_trace: bool,
+    // Refuse CONNECT unless `forward_proxy_mode` tunnels it.
+    pub block_connect: bool,
+    // Answer requests failing auth on `stealth_paths` with `stealth_status`
+    // and `stealth_body` instead of a 401.
+    pub stealth_mode: bool,
+    pub stealth_paths: Vec<String>,
+    pub stealth_status: u16,
+    pub stealth_body: String,
+    // Answer authorized requests with a JSON description of what would be
+    // forwarded, without contacting the upstream.
+    pub echo_mode: bool,
+    // Act as a forward proxy: tunnel authorized `CONNECT` requests and send
+    // absolute-form requests (`GET http://host/path`) to the host they name.
+    pub forward_proxy_mode: bool,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            auth_mode: AuthMode::Token,
+            auth_token: None,
+            auth_tokens: Vec::new(),
+            auth_token_file: None,
+            auth_scheme: AuthScheme::Bearer,
+            auth_header: "Authorization".to_string(),
+            strip_auth_header: true,
+            upstream_auth_header: None,
+            auth_deny_status: 401,
+            auth_deny_body: None,
+            basic_auth_user: None,
+            basic_auth_pass: None,
+            jwt_secret: None,
+            jwt_public_key_path: None,
+            jwt_algorithm: None,
+            jwt_audience: None,
+            jwt_issuer: None,
+            jwt_forward_subject: false,
+            introspect_url: None,
+            introspect_timeout_ms: 2000,
+            introspect_cache_secs: None,
+            introspect_failure_status: 401,
+            upstream_url: None,
+            upstream_srv: None,
+            upstream_srv_refresh_secs: 30,
+            canary_url: None,
+            canary_percent: 0.0,
+            canary_header: "X-Canary".to_string(),
+            mirror_url: None,
+            mirror_percent: 100.0,
+            mirror_max_body_bytes: 1024 * 1024,
+            routes: Vec::new(),
+            no_route_body: None,
+            no_route_content_type: "text/plain; charset=utf-8".to_string(),
+            error_pages: BTreeMap::new(),
+            upstream_path_prefix: None,
+            request_headers: Vec::new(),
+            response_headers: Vec::new(),
+            add_via_header: false,
+            strip_server_header: false,
+            upstream_insecure_skip_verify: false,
+            upstream_http2: false,
+            bind_addr: BindAddrs(vec![BindAddr::Tcp(([127, 0, 0, 1], 3000).into())]),
+            unix_socket_mode: None,
+            reuse_addr: true,
+            listen_backlog: 1024,
+            tcp_nodelay: false,
+            tcp_keepalive_secs: None,
+            http2: false,
+            keep_alive: true,
+            max_requests_per_conn: None,
+            client_idle_timeout_secs: None,
+            proxy_protocol: None,
+            max_conns_per_ip: None,
+            tls_cert_path: None,
+            tls_key_path: None,
+            metrics_addr: ([127, 0, 0, 1], 9090).into(),
+            access_log_file: None,
+            access_log_max_bytes: 100 * 1024 * 1024,
+            access_log_max_files: 5,
+            pool_idle_timeout_secs: 90,
+            pool_max_idle_per_host: usize::MAX,
+            upstream_keep_alive: true,
+            upstream_timeout_ms: 30_000,
+            connect_timeout_ms: None,
+            slo_threshold_ms: None,
+            trace_sample_rate: None,
+            expect_continue_timeout_ms: None,
+            upstream_max_retries: 2,
+            upstream_retry_backoff_ms: 50,
+            retry_budget_ratio: None,
+            retry_buffer_bytes: None,
+            max_spool_bytes: None,
+            spool_dir: None,
+            rate_limit_rps: None,
+            rate_limit_burst: None,
+            max_body_bytes: None,
+            request_body_timeout_ms: None,
+            max_headers: None,
+            max_header_bytes: None,
+            health_path: "/healthz".to_string(),
+            ready_path: "/readyz".to_string(),
+            version_path: "/version".to_string(),
+            upstream_health_path: "/".to_string(),
+            upstream_health_timeout_ms: 2000,
+            ready_cache_secs: 5,
+            wait_for_upstream: false,
+            wait_for_upstream_secs: 60,
+            shutdown_grace_secs: 30,
+            cb_failure_threshold: None,
+            slow_start_secs: None,
+            cb_open_secs: 30,
+            cors_allowed_origins: Vec::new(),
+            cors_allowed_methods: "GET, HEAD, POST, PUT, PATCH, DELETE".to_string(),
+            cors_allowed_headers: "authorization, content-type".to_string(),
+            cors_max_age_secs: None,
+            enable_compression: false,
+            compression_min_bytes: 1024,
+            enable_decompression: false,
+            admin_enabled: false,
+            admin_token: None,
+            maintenance_mode: false,
+            maintenance_status: 503,
+            maintenance_body: "Service Unavailable: down for maintenance".to_string(),
+            error_format: ErrorFormat::Text,
+            sticky_sessions: None,
+            sticky_cookie: "session_id".to_string(),
+            max_concurrent_requests: None,
+            concurrency_queue_ms: None,
+            max_outstanding_upstream: None,
+            ip_allow: CidrList::default(),
+            ip_deny: CidrList::default(),
+            public_url: None,
+            normalize_path: false,
+            preserve_trailing_slash: true,
+            cache_max_entries: None,
+            cache_max_bytes: 64 * 1024 * 1024,
+            allowed_methods: Vec::new(),
+            block_trace: true,
+            block_connect: true,
+            stealth_mode: false,
+            stealth_paths: vec!["/".to_string(), "/favicon.ico".to_string()],
+            stealth_status: 404,
+            stealth_body: String::new(),
+            echo_mode: false,
+            forward_proxy_mode: false,
+        }
+    }
+}
+
+impl Config {
+    // Load the config file named in `CONFIG_FILE` (if any), apply
+    // environment overrides, and check required settings.
+    pub fn load() -> Result<Config, ConfigError> {
+        Config::load_from(None)
+    }
+
+    // Like `load`, but reading `path` when given instead of `CONFIG_FILE`.
+    pub fn load_from(path: Option<&Path>) -> Result<Config, ConfigError> {
+        let path = match path {
+            Some(path) => Some(path.to_path_buf()),
+            None => env::var_os("CONFIG_FILE").map(PathBuf::from),
+        };
+        let mut config = match path {
+            Some(path) => Config::from_file(&path)?,
+            None => Config::default(),
+        };
+        config.apply_env()?;
+        config.read_token_file()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Config, ConfigError> {
+        let text = fs::read_to_string(path).map_err(|source| ConfigError::Read {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        let parse_error = |source| ConfigError::Parse {
+            path: path.to_path_buf(),
+            source,
+        };
+        let mut value = toml::Value::Table(toml::from_str(&text).map_err(parse_error)?);
+        interpolate(&mut value).map_err(|reason| ConfigError::Interpolate {
+            path: path.to_path_buf(),
+            reason,
+        })?;
+        value.try_into().map_err(parse_error)
+    }
+
+    // Replace `auth_token` with the contents of `auth_token_file`, minus
+    // trailing whitespace, when one is set.
+    fn read_token_file(&mut self) -> Result<(), ConfigError> {
+        let Some(path) = &self.auth_token_file else {
+            return Ok(());
+        };
+        let text = fs::read_to_string(path).map_err(|source| ConfigError::ReadTokenFile {
+            path: path.clone(),
+            source,
+        })?;
+        let token = text.trim_end();
+        if token.is_empty() {
+            return Err(ConfigError::Invalid("auth_token_file is empty"));
+        }
+        self.auth_token = Some(token.to_string());
+        Ok(())
+    }
+
+    // Environment variables take precedence over anything from the file.
+    fn apply_env(&mut self) -> Result<(), ConfigError> {
+        env_override("AUTH_MODE", &mut self.auth_mode)?;
+        env_override_opt("AUTH_TOKEN", &mut self.auth_token)?;
+        env_override_list("AUTH_TOKENS", &mut self.auth_tokens);
+        env_override_opt("AUTH_TOKEN_FILE", &mut self.auth_token_file)?;
+        env_override("AUTH_SCHEME", &mut self.auth_scheme)?;
+        env_override("AUTH_HEADER", &mut self.auth_header)?;
+        env_override("STRIP_AUTH_HEADER", &mut self.strip_auth_header)?;
+        env_override_opt("UPSTREAM_AUTH_HEADER", &mut self.upstream_auth_header)?;
+        env_override("AUTH_DENY_STATUS", &mut self.auth_deny_status)?;
+        env_override_opt("AUTH_DENY_BODY", &mut self.auth_deny_body)?;
+        env_override_opt("BASIC_AUTH_USER", &mut self.basic_auth_user)?;
+        env_override_opt("BASIC_AUTH_PASS", &mut self.basic_auth_pass)?;
+        env_override_opt("JWT_SECRET", &mut self.jwt_secret)?;
+        env_override_opt("JWT_PUBLIC_KEY_PATH", &mut self.jwt_public_key_path)?;
+        env_override_opt("JWT_ALGORITHM", &mut self.jwt_algorithm)?;
+        env_override_opt("JWT_AUDIENCE", &mut self.jwt_audience)?;
+        env_override_opt("JWT_ISSUER", &mut self.jwt_issuer)?;
+        env_override("JWT_FORWARD_SUBJECT", &mut self.jwt_forward_subject)?;
+        env_override_opt("INTROSPECT_URL", &mut self.introspect_url)?;
+        env_override("INTROSPECT_TIMEOUT_MS", &mut self.introspect_timeout_ms)?;
+        env_override_opt("INTROSPECT_CACHE_SECS", &mut self.introspect_cache_secs)?;
+        env_override("INTROSPECT_FAILURE_STATUS", &mut self.introspect_failure_status)?;
+        env_override_opt("UPSTREAM_URL", &mut self.upstream_url)?;
+        env_override_opt("UPSTREAM_SRV", &mut self.upstream_srv)?;
+        env_override("UPSTREAM_SRV_REFRESH_SECS", &mut self.upstream_srv_refresh_secs)?;
+        env_override_opt("CANARY_URL", &mut self.canary_url)?;
+        env_override("CANARY_PERCENT", &mut self.canary_percent)?;
+        env_override("CANARY_HEADER", &mut self.canary_header)?;
+        env_override_opt("MIRROR_URL", &mut self.mirror_url)?;
+        env_override("MIRROR_PERCENT", &mut self.mirror_percent)?;
+        env_override("MIRROR_MAX_BODY_BYTES", &mut self.mirror_max_body_bytes)?;
+        env_override_opt("UPSTREAM_PATH_PREFIX", &mut self.upstream_path_prefix)?;
+        env_override("ADD_VIA_HEADER", &mut self.add_via_header)?;
+        env_override("STRIP_SERVER_HEADER", &mut self.strip_server_header)?;
+        env_override("UPSTREAM_INSECURE_SKIP_VERIFY", &mut self.upstream_insecure_skip_verify)?;
+        env_override("UPSTREAM_HTTP2", &mut self.upstream_http2)?;
+        env_override("BIND_ADDR", &mut self.bind_addr)?;
+        env_override("HTTP2", &mut self.http2)?;
+        env_override("KEEP_ALIVE", &mut self.keep_alive)?;
+        env_override_opt("MAX_REQUESTS_PER_CONN", &mut self.max_requests_per_conn)?;
+        env_override_opt("CLIENT_IDLE_TIMEOUT_SECS", &mut self.client_idle_timeout_secs)?;
+        env_override_opt("PROXY_PROTOCOL", &mut self.proxy_protocol)?;
+        env_override_opt("MAX_CONNS_PER_IP", &mut self.max_conns_per_ip)?;
+        env_override_opt("UNIX_SOCKET_MODE", &mut self.unix_socket_mode)?;
+        env_override("REUSE_ADDR", &mut self.reuse_addr)?;
+        env_override("LISTEN_BACKLOG", &mut self.listen_backlog)?;
+        env_override("TCP_NODELAY", &mut self.tcp_nodelay)?;
+        env_override_opt("TCP_KEEPALIVE_SECS", &mut self.tcp_keepalive_secs)?;
+        env_override_opt("TLS_CERT_PATH", &mut self.tls_cert_path)?;
+        env_override_opt("TLS_KEY_PATH", &mut self.tls_key_path)?;
+        env_override("METRICS_ADDR", &mut self.metrics_addr)?;
+        env_override_opt("ACCESS_LOG_FILE", &mut self.access_log_file)?;
+        env_override("ACCESS_LOG_MAX_BYTES", &mut self.access_log_max_bytes)?;
+        env_override("ACCESS_LOG_MAX_FILES", &mut self.access_log_max_files)?;
+        env_override("POOL_IDLE_TIMEOUT_SECS", &mut self.pool_idle_timeout_secs)?;
+        env_override("POOL_MAX_IDLE_PER_HOST", &mut self.pool_max_idle_per_host)?;
+        env_override("UPSTREAM_KEEP_ALIVE", &mut self.upstream_keep_alive)?;
+        env_override("UPSTREAM_TIMEOUT_MS", &mut self.upstream_timeout_ms)?;
+        env_override_opt("CONNECT_TIMEOUT_MS", &mut self.connect_timeout_ms)?;
+        env_override_opt("SLO_THRESHOLD_MS", &mut self.slo_threshold_ms)?;
+        env_override_opt("TRACE_SAMPLE_RATE", &mut self.trace_sample_rate)?;
+        env_override_opt("NO_ROUTE_BODY", &mut self.no_route_body)?;
+        env_override("NO_ROUTE_CONTENT_TYPE", &mut self.no_route_content_type)?;
+        for (name, path) in env::vars() {
+            if let Some(status) = name.strip_prefix("ERROR_PAGE_") {
+                self.error_pages.insert(status.to_string(), PathBuf::from(path));
+            }
+        }
+        env_override_opt("EXPECT_CONTINUE_TIMEOUT_MS", &mut self.expect_continue_timeout_ms)?;
+        env_override("UPSTREAM_MAX_RETRIES", &mut self.upstream_max_retries)?;
+        env_override("UPSTREAM_RETRY_BACKOFF_MS", &mut self.upstream_retry_backoff_ms)?;
+        env_override_opt("RETRY_BUDGET_RATIO", &mut self.retry_budget_ratio)?;
+        env_override_opt("RETRY_BUFFER_BYTES", &mut self.retry_buffer_bytes)?;
+        env_override_opt("MAX_SPOOL_BYTES", &mut self.max_spool_bytes)?;
+        env_override_opt("SPOOL_DIR", &mut self.spool_dir)?;
+        env_override_opt("RATE_LIMIT_RPS", &mut self.rate_limit_rps)?;
+        env_override_opt("RATE_LIMIT_BURST", &mut self.rate_limit_burst)?;
+        env_override_opt("MAX_BODY_BYTES", &mut self.max_body_bytes)?;
+        env_override_opt("REQUEST_BODY_TIMEOUT_MS", &mut self.request_body_timeout_ms)?;
+        env_override_opt("MAX_HEADERS", &mut self.max_headers)?;
+        env_override_opt("MAX_HEADER_BYTES", &mut self.max_header_bytes)?;
+        env_override("HEALTH_PATH", &mut self.health_path)?;
+        env_override("READY_PATH", &mut self.ready_path)?;
+        env_override("VERSION_PATH", &mut self.version_path)?;
+        env_override("UPSTREAM_HEALTH_PATH", &mut self.upstream_health_path)?;
+        env_override("UPSTREAM_HEALTH_TIMEOUT_MS", &mut self.upstream_health_timeout_ms)?;
+        env_override("READY_CACHE_SECS", &mut self.ready_cache_secs)?;
+        env_override("WAIT_FOR_UPSTREAM", &mut self.wait_for_upstream)?;
+        env_override("WAIT_FOR_UPSTREAM_SECS", &mut self.wait_for_upstream_secs)?;
+        env_override("SHUTDOWN_GRACE_SECS", &mut self.shutdown_grace_secs)?;
+        env_override_opt("CB_FAILURE_THRESHOLD", &mut self.cb_failure_threshold)?;
+        env_override("CB_OPEN_SECS", &mut self.cb_open_secs)?;
+        env_override_opt("SLOW_START_SECS", &mut self.slow_start_secs)?;
+        env_override_list("CORS_ALLOWED_ORIGINS", &mut self.cors_allowed_origins);
+        env_override("CORS_ALLOWED_METHODS", &mut self.cors_allowed_methods)?;
+        env_override("CORS_ALLOWED_HEADERS", &mut self.cors_allowed_headers)?;
+        env_override_opt("CORS_MAX_AGE_SECS", &mut self.cors_max_age_secs)?;
+        env_override("ENABLE_COMPRESSION", &mut self.enable_compression)?;
+        env_override("COMPRESSION_MIN_BYTES", &mut self.compression_min_bytes)?;
+        env_override("ENABLE_DECOMPRESSION", &mut self.enable_decompression)?;
+        env_override("ADMIN_ENABLED", &mut self.admin_enabled)?;
+        env_override_opt("ADMIN_TOKEN", &mut self.admin_token)?;
+        env_override("MAINTENANCE_MODE", &mut self.maintenance_mode)?;
+        env_override("MAINTENANCE_STATUS", &mut self.maintenance_status)?;
+        env_override("MAINTENANCE_BODY", &mut self.maintenance_body)?;
+        env_override("ERROR_FORMAT", &mut self.error_format)?;
+        env_override_opt("STICKY_SESSIONS", &mut self.sticky_sessions)?;
+        env_override("STICKY_COOKIE", &mut self.sticky_cookie)?;
+        env_override_opt("MAX_CONCURRENT_REQUESTS", &mut self.max_concurrent_requests)?;
+        env_override_opt("CONCURRENCY_QUEUE_MS", &mut self.concurrency_queue_ms)?;
+        env_override_opt("MAX_OUTSTANDING_UPSTREAM", &mut self.max_outstanding_upstream)?;
+        env_override("IP_ALLOW", &mut self.ip_allow)?;
+        env_override("IP_DENY", &mut self.ip_deny)?;
+        env_override_opt("PUBLIC_URL", &mut self.public_url)?;
+        env_override("NORMALIZE_PATH", &mut self.normalize_path)?;
+        env_override("PRESERVE_TRAILING_SLASH", &mut self.preserve_trailing_slash)?;
+        env_override_opt("CACHE_MAX_ENTRIES", &mut self.cache_max_entries)?;
+        env_override("CACHE_MAX_BYTES", &mut self.cache_max_bytes)?;
+        env_override_list("ALLOWED_METHODS", &mut self.allowed_methods);
+        env_override("BLOCK_TRACE", &mut self.block_trace)?;
+        env_override("BLOCK_CONNECT", &mut self.block_connect)?;
+        env_override("STEALTH_MODE", &mut self.stealth_mode)?;
+        env_override_list("STEALTH_PATHS", &mut self.stealth_paths);
+        env_override("STEALTH_STATUS", &mut self.stealth_status)?;
+        env_override("STEALTH_BODY", &mut self.stealth_body)?;
+        env_override("ECHO_MODE", &mut self.echo_mode)?;
+        env_override("FORWARD_PROXY_MODE", &mut self.forward_proxy_mode)?;
+        Ok(())
+    }
+
+    // Check the settings are complete and consistent. `load` does this
+    // already; configs built in code should be checked before use.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        self.validate_settings()?;
+        // A forward proxy can do without a default upstream.
+        let has_upstream = self.upstream_url.is_some() || self.upstream_srv.is_some() || !self.routes.is_empty();
+        if !has_upstream && !self.forward_proxy_mode {
+            return Err(ConfigError::Missing("upstream_url (UPSTREAM_URL), upstream_srv (UPSTREAM_SRV) or routes"));
+        }
+        Ok(())
+    }
+
+    // Everything `validate` checks except that some upstream is configured,
+    // for proxies choosing upstreams in code.
+    pub fn validate_settings(&self) -> Result<(), ConfigError> {
+        match self.auth_mode {
+            AuthMode::Token if self.tokens().is_empty() => {
+                return Err(ConfigError::Missing("auth_token / auth_tokens / auth_token_file (AUTH_TOKEN / AUTH_TOKENS / AUTH_TOKEN_FILE)"));
+            }
+            AuthMode::Basic if self.basic_credentials().is_none() => {
+                return Err(ConfigError::Missing(
+                    "basic_auth_user (no `:`) and basic_auth_pass (BASIC_AUTH_USER / BASIC_AUTH_PASS)",
+                ));
+            }
+            AuthMode::Jwt => match (&self.jwt_secret, &self.jwt_public_key_path) {
+                (None, None) => {
+                    return Err(ConfigError::Missing(
+                        "jwt_secret or jwt_public_key_path (JWT_SECRET / JWT_PUBLIC_KEY_PATH)",
+                    ));
+                }
+                (Some(_), Some(_)) => {
+                    return Err(ConfigError::Invalid(
+                        "set only one of jwt_secret and jwt_public_key_path",
+                    ));
+                }
+                _ => {}
+            },
+            AuthMode::Introspect => match &self.introspect_url {
+                None => return Err(ConfigError::Missing("introspect_url (INTROSPECT_URL)")),
+                Some(url) if upstream_uri(url).is_err() => {
+                    return Err(invalid_url("introspect_url", url));
+                }
+                Some(_) if !matches!(self.introspect_failure_status, 401 | 503) => {
+                    return Err(ConfigError::Invalid("introspect_failure_status must be 401 or 503"));
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+        if self.upstream_srv.is_some() && self.upstream_srv_refresh_secs == 0 {
+            return Err(ConfigError::Invalid("upstream_srv_refresh_secs must be at least 1"));
+        }
+        if self.routes.iter().any(|r| r.public && !r.auth_tokens.is_empty()) {
+            return Err(ConfigError::Invalid("a route cannot be public and have auth_tokens"));
+        }
+        let error_status = |s: &str| s.parse::<u16>().is_ok_and(|s| (400..600).contains(&s));
+        if !self.error_pages.keys().all(|s| error_status(s)) {
+            return Err(ConfigError::Invalid("error_pages keys must be 4xx or 5xx status codes"));
+        }
+        if HeaderValue::from_str(&self.no_route_content_type).is_err() {
+            return Err(ConfigError::Invalid("no_route_content_type must be a valid header value"));
+        }
+        if self.routes.iter().any(|r| r.timeout_ms == Some(0)) {
+            return Err(ConfigError::Invalid("a route's timeout_ms must be greater than zero"));
+        }
+        if let Some(prefix) = &self.upstream_path_prefix {
+            let valid = prefix.starts_with('/') && !prefix.contains('?') && prefix.parse::<PathAndQuery>().is_ok();
+            if !valid {
+                return Err(ConfigError::Invalid("upstream_path_prefix must be a path like /service/v1"));
+            }
+        }
+        if self.trace_sample_rate.is_some_and(|r| !(0.0..=1.0).contains(&r)) {
+            return Err(ConfigError::Invalid("trace_sample_rate must be between 0.0 and 1.0"));
+        }
+        if self.retry_budget_ratio.is_some_and(|r| !(r > 0.0 && r.is_finite())) {
+            return Err(ConfigError::Invalid("retry_budget_ratio must be a positive number like 0.2"));
+        }
+        if self.rate_limit_rps.is_some_and(|rps| !(rps > 0.0 && rps.is_finite())) {
+            return Err(ConfigError::Invalid("rate_limit_rps must be a positive number"));
+        }
+        if !(0.0..=100.0).contains(&self.canary_percent) {
+            return Err(ConfigError::Invalid("canary_percent must be between 0 and 100"));
+        }
+        if HeaderName::from_bytes(self.canary_header.as_bytes()).is_err() {
+            return Err(ConfigError::Invalid("canary_header must be a valid header name"));
+        }
+        if let Some(url) = self.mirror_url.as_deref().filter(|url| upstream_uri(url).is_err()) {
+            return Err(invalid_url("mirror_url", url));
+        }
+        if self.max_requests_per_conn == Some(0) {
+            return Err(ConfigError::Invalid("max_requests_per_conn must be greater than zero"));
+        }
+        if self.max_spool_bytes.is_some_and(|max| max <= self.retry_buffer_bytes.unwrap_or(0)) {
+            return Err(ConfigError::Invalid("max_spool_bytes must be greater than retry_buffer_bytes"));
+        }
+        if self.request_body_timeout_ms == Some(0) {
+            return Err(ConfigError::Invalid("request_body_timeout_ms must be greater than zero"));
+        }
+        if self.access_log_max_bytes == 0 {
+            return Err(ConfigError::Invalid("access_log_max_bytes must be greater than zero"));
+        }
+        if self.max_conns_per_ip == Some(0) {
+            return Err(ConfigError::Invalid("max_conns_per_ip must be greater than zero"));
+        }
+        if self.client_idle_timeout_secs == Some(0) {
+            return Err(ConfigError::Invalid("client_idle_timeout_secs must be greater than zero"));
+        }
+        if self.slow_start_secs == Some(0) {
+            return Err(ConfigError::Invalid("slow_start_secs must be greater than zero"));
+        }
+        if !(0.0..=100.0).contains(&self.mirror_percent) {
+            return Err(ConfigError::Invalid("mirror_percent must be between 0 and 100"));
+        }
+        if HeaderName::from_bytes(self.auth_header.as_bytes()).is_err() {
+            return Err(ConfigError::Invalid("auth_header must be a valid header name"));
+        }
+        if self.upstream_auth_header.as_deref().is_some_and(|v| HeaderValue::from_str(v).is_err()) {
+            return Err(ConfigError::Invalid("upstream_auth_header must be a valid header value"));
+        }
+        if self.max_concurrent_requests == Some(0) {
+            return Err(ConfigError::Invalid("max_concurrent_requests must be at least 1"));
+        }
+        if self.max_outstanding_upstream == Some(0) {
+            return Err(ConfigError::Invalid("max_outstanding_upstream must be at least 1"));
+        }
+        if self.methods().is_err() {
+            return Err(ConfigError::Invalid("allowed_methods must be HTTP method names"));
+        }
+        if self.max_headers.is_some_and(|n| n == 0 || n > headers::MAX_PARSED) {
+            return Err(ConfigError::Invalid("max_headers must be between 1 and 100"));
+        }
+        if self.max_header_bytes == Some(0) {
+            return Err(ConfigError::Invalid("max_header_bytes must be greater than zero"));
+        }
+        if !StatusCode::from_u16(self.auth_deny_status).is_ok_and(|s| s.is_client_error()) {
+            return Err(ConfigError::Invalid("auth_deny_status must be a 4xx status code"));
+        }
+        if !StatusCode::from_u16(self.maintenance_status).is_ok_and(|s| s.is_server_error()) {
+            return Err(ConfigError::Invalid("maintenance_status must be a 5xx status code"));
+        }
+        if StatusCode::from_u16(self.stealth_status).is_err() {
+            return Err(ConfigError::Invalid("stealth_status must be an HTTP status code"));
+        }
+        if self.cache_max_entries == Some(0) {
+            return Err(ConfigError::Invalid("cache_max_entries must be at least 1"));
+        }
+        if self.cb_failure_threshold == Some(0) {
+            return Err(ConfigError::Invalid("cb_failure_threshold must be at least 1"));
+        }
+        match (&self.tls_cert_path, &self.tls_key_path) {
+            (Some(_), None) => return Err(ConfigError::Missing("tls_key_path (TLS_KEY_PATH)")),
+            (None, Some(_)) => return Err(ConfigError::Missing("tls_cert_path (TLS_CERT_PATH)")),
+            _ => {}
+        }
+        if HeaderValue::from_str(&self.cors_allowed_methods).is_err()
+            || HeaderValue::from_str(&self.cors_allowed_headers).is_err()
+        {
+            return Err(ConfigError::Invalid(
+                "cors_allowed_methods and cors_allowed_headers must be valid header values",
+            ));
+        }
+        if let Some(url) = self.public_url.as_deref().filter(|url| upstream_uri(url).is_err()) {
+            return Err(invalid_url("public_url", url));
+        }
+        if self.admin_enabled && self.admin_token.as_deref().is_none_or(str::is_empty) {
+            return Err(ConfigError::Missing("admin_token (ADMIN_TOKEN)"));
+        }
+        Ok(())
+    }
+
+    // `allowed_methods` parsed, less any blocked ones; names are matched
+    // case-insensitively.
+    pub fn methods(&self) -> Result<Vec<Method>, InvalidMethod> {
+        let blocked = self.blocked_methods();
+        let mut methods = Vec::new();
+        for m in &self.allowed_methods {
+            let method = Method::from_bytes(m.trim().to_ascii_uppercase().as_bytes())?;
+            if !blocked.contains(&method) {
+                methods.push(method);
+            }
+        }
+        Ok(methods)
+    }
+
+    // Methods refused by `block_trace` and `block_connect`.
+    pub fn blocked_methods(&self) -> Vec<Method> {
+        let mut blocked = Vec::new();
+        if self.block_trace {
+            blocked.push(Method::TRACE);
+            blocked.push(Method::from_bytes(b"TRACK").expect("TRACK is a valid method"));
+        }
+        if self.block_connect && !self.forward_proxy_mode {
+            blocked.push(Method::CONNECT);
+        }
+        blocked
+    }
+
+    // All valid tokens: `auth_token` merged with `auth_tokens`. Both may be
+    // set during a rotation window.
+    pub fn tokens(&self) -> HashSet<String> {
+        self.auth_token
+            .iter()
+            .chain(self.auth_tokens.iter())
+            .filter(|t| !t.is_empty())
+            .cloned()
+            .collect()
+    }
+
+    // Where to get the JWT verification key, if one is configured.
+    pub fn jwt_key(&self) -> Option<JwtKey<'_>> {
+        match (&self.jwt_secret, &self.jwt_public_key_path) {
+            (Some(secret), _) => Some(JwtKey::Secret(secret)),
+            (None, Some(path)) => Some(JwtKey::PublicKeyPem(path)),
+            (None, None) => None,
+        }
+    }
+
+    // The Basic credentials, when both halves are set. The username may not
+    // contain a colon, since it separates the two in the header.
+    pub fn basic_credentials(&self) -> Option<BasicCredentials> {
+        match (&self.basic_auth_user, &self.basic_auth_pass) {
+            (Some(user), Some(pass)) if !user.is_empty() && !user.contains(':') => {
+                Some(BasicCredentials {
+                    user: user.clone(),
+                    pass: pass.clone(),
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+// Fill in `${VAR}` references to environment variables in every string
+// value, so secrets can stay out of the file. `${VAR:-default}` falls back
+// to `default` when `VAR` is unset or empty; any other unset variable is an
+// error. `$${` stands for a literal `${`.
+fn interpolate(value: &mut toml::Value) -> Result<(), String> {
+    match value {
+        toml::Value::String(s) => *s = interpolate_str(s)?,
+        toml::Value::Array(values) => values.iter_mut().try_for_each(interpolate)?,
+        toml::Value::Table(table) => table.iter_mut().try_for_each(|(_, v)| interpolate(v))?,
+        _ => {}
+    }
+    Ok(())
+}
+
+fn interpolate_str(s: &str) -> Result<String, String> {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find('$') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(after) = rest.strip_prefix("$${") {
+            out.push_str("${");
+            rest = after;
+            continue;
+        }
+        let Some(after) = rest.strip_prefix("${") else {
+            out.push('$');
+            rest = &rest[1..];
+            continue;
+        };
+        let end = after.find('}').ok_or_else(|| format!("unterminated `${{` in {:?}", s))?;
+        let (name, default) = match after[..end].split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (&after[..end], None),
+        };
+        let value = env::var(name).ok().filter(|v| default.is_none() || !v.is_empty());
+        match (value, default) {
+            (Some(value), _) => out.push_str(&value),
+            (None, Some(default)) => out.push_str(default),
+            (None, None) => return Err(format!("environment variable {} is not set", name)),
+        }
+        rest = &after[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+// Parse an upstream URI (or any other URL the proxy sends requests to),
+// which must be absolute `http` or `https` with a host. Errors say what is
+// missing and how to fix it.
+fn upstream_uri(u: &str) -> Result<Uri, String> {
+    let Some((scheme, rest)) = u.split_once("://") else {
+        return Err(format!("{}: missing scheme, use e.g. http://{}", u, u));
+    };
+    if !scheme.eq_ignore_ascii_case("http") && !scheme.eq_ignore_ascii_case("https") {
+        return Err(format!("{}: unsupported scheme `{}`, use http or https", u, scheme));
+    }
+    let authority = &rest[..rest.find(['/', '?', '#']).unwrap_or(rest.len())];
+    let host = authority.rsplit('@').next().unwrap_or_default();
+    if host.is_empty() || host.starts_with(':') {
+        return Err(format!("{}: missing host, use e.g. {}://backend:8080", u, scheme));
+    }
+    u.parse::<Uri>().map_err(|e| format!("{}: {}", u, e))
+}
+
+// The error for `setting`'s `url`, which `upstream_uri` rejects.
+fn invalid_url(setting: &'static str, url: &str) -> ConfigError {
+    ConfigError::InvalidUrl {
+        setting,
+        reason: upstream_uri(url).expect_err("rejected URL"),
+    }
+}
+
+fn split_list(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|t| !t.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+fn parse_env<T>(name: &str, value: String) -> Result<T, ConfigError>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    value.parse().map_err(|e: T::Err| ConfigError::InvalidEnv {
+        name: name.to_string(),
+        reason: e.to_string(),
+        value,
+    })
+}
+
+// Replace `target` with the parsed value of env var `name`, if it is set.
+fn env_override<T>(name: &str, target: &mut T) -> Result<(), ConfigError>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    if let Ok(value) = env::var(name) {
+        *target = parse_env(name, value)?;
+    }
+    Ok(())
+}
+
+// Replace `target` with the comma-separated list in env var `name`, if set.
+fn env_override_list(name: &str, target: &mut Vec<String>) {
+    if let Ok(list) = env::var(name) {
+        *target = split_list(&list);
+    }
+}
+
+fn env_override_opt<T>(name: &str, target: &mut Option<T>) -> Result<(), ConfigError>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    if let Ok(value) = env::var(name) {
+        *target = Some(parse_env(name, value)?);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Otherwise valid settings.
+    fn token_auth() -> Config {
+        Config {
+            auth_token: Some("secret".to_string()),
+            ..Config::default()
+        }
+    }
+
+    // Parse `toml` as the config file `name`.
+    fn from_toml(name: &str, toml: &str) -> Result<Config, ConfigError> {
+        let path = env::temp_dir().join(format!("simple-proxy-{}-{}.toml", name, std::process::id()));
+        fs::write(&path, toml).unwrap();
+        let config = Config::from_file(&path);
+        fs::remove_file(&path).unwrap();
+        config
+    }
+
+    #[test]
+    fn sample_file_is_loaded() {
+        let config = from_toml(
+            "sample",
+            r#"
+            bind_addr = "0.0.0.0:8080"
+            upstream_url = "http://backend:9000"
+            auth_tokens = ["one", "two"]
+            upstream_timeout_ms = 5000
+
+            [[routes]]
+            prefix = "/auth"
+            upstream = "http://auth:9001"
+            strip_prefix = true
+        "#,
+        )
+        .unwrap();
+        assert_eq!(config.bind_addr, "0.0.0.0:8080".parse().unwrap());
+        assert_eq!(config.upstream_url.unwrap().uris[0], "http://backend:9000/");
+        assert_eq!(config.auth_tokens, ["one", "two"]);
+        assert_eq!(config.upstream_timeout_ms, 5000);
+        assert_eq!(config.routes[0].prefix, "/auth");
+        assert!(config.routes[0].strip_prefix);
+        // Unset settings keep their defaults.
+        assert_eq!(config.max_body_bytes, None);
+        assert_eq!(config.health_path, "/healthz");
+    }
+
+    #[test]
+    fn load_from_reads_the_given_file() {
+        let path = env::temp_dir().join(format!("simple-proxy-load-from-{}.toml", std::process::id()));
+        fs::write(&path, "auth_token = \"from-file\"\nupstream_url = \"http://backend:9000\"\n").unwrap();
+        let config = Config::load_from(Some(&path));
+        fs::remove_file(&path).unwrap();
+        assert_eq!(config.unwrap().upstream_url.unwrap().uris[0], "http://backend:9000/");
+
+        let err = Config::load_from(Some(&path)).unwrap_err();
+        assert!(matches!(err, ConfigError::Read { .. }), "{:?}", err);
+    }
+
+    #[test]
+    fn invalid_file_names_it() {
+        let err = from_toml("invalid", "upstream_url = [").unwrap_err();
+        assert!(matches!(err, ConfigError::Parse { .. }), "{:?}", err);
+        assert!(err.to_string().starts_with("invalid config file "), "{}", err);
+        assert!(err.to_string().contains("simple-proxy-invalid-"), "{}", err);
+
+        let err = from_toml("unknown-key", "upstream_urls = \"http://a\"").unwrap_err();
+        assert!(err.to_string().contains("unknown field `upstream_urls`"), "{}", err);
+
+        let missing = Path::new("/nonexistent/proxy.toml");
+        let err = Config::from_file(missing).unwrap_err();
+        assert!(matches!(err, ConfigError::Read { .. }), "{:?}", err);
+    }
+
+    fn upstream_error(list: &str) -> String {
+        list.parse::<UpstreamList>().unwrap_err()
+    }
+
+    #[test]
+    fn upstream_needs_scheme_and_host() {
+        assert_eq!(
+            upstream_error("localhost:3000"),
+            "localhost:3000: missing scheme, use e.g. http://localhost:3000"
+        );
+        assert_eq!(upstream_error("http://"), "http://: missing host, use e.g. http://backend:8080");
+        assert_eq!(
+            upstream_error("ftp://files:21"),
+            "ftp://files:21: unsupported scheme `ftp`, use http or https"
+        );
+        // Every replica of a list is checked.
+        assert_eq!(
+            upstream_error("http://a:8080, localhost:3000"),
+            "localhost:3000: missing scheme, use e.g. http://localhost:3000"
+        );
+        assert!("http://a:8080, https://b;weight=2".parse::<UpstreamList>().is_ok());
+    }
+
+    #[test]
+    fn other_urls_are_checked_like_upstreams() {
+        let config = Config {
+            mirror_url: Some("ftp://shadow".to_string()),
+            ..token_auth()
+        };
+        assert_eq!(
+            config.validate_settings().unwrap_err().to_string(),
+            "invalid mirror_url: ftp://shadow: unsupported scheme `ftp`, use http or https"
+        );
+        let config = Config {
+            public_url: Some("proxy.example.com".to_string()),
+            ..token_auth()
+        };
+        assert_eq!(
+            config.validate_settings().unwrap_err().to_string(),
+            "invalid public_url: proxy.example.com: missing scheme, use e.g. http://proxy.example.com"
+        );
+    }
+
+    #[test]
+    fn interpolates_environment_variables() {
+        env::set_var("INTERPOLATE_TEST_HOST", "backend");
+        assert_eq!(interpolate_str("http://${INTERPOLATE_TEST_HOST}:8080").unwrap(), "http://backend:8080");
+        assert_eq!(interpolate_str("no references, $5").unwrap(), "no references, $5");
+        assert_eq!(interpolate_str("$${INTERPOLATE_TEST_HOST}").unwrap(), "${INTERPOLATE_TEST_HOST}");
+        assert_eq!(
+            interpolate_str("${INTERPOLATE_TEST_HOST").unwrap_err(),
+            "unterminated `${` in \"${INTERPOLATE_TEST_HOST\""
+        );
+    }
+
+    #[test]
+    fn missing_variable_is_an_error() {
+        env::remove_var("INTERPOLATE_TEST_MISSING");
+        assert_eq!(
+            interpolate_str("${INTERPOLATE_TEST_MISSING}").unwrap_err(),
+            "environment variable INTERPOLATE_TEST_MISSING is not set"
+        );
+    }
+
+    #[test]
+    fn default_applies_when_unset_or_empty() {
+        env::remove_var("INTERPOLATE_TEST_UNSET");
+        env::set_var("INTERPOLATE_TEST_EMPTY", "");
+        env::set_var("INTERPOLATE_TEST_SET", "given");
+        assert_eq!(interpolate_str("${INTERPOLATE_TEST_UNSET:-fallback}").unwrap(), "fallback");
+        assert_eq!(interpolate_str("${INTERPOLATE_TEST_EMPTY:-fallback}").unwrap(), "fallback");
+        assert_eq!(interpolate_str("${INTERPOLATE_TEST_SET:-fallback}").unwrap(), "given");
+        assert_eq!(interpolate_str("${INTERPOLATE_TEST_UNSET:-}").unwrap(), "");
+    }
+
+    #[test]
+    fn config_file_values_are_interpolated() {
+        env::set_var("INTERPOLATE_TEST_TOKEN", "s3cret");
+        let toml = r#"
+            auth_token = "${INTERPOLATE_TEST_TOKEN}"
+            allowed_methods = ["GET", "${INTERPOLATE_TEST_METHOD:-POST}"]
+            [[routes]]
+            prefix = "/api"
+            upstream = "http://${INTERPOLATE_TEST_API:-api}:9000"
+        "#;
+        let config = from_toml("interpolate", toml).unwrap();
+        assert_eq!(config.auth_token.as_deref(), Some("s3cret"));
+        assert_eq!(config.allowed_methods, ["GET", "POST"]);
+        assert_eq!(config.routes[0].upstream.uris[0], "http://api:9000/");
+    }
+
+    #[test]
+    fn token_file_replaces_the_token() {
+        let path = env::temp_dir().join(format!("simple-proxy-token-{}", std::process::id()));
+        let with_file = || Config {
+            auth_token_file: Some(path.clone()),
+            ..token_auth()
+        };
+
+        fs::write(&path, "from-file\n").unwrap();
+        let mut config = with_file();
+        config.read_token_file().unwrap();
+        assert_eq!(config.auth_token.as_deref(), Some("from-file"));
+
+        fs::write(&path, " \n").unwrap();
+        let err = with_file().read_token_file().unwrap_err();
+        assert!(matches!(err, ConfigError::Invalid("auth_token_file is empty")), "{:?}", err);
+
+        fs::remove_file(&path).unwrap();
+        let err = with_file().read_token_file().unwrap_err();
+        assert!(matches!(err, ConfigError::ReadTokenFile { .. }), "{:?}", err);
+    }
+}
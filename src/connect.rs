@@ -0,0 +1,38 @@
+// CONNECT tunneling for forward-proxy mode (`FORWARD_PROXY_MODE`).
+//
+// An authorized `CONNECT host:port` request is answered with 200 once a TCP
+// connection to `host:port` is open. Hyper then hands over the client
+// connection and bytes are copied both ways until either side closes; what
+// flows through the tunnel (usually TLS) is never looked at.
+
+use crate::error::ProxyError;
+use hyper::{Body, Request, Response};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tracing::debug;
+
+// Open the tunnel a CONNECT request asks for, giving up on the dial after
+// `timeout`. The target must be `host:port`.
+pub async fn tunnel(mut req: Request<Body>, timeout: Duration) -> Result<Response<Body>, ProxyError> {
+    let target = match req.uri().authority() {
+        Some(authority) if authority.port_u16().is_some() => authority.as_str().to_string(),
+        _ => return Err(ProxyError::BadRequest(format!("CONNECT target {} is not host:port", req.uri()))),
+    };
+    let mut upstream = match tokio::time::timeout(timeout, TcpStream::connect(&target)).await {
+        Ok(Ok(stream)) => stream,
+        Ok(Err(e)) => return Err(ProxyError::Connect(target, e)),
+        Err(_) => return Err(ProxyError::Timeout),
+    };
+    let client = hyper::upgrade::on(&mut req);
+    tokio::spawn(async move {
+        match client.await {
+            Ok(mut client) => {
+                if let Err(e) = tokio::io::copy_bidirectional(&mut client, &mut upstream).await {
+                    debug!("tunnel to {} closed with error: {}", target, e);
+                }
+            }
+            Err(e) => debug!("CONNECT upgrade failed: {}", e),
+        }
+    });
+    Ok(Response::new(Body::empty()))
+}
@@ -0,0 +1,57 @@
+// Per-client-IP connection limit (`MAX_CONNS_PER_IP`).
+//
+// Open connections are counted per client IP in a shared map. A connection
+// holds a permit for as long as it's open; dropping the permit, however the
+// connection ended, gives the slot back and forgets IPs left with none, so
+// the map only ever holds clients with open connections.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+pub struct ConnLimiter {
+    max: usize,
+    open: Mutex<HashMap<IpAddr, usize>>,
+}
+
+// One of a client's connection slots, returned on drop.
+pub struct ConnPermit {
+    limiter: Arc<ConnLimiter>,
+    ip: IpAddr,
+}
+
+impl ConnLimiter {
+    pub fn new(max: usize) -> ConnLimiter {
+        ConnLimiter {
+            max,
+            open: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Take a slot for a new connection from `ip`, or `None` if it already
+    // has `max` open.
+    pub fn acquire(self: &Arc<Self>, ip: IpAddr) -> Option<ConnPermit> {
+        let mut open = self.open.lock().unwrap();
+        let count = open.entry(ip).or_insert(0);
+        if *count >= self.max {
+            return None;
+        }
+        *count += 1;
+        Some(ConnPermit {
+            limiter: self.clone(),
+            ip,
+        })
+    }
+}
+
+impl Drop for ConnPermit {
+    fn drop(&mut self) {
+        let mut open = self.limiter.open.lock().unwrap();
+        if let Some(count) = open.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.ip);
+            }
+        }
+    }
+}
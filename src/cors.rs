@@ -0,0 +1,77 @@
+// CORS support for browser clients calling the proxy directly.
+//
+// Preflight requests (`OPTIONS` with `Access-Control-Request-Method`) are
+// answered by the proxy itself, without auth or forwarding. Other requests
+// from an allowed origin get `Access-Control-Allow-Origin` added to the
+// response. Allowed origins are echoed back individually (never `*`), with
+// `Vary: Origin`, so caches keep per-origin responses apart.
+
+use hyper::header::{
+    HeaderValue, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
+    ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_METHOD, ORIGIN,
+    VARY,
+};
+use crate::error::text_response;
+use hyper::{Body, Method, Request, Response, StatusCode};
+
+pub struct Cors {
+    // `*` allows every origin.
+    origins: Vec<String>,
+    methods: HeaderValue,
+    headers: HeaderValue,
+    max_age: Option<u64>,
+}
+
+impl Cors {
+    pub fn new(
+        origins: Vec<String>,
+        methods: HeaderValue,
+        headers: HeaderValue,
+        max_age: Option<u64>,
+    ) -> Cors {
+        Cors {
+            origins,
+            methods,
+            headers,
+            max_age,
+        }
+    }
+
+    fn allows(&self, origin: &str) -> bool {
+        self.origins
+            .iter()
+            .any(|o| o == "*" || o.eq_ignore_ascii_case(origin))
+    }
+
+    // Answer a preflight request, or return `None` if `req` isn't one.
+    pub fn preflight(&self, req: &Request<Body>) -> Option<Response<Body>> {
+        let headers = req.headers();
+        if req.method() != Method::OPTIONS || !headers.contains_key(ACCESS_CONTROL_REQUEST_METHOD) {
+            return None;
+        }
+        let origin = headers.get(ORIGIN)?;
+        if !origin.to_str().is_ok_and(|o| self.allows(o)) {
+            return Some(text_response(StatusCode::FORBIDDEN, "CORS origin not allowed"));
+        }
+        let mut resp = text_response(StatusCode::NO_CONTENT, Body::empty());
+        let out = resp.headers_mut();
+        out.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone());
+        out.insert(ACCESS_CONTROL_ALLOW_METHODS, self.methods.clone());
+        out.insert(ACCESS_CONTROL_ALLOW_HEADERS, self.headers.clone());
+        out.insert(VARY, HeaderValue::from_static("Origin"));
+        if let Some(max_age) = self.max_age {
+            out.insert(ACCESS_CONTROL_MAX_AGE, max_age.into());
+        }
+        Some(resp)
+    }
+
+    // Add the allow-origin header to a response for a request from `origin`.
+    pub fn apply(&self, origin: Option<&HeaderValue>, resp: &mut Response<Body>) {
+        let Some(origin) = origin else { return };
+        if origin.to_str().is_ok_and(|o| self.allows(o)) {
+            let headers = resp.headers_mut();
+            headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone());
+            headers.append(VARY, HeaderValue::from_static("Origin"));
+        }
+    }
+}
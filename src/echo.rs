@@ -0,0 +1,38 @@
+// Echo mode (`ECHO_MODE`), for debugging routing and header rules.
+//
+// Instead of being sent upstream, an authorized request is answered with a
+// JSON description of exactly what would have been sent: method, upstream
+// URI, headers after every rewrite, and the body's length. The body itself
+// is counted as it streams in and discarded, so echoing a large upload
+// doesn't hold it in memory.
+
+use crate::error::json_response;
+use hyper::body::HttpBody;
+use hyper::http::request::Parts;
+use hyper::{Body, Response, StatusCode};
+use serde_json::{Map, Value};
+
+pub async fn response(parts: Parts, mut body: Body) -> Result<Response<Body>, hyper::Error> {
+    let mut body_bytes = 0;
+    while let Some(chunk) = body.data().await {
+        body_bytes += chunk?.len();
+    }
+    let mut headers = Map::new();
+    for (name, value) in &parts.headers {
+        let value = Value::String(String::from_utf8_lossy(value.as_bytes()).into_owned());
+        match headers.get_mut(name.as_str()) {
+            Some(Value::Array(values)) => values.push(value),
+            _ => {
+                headers.insert(name.as_str().to_string(), Value::Array(vec![value]));
+            }
+        }
+    }
+    let echo = serde_json::json!({
+        "method": parts.method.as_str(),
+        "uri": parts.uri.to_string(),
+        "version": format!("{:?}", parts.version),
+        "headers": headers,
+        "body_bytes": body_bytes,
+    });
+    Ok(json_response(StatusCode::OK, echo.to_string()))
+}
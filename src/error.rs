@@ -0,0 +1,251 @@
+// Errors raised while proxying a request, and the plain responses the proxy
+// produces itself. `StartupError` covers the proxy failing to start at all.
+//
+// Nothing on the request path panics: a request that can't be forwarded
+// becomes a `ProxyError`, which maps to a status code and a short body.
+// With `ERROR_FORMAT=json` those bodies are re-rendered as JSON objects.
+
+use crate::config::ConfigError;
+use hyper::header::{HeaderValue, CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::{Body, Response, StatusCode};
+use serde::Deserialize;
+use std::error::Error as _;
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use std::time::Duration;
+
+#[derive(Debug)]
+pub enum ProxyError {
+    // The client's request can't be forwarded as given (e.g. an unparseable
+    // path); answered with 400.
+    BadRequest(String),
+    // The upstream request couldn't be built from the configured upstream
+    // and the routed path; answered with 502.
+    InvalidUpstream(String),
+    // The upstream couldn't be reached or failed mid-request; 502.
+    Upstream(hyper::Error),
+    // A CONNECT tunnel's target couldn't be reached; 502.
+    Connect(String, io::Error),
+    // The request body couldn't be spooled to disk for retries; 502.
+    Spool(io::Error),
+    // The upstream didn't send response headers in time; 504.
+    Timeout,
+    // The circuit breaker is open, so the upstream wasn't tried; 503.
+    CircuitOpen,
+}
+
+impl ProxyError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ProxyError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ProxyError::InvalidUpstream(_)
+            | ProxyError::Upstream(_)
+            | ProxyError::Connect(..)
+            | ProxyError::Spool(_) => StatusCode::BAD_GATEWAY,
+            ProxyError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            ProxyError::CircuitOpen => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+    // The response sent to the client. Details stay in the logs.
+    pub fn response(&self) -> Response<Body> {
+        let status = self.status();
+        text_response(status, status.canonical_reason().unwrap_or("Error"))
+    }
+
+    // What went wrong talking to the upstream; `None` for errors that aren't
+    // the upstream's (or the network's) doing.
+    pub fn upstream_kind(&self) -> Option<UpstreamErrorKind> {
+        match self {
+            ProxyError::Upstream(e) => Some(UpstreamErrorKind::of(e)),
+            ProxyError::Connect(_, e) if e.kind() == io::ErrorKind::TimedOut => {
+                Some(UpstreamErrorKind::Timeout)
+            }
+            ProxyError::Connect(..) => Some(UpstreamErrorKind::Connect),
+            ProxyError::Timeout => Some(UpstreamErrorKind::Timeout),
+            ProxyError::BadRequest(_) | ProxyError::InvalidUpstream(_) => None,
+            ProxyError::Spool(_) | ProxyError::CircuitOpen => None,
+        }
+    }
+}
+
+// Categories of upstream failure, counted in `proxy_upstream_errors_total`
+// and logged with the error, so a 502 can be told apart at a glance.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UpstreamErrorKind {
+    // The upstream's host name didn't resolve.
+    Dns,
+    // No connection could be opened (refused, unreachable, TLS failure).
+    Connect,
+    // The upstream (or connecting to it) took too long.
+    Timeout,
+    // The upstream broke HTTP: an unparseable response, or the connection
+    // closed before the response was complete.
+    Protocol,
+    // The request body couldn't be sent.
+    Body,
+}
+
+impl UpstreamErrorKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            UpstreamErrorKind::Dns => "dns",
+            UpstreamErrorKind::Connect => "connect",
+            UpstreamErrorKind::Timeout => "timeout",
+            UpstreamErrorKind::Protocol => "protocol",
+            UpstreamErrorKind::Body => "body",
+        }
+    }
+
+    // Hyper doesn't say why a connect failed, only the connector's message
+    // does: `HttpConnector` reports resolver failures as "dns error".
+    fn of(e: &hyper::Error) -> UpstreamErrorKind {
+        let mut causes = std::iter::successors(e.source(), |&cause| cause.source());
+        let timed_out = |cause: &(dyn std::error::Error + 'static)| {
+            cause
+                .downcast_ref::<io::Error>()
+                .is_some_and(|e| e.kind() == io::ErrorKind::TimedOut)
+        };
+        if e.is_timeout() || causes.clone().any(timed_out) {
+            UpstreamErrorKind::Timeout
+        } else if e.is_connect() && causes.any(|cause| cause.to_string().starts_with("dns error")) {
+            UpstreamErrorKind::Dns
+        } else if e.is_connect() {
+            UpstreamErrorKind::Connect
+        } else if e.is_user() || e.to_string().starts_with("error writing a body") {
+            UpstreamErrorKind::Body
+        } else {
+            UpstreamErrorKind::Protocol
+        }
+    }
+}
+
+impl fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProxyError::BadRequest(msg) => write!(f, "bad request: {}", msg),
+            ProxyError::InvalidUpstream(msg) => write!(f, "invalid upstream request: {}", msg),
+            ProxyError::Upstream(e) => write!(f, "upstream request failed: {}", e),
+            ProxyError::Connect(target, e) => write!(f, "cannot open tunnel to {}: {}", target, e),
+            ProxyError::Spool(e) => write!(f, "cannot spool request body: {}", e),
+            ProxyError::Timeout => write!(f, "upstream timed out"),
+            ProxyError::CircuitOpen => write!(f, "circuit breaker is open"),
+        }
+    }
+}
+
+impl std::error::Error for ProxyError {}
+
+impl From<hyper::Error> for ProxyError {
+    fn from(e: hyper::Error) -> ProxyError {
+        ProxyError::Upstream(e)
+    }
+}
+
+// Why a proxy couldn't be built or start serving.
+#[derive(Debug)]
+pub enum StartupError {
+    Config(ConfigError),
+    // Auth settings that can't be used, e.g. an unreadable JWT key.
+    Auth(String),
+    // An error page file that can't be read.
+    ErrorPages(String),
+    Tls(String),
+    // No DNS resolver for the SRV upstream.
+    Srv { name: String, reason: String },
+    // `wait_for_upstream` gave up.
+    UpstreamNotReady { waited: Duration, reason: String },
+    Bind { addr: String, source: io::Error },
+    // A listener task died while serving.
+    Listener(String),
+}
+
+impl fmt::Display for StartupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StartupError::Config(e) => write!(f, "configuration error: {}", e),
+            StartupError::Auth(msg) => write!(f, "auth configuration error: {}", msg),
+            StartupError::ErrorPages(msg) => write!(f, "error page configuration error: {}", msg),
+            StartupError::Tls(msg) => write!(f, "TLS configuration error: {}", msg),
+            StartupError::Srv { name, reason } => {
+                write!(f, "cannot set up DNS resolver for {}: {}", name, reason)
+            }
+            StartupError::UpstreamNotReady { waited, reason } => {
+                write!(f, "upstream not ready after {:?} (last error: {}), giving up", waited, reason)
+            }
+            StartupError::Bind { addr, source } => write!(f, "cannot bind {}: {}", addr, source),
+            StartupError::Listener(reason) => write!(f, "listener stopped: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for StartupError {}
+
+// A plain-text response generated by the proxy.
+pub fn text_response(status: StatusCode, body: impl Into<Body>) -> Response<Body> {
+    let mut resp = Response::new(body.into());
+    *resp.status_mut() = status;
+    resp
+}
+
+// How proxy-generated error bodies are rendered.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ErrorFormat {
+    // The short message as plain text (the default).
+    #[default]
+    Text,
+    // `{"error":"...","message":"...","request_id":"..."}`.
+    Json,
+}
+
+impl FromStr for ErrorFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ErrorFormat, String> {
+        if s.eq_ignore_ascii_case("text") {
+            Ok(ErrorFormat::Text)
+        } else if s.eq_ignore_ascii_case("json") {
+            Ok(ErrorFormat::Json)
+        } else {
+            Err(format!("unknown error format `{}` (expected `text` or `json`)", s))
+        }
+    }
+}
+
+// Turn a plain-text error generated by the proxy into the JSON form. The
+// `error` code is the status's reason phrase in snake case (`bad_gateway`)
+// and `message` is the original text. Other headers are kept.
+pub async fn into_json_error(resp: Response<Body>, request_id: &str) -> Response<Body> {
+    let (mut parts, body) = resp.into_parts();
+    // Proxy-generated bodies are already in memory, so this doesn't wait.
+    let message = match hyper::body::to_bytes(body).await {
+        Ok(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+        Err(_) => String::new(),
+    };
+    let code = parts
+        .status
+        .canonical_reason()
+        .unwrap_or("error")
+        .to_ascii_lowercase()
+        .replace([' ', '-'], "_");
+    let body = serde_json::json!({
+        "error": code,
+        "message": message,
+        "request_id": request_id,
+    });
+    parts
+        .headers
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    parts.headers.remove(CONTENT_LENGTH);
+    Response::from_parts(parts, Body::from(body.to_string()))
+}
+
+// A JSON response generated by the proxy.
+pub fn json_response(status: StatusCode, body: impl Into<Body>) -> Response<Body> {
+    let mut resp = text_response(status, body);
+    resp.headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    resp
+}
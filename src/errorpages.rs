@@ -0,0 +1,70 @@
+// Custom error pages (`ERROR_PAGE_<status>`), served from files in place of
+// the proxy's own error bodies.
+//
+// Pages are read into memory at startup and again on SIGHUP, so a request
+// never waits on the disk and a page edited in place takes effect on the
+// next reload. The content type follows the file extension. Only responses
+// the proxy generates itself are replaced; upstream errors pass through.
+
+use hyper::body::Bytes;
+use hyper::header::{HeaderValue, CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::{Body, Response, StatusCode};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+struct Page {
+    body: Bytes,
+    content_type: HeaderValue,
+}
+
+#[derive(Default)]
+pub struct ErrorPages(HashMap<StatusCode, Page>);
+
+impl ErrorPages {
+    // Read every configured page. The status codes are checked by config
+    // validation; a file that can't be read is an error.
+    pub fn load(paths: &BTreeMap<String, PathBuf>) -> Result<ErrorPages, String> {
+        let mut pages = HashMap::new();
+        for (status, path) in paths {
+            let status = status
+                .parse::<u16>()
+                .ok()
+                .and_then(|s| StatusCode::from_u16(s).ok())
+                .ok_or_else(|| format!("{} is not a status code", status))?;
+            let body = fs::read(path).map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+            let content_type = HeaderValue::from_static(content_type(path));
+            pages.insert(
+                status,
+                Page {
+                    body: body.into(),
+                    content_type,
+                },
+            );
+        }
+        Ok(ErrorPages(pages))
+    }
+
+    // Replace the body of `resp` with the page for its status, if there is
+    // one. Other headers (e.g. `Retry-After`, `Allow`) are kept.
+    pub fn apply(&self, resp: &mut Response<Body>) -> bool {
+        let Some(page) = self.0.get(&resp.status()) else {
+            return false;
+        };
+        resp.headers_mut().insert(CONTENT_TYPE, page.content_type.clone());
+        resp.headers_mut().remove(CONTENT_LENGTH);
+        *resp.body_mut() = Body::from(page.body.clone());
+        true
+    }
+}
+
+fn content_type(path: &Path) -> &'static str {
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+    match extension.to_ascii_lowercase().as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "json" => "application/json",
+        "xml" => "application/xml",
+        "txt" => "text/plain; charset=utf-8",
+        _ => "application/octet-stream",
+    }
+}
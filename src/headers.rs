@@ -0,0 +1,217 @@
+// Header rewriting applied to proxied requests and responses.
+
+use crate::ConnInfo;
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, CONNECTION, EXPECT, HOST, LOCATION, TE, VIA};
+use hyper::http::request::Parts;
+use hyper::http::uri::Authority;
+use hyper::{Uri, Version};
+use uuid::Uuid;
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+const X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+pub const X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+// How the proxy names itself in `Via`.
+const VIA_PSEUDONYM: &str = "ezproxy";
+
+// Hyper parses at most this many headers per HTTP/1.1 request and answers
+// 431 beyond it.
+pub const MAX_PARSED: usize = 100;
+
+// Hyper needs a read buffer of at least this size.
+const MIN_READ_BUF: usize = 8192;
+
+// Hop-by-hop headers (RFC 9110 section 7.6.1) describe a single connection
+// and must not be forwarded. `Proxy-Connection` is a non-standard alias of
+// `Connection` still sent by some clients. `Trailer` is end-to-end: it
+// announces trailer fields, which pass through wherever both hops can carry
+// them (Hyper drops HTTP/1.1 trailers, so in practice HTTP/2).
+const HOP_BY_HOP: [&str; 8] = [
+    "connection",
+    "proxy-connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "transfer-encoding",
+    "upgrade",
+];
+
+// Tell the upstream who the real client is: append the peer IP to any
+// existing `X-Forwarded-For` chain and record the original scheme and Host.
+// HTTP/2 clients send no Host header, so the URI's authority stands in.
+pub fn set_forwarded_headers(parts: &mut Parts, conn: ConnInfo) {
+    let headers = &mut parts.headers;
+    let client_ip = conn.remote_addr.ip().to_string();
+    let prior: Vec<&str> = headers
+        .get_all(X_FORWARDED_FOR)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .collect();
+    let chain = if prior.is_empty() {
+        client_ip
+    } else {
+        format!("{}, {}", prior.join(", "), client_ip)
+    };
+    // Every part of the chain is a visible-ASCII header string or an IP, so
+    // this can't fail in practice; if it did the header is left as it was.
+    if let Ok(chain) = HeaderValue::from_str(&chain) {
+        headers.insert(X_FORWARDED_FOR, chain);
+    }
+
+    let proto = if conn.tls { "https" } else { "http" };
+    headers.insert(X_FORWARDED_PROTO, HeaderValue::from_static(proto));
+
+    let host = headers.get(HOST).cloned().or_else(|| {
+        let authority = parts.uri.authority()?;
+        HeaderValue::from_str(authority.as_str()).ok()
+    });
+    if let Some(host) = host {
+        headers.insert(X_FORWARDED_HOST, host);
+    }
+}
+
+// Append the proxy to any existing `Via` chain (RFC 9110 section 7.6.3),
+// with the protocol version the message was received with.
+pub fn append_via(headers: &mut HeaderMap, version: Version) {
+    let protocol = match version {
+        Version::HTTP_09 => "0.9",
+        Version::HTTP_10 => "1.0",
+        Version::HTTP_2 => "2",
+        Version::HTTP_3 => "3",
+        _ => "1.1",
+    };
+    let hop = format!("{} {}", protocol, VIA_PSEUDONYM);
+    let prior: Vec<&str> = headers.get_all(VIA).iter().filter_map(|v| v.to_str().ok()).collect();
+    let chain = if prior.is_empty() {
+        hop
+    } else {
+        format!("{}, {}", prior.join(", "), hop)
+    };
+    // As with X-Forwarded-For, a chain that isn't a valid value is left alone.
+    if let Ok(chain) = HeaderValue::from_str(&chain) {
+        headers.insert(VIA, chain);
+    }
+}
+
+// True when the client waits for `100 Continue` before sending its body.
+pub fn expects_continue(headers: &HeaderMap) -> bool {
+    headers
+        .get(EXPECT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.trim().eq_ignore_ascii_case("100-continue"))
+}
+
+// Whether a request's headers exceed the configured count or total size.
+// Each header counts its name, value and the `: ` and CRLF around them.
+pub fn over_limits(headers: &HeaderMap, max_count: Option<usize>, max_bytes: Option<usize>) -> bool {
+    if max_count.is_some_and(|max| headers.len() > max) {
+        return true;
+    }
+    max_bytes.is_some_and(|max| {
+        let size: usize = headers.iter().map(|(name, value)| name.as_str().len() + value.len() + 4).sum();
+        size > max
+    })
+}
+
+// HTTP/1.1 read buffer size for a header size limit: big enough that Hyper
+// never refuses headers within the limit itself, small enough that a flood
+// of header bytes is refused without being buffered in full.
+// The extra `MIN_READ_BUF` leaves room for the request line.
+pub fn read_buf_size(max_bytes: usize) -> usize {
+    max_bytes.saturating_add(MIN_READ_BUF)
+}
+
+// Remove hop-by-hop headers, including any header named in `Connection`.
+// `TE: trailers` is kept: it is the one TE value HTTP/2 allows, and gRPC
+// servers require it.
+pub fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    let te_trailers = headers
+        .get_all(TE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|t| t.trim().eq_ignore_ascii_case("trailers"));
+    let listed: Vec<HeaderName> = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect();
+    for name in listed {
+        headers.remove(name);
+    }
+    for name in HOP_BY_HOP {
+        headers.remove(name);
+    }
+    if te_trailers {
+        headers.insert(TE, HeaderValue::from_static("trailers"));
+    }
+}
+
+// Make sure the request carries an `X-Request-Id`: a non-empty one from the
+// client is kept, otherwise a random UUID is set. Returns the ID.
+pub fn ensure_request_id(headers: &mut HeaderMap) -> HeaderValue {
+    if let Some(id) = headers.get(X_REQUEST_ID).filter(|v| !v.is_empty()) {
+        return id.clone();
+    }
+    let id = HeaderValue::from_str(&Uuid::new_v4().to_string()).expect("a UUID is a valid header value");
+    headers.insert(X_REQUEST_ID, id.clone());
+    id
+}
+
+// Point an absolute `Location` aimed at one of `upstreams` back at the
+// proxy's `public` scheme and host, keeping the path, query and fragment.
+// Relative redirects already resolve against the proxy, and redirects to
+// other hosts are left as they are.
+pub fn rewrite_location(headers: &mut HeaderMap, upstreams: &[Uri], public: &Uri) {
+    let location = match headers.get(LOCATION).and_then(|v| v.to_str().ok()) {
+        Some(location) => location,
+        None => return,
+    };
+    let (scheme, rest) = match location.split_once("://") {
+        Some(split) => split,
+        None => return,
+    };
+    let end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+    let (authority, tail) = rest.split_at(end);
+    let authority = match authority.parse::<Authority>() {
+        Ok(authority) => authority,
+        Err(_) => return,
+    };
+    if !upstreams.iter().any(|upstream| same_host(upstream, scheme, &authority)) {
+        return;
+    }
+    let (public_scheme, public_authority) = match (public.scheme_str(), public.authority()) {
+        (Some(scheme), Some(authority)) => (scheme, authority),
+        _ => return,
+    };
+    let rewritten = format!("{}://{}{}", public_scheme, public_authority, tail);
+    if let Ok(value) = HeaderValue::from_str(&rewritten) {
+        headers.insert(LOCATION, value);
+    }
+}
+
+// Whether `scheme://authority` names the same host and port as `upstream`,
+// counting an omitted port as the scheme's default.
+fn same_host(upstream: &Uri, scheme: &str, authority: &Authority) -> bool {
+    let upstream_host = match upstream.host() {
+        Some(host) => host,
+        None => return false,
+    };
+    let upstream_port = upstream.port_u16().or_else(|| default_port(upstream.scheme_str().unwrap_or("http")));
+    let port = authority.port_u16().or_else(|| default_port(scheme));
+    upstream_host.eq_ignore_ascii_case(authority.host()) && upstream_port == port
+}
+
+fn default_port(scheme: &str) -> Option<u16> {
+    if scheme.eq_ignore_ascii_case("http") {
+        Some(80)
+    } else if scheme.eq_ignore_ascii_case("https") {
+        Some(443)
+    } else {
+        None
+    }
+}
@@ -0,0 +1,122 @@
+// Token introspection against an external auth service
+// (`AUTH_MODE=introspect`).
+//
+// The presented token is sent to the introspection URL as
+// `Authorization: Bearer <token>` and the request is allowed only when the
+// service answers 200. Any other answer rejects it. When the service can't
+// be reached, times out, or answers 5xx, the request fails closed with the
+// configured status (401 or 503). Approvals can be cached for a short TTL so
+// busy clients don't cost a round trip per request.
+
+use crate::tls;
+use hyper::client::HttpConnector;
+use hyper::header::{HeaderValue, AUTHORIZATION};
+use hyper::{Body, Client, Method, Request, StatusCode, Uri};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+// Expired approvals are swept once the cache holds this many.
+const SWEEP_AT: usize = 10_000;
+
+// What the introspection service said about a token.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Allowed,
+    Denied,
+    // The service couldn't give an answer.
+    Unavailable,
+}
+
+pub struct Introspector {
+    url: Uri,
+    client: Client<HttpsConnector<HttpConnector>>,
+    timeout: Duration,
+    // How long an approval is remembered; not at all when `None`.
+    cache_ttl: Option<Duration>,
+    // Approved tokens and when the approval expires.
+    approved: Mutex<HashMap<String, Instant>>,
+    // Status for requests refused because the service is unavailable.
+    pub failure_status: StatusCode,
+}
+
+impl fmt::Debug for Introspector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Introspector")
+            .field("url", &self.url)
+            .field("timeout", &self.timeout)
+            .field("cache_ttl", &self.cache_ttl)
+            .field("failure_status", &self.failure_status)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Introspector {
+    pub fn new(
+        url: Uri,
+        timeout: Duration,
+        cache_ttl: Option<Duration>,
+        failure_status: StatusCode,
+    ) -> Introspector {
+        let connector = HttpsConnectorBuilder::new()
+            .with_tls_config(tls::client_config(false))
+            .https_or_http()
+            .enable_http1()
+            .build();
+        Introspector {
+            url,
+            client: Client::builder().build(connector),
+            timeout,
+            cache_ttl,
+            approved: Mutex::new(HashMap::new()),
+            failure_status,
+        }
+    }
+
+    pub async fn check(&self, token: &str) -> Verdict {
+        if let Some(until) = self.approved.lock().unwrap().get(token) {
+            if *until > Instant::now() {
+                return Verdict::Allowed;
+            }
+        }
+        let verdict = self.ask(token).await;
+        if let (Verdict::Allowed, Some(ttl)) = (verdict, self.cache_ttl) {
+            let now = Instant::now();
+            let mut approved = self.approved.lock().unwrap();
+            if approved.len() >= SWEEP_AT {
+                approved.retain(|_, until| *until > now);
+            }
+            approved.insert(token.to_string(), now + ttl);
+        }
+        verdict
+    }
+
+    async fn ask(&self, token: &str) -> Verdict {
+        let Ok(bearer) = HeaderValue::from_str(&format!("Bearer {}", token)) else {
+            return Verdict::Denied;
+        };
+        let mut req = Request::new(Body::empty());
+        *req.method_mut() = Method::GET;
+        *req.uri_mut() = self.url.clone();
+        req.headers_mut().insert(AUTHORIZATION, bearer);
+        match tokio::time::timeout(self.timeout, self.client.request(req)).await {
+            Ok(Ok(resp)) if resp.status() == StatusCode::OK => Verdict::Allowed,
+            Ok(Ok(resp)) if resp.status().is_server_error() => {
+                warn!(status = resp.status().as_u16(), "introspection service failed");
+                Verdict::Unavailable
+            }
+            Ok(Ok(_)) => Verdict::Denied,
+            Ok(Err(e)) => {
+                warn!(error = %e, "introspection service unreachable");
+                Verdict::Unavailable
+            }
+            Err(_) => {
+                warn!("introspection service timed out");
+                Verdict::Unavailable
+            }
+        }
+    }
+}
@@ -0,0 +1,125 @@
+// Client IP allow/deny lists (`IP_ALLOW` / `IP_DENY`).
+//
+// Entries are IPv4 or IPv6 CIDR ranges (`10.0.0.0/8`, `2001:db8::/32`) or
+// single addresses. A client matching the deny list is always refused; when
+// the allow list is non-empty, a client must also match it. IPv4-mapped IPv6
+// peers (`::ffff:10.1.2.3`) are matched as IPv4.
+
+use serde::{Deserialize, Deserializer};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, canonical(ip)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_matches(u32::from(net).into(), u32::from(ip).into(), 32, self.prefix_len)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_matches(u128::from(net), u128::from(ip), 128, self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Cidr, String> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (s, None),
+        };
+        let network = canonical(addr.parse::<IpAddr>().map_err(|e| format!("{}: {}", s, e))?);
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => len
+                .parse::<u8>()
+                .ok()
+                .filter(|&len| len <= max)
+                .ok_or_else(|| format!("{}: prefix length must be 0-{}", s, max))?,
+            None => max,
+        };
+        Ok(Cidr { network, prefix_len })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+// A comma-separated list of CIDRs or, in the config file, an array.
+#[derive(Clone, Debug, Default)]
+pub struct CidrList(pub Vec<Cidr>);
+
+impl FromStr for CidrList {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<CidrList, String> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(str::parse)
+            .collect::<Result<Vec<_>, _>>()
+            .map(CidrList)
+    }
+}
+
+impl<'de> Deserialize<'de> for CidrList {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<CidrList, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            One(String),
+            Many(Vec<String>),
+        }
+        let joined = match Repr::deserialize(deserializer)? {
+            Repr::One(s) => s,
+            Repr::Many(v) => v.join(","),
+        };
+        joined.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Debug)]
+pub struct IpFilter {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+}
+
+impl IpFilter {
+    pub fn new(allow: Vec<Cidr>, deny: Vec<Cidr>) -> IpFilter {
+        IpFilter { allow, deny }
+    }
+
+    // Deny takes precedence; an empty allow list allows everyone else.
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|cidr| cidr.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(ip))
+    }
+}
+
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        v4 => v4,
+    }
+}
+
+// Whether the top `prefix_len` of `bits` address bits agree.
+fn prefix_matches(network: u128, ip: u128, bits: u32, prefix_len: u8) -> bool {
+    let shift = bits - u32::from(prefix_len);
+    shift >= bits || (network >> shift) == (ip >> shift)
+}
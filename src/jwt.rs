@@ -0,0 +1,105 @@
+// JWT bearer token validation (`AUTH_MODE=jwt`).
+//
+// Tokens are verified against an HMAC secret or an RSA/EC/Ed25519 public key
+// (PEM). `exp` is always required; `aud` and `iss` are required and checked
+// when configured. Only the pinned algorithm is accepted, so a token can't
+// pick a weaker one through its header.
+
+use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+// Where the verification key comes from.
+pub enum JwtKey<'a> {
+    Secret(&'a str),
+    PublicKeyPem(&'a Path),
+}
+
+// The claims the proxy looks at; everything else is ignored.
+#[derive(Debug, Deserialize)]
+pub struct Claims {
+    pub sub: Option<String>,
+}
+
+pub struct JwtValidator {
+    key: DecodingKey,
+    validation: Validation,
+    // Set `X-Auth-Subject` on forwarded requests from the `sub` claim.
+    pub forward_subject: bool,
+}
+
+impl fmt::Debug for JwtValidator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JwtValidator")
+            .field("validation", &self.validation)
+            .field("forward_subject", &self.forward_subject)
+            .finish_non_exhaustive()
+    }
+}
+
+impl JwtValidator {
+    // Build a validator. The algorithm defaults to HS256 for a secret and
+    // RS256 for a public key. Errors are descriptive strings for startup.
+    pub fn new(
+        key: JwtKey<'_>,
+        algorithm: Option<Algorithm>,
+        audience: Option<&str>,
+        issuer: Option<&str>,
+        forward_subject: bool,
+    ) -> Result<JwtValidator, String> {
+        let (key, algorithm) = match key {
+            JwtKey::Secret(secret) => {
+                let algorithm = algorithm.unwrap_or(Algorithm::HS256);
+                if !matches!(algorithm, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512) {
+                    return Err(format!("{:?} needs a public key, not a secret", algorithm));
+                }
+                (DecodingKey::from_secret(secret.as_bytes()), algorithm)
+            }
+            JwtKey::PublicKeyPem(path) => {
+                let algorithm = algorithm.unwrap_or(Algorithm::RS256);
+                let pem = fs::read(path).map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+                let key = match algorithm {
+                    Algorithm::RS256
+                    | Algorithm::RS384
+                    | Algorithm::RS512
+                    | Algorithm::PS256
+                    | Algorithm::PS384
+                    | Algorithm::PS512 => DecodingKey::from_rsa_pem(&pem),
+                    Algorithm::ES256 | Algorithm::ES384 => DecodingKey::from_ec_pem(&pem),
+                    Algorithm::EdDSA => DecodingKey::from_ed_pem(&pem),
+                    Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => {
+                        return Err(format!("{:?} needs a secret, not a public key", algorithm));
+                    }
+                };
+                let key = key.map_err(|e| format!("invalid public key {}: {}", path.display(), e))?;
+                (key, algorithm)
+            }
+        };
+
+        let mut validation = Validation::new(algorithm);
+        match audience {
+            Some(aud) => {
+                validation.set_audience(&[aud]);
+                validation.required_spec_claims.insert("aud".to_string());
+            }
+            // Without a configured audience any `aud` claim is acceptable.
+            None => validation.validate_aud = false,
+        }
+        if let Some(iss) = issuer {
+            validation.set_issuer(&[iss]);
+            validation.required_spec_claims.insert("iss".to_string());
+        }
+        Ok(JwtValidator {
+            key,
+            validation,
+            forward_subject,
+        })
+    }
+
+    // Verify the signature and registered claims of a compact JWT.
+    pub fn validate(&self, token: &str) -> Result<Claims, jsonwebtoken::errors::Error> {
+        decode::<Claims>(token, &self.key, &self.validation).map(|data| data.claims)
+    }
+}
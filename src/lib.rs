@@ -0,0 +1,1804 @@
+// Simple HTTP proxy with auth validation using hyper and tower
+//
+// Requests are checked against the configured token, Basic credentials,
+// JWT key or introspection service. If the credentials are missing or don't
+// match, the proxy returns a 401 Unauthorized response. Otherwise, it
+// forwards the request to the configured upstream server. Settings come from
+// a `ProxyConfig`, which the `simple_proxy` binary fills from the
+// environment and/or a TOML file; see `config.rs`.
+//
+// Embedders build the same proxy in their own process:
+//
+//     let mut config = ProxyConfig::default();
+//     config.auth_token = Some("secret".to_string());
+//     config.upstream_url = Some("http://127.0.0.1:3000".parse()?);
+//     Proxy::builder(config).build()?.serve().await?;
+//
+// or hand requests they accept themselves to `Proxy::handle`.
+//
+// The implementation uses Hyper's client and server APIs together with Tower's
+// Service traits for clean separation of concerns.
+
+mod admin;
+pub mod auth;
+mod balancer;
+mod body;
+mod breaker;
+mod budget;
+mod cache;
+mod canary;
+mod compress;
+mod concurrency;
+pub mod config;
+mod connect;
+mod connlimit;
+mod echo;
+mod error;
+mod errorpages;
+mod cors;
+mod headers;
+mod introspect;
+mod ipfilter;
+mod jwt;
+pub mod logfile;
+mod maintenance;
+mod metrics;
+mod middleware;
+mod mirror;
+mod normalize;
+mod proxyproto;
+mod ratelimit;
+mod routes;
+mod rules;
+mod server;
+mod spool;
+mod srv;
+pub mod telemetry;
+mod tls;
+mod tracecontext;
+mod transform;
+mod upgrade;
+
+pub use config::Config as ProxyConfig;
+pub use error::StartupError;
+pub use middleware::ProxyService;
+pub use routes::UpstreamSelector;
+pub use transform::ResponseTransform;
+
+use hyper::body::{Bytes, HttpBody};
+use hyper::server::conn::Http;
+use hyper::{Body, Client, Method, Request, Response, StatusCode, Uri, Version};
+use hyper::client::HttpConnector;
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use futures_util::FutureExt;
+use std::any::Any;
+use std::collections::HashSet;
+use std::convert::Infallible;
+use std::env;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::panic::AssertUnwindSafe;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tokio_rustls::TlsAcceptor;
+use admin::Admin;
+use auth::{authorize, AuthConfig};
+use balancer::{Balancer, Stickiness};
+use breaker::CircuitBreaker;
+use budget::RetryBudget;
+use cache::{CacheLookup, ResponseCache};
+use canary::Canary;
+use compress::Compression;
+use concurrency::ConcurrencyLimit;
+use config::{BindAddr, SocketMode, UpstreamList};
+use connlimit::ConnLimiter;
+use cors::Cors;
+use ipfilter::IpFilter;
+use maintenance::Maintenance;
+use error::{json_response, text_response, ErrorFormat, ProxyError, UpstreamErrorKind};
+use errorpages::ErrorPages;
+use metrics::Metrics;
+use middleware::Middleware;
+use mirror::Mirror;
+use normalize::PathNormalizer;
+use proxyproto::ProxyProtocol;
+use ratelimit::RateLimiter;
+use routes::{Route, Router, Target};
+use server::SocketOptions;
+use spool::{ReplayBody, SpoolError, Spooled};
+use srv::SrvUpstream;
+use tracecontext::{Sampler, Trace};
+use tower::{Layer, Service, ServiceExt};
+use tracing::{debug, error, info, warn};
+use tracing::{Instrument, Span};
+use http::uri::PathAndQuery;
+use http::header::{
+    HeaderName, HeaderValue, ACCEPT_ENCODING, AUTHORIZATION, ALLOW, CONNECTION, CONTENT_TYPE, HOST, ORIGIN, RETRY_AFTER,
+    SERVER, UPGRADE,
+};
+
+// Client used for all upstream traffic, HTTP or HTTPS.
+type HttpClient = Client<HttpsConnector<HttpConnector>>;
+
+// Retry settings for transient upstream failures.
+#[derive(Clone, Copy, Debug)]
+struct RetryPolicy {
+    max_retries: u32,
+    // Delay before the first retry; doubled for each subsequent attempt.
+    backoff: Duration,
+    // Buffer request bodies of at most this many bytes so they can be
+    // replayed; such requests are retried whatever their method.
+    buffer_bytes: Option<u64>,
+    // Likewise for larger bodies, spooled to disk up to this many bytes.
+    spool_bytes: Option<u64>,
+}
+
+// Methods clients may use; the rest are refused with 405.
+struct MethodFilter {
+    allowed: Vec<Method>,
+    // The `Allow` header sent with the 405.
+    allow: HeaderValue,
+}
+
+impl MethodFilter {
+    fn new(allowed: Vec<Method>) -> MethodFilter {
+        let list = allowed.iter().map(Method::as_str).collect::<Vec<_>>().join(", ");
+        let allow = HeaderValue::from_str(&list).expect("method names are valid header values");
+        MethodFilter { allowed, allow }
+    }
+
+    // `None` when `method` is allowed, otherwise the 405 to send.
+    fn check(&self, method: &Method) -> Option<Response<Body>> {
+        if self.allowed.contains(method) {
+            return None;
+        }
+        let mut resp = text_response(StatusCode::METHOD_NOT_ALLOWED, "Method Not Allowed");
+        resp.headers_mut().insert(ALLOW, self.allow.clone());
+        Some(resp)
+    }
+}
+
+// Quiet answers for scanners: requests failing auth on these paths get a
+// fixed response instead of a 401 revealing there's something to log into.
+struct Stealth {
+    paths: HashSet<String>,
+    status: StatusCode,
+    body: Bytes,
+}
+
+impl Stealth {
+    fn response(&self, path: &str) -> Option<Response<Body>> {
+        self.paths
+            .contains(path)
+            .then(|| text_response(self.status, self.body.clone()))
+    }
+}
+
+// The configured 404 for requests matching no route.
+struct NoRoutePage {
+    body: Bytes,
+    content_type: HeaderValue,
+}
+
+impl NoRoutePage {
+    fn response(&self) -> Response<Body> {
+        let mut resp = text_response(StatusCode::NOT_FOUND, self.body.clone());
+        resp.headers_mut().insert(CONTENT_TYPE, self.content_type.clone());
+        resp
+    }
+}
+
+// Only safe methods are retried: replaying them can't cause side effects.
+fn is_idempotent(method: &Method) -> bool {
+    matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS)
+}
+
+// Errors worth retrying: the request never reached the upstream, or the
+// connection was dropped or reset before a response arrived.
+fn is_transient(err: &hyper::Error) -> bool {
+    let reset = std::error::Error::source(err)
+        .and_then(|source| source.downcast_ref::<std::io::Error>())
+        .is_some_and(|io| io.kind() == std::io::ErrorKind::ConnectionReset);
+    err.is_connect() || err.is_incomplete_message() || err.is_closed() || reset
+}
+
+// Rebuild a request from its parts and buffered body for another attempt.
+fn replay_request(parts: &http::request::Parts, body: &ReplayBody) -> Request<Body> {
+    let mut req = Request::new(body.body());
+    *req.method_mut() = parts.method.clone();
+    *req.uri_mut() = parts.uri.clone();
+    *req.version_mut() = parts.version;
+    *req.headers_mut() = parts.headers.clone();
+    req
+}
+
+// Details about the client connection a request arrived on.
+#[derive(Clone, Copy, Debug)]
+struct ConnInfo {
+    remote_addr: SocketAddr,
+    tls: bool,
+}
+
+// Point the request at `upstream`, keeping the routed path and query.
+fn point_at(
+    parts: &mut http::request::Parts,
+    upstream: &Uri,
+    path_and_query: &PathAndQuery,
+) -> Result<(), ProxyError> {
+    let mut uri_parts = upstream.clone().into_parts();
+    uri_parts.path_and_query = Some(path_and_query.clone());
+    parts.uri = Uri::from_parts(uri_parts)
+        .map_err(|e| ProxyError::InvalidUpstream(format!("{}: {}", upstream, e)))?;
+    // Rewrite the Host header to the upstream's host:port. A non-default port
+    // (e.g. `http://backend:8080`) is kept, since the authority includes it.
+    if let Some(authority) = upstream.authority() {
+        let host = HeaderValue::from_str(authority.as_str())
+            .map_err(|e| ProxyError::InvalidUpstream(format!("{}: {}", upstream, e)))?;
+        parts.headers.insert(HOST, host);
+    }
+    Ok(())
+}
+
+// Send a request whose body is in memory or spooled, retrying transient
+// failures when the method is safe to replay (or buffering is enabled), with
+// exponential backoff between attempts. With a budget, retries also need its
+// approval.
+async fn send_with_retries(
+    client: &HttpClient,
+    parts: &http::request::Parts,
+    body: &ReplayBody,
+    retry: RetryPolicy,
+    budget: Option<&RetryBudget>,
+) -> Result<Response<Body>, hyper::Error> {
+    let buffering = retry.buffer_bytes.is_some() || retry.spool_bytes.is_some();
+    let replayable = is_idempotent(&parts.method) || buffering;
+    let max_retries = if replayable { retry.max_retries } else { 0 };
+    let mut attempt = 0;
+    loop {
+        match client.request(replay_request(parts, body)).await {
+            Err(e) if attempt < max_retries && is_transient(&e) => {
+                if budget.is_some_and(|budget| !budget.withdraw()) {
+                    debug!(error = %e, "retry budget exhausted, not retrying");
+                    return Err(e);
+                }
+                tokio::time::sleep(retry.backoff * 2u32.saturating_pow(attempt)).await;
+                attempt += 1;
+            }
+            result => {
+                if let (Ok(_), Some(budget)) = (&result, budget) {
+                    budget.deposit();
+                }
+                return result;
+            }
+        }
+    }
+}
+
+// Forward the request to the upstream server.
+async fn forward(
+    mut req: Request<Body>,
+    mut target: Target<'_>,
+    state: &ProxyState,
+    conn: ConnInfo,
+) -> Result<Response<Body>, ProxyError> {
+    // WebSocket handshakes keep their upgrade headers; the client side of the
+    // upgrade must be claimed before the request is taken apart.
+    let websocket = upgrade::websocket_protocol(req.headers());
+    let client_upgrade = websocket.is_some().then(|| hyper::upgrade::on(&mut req));
+
+    // Clone the request method and headers.
+    let (mut parts, body) = req.into_parts();
+    // Some of the default upstream's traffic may be diverted to the canary.
+    if let (true, Some(canary)) = (target.default_upstream, &state.canary) {
+        if let Some(upstream) = canary.select(&parts.headers) {
+            target.upstream = upstream;
+        }
+    }
+    // Must run before the Host rewrite so X-Forwarded-Host sees the original.
+    headers::set_forwarded_headers(&mut parts, conn);
+    headers::strip_hop_by_hop(&mut parts.headers);
+    // Via names the version the client used, so this precedes the version
+    // change below.
+    if state.add_via_header {
+        headers::append_via(&mut parts.headers, parts.version);
+    }
+    // Keyed on what the client sent, before any configured rewrites.
+    let affinity = state
+        .sticky
+        .as_ref()
+        .map(|sticky| sticky.affinity(&parts.headers, conn.remote_addr.ip()));
+    // The client's credentials are for the proxy; the upstream gets its own,
+    // if any.
+    if state.strip_auth_header {
+        let header = state.auth.read().unwrap().header.clone();
+        parts.headers.remove(header);
+    }
+    if let Some(credentials) = &state.upstream_auth {
+        parts.headers.insert(AUTHORIZATION, credentials.clone());
+    }
+    rules::apply(&state.request_headers, &mut parts.headers);
+    if state.otel {
+        telemetry::inject(&mut parts.headers);
+    }
+    // The client's protocol version says nothing about the upstream hop;
+    // Hyper refuses to send an HTTP/2 request over an HTTP/1 connection.
+    parts.version = if state.upstream_http2 { Version::HTTP_2 } else { Version::HTTP_11 };
+    if let Some(protocol) = websocket {
+        upgrade::restore_headers(&mut parts.headers, protocol);
+    }
+
+    // Echo mode describes the request instead of sending it.
+    if state.echo_mode {
+        let upstream = target.upstream.replicas(affinity).next().ok_or_else(|| {
+            ProxyError::InvalidUpstream("no replicas configured".to_string())
+        })?;
+        point_at(&mut parts, upstream, &target.path_and_query)?;
+        return echo::response(parts, body)
+            .await
+            .map_err(|e| ProxyError::BadRequest(format!("cannot read request body: {}", e)));
+    }
+
+    // A copy may go to the mirror once the primary has the whole body.
+    // Upgrades are never mirrored: the copy couldn't follow the tunnel.
+    let body = match &state.mirror {
+        Some(mirror) if client_upgrade.is_none() => {
+            mirror.tap(&parts, &target.path_and_query, body, &state.client)
+        }
+        _ => body,
+    };
+
+    // Hyper's client drops interim responses, so an upstream's 100 Continue
+    // can't be relayed; the client gets its 100 once the body is first read,
+    // which is as soon as the request goes upstream. With a hold-back
+    // configured, the body is read only after a moment instead: an upstream
+    // refusing the upload (417, 401, 413...) answers within that window and
+    // the client sees that final status without sending anything.
+    let hold_back = state.expect_continue_timeout.filter(|_| headers::expects_continue(&parts.headers));
+    let (body, body_started) = match hold_back {
+        Some(delay) if !body.is_end_stream() => {
+            let (body, started) = body::delay_start(body, delay);
+            (body, Some(started))
+        }
+        _ => (body, None),
+    };
+
+    // Request bodies stream to the upstream chunk by chunk, with Hyper's
+    // backpressure bounding what's in memory; only small bodies are ever
+    // buffered, and only when RETRY_BUFFER_BYTES asks for it. Likewise the
+    // upstream's status, headers and body are returned as they are, the
+    // body streaming through without being buffered.
+    let mut resp = send(parts, body, &target, affinity, state).await?;
+    let accepted = resp.headers().get(UPGRADE).cloned();
+    headers::strip_hop_by_hop(resp.headers_mut());
+    if state.add_via_header {
+        let version = resp.version();
+        headers::append_via(resp.headers_mut(), version);
+    }
+    if state.strip_server_header {
+        resp.headers_mut().remove(SERVER);
+    }
+    // Hyper would keep reading the refused body as the next request, so the
+    // client has to start over on a new connection.
+    if body_started.is_some_and(|started| !started.load(Ordering::Relaxed)) {
+        resp.headers_mut().insert(CONNECTION, HeaderValue::from_static("close"));
+    }
+    if let Some(public_url) = &state.public_url {
+        headers::rewrite_location(resp.headers_mut(), target.upstream.all(), public_url);
+    }
+    if let (Some(client_upgrade), Some(protocol)) = (client_upgrade, accepted) {
+        if upgrade::tunnel(client_upgrade, &mut resp) {
+            upgrade::restore_headers(resp.headers_mut(), protocol);
+        }
+    }
+    Ok(resp)
+}
+
+// Send the prepared request to one of the target's replicas, starting with
+// the one `affinity` picks when sticky sessions are on.
+async fn send(
+    mut parts: http::request::Parts,
+    body: Body,
+    target: &Target<'_>,
+    affinity: Option<u64>,
+    state: &ProxyState,
+) -> Result<Response<Body>, ProxyError> {
+    // Replicas in round-robin (or sticky) order; later ones are failover
+    // candidates.
+    let mut replicas = target.upstream.replicas(affinity);
+    let no_replicas = || ProxyError::InvalidUpstream("no replicas configured".to_string());
+
+    // A streamed body is consumed by the first attempt and can't be replayed,
+    // so such requests get a single try against the selected replica. Only
+    // bodies declaring a small enough Content-Length are buffered, so large
+    // uploads keep streaming unless spooling to disk is on, which takes
+    // bodies up to its own cap (or of unknown length, until they pass it).
+    // Reading a body up front would send the client its 100 Continue before
+    // the upstream has seen the request, so bodies behind
+    // `Expect: 100-continue` always stream. `Err` holds a streamed body.
+    let spool_max = state.retry.spool_bytes;
+    let body = match state.retry.buffer_bytes {
+        _ if body.is_end_stream() => Ok(ReplayBody::Memory(Bytes::new())),
+        _ if headers::expects_continue(&parts.headers) => Err(body),
+        Some(max) if body::content_length_at_most(&parts.headers, max) => {
+            Ok(ReplayBody::Memory(hyper::body::to_bytes(body).await?))
+        }
+        _ => match spool_max.filter(|&max| !body::content_length_exceeds(&parts.headers, max)) {
+            Some(max) => match spool::spool(body, &state.spool_dir, max).await {
+                Ok(Spooled::Complete(file)) => Ok(ReplayBody::Spooled(file)),
+                Ok(Spooled::TooLarge(body)) => Err(body),
+                Err(SpoolError::Body(e)) => return Err(ProxyError::Upstream(e)),
+                Err(SpoolError::Io(e)) => return Err(ProxyError::Spool(e)),
+            },
+            None => Err(body),
+        },
+    };
+    let body = match body {
+        Ok(body) => body,
+        Err(body) => {
+            let upstream = replicas.next().ok_or_else(no_replicas)?;
+            point_at(&mut parts, upstream, &target.path_and_query)?;
+            let result = state.client.request(Request::from_parts(parts, body)).await;
+            target.upstream.record(upstream, !result.as_ref().is_err_and(hyper::Error::is_connect));
+            return Ok(result?);
+        }
+    };
+
+    // Requests with a replayable body fail over to the next replica when one
+    // can't be reached; a connect error means the request was never sent.
+    let mut last_err = None;
+    for upstream in replicas {
+        point_at(&mut parts, upstream, &target.path_and_query)?;
+        let budget = state.retry_budget.as_ref();
+        match send_with_retries(&state.client, &parts, &body, state.retry, budget).await {
+            Err(e) if e.is_connect() => {
+                warn!(%upstream, error = %e, "upstream unreachable, trying next replica");
+                target.upstream.record(upstream, false);
+                last_err = Some(e);
+            }
+            result => {
+                target.upstream.record(upstream, true);
+                return Ok(result?);
+            }
+        }
+    }
+    Err(last_err.map_or_else(no_replicas, ProxyError::Upstream))
+}
+
+// Outcome of an upstream readiness probe. The error is a short category used
+// in the 503 body so operators can tell a dead backend from a slow one.
+#[derive(Clone, Debug)]
+enum Readiness {
+    Ready,
+    NotReady(String),
+}
+
+// Probes the upstream for `/readyz`, caching the last result so frequent
+// load-balancer checks don't hammer the backend.
+struct ReadinessCheck {
+    path: String,
+    timeout: Duration,
+    cache_ttl: Duration,
+    last: Mutex<Option<(Instant, Readiness)>>,
+}
+
+impl ReadinessCheck {
+    async fn check(&self, upstream_base: &Uri, client: &HttpClient) -> Readiness {
+        if let Some((at, result)) = self.last.lock().unwrap().as_ref() {
+            if at.elapsed() < self.cache_ttl {
+                return result.clone();
+            }
+        }
+        let result = self.probe(upstream_base, client).await;
+        *self.last.lock().unwrap() = Some((Instant::now(), result.clone()));
+        result
+    }
+
+    async fn probe(&self, upstream_base: &Uri, client: &HttpClient) -> Readiness {
+        let mut parts = upstream_base.clone().into_parts();
+        parts.path_and_query = match self.path.parse() {
+            Ok(pq) => Some(pq),
+            Err(_) => return Readiness::NotReady("invalid_health_path".to_string()),
+        };
+        let uri = match Uri::from_parts(parts) {
+            Ok(uri) => uri,
+            Err(_) => return Readiness::NotReady("invalid_health_path".to_string()),
+        };
+        match tokio::time::timeout(self.timeout, client.get(uri)).await {
+            Ok(Ok(resp)) if resp.status().is_success() => Readiness::Ready,
+            Ok(Ok(resp)) => Readiness::NotReady(format!("status_{}", resp.status().as_u16())),
+            Ok(Err(e)) if e.is_connect() => Readiness::NotReady("connect".to_string()),
+            Ok(Err(_)) => Readiness::NotReady("upstream_error".to_string()),
+            Err(_) => Readiness::NotReady("timeout".to_string()),
+        }
+    }
+}
+
+// Where readiness probes go: the first target of an SRV upstream, which
+// stands in for the default one, or else the router's primary upstream.
+// `None` while the SRV record hasn't resolved.
+fn probe_target(state: &ProxyState) -> Option<Uri> {
+    match &state.srv {
+        Some(srv) => srv.replicas().map(|upstream| upstream.first().clone()),
+        None => state.router.primary().map(|upstream| upstream.first().clone()),
+    }
+}
+
+// Pause between startup probes of the upstream.
+const UPSTREAM_WAIT_INTERVAL: Duration = Duration::from_secs(1);
+
+// Probe the upstream until it's ready, for at most `max_wait`. The error
+// names the last reason it wasn't.
+async fn wait_for_upstream(state: &ProxyState, max_wait: Duration) -> Result<(), String> {
+    let deadline = Instant::now() + max_wait;
+    loop {
+        let reason = match probe_target(state) {
+            Some(upstream) => match state.readiness.probe(&upstream, &state.client).await {
+                Readiness::Ready => return Ok(()),
+                Readiness::NotReady(reason) => reason,
+            },
+            None => "no_upstream".to_string(),
+        };
+        if Instant::now() + UPSTREAM_WAIT_INTERVAL >= deadline {
+            return Err(reason);
+        }
+        info!(reason, "upstream not ready yet, waiting");
+        tokio::time::sleep(UPSTREAM_WAIT_INTERVAL).await;
+    }
+}
+
+fn readiness_response(readiness: Readiness) -> Response<Body> {
+    let (status, body) = match readiness {
+        Readiness::Ready => (StatusCode::OK, r#"{"status":"ready"}"#.to_string()),
+        Readiness::NotReady(reason) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!(r#"{{"status":"unavailable","error":"{}"}}"#, reason),
+        ),
+    };
+    json_response(status, body)
+}
+
+// Everything a request handler needs, built once at startup and shared
+// across connections behind an `Arc`.
+struct ProxyState {
+    // Swapped wholesale on SIGHUP; each request works with the snapshot it
+    // read, so a reload never affects requests already in flight.
+    auth: RwLock<Arc<AuthConfig>>,
+    // Swapped wholesale on reload, like `auth`.
+    error_pages: RwLock<Arc<ErrorPages>>,
+    router: Router,
+    // Embedder layers around the pipeline.
+    middleware: Option<Middleware>,
+    // Embedder upstream choice, ahead of the router.
+    selector: Option<Box<dyn UpstreamSelector>>,
+    // Embedder rewrite of response bodies.
+    transform: Option<Box<dyn ResponseTransform>>,
+    // Drop the client's credentials before forwarding.
+    strip_auth_header: bool,
+    // Sent to upstreams as `Authorization`.
+    upstream_auth: Option<HeaderValue>,
+    // Prepended to every forwarded path.
+    upstream_path_prefix: Option<String>,
+    client: HttpClient,
+    // Deadline for the upstream to return response headers, covering retries.
+    upstream_timeout: Duration,
+    // How long an `Expect: 100-continue` body is held back for the upstream
+    // to refuse it.
+    expect_continue_timeout: Option<Duration>,
+    retry: RetryPolicy,
+    retry_budget: Option<RetryBudget>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    // Largest request body accepted, if limited.
+    max_body_bytes: Option<u64>,
+    // How long a request body may take to arrive, if limited.
+    request_body_timeout: Option<Duration>,
+    // Where request bodies are spooled for retries (`MAX_SPOOL_BYTES`).
+    spool_dir: PathBuf,
+    max_headers: Option<usize>,
+    // Close client connections after this many requests.
+    max_requests_per_conn: Option<u64>,
+    // Close client connections idle for this long.
+    client_idle_timeout: Option<Duration>,
+    // Read the client address from a PROXY protocol header.
+    proxy_protocol: Option<ProxyProtocol>,
+    // Open connections allowed per client IP.
+    conn_limiter: Option<Arc<ConnLimiter>>,
+    max_header_bytes: Option<usize>,
+    // Path answered directly by the proxy for liveness probes.
+    health_path: String,
+    // Path answered by probing the upstream, for readiness probes.
+    ready_path: String,
+    readiness: ReadinessCheck,
+    // Path reporting the running build.
+    version_path: String,
+    metrics: Arc<Metrics>,
+    // Upstream latency within which a request meets the SLO.
+    slo_threshold: Option<Duration>,
+    // Speak HTTP/2 to upstreams.
+    upstream_http2: bool,
+    request_headers: Vec<rules::HeaderRule>,
+    response_headers: Vec<rules::HeaderRule>,
+    // Append to `Via` both ways; drop the upstream's `Server`.
+    add_via_header: bool,
+    strip_server_header: bool,
+    cors: Option<Cors>,
+    compression: Option<Compression>,
+    // Decode upstream gzip/deflate for clients that don't accept it.
+    decompression: bool,
+    breaker: Option<CircuitBreaker>,
+    admin: Option<Admin>,
+    maintenance: Arc<Maintenance>,
+    error_format: ErrorFormat,
+    sticky: Option<Stickiness>,
+    concurrency: Option<ConcurrencyLimit>,
+    // Cap on requests waiting for upstream response headers.
+    outstanding: Option<ConcurrencyLimit>,
+    // Trace requests with OpenTelemetry.
+    otel: bool,
+    // Continue or start W3C trace context.
+    trace_sampler: Option<Sampler>,
+    ip_filter: Option<IpFilter>,
+    // Rewrite upstream `Location` headers to this scheme and host.
+    public_url: Option<Uri>,
+    normalize_path: Option<PathNormalizer>,
+    cache: Option<Arc<ResponseCache>>,
+    methods: Option<MethodFilter>,
+    // Refused even without an allow-list.
+    blocked_methods: Vec<Method>,
+    // Replaces the default upstream when discovering it through DNS SRV.
+    srv: Option<Arc<SrvUpstream>>,
+    stealth: Option<Stealth>,
+    no_route: Option<NoRoutePage>,
+    canary: Option<Canary>,
+    mirror: Option<Mirror>,
+    // Answer with the request that would be forwarded instead of sending it.
+    echo_mode: bool,
+    // Tunnel CONNECT requests and follow absolute-form request URIs.
+    forward_proxy: bool,
+}
+
+// Liveness response served by the proxy itself; never touches the upstream.
+fn health_response() -> Response<Body> {
+    json_response(StatusCode::OK, r#"{"status":"ok"}"#)
+}
+
+// Which build is running; the commit and build time come from `build.rs`.
+fn version_response() -> Response<Body> {
+    let body = serde_json::json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "git_hash": env!("GIT_HASH"),
+        "build_time": env!("BUILD_TIME"),
+    });
+    json_response(StatusCode::OK, body.to_string())
+}
+
+// How a request was resolved, recorded in the access log so auth failures
+// and gateway errors stand out from successfully proxied traffic.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Outcome {
+    Proxied,
+    Health,
+    Ready,
+    Version,
+    IpDenied,
+    RateLimited,
+    Preflight,
+    AuthFailed,
+    Stealth,
+    Admin,
+    Maintenance,
+    Tunnel,
+    CacheHit,
+    Echo,
+    MethodNotAllowed,
+    NoRoute,
+    NoUpstream,
+    BadRequest,
+    PayloadTooLarge,
+    RequestTimeout,
+    HeadersTooLarge,
+    ClientAborted,
+    CircuitOpen,
+    Overloaded,
+    UpstreamSaturated,
+    BadGateway,
+    GatewayTimeout,
+}
+
+impl Outcome {
+    fn as_str(self) -> &'static str {
+        match self {
+            Outcome::Proxied => "proxied",
+            Outcome::Health => "health",
+            Outcome::Ready => "ready",
+            Outcome::Version => "version",
+            Outcome::IpDenied => "ip_denied",
+            Outcome::RateLimited => "rate_limited",
+            Outcome::Preflight => "preflight",
+            Outcome::AuthFailed => "auth_failed",
+            Outcome::Stealth => "stealth",
+            Outcome::Admin => "admin",
+            Outcome::Maintenance => "maintenance",
+            Outcome::Tunnel => "tunnel",
+            Outcome::CacheHit => "cache_hit",
+            Outcome::Echo => "echo",
+            Outcome::MethodNotAllowed => "method_not_allowed",
+            Outcome::NoRoute => "no_route",
+            Outcome::NoUpstream => "no_upstream",
+            Outcome::BadRequest => "bad_request",
+            Outcome::PayloadTooLarge => "payload_too_large",
+            Outcome::RequestTimeout => "request_timeout",
+            Outcome::HeadersTooLarge => "headers_too_large",
+            Outcome::ClientAborted => "client_aborted",
+            Outcome::CircuitOpen => "circuit_open",
+            Outcome::Overloaded => "overloaded",
+            Outcome::UpstreamSaturated => "upstream_saturated",
+            Outcome::BadGateway => "bad_gateway",
+            Outcome::GatewayTimeout => "gateway_timeout",
+        }
+    }
+}
+
+// Result of dispatching a request: the response plus what the access log
+// needs to know about how it was produced.
+struct Dispatched {
+    response: Response<Body>,
+    outcome: Outcome,
+    upstream_latency: Option<Duration>,
+}
+
+impl Dispatched {
+    fn local(response: Response<Body>, outcome: Outcome) -> Dispatched {
+        Dispatched {
+            response,
+            outcome,
+            upstream_latency: None,
+        }
+    }
+}
+
+fn payload_too_large() -> Response<Body> {
+    text_response(StatusCode::PAYLOAD_TOO_LARGE, "Payload Too Large")
+}
+
+fn request_timeout() -> Response<Body> {
+    text_response(StatusCode::REQUEST_TIMEOUT, "Request Timeout")
+}
+
+// nginx's non-standard status for a request the client gave up on. It never
+// reaches the client, but keeps aborts apart from 502s in logs and metrics.
+const CLIENT_CLOSED_REQUEST: u16 = 499;
+
+fn client_closed_request() -> Response<Body> {
+    let status = StatusCode::from_u16(CLIENT_CLOSED_REQUEST).expect("499 is a valid status code");
+    text_response(status, "Client Closed Request")
+}
+
+// A failure reading the client's body while streaming it upstream: the
+// client went away mid-upload, not the upstream. Hyper reports it as an
+// error from the request body, caused by the inbound connection's error.
+fn is_client_abort(err: &hyper::Error) -> bool {
+    err.is_user() && std::error::Error::source(err).is_some_and(|source| source.is::<hyper::Error>())
+}
+
+fn too_many_requests(wait: Duration) -> Response<Body> {
+    let retry_after = wait.as_secs_f64().ceil().max(1.0) as u64;
+    let mut resp = text_response(StatusCode::TOO_MANY_REQUESTS, "Too Many Requests");
+    resp.headers_mut().insert(RETRY_AFTER, retry_after.into());
+    resp
+}
+
+// The scheme and host of an absolute-form request URI
+// (`GET http://host/path`), as sent to forward proxies.
+fn absolute_target(uri: &Uri) -> Option<Uri> {
+    let scheme = uri.scheme()?;
+    if scheme != &http::uri::Scheme::HTTP && scheme != &http::uri::Scheme::HTTPS {
+        return None;
+    }
+    Uri::builder()
+        .scheme(scheme.clone())
+        .authority(uri.authority()?.clone())
+        .path_and_query("/")
+        .build()
+        .ok()
+}
+
+async fn dispatch(mut req: Request<Body>, state: &ProxyState, conn: ConnInfo) -> Dispatched {
+    if headers::over_limits(req.headers(), state.max_headers, state.max_header_bytes) {
+        let status = StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE;
+        let resp = text_response(status, "Request Header Fields Too Large");
+        return Dispatched::local(resp, Outcome::HeadersTooLarge);
+    }
+
+    // Health probes are answered before auth so they need no credentials.
+    if req.uri().path() == state.health_path {
+        return Dispatched::local(health_response(), Outcome::Health);
+    }
+    if req.uri().path() == state.version_path {
+        return Dispatched::local(version_response(), Outcome::Version);
+    }
+    if req.uri().path() == state.ready_path {
+        let readiness = match probe_target(state) {
+            Some(upstream) => state.readiness.check(&upstream, &state.client).await,
+            None => Readiness::NotReady("no_upstream".to_string()),
+        };
+        return Dispatched::local(readiness_response(readiness), Outcome::Ready);
+    }
+
+    // Network-level restrictions come before credentials are looked at.
+    if let Some(filter) = &state.ip_filter {
+        if !filter.permits(conn.remote_addr.ip()) {
+            return Dispatched::local(text_response(StatusCode::FORBIDDEN, "Forbidden"), Outcome::IpDenied);
+        }
+    }
+
+    // Throttle per client IP before doing any real work.
+    if let Some(limiter) = &state.rate_limiter {
+        if let Err(wait) = limiter.check(conn.remote_addr.ip()) {
+            return Dispatched::local(too_many_requests(wait), Outcome::RateLimited);
+        }
+    }
+
+    // Admin endpoints check their own token and are never forwarded.
+    if let Some(admin) = &state.admin {
+        match admin.handle(&req) {
+            Some(Ok(resp)) => return Dispatched::local(resp, Outcome::Admin),
+            Some(Err(resp)) => return Dispatched::local(resp, Outcome::AuthFailed),
+            None => {}
+        }
+    }
+
+    // In maintenance mode nothing past this point is served or forwarded.
+    if let Some(resp) = state.maintenance.response() {
+        return Dispatched::local(resp, Outcome::Maintenance);
+    }
+
+    // Browsers send CORS preflights without credentials, so answer them
+    // before auth and never forward them.
+    if let Some(preflight) = state.cors.as_ref().and_then(|cors| cors.preflight(&req)) {
+        return Dispatched::local(preflight, Outcome::Preflight);
+    }
+
+    // Disallowed methods never reach the upstream. Blocked ones are left out
+    // of any allow-list, and refused without one too.
+    let refused = match &state.methods {
+        Some(methods) => methods.check(req.method()),
+        None => state
+            .blocked_methods
+            .contains(req.method())
+            .then(|| text_response(StatusCode::METHOD_NOT_ALLOWED, "Method Not Allowed")),
+    };
+    if let Some(resp) = refused {
+        return Dispatched::local(resp, Outcome::MethodNotAllowed);
+    }
+
+    // Forward-proxy tunnels go wherever the client asks, so there's nothing
+    // to route; only the global credentials apply.
+    if state.forward_proxy && req.method() == Method::CONNECT {
+        let auth = state.auth.read().unwrap().clone();
+        let req = match authorize(req, &auth, None).await {
+            Ok(req) => req,
+            Err(resp) => return Dispatched::local(resp, Outcome::AuthFailed),
+        };
+        return match connect::tunnel(req, state.upstream_timeout).await {
+            Ok(resp) => Dispatched::local(resp, Outcome::Tunnel),
+            Err(e) => {
+                let outcome = match e {
+                    ProxyError::BadRequest(_) => Outcome::BadRequest,
+                    ProxyError::Timeout => Outcome::GatewayTimeout,
+                    _ => Outcome::BadGateway,
+                };
+                let kind = e.upstream_kind().map(UpstreamErrorKind::as_str);
+                if let Some(kind) = kind {
+                    state.metrics.observe_upstream_error(kind);
+                }
+                warn!(error = %e, kind, "cannot open tunnel");
+                Dispatched::local(e.response(), outcome)
+            }
+        };
+    }
+
+    // Normalize the path before routing, so `/api/../admin` is routed and
+    // authorized as `/admin` rather than under `/api`.
+    if let Some(normalizer) = &state.normalize_path {
+        match normalizer.apply(req.uri()) {
+            Some(uri) => *req.uri_mut() = uri,
+            None => {
+                let err = ProxyError::BadRequest(format!("{}: path escapes the root", req.uri()));
+                warn!(error = %err, "cannot forward request");
+                return Dispatched::local(err.response(), Outcome::BadRequest);
+            }
+        }
+    }
+
+    // Routing comes first since routes may carry their own auth settings.
+    // Requests matching no route still need the global credentials, so the
+    // 404 doesn't reveal anything to unauthenticated clients.
+    // An embedder's selector gets first say, then in forward-proxy mode the
+    // host named by an absolute-form request URI.
+    let chosen = state
+        .selector
+        .as_ref()
+        .and_then(|selector| selector.select(&req))
+        .or_else(|| state.forward_proxy.then(|| absolute_target(req.uri())).flatten())
+        .map(|uri| Balancer::new(vec![uri], vec![1], None));
+    let selected = match &chosen {
+        Some(upstream) => Some(routes::chosen(req.uri(), upstream)),
+        None => state.router.select(req.uri()),
+    };
+    // With an SRV upstream, requests matching no route go to its current
+    // targets, if any have resolved.
+    let srv_replicas = match (&selected, &state.srv) {
+        (None, Some(srv)) => srv.replicas(),
+        _ => None,
+    };
+    let selected = match selected {
+        None => srv_replicas.as_ref().map(|upstream| routes::fallback(req.uri(), upstream)),
+        selected => selected,
+    };
+    let route = match &selected {
+        Some(Ok(target)) => target.route,
+        _ => None,
+    };
+
+    // Then run the auth check.
+    let auth = state.auth.read().unwrap().clone();
+    let stealth = state.stealth.as_ref().and_then(|stealth| stealth.response(req.uri().path()));
+    let authenticated_req = match authorize(req, &auth, route).await {
+        Ok(req) => req,
+        Err(auth_resp) => match stealth {
+            Some(resp) => return Dispatched::local(resp, Outcome::Stealth),
+            None => return Dispatched::local(auth_resp, Outcome::AuthFailed),
+        },
+    };
+
+    let mut target = match selected {
+        Some(Ok(target)) => target,
+        Some(Err(e)) => {
+            let err = ProxyError::BadRequest(format!("{}: {}", authenticated_req.uri(), e));
+            warn!(error = %err, "cannot forward request");
+            return Dispatched::local(err.response(), Outcome::BadRequest);
+        }
+        None if state.srv.is_some() => {
+            let resp = text_response(StatusCode::SERVICE_UNAVAILABLE, "No upstream available");
+            return Dispatched::local(resp, Outcome::NoUpstream);
+        }
+        None => {
+            let resp = match &state.no_route {
+                Some(page) => page.response(),
+                None => text_response(StatusCode::NOT_FOUND, "No route"),
+            };
+            return Dispatched::local(resp, Outcome::NoRoute);
+        }
+    };
+    if let Some(prefix) = &state.upstream_path_prefix {
+        if let Err(e) = target.prepend(prefix) {
+            let err = ProxyError::BadRequest(format!("{}: {}", authenticated_req.uri(), e));
+            warn!(error = %err, "cannot forward request");
+            return Dispatched::local(err.response(), Outcome::BadRequest);
+        }
+    }
+
+    // Enforce the body size limit: reject a declared oversize body outright,
+    // and cap streamed bodies as they are forwarded.
+    let mut body_exceeded = None;
+    let authenticated_req = match state.max_body_bytes {
+        Some(max) if body::content_length_exceeds(authenticated_req.headers(), max) => {
+            return Dispatched::local(payload_too_large(), Outcome::PayloadTooLarge);
+        }
+        Some(max) if !authenticated_req.body().is_end_stream() => {
+            let (parts, body) = authenticated_req.into_parts();
+            let (body, exceeded) = body::limit(body, max);
+            body_exceeded = Some(exceeded);
+            Request::from_parts(parts, body)
+        }
+        _ => authenticated_req,
+    };
+    // A body trickling in too slowly is cut off at its deadline.
+    let mut body_expired = None;
+    let mut authenticated_req = match state.request_body_timeout {
+        Some(timeout) if !authenticated_req.body().is_end_stream() => {
+            let (parts, body) = authenticated_req.into_parts();
+            let (body, expired) = body::deadline(body, timeout);
+            body_expired = Some(expired);
+            Request::from_parts(parts, body)
+        }
+        _ => authenticated_req,
+    };
+
+    // Fresh cached responses are served without touching the upstream.
+    // Credentials in the configured auth header count for caching like
+    // those in `Authorization`.
+    let chosen_upstream = chosen.as_ref().map(Balancer::first);
+    let cache_miss = match &state.cache {
+        Some(cache) => match cache.lookup(&mut authenticated_req, chosen_upstream, &auth.header) {
+            CacheLookup::Hit(resp) => return Dispatched::local(resp, Outcome::CacheHit),
+            CacheLookup::Miss(miss) => Some(miss),
+            CacheLookup::Bypass => None,
+        },
+        None => None,
+    };
+
+    // Cap requests in flight to the upstream. The slot is held until the
+    // response body has been sent, not just until headers arrive.
+    let slot = match &state.concurrency {
+        Some(limit) => match limit.acquire().await {
+            Some(slot) => Some(slot),
+            None => {
+                let resp = text_response(StatusCode::SERVICE_UNAVAILABLE, "Too many concurrent requests");
+                return Dispatched::local(resp, Outcome::Overloaded);
+            }
+        },
+        None => None,
+    };
+
+    // Shed load while too many requests are already waiting on the upstream,
+    // rather than piling more onto its connection pool. The permit is held
+    // until response headers arrive.
+    let outstanding = match &state.outstanding {
+        Some(limit) => match limit.acquire().await {
+            Some(permit) => Some(permit),
+            None => {
+                let resp = text_response(StatusCode::SERVICE_UNAVAILABLE, "Upstream saturated");
+                return Dispatched::local(resp, Outcome::UpstreamSaturated);
+            }
+        },
+        None => None,
+    };
+
+    // While the circuit breaker is open, fail fast without touching the
+    // upstream.
+    let permit = match &state.breaker {
+        Some(breaker) => match breaker.acquire() {
+            Some(permit) => Some(permit),
+            None => {
+                let resp = ProxyError::CircuitOpen.response();
+                return Dispatched::local(resp, Outcome::CircuitOpen);
+            }
+        },
+        None => None,
+    };
+
+    // Forward the request; failures become a `ProxyError` response, and an
+    // upstream that doesn't send headers in time a 504. A route may set its
+    // own deadline.
+    let started = Instant::now();
+    let timeout = target.timeout.unwrap_or(state.upstream_timeout);
+    let forwarded = forward(authenticated_req, target, state, conn);
+    let in_flight = state.metrics.upstream_request_started();
+    let result = match tokio::time::timeout(timeout, forwarded).await {
+        Ok(result) => result,
+        Err(_) => Err(ProxyError::Timeout),
+    };
+    drop(in_flight);
+    drop(outstanding);
+    let body_too_large = body_exceeded.is_some_and(|f| f.load(Ordering::Relaxed));
+    let body_too_slow = body_expired.is_some_and(|f| f.load(Ordering::Relaxed));
+    // A client going away mid-upload fails the upstream request as well, but
+    // says nothing about the upstream's health.
+    let client_aborted = !body_too_large
+        && !body_too_slow
+        && matches!(&result, Err(ProxyError::Upstream(e)) if is_client_abort(e));
+    if let Some(permit) = permit.filter(|_| !client_aborted) {
+        // Only the upstream's own failures count; an oversized or slow body
+        // is the client's fault.
+        let upstream_failed = matches!(result, Err(ProxyError::Upstream(_) | ProxyError::Timeout));
+        permit.record(!upstream_failed || body_too_large || body_too_slow);
+    }
+    let result = match (result, &state.cache, cache_miss) {
+        (Ok(resp), Some(cache), Some(miss)) => Ok(cache.store(miss, resp)),
+        (result, _, _) => result,
+    };
+    let proxied = if state.echo_mode { Outcome::Echo } else { Outcome::Proxied };
+    let (response, outcome) = match result {
+        Ok(resp) => match slot {
+            Some(slot) => {
+                let (parts, body) = resp.into_parts();
+                let body = body::hold_until_end(body, slot);
+                (Response::from_parts(parts, body), proxied)
+            }
+            None => (resp, proxied),
+        },
+        Err(ProxyError::Upstream(_)) if body_too_large => (payload_too_large(), Outcome::PayloadTooLarge),
+        Err(_) if body_too_slow => (request_timeout(), Outcome::RequestTimeout),
+        Err(ProxyError::Upstream(_)) if client_aborted => (client_closed_request(), Outcome::ClientAborted),
+        Err(e) => {
+            let outcome = match e {
+                ProxyError::BadRequest(_) => Outcome::BadRequest,
+                ProxyError::InvalidUpstream(_)
+                | ProxyError::Upstream(_)
+                | ProxyError::Connect(..)
+                | ProxyError::Spool(_) => Outcome::BadGateway,
+                ProxyError::Timeout => Outcome::GatewayTimeout,
+                ProxyError::CircuitOpen => Outcome::CircuitOpen,
+            };
+            let kind = e.upstream_kind().map(UpstreamErrorKind::as_str);
+            if let Some(kind) = kind {
+                state.metrics.observe_upstream_error(kind);
+            }
+            match outcome {
+                Outcome::BadRequest => warn!(error = %e, "cannot forward request"),
+                Outcome::BadGateway => error!(error = %e, kind, "proxying failed"),
+                Outcome::GatewayTimeout => warn!(error = %e, kind, "proxying failed"),
+                _ => {}
+            }
+            (e.response(), outcome)
+        }
+    };
+    Dispatched {
+        response,
+        outcome,
+        upstream_latency: (!client_aborted).then(|| started.elapsed()),
+    }
+}
+
+async fn handle(
+    mut req: Request<Body>,
+    state: Arc<ProxyState>,
+    conn: ConnInfo,
+) -> Result<Response<Body>, Infallible> {
+    // Tag the request before anything else so every response, including the
+    // proxy's own errors, can be correlated with the logs.
+    let request_id = headers::ensure_request_id(req.headers_mut());
+    // Settled before the span is made, so it joins the same trace.
+    let trace = state.trace_sampler.as_ref().map(|sampler| sampler.apply(req.headers_mut()));
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let origin = req.headers().get(ORIGIN).cloned();
+    let accept_encoding = req.headers().get(ACCEPT_ENCODING).cloned();
+    let span = if state.otel { telemetry::request_span(&req) } else { Span::none() };
+    // A bug tripping over an unusual upstream response must not take the
+    // connection down with it: a panic while handling the request becomes
+    // a logged 502.
+    let mut abort_watch = AbortWatch {
+        method: &method,
+        path: &path,
+        conn,
+        request_id: &request_id,
+        metrics: &state.metrics,
+        armed: true,
+    };
+    let dispatched = AssertUnwindSafe(dispatch(req, &state, conn))
+        .catch_unwind()
+        .instrument(span.clone())
+        .await;
+    abort_watch.armed = false;
+    let mut dispatched = dispatched.unwrap_or_else(|panic| {
+        error!(panic = panic_message(&*panic), "request handler panicked");
+        let resp = text_response(StatusCode::BAD_GATEWAY, "Bad Gateway");
+        Dispatched::local(resp, Outcome::BadGateway)
+    });
+    // Only the proxy's own errors get an error page or are reformatted;
+    // upstream responses, probe bodies, stealth responses and a configured
+    // no-route page pass through as they are.
+    let status = dispatched.response.status();
+    let custom_no_route = dispatched.outcome == Outcome::NoRoute && state.no_route.is_some();
+    let own_error = (status.is_client_error() || status.is_server_error())
+        && !custom_no_route
+        && !matches!(
+            dispatched.outcome,
+            Outcome::Proxied | Outcome::CacheHit | Outcome::Health | Outcome::Ready | Outcome::Stealth
+        );
+    if own_error {
+        let error_pages = state.error_pages.read().unwrap().clone();
+        if !error_pages.apply(&mut dispatched.response) && state.error_format == ErrorFormat::Json {
+            let request_id = request_id.to_str().unwrap_or("");
+            dispatched.response = error::into_json_error(dispatched.response, request_id).await;
+        }
+    }
+    if let Some(cors) = &state.cors {
+        if dispatched.outcome != Outcome::Preflight {
+            cors.apply(origin.as_ref(), &mut dispatched.response);
+        }
+    }
+    // Decoding first lets compression re-encode in a form the client takes.
+    let from_upstream = matches!(dispatched.outcome, Outcome::Proxied | Outcome::CacheHit);
+    if from_upstream && method != Method::HEAD {
+        if state.decompression {
+            compress::decode_unaccepted(accept_encoding.as_ref(), &mut dispatched.response);
+        }
+        let status = dispatched.response.status();
+        let has_body =
+            !status.is_informational() && status != StatusCode::NO_CONTENT && status != StatusCode::NOT_MODIFIED;
+        if let Some(transform) = state.transform.as_ref().filter(|_| has_body) {
+            transform::apply(transform.as_ref(), &mut dispatched.response);
+        }
+        if let Some(compression) = &state.compression {
+            compression.apply(accept_encoding.as_ref(), &mut dispatched.response);
+        }
+    }
+    rules::apply(&state.response_headers, dispatched.response.headers_mut());
+    dispatched
+        .response
+        .headers_mut()
+        .insert(headers::X_REQUEST_ID, request_id.clone());
+    let slo = slo_tag(state.slo_threshold, dispatched.upstream_latency);
+    let request_id = request_id.to_str().unwrap_or("-");
+    log_access(&method, &path, conn, request_id, &dispatched, slo, trace.as_ref());
+    state.metrics.observe_request(dispatched.response.status().as_u16());
+    if let Some(slo) = slo {
+        state.metrics.observe_slo(slo);
+    }
+    span.record("http.status_code", dispatched.response.status().as_u16());
+    if let Some(latency) = dispatched.upstream_latency {
+        state.metrics.observe_upstream(latency);
+        span.record("upstream_ms", latency.as_millis() as u64);
+    }
+    Ok(dispatched.response)
+}
+
+// The message a panic was raised with, if it has one.
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    match panic.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => panic.downcast_ref::<String>().map_or("(no message)", String::as_str),
+    }
+}
+
+// Notes a request whose client disconnected before it was answered. Hyper
+// drops the handler's future then, so this runs from `Drop` rather than on
+// the normal access-log path.
+struct AbortWatch<'a> {
+    method: &'a Method,
+    path: &'a str,
+    conn: ConnInfo,
+    request_id: &'a HeaderValue,
+    metrics: &'a Metrics,
+    armed: bool,
+}
+
+impl Drop for AbortWatch<'_> {
+    fn drop(&mut self) {
+        if !self.armed {
+            return;
+        }
+        let client_ip = self.conn.remote_addr.ip();
+        let request_id = self.request_id.to_str().unwrap_or("-");
+        debug!(
+            target: "access",
+            method = %self.method, path = self.path, status = CLIENT_CLOSED_REQUEST,
+            outcome = Outcome::ClientAborted.as_str(), %client_ip, request_id,
+            "client aborted"
+        );
+        self.metrics.observe_request(CLIENT_CLOSED_REQUEST);
+    }
+}
+
+// `met` or `violated` for a request that reached the upstream, judged by
+// its upstream latency; `None` without a threshold or upstream round trip.
+fn slo_tag(threshold: Option<Duration>, latency: Option<Duration>) -> Option<&'static str> {
+    let (threshold, latency) = (threshold?, latency?);
+    Some(if latency <= threshold { "met" } else { "violated" })
+}
+
+// Emit one structured access-log line per request. Failures are logged at
+// `warn` so they can be filtered on level as well as on `outcome`.
+fn log_access(
+    method: &Method,
+    path: &str,
+    conn: ConnInfo,
+    request_id: &str,
+    dispatched: &Dispatched,
+    slo: Option<&'static str>,
+    trace: Option<&Trace>,
+) {
+    let status = dispatched.response.status().as_u16();
+    let outcome = dispatched.outcome.as_str();
+    let client_ip = conn.remote_addr.ip();
+    let upstream_ms = dispatched.upstream_latency.map(|d| d.as_millis() as u64);
+    let trace_id = trace.and_then(|t| t.id.as_deref());
+    let sampled = trace.map(|t| t.sampled);
+    match dispatched.outcome {
+        Outcome::ClientAborted => debug!(
+            target: "access",
+            %method, path, status, outcome, %client_ip, upstream_ms, slo, request_id, trace_id, sampled,
+            "client aborted"
+        ),
+        Outcome::IpDenied
+        | Outcome::RateLimited
+        | Outcome::AuthFailed
+        | Outcome::MethodNotAllowed
+        | Outcome::NoUpstream
+        | Outcome::BadRequest
+        | Outcome::HeadersTooLarge
+        | Outcome::CircuitOpen
+        | Outcome::Overloaded
+        | Outcome::UpstreamSaturated
+        | Outcome::BadGateway
+        | Outcome::GatewayTimeout => warn!(
+            target: "access",
+            %method, path, status, outcome, %client_ip, upstream_ms, slo, request_id, trace_id, sampled,
+            "request failed"
+        ),
+        _ => info!(
+            target: "access",
+            %method, path, status, outcome, %client_ip, upstream_ms, slo, request_id, trace_id, sampled,
+            "request handled"
+        ),
+    }
+}
+
+// Builds a `Proxy` from its config. Obtained from `Proxy::builder`.
+pub struct ProxyBuilder {
+    config: ProxyConfig,
+    middleware: Option<Middleware>,
+    selector: Option<Box<dyn UpstreamSelector>>,
+    transform: Option<Box<dyn ResponseTransform>>,
+}
+
+impl ProxyBuilder {
+    // Wrap the request pipeline in a Tower layer, or a whole stack given as
+    // a `ServiceBuilder`. Layers added first run first.
+    pub fn layer<L>(mut self, layer: L) -> ProxyBuilder
+    where
+        L: Layer<ProxyService> + Send + Sync + 'static,
+        L::Service:
+            Service<Request<Body>, Response = Response<Body>, Error = Infallible> + Clone + Send + 'static,
+        <L::Service as Service<Request<Body>>>::Future: Send + 'static,
+    {
+        self.middleware = Some(match self.middleware {
+            Some(middleware) => middleware.then(layer),
+            None => Middleware::new(layer),
+        });
+        self
+    }
+
+    // Choose upstreams in code, ahead of the configured routes. With a
+    // selector the config needs no upstream of its own; requests the
+    // selector passes on then get 404 unless a route matches.
+    pub fn select_upstream(mut self, selector: impl UpstreamSelector + 'static) -> ProxyBuilder {
+        self.selector = Some(Box::new(selector));
+        self
+    }
+
+    // Rewrite the bodies of upstream responses, e.g. to inject a banner into
+    // HTML. See `ResponseTransform`.
+    pub fn transform_response(mut self, transform: impl ResponseTransform + 'static) -> ProxyBuilder {
+        self.transform = Some(Box::new(transform));
+        self
+    }
+
+    // Validate the config and set up everything the proxy's requests share:
+    // auth, routing, the upstream client and so on. Must be called within a
+    // Tokio runtime, which drives background tasks such as SRV refreshes.
+    pub fn build(self) -> Result<Proxy, StartupError> {
+        let config = self.config;
+        match self.selector {
+            Some(_) => config.validate_settings(),
+            None => config.validate(),
+        }
+        .map_err(StartupError::Config)?;
+        // A JWT key that can't be loaded is fatal, like a bad TLS certificate.
+        let auth = AuthConfig::from_config(&config).map_err(StartupError::Auth)?;
+        let error_pages = ErrorPages::load(&config.error_pages).map_err(StartupError::ErrorPages)?;
+        let slow_start = config.slow_start_secs.map(Duration::from_secs);
+        let balancer = |list: &UpstreamList| Balancer::new(list.uris.clone(), list.weights.clone(), slow_start);
+        let routes = config
+            .routes
+            .iter()
+            .map(|r| Route {
+                prefix: r.prefix.clone(),
+                upstream: balancer(&r.upstream),
+                strip_prefix: r.strip_prefix,
+                timeout: r.timeout_ms.map(Duration::from_millis),
+            })
+            .collect();
+        let srv = match &config.upstream_srv {
+            Some(name) => {
+                let srv = SrvUpstream::new(name.clone()).map_err(|reason| StartupError::Srv {
+                    name: name.clone(),
+                    reason,
+                })?;
+                let srv = Arc::new(srv);
+                srv::spawn_refresher(srv.clone(), Duration::from_secs(config.upstream_srv_refresh_secs));
+                Some(srv)
+            }
+            None => None,
+        };
+        let default_upstream = match &srv {
+            Some(_) => None,
+            None => config.upstream_url.as_ref().map(balancer),
+        };
+        let router = Router::new(routes, default_upstream);
+        let upstream_timeout = Duration::from_millis(config.upstream_timeout_ms);
+        let retry = RetryPolicy {
+            max_retries: config.upstream_max_retries,
+            backoff: Duration::from_millis(config.upstream_retry_backoff_ms),
+            buffer_bytes: config.retry_buffer_bytes,
+            spool_bytes: config.max_spool_bytes,
+        };
+        let readiness = ReadinessCheck {
+            path: config.upstream_health_path.clone(),
+            timeout: Duration::from_millis(config.upstream_health_timeout_ms),
+            cache_ttl: Duration::from_secs(config.ready_cache_secs),
+            last: Mutex::new(None),
+        };
+        let metrics = Arc::new(Metrics::new());
+        let rate_limiter = config.rate_limit_rps.map(|rps| {
+            let burst = config.rate_limit_burst.unwrap_or(rps.ceil() as u32);
+            let limiter = Arc::new(RateLimiter::new(rps, burst));
+            ratelimit::spawn_sweeper(limiter.clone(), Duration::from_secs(60));
+            limiter
+        });
+        let cors = (!config.cors_allowed_origins.is_empty()).then(|| {
+            Cors::new(
+                config.cors_allowed_origins.clone(),
+                HeaderValue::from_str(&config.cors_allowed_methods).expect("validated by Config::load"),
+                HeaderValue::from_str(&config.cors_allowed_headers).expect("validated by Config::load"),
+                config.cors_max_age_secs,
+            )
+        });
+
+        // Terminate TLS on the listener when a certificate and key are configured.
+        let tls = match (&config.tls_cert_path, &config.tls_key_path) {
+            (Some(cert), Some(key)) => {
+                let tls_config = tls::server_config(cert, key, config.http2).map_err(StartupError::Tls)?;
+                Some(TlsAcceptor::from(tls_config))
+            }
+            _ => None,
+        };
+
+        // Build the client once so every request shares the same connection pool.
+        // Cloning a Hyper client is cheap: clones share the underlying pool.
+        // The connector speaks both plain HTTP and HTTPS, chosen per request by
+        // the upstream URI's scheme.
+        let mut http = HttpConnector::new();
+        http.enforce_http(false);
+        // A failed connect is a 502 like any connection error, and is retried
+        // for bodiless requests within the overall upstream deadline.
+        http.set_connect_timeout(config.connect_timeout_ms.map(Duration::from_millis));
+        http.set_nodelay(config.tcp_nodelay);
+        http.set_keepalive(config.tcp_keepalive_secs.map(Duration::from_secs));
+        // With `upstream_http2` every upstream connection is HTTP/2: negotiated
+        // via ALPN over TLS, prior knowledge (h2c) over plain HTTP.
+        let builder = HttpsConnectorBuilder::new()
+            .with_tls_config(tls::client_config(config.upstream_insecure_skip_verify))
+            .https_or_http();
+        let connector = if config.upstream_http2 {
+            builder.enable_http2().wrap_connector(http)
+        } else {
+            builder.enable_http1().wrap_connector(http)
+        };
+        // Without upstream keep-alive no connection is kept for reuse.
+        let max_idle = if config.upstream_keep_alive { config.pool_max_idle_per_host } else { 0 };
+        let client: HttpClient = Client::builder()
+            .http2_only(config.upstream_http2)
+            .pool_idle_timeout(Duration::from_secs(config.pool_idle_timeout_secs))
+            .pool_max_idle_per_host(max_idle)
+            .build(connector);
+        let maintenance = Arc::new(Maintenance::new(
+            config.maintenance_mode,
+            StatusCode::from_u16(config.maintenance_status).expect("validated by Config::load"),
+            config.maintenance_body.clone(),
+        ));
+
+        let state = Arc::new(ProxyState {
+            auth: RwLock::new(Arc::new(auth)),
+            error_pages: RwLock::new(Arc::new(error_pages)),
+            router,
+            middleware: self.middleware,
+            selector: self.selector,
+            transform: self.transform,
+            strip_auth_header: config.strip_auth_header,
+            upstream_auth: config
+                .upstream_auth_header
+                .as_ref()
+                .map(|v| HeaderValue::from_str(v).expect("validated by Config::load")),
+            upstream_path_prefix: config.upstream_path_prefix.clone(),
+            client,
+            upstream_timeout,
+            expect_continue_timeout: config.expect_continue_timeout_ms.map(Duration::from_millis),
+            retry,
+            retry_budget: config.retry_budget_ratio.map(RetryBudget::new),
+            rate_limiter,
+            max_body_bytes: config.max_body_bytes,
+            request_body_timeout: config.request_body_timeout_ms.map(Duration::from_millis),
+            spool_dir: config.spool_dir.clone().unwrap_or_else(env::temp_dir),
+            max_headers: config.max_headers,
+            max_requests_per_conn: config.max_requests_per_conn,
+            client_idle_timeout: config.client_idle_timeout_secs.map(Duration::from_secs),
+            proxy_protocol: config.proxy_protocol,
+            conn_limiter: config.max_conns_per_ip.map(|max| Arc::new(ConnLimiter::new(max))),
+            max_header_bytes: config.max_header_bytes,
+            health_path: config.health_path.clone(),
+            ready_path: config.ready_path.clone(),
+            readiness,
+            version_path: config.version_path.clone(),
+            metrics,
+            slo_threshold: config.slo_threshold_ms.map(Duration::from_millis),
+            upstream_http2: config.upstream_http2,
+            request_headers: config.request_headers.clone(),
+            response_headers: config.response_headers.clone(),
+            add_via_header: config.add_via_header,
+            strip_server_header: config.strip_server_header,
+            cors,
+            compression: config
+                .enable_compression
+                .then(|| Compression::new(config.compression_min_bytes)),
+            decompression: config.enable_decompression,
+            admin: config.admin_enabled.then(|| {
+                let token = config.admin_token.clone().expect("validated by Config::load");
+                Admin::new(token, maintenance.clone())
+            }),
+            maintenance,
+            error_format: config.error_format,
+            sticky: config
+                .sticky_sessions
+                .map(|key| Stickiness::new(key, config.sticky_cookie.clone())),
+            concurrency: config.max_concurrent_requests.map(|max| {
+                ConcurrencyLimit::new(max, config.concurrency_queue_ms.map(Duration::from_millis))
+            }),
+            outstanding: config.max_outstanding_upstream.map(|max| ConcurrencyLimit::new(max, None)),
+            otel: telemetry::enabled(),
+            trace_sampler: config.trace_sample_rate.map(Sampler::new),
+            ip_filter: (!config.ip_allow.0.is_empty() || !config.ip_deny.0.is_empty())
+                .then(|| IpFilter::new(config.ip_allow.0.clone(), config.ip_deny.0.clone())),
+            public_url: config
+                .public_url
+                .as_ref()
+                .map(|url| url.parse().expect("validated by Config::load")),
+            normalize_path: config.normalize_path.then_some(PathNormalizer {
+                preserve_trailing_slash: config.preserve_trailing_slash,
+            }),
+            cache: config
+                .cache_max_entries
+                .map(|max| Arc::new(ResponseCache::new(max, config.cache_max_bytes))),
+            methods: (!config.allowed_methods.is_empty())
+                .then(|| MethodFilter::new(config.methods().expect("validated by Config::load"))),
+            blocked_methods: config.blocked_methods(),
+            srv,
+            stealth: config.stealth_mode.then(|| Stealth {
+                paths: config.stealth_paths.iter().cloned().collect(),
+                status: StatusCode::from_u16(config.stealth_status).expect("validated by Config::load"),
+                body: Bytes::from(config.stealth_body.clone()),
+            }),
+            no_route: config.no_route_body.as_ref().map(|body| NoRoutePage {
+                body: Bytes::from(body.clone()),
+                content_type: HeaderValue::from_str(&config.no_route_content_type)
+                    .expect("validated by Config::load"),
+            }),
+            canary: config.canary_url.as_ref().map(|url| {
+                let header = HeaderName::from_bytes(config.canary_header.as_bytes())
+                    .expect("validated by Config::load");
+                Canary::new(balancer(url), config.canary_percent, header)
+            }),
+            mirror: config.mirror_url.as_ref().map(|url| {
+                Mirror::new(
+                    url.parse().expect("validated by Config::load"),
+                    config.mirror_percent,
+                    config.mirror_max_body_bytes,
+                    upstream_timeout,
+                )
+            }),
+            echo_mode: config.echo_mode,
+            forward_proxy: config.forward_proxy_mode,
+            breaker: config
+                .cb_failure_threshold
+                .map(|threshold| CircuitBreaker::new(threshold, Duration::from_secs(config.cb_open_secs))),
+        });
+
+        // Connections are HTTP/1.1 only unless HTTP/2 is enabled, in which
+        // case Hyper also accepts h2 (via ALPN with TLS, or prior knowledge
+        // without).
+        let mut http = Http::new();
+        http.http1_only(!config.http2);
+        http.http1_keep_alive(config.keep_alive);
+        // With a header size limit, Hyper stops reading well before
+        // buffering an unbounded header block.
+        if let Some(max) = config.max_header_bytes {
+            http.max_buf_size(headers::read_buf_size(max));
+            http.http2_max_header_list_size(u32::try_from(max).unwrap_or(u32::MAX));
+        }
+        let listen = Listen {
+            addrs: config.bind_addr.0.clone(),
+            unix_socket_mode: config.unix_socket_mode,
+            socket_options: SocketOptions {
+                reuse_addr: config.reuse_addr,
+                backlog: config.listen_backlog,
+                nodelay: config.tcp_nodelay,
+                keepalive: config.tcp_keepalive_secs.map(Duration::from_secs),
+            },
+            tls,
+            http,
+            metrics_addr: config.metrics_addr,
+            shutdown_grace: Duration::from_secs(config.shutdown_grace_secs),
+            wait_for_upstream: config
+                .wait_for_upstream
+                .then(|| Duration::from_secs(config.wait_for_upstream_secs)),
+        };
+        Ok(Proxy {
+            state,
+            listen: Arc::new(listen),
+        })
+    }
+}
+
+// Where and how a proxy accepts connections.
+struct Listen {
+    addrs: Vec<BindAddr>,
+    unix_socket_mode: Option<SocketMode>,
+    socket_options: SocketOptions,
+    tls: Option<TlsAcceptor>,
+    http: Http,
+    metrics_addr: SocketAddr,
+    shutdown_grace: Duration,
+    // How long to wait for the upstream before listening, if at all.
+    wait_for_upstream: Option<Duration>,
+}
+
+// A configured proxy, ready to serve. Clones are cheap and share all state,
+// so one clone can serve while another reloads the auth settings.
+#[derive(Clone)]
+pub struct Proxy {
+    state: Arc<ProxyState>,
+    listen: Arc<Listen>,
+}
+
+impl Proxy {
+    pub fn builder(config: ProxyConfig) -> ProxyBuilder {
+        ProxyBuilder {
+            config,
+            middleware: None,
+            selector: None,
+            transform: None,
+        }
+    }
+
+    // The pipeline (auth, routing, forwarding) inside any added layers, as
+    // a Tower service for requests from the client at `remote_addr`, for
+    // embedders serving connections themselves.
+    pub fn service(&self, remote_addr: SocketAddr) -> ProxyService {
+        middleware::service(self.state.clone(), ConnInfo { remote_addr, tls: false })
+    }
+
+    // Run one request through `service`.
+    pub async fn handle(&self, req: Request<Body>, remote_addr: SocketAddr) -> Response<Body> {
+        match self.service(remote_addr).oneshot(req).await {
+            Ok(resp) => resp,
+            Err(never) => match never {},
+        }
+    }
+
+    // Replace the auth settings (tokens, Basic credentials, JWT key) with
+    // those in `config`. In-flight requests finish under the settings they
+    // started with. On error the current settings stay in place.
+    pub fn reload_auth(&self, config: &ProxyConfig) -> Result<(), String> {
+        let auth = AuthConfig::from_config(config)?;
+        *self.state.auth.write().unwrap() = Arc::new(auth);
+        Ok(())
+    }
+
+    // Re-read the error page files from `config`. On error the current
+    // pages stay in place.
+    pub fn reload_error_pages(&self, config: &ProxyConfig) -> Result<(), String> {
+        let error_pages = ErrorPages::load(&config.error_pages)?;
+        *self.state.error_pages.write().unwrap() = Arc::new(error_pages);
+        Ok(())
+    }
+
+    // Turn maintenance mode on or off, as `/admin/maintenance` does.
+    pub fn set_maintenance(&self, on: bool) {
+        self.state.maintenance.set(on);
+    }
+
+    // Serve on the configured addresses until SIGINT, SIGTERM, or the admin
+    // shutdown endpoint, then drain connections.
+    pub async fn serve(&self) -> Result<(), StartupError> {
+        self.serve_with_shutdown(shutdown_signal()).await
+    }
+
+    // Like `serve`, but `signal` resolving takes the place of SIGINT and
+    // SIGTERM.
+    pub async fn serve_with_shutdown(&self, signal: impl Future<Output = ()>) -> Result<(), StartupError> {
+        let state = &self.state;
+        let listen = &*self.listen;
+
+        // Hold off listening until the upstream answers, so orchestrators
+        // don't see the proxy as up while it can only return errors.
+        if let Some(max_wait) = listen.wait_for_upstream {
+            info!("Waiting up to {:?} for the upstream to become ready", max_wait);
+            if let Err(reason) = wait_for_upstream(state, max_wait).await {
+                return Err(StartupError::UpstreamNotReady { waited: max_wait, reason });
+            }
+            info!("Upstream is ready");
+        }
+
+        // Metrics live on their own listener so scraping needs no auth token.
+        let metrics = state.metrics.clone();
+        let metrics_addr = listen.metrics_addr;
+        tokio::spawn(async move {
+            info!("Serving metrics on http://{}/metrics", metrics_addr);
+            if let Err(e) = metrics::serve(metrics_addr, metrics).await {
+                error!("metrics server error: {}", e);
+            }
+        });
+
+        // Every address is bound before any is served, so a bad one stops
+        // startup rather than leaving the proxy half up.
+        let scheme = if listen.tls.is_some() { "https" } else { "http" };
+        let mut listeners = Vec::with_capacity(listen.addrs.len());
+        for addr in &listen.addrs {
+            let listener = server::bind(addr, listen.unix_socket_mode, listen.socket_options)
+                .await
+                .map_err(|source| StartupError::Bind {
+                    addr: addr.to_string(),
+                    source,
+                })?;
+            listeners.push(listener);
+            match addr {
+                BindAddr::Tcp(addr) => info!("Listening on {}://{}", scheme, addr),
+                BindAddr::Unix(_) => info!("Listening on {} ({})", addr, scheme),
+            }
+        }
+
+        // Once triggered, the server stops accepting and lets in-flight
+        // requests finish; whatever is still open after the grace period is
+        // dropped.
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let mut servers = JoinSet::new();
+        for listener in listeners {
+            servers.spawn(server::run(
+                listener,
+                listen.http.clone(),
+                listen.tls.clone(),
+                state.clone(),
+                shutdown_rx.clone(),
+            ));
+        }
+        // A listener only returns on shutdown, so one finishing early means its
+        // task panicked; the others are drained and the failure is reported.
+        let failed = tokio::select! {
+            stopped = servers.join_next() => Some(match stopped {
+                Some(Err(e)) => e.to_string(),
+                _ => "listener exited".to_string(),
+            }),
+            _ = signal => None,
+            _ = admin_shutdown(state) => None,
+        };
+
+        match &failed {
+            Some(reason) => error!("Listener stopped unexpectedly ({}), shutting down", reason),
+            None => info!(
+                "Shutdown signal received, draining connections (grace period {:?})",
+                listen.shutdown_grace
+            ),
+        }
+        let _ = shutdown_tx.send(true);
+        let drained = async { while servers.join_next().await.is_some() {} };
+        match tokio::time::timeout(listen.shutdown_grace, drained).await {
+            Ok(_) => info!("Shutdown complete"),
+            Err(_) => warn!("Grace period elapsed, closing remaining connections"),
+        }
+        match failed {
+            Some(reason) => Err(StartupError::Listener(reason)),
+            None => Ok(()),
+        }
+    }
+}
+
+// Build a proxy from `config` and serve it until shutdown.
+pub async fn run(config: ProxyConfig) -> Result<(), StartupError> {
+    Proxy::builder(config).build()?.serve().await
+}
+
+// Resolves when shutdown is requested through the admin endpoint; never if
+// admin endpoints are disabled.
+async fn admin_shutdown(state: &ProxyState) {
+    match &state.admin {
+        Some(admin) => admin.shutdown_requested().await,
+        None => std::future::pending().await,
+    }
+}
+
+// Resolves when the process receives SIGINT (Ctrl-C) or SIGTERM.
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("failed to install SIGTERM handler");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+}
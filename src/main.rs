@@ -1,118 +1,165 @@
-// Simple HTTP proxy with auth validation using hyper and tower
-//
-// This server validates the "Authorization" header against a token set in the
-// `AUTH_TOKEN` environment variable. If the header is missing or does not match,
-// it returns a 401 Unauthorized response. Otherwise, it forwards the request
-// to an upstream server defined by the `UPSTREAM_URL` environment variable.
-//
-// The implementation uses Hyper's client and server APIs together with Tower's
-// Service traits for clean separation of concerns.
+// The `simple_proxy` binary: reads its settings from the environment and/or
+// a TOML file, sets up logging, and runs the proxy from the library until
+// SIGINT or SIGTERM. SIGHUP reloads the auth settings, error pages and
+// maintenance mode.
 
-use hyper::{Body, Client, Request, Response, Server, Uri};
-use hyper::service::{make_service_fn, service_fn};
-use hyper::client::HttpConnector;
-use std::convert::Infallible;
+use simple_proxy::logfile::RotatingFile;
+use simple_proxy::{telemetry, Proxy, ProxyConfig};
 use std::env;
-use std::net::SocketAddr;
-use tower::ServiceBuilder;
-use http::header::AUTHORIZATION;
+use std::path::PathBuf;
+use std::process;
+use std::sync::Mutex;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{error, info, Metadata};
+use tracing_subscriber::filter::filter_fn;
+use tracing_subscriber::fmt;
+use tracing_subscriber::layer::{Layer, SubscriberExt};
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::EnvFilter;
 
-// Simple auth middleware – checks the Authorization header against a token.
-async fn authorize(req: Request<Body>, auth_token: String) -> Result<Request<Body>, Response<Body>> {
-    // Extract the header value
-    match req.headers().get(AUTHORIZATION) {
-        Some(value) => {
-            if value.to_str().ok() == Some(&auth_token) {
-                Ok(req)
-            } else {
-                Err(Response::builder()
-                    .status(401)
-                    .body(Body::from("Invalid auth token"))
-                    .unwrap())
+// Install the global tracing subscriber. `RUST_LOG` controls filtering
+// (default `info`); `LOG_FORMAT=json` switches to JSON lines on stdout.
+// With `access_log_file` set in `config`, access log lines go to that file
+// instead, in the same format. With `OTEL_EXPORTER_OTLP_ENDPOINT` set, spans
+// are also exported over OTLP; returns whether that is on.
+fn init_logging(config: Option<&ProxyConfig>) -> bool {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let json = matches!(env::var("LOG_FORMAT"), Ok(v) if v.eq_ignore_ascii_case("json"));
+    let access_log = config.and_then(|config| Some((config.access_log_file.as_ref()?, config)));
+    let (access_file, file_err) = match access_log {
+        Some((path, config)) => {
+            match RotatingFile::open(path, config.access_log_max_bytes, config.access_log_max_files) {
+                Ok(file) => (Some(Mutex::new(file)), None),
+                Err(e) => (None, Some(format!("{}: {}", path.display(), e))),
             }
         }
-        None => Err(Response::builder()
-            .status(401)
-            .body(Body::from("Missing Authorization header"))
-            .unwrap()),
+        None => (None, None),
+    };
+    let to_file = access_file.is_some();
+    let stdout = filter_fn(move |meta| !(to_file && is_access(meta)));
+    let (stdout_text, stdout_json) = if json {
+        (None, Some(fmt::layer().json().with_filter(stdout)))
+    } else {
+        (Some(fmt::layer().with_filter(stdout)), None)
+    };
+    let (file_text, file_json) = match access_file {
+        Some(file) if json => {
+            let layer = fmt::layer().json().with_writer(file);
+            (None, Some(layer.with_filter(filter_fn(is_access))))
+        }
+        Some(file) => {
+            let layer = fmt::layer().with_ansi(false).with_writer(file);
+            (Some(layer.with_filter(filter_fn(is_access))), None)
+        }
+        None => (None, None),
+    };
+    let (otel, otel_err) = match env::var("OTEL_EXPORTER_OTLP_ENDPOINT") {
+        Ok(endpoint) if !endpoint.is_empty() => match telemetry::init(&endpoint) {
+            Ok(tracer) => (Some(tracing_opentelemetry::layer().with_tracer(tracer)), None),
+            Err(e) => (None, Some(e)),
+        },
+        _ => (None, None),
+    };
+    let enabled = otel.is_some();
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(stdout_text)
+        .with(stdout_json)
+        .with(file_text)
+        .with(file_json)
+        .with(otel)
+        .init();
+    if let Some(e) = file_err {
+        error!("cannot open access log file, logging to stdout: {}", e);
     }
-}
-
-// Forward the request to the upstream server.
-async fn forward(req: Request<Body>, upstream_base: Uri) -> Result<Response<Body>, hyper::Error> {
-    // Build new URI preserving path and query.
-    let orig_uri = req.uri();
-    // Extract the path and query from the original request.
-    let path_and_query = orig_uri.path_and_query().map(|pq| pq.as_str()).unwrap_or("/");
-    // Clone upstream_base to avoid moving it.
-    let mut parts = upstream_base.clone().into_parts();
-    parts.path_and_query = Some(path_and_query.parse().unwrap());
-    let uri = Uri::from_parts(parts).expect("valid upstream URI");
-
-    // Clone the request method and headers.
-    let (mut parts_req, body) = req.into_parts();
-    parts_req.uri = uri;
-    // Optionally adjust Host header to match upstream host.
-    if let Some(authority) = upstream_base.authority() {
-        parts_req.headers.insert("host", authority.as_str().parse().unwrap());
+    if let Some(e) = otel_err {
+        error!("cannot start OpenTelemetry exporter, tracing disabled: {}", e);
     }
-    let new_req = Request::from_parts(parts_req, body);
+    enabled
+}
 
-    // Use a Hyper client to send the request.
-    let client: Client<HttpConnector> = Client::new();
-    client.request(new_req).await
+fn is_access(meta: &Metadata<'_>) -> bool {
+    meta.target() == "access"
 }
 
-async fn handle(req: Request<Body>, auth_token: String, upstream_base: Uri) -> Result<Response<Body>, Infallible> {
-    // First, run the auth check.
-    match authorize(req, auth_token).await {
-        Ok(authenticated_req) => {
-            // Forward the request; any client error becomes a 502 response.
-            match forward(authenticated_req, upstream_base).await {
-                Ok(resp) => Ok(resp),
-                Err(_) => Ok(Response::builder()
-                    .status(502)
-                    .body(Body::from("Bad Gateway"))
-                    .unwrap()),
-            }
+// Find `--config <path>` or `--config=<path>` among the process arguments.
+fn config_path_from_args<I>(mut args: I) -> Result<Option<PathBuf>, String>
+where
+    I: Iterator<Item = String>,
+{
+    let mut path = None;
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            let value = args.next().ok_or_else(|| "--config requires a path".to_string())?;
+            path = Some(PathBuf::from(value));
+        } else if let Some(value) = arg.strip_prefix("--config=") {
+            path = Some(PathBuf::from(value));
+        } else {
+            return Err(format!("unknown argument `{}`", arg));
         }
-        Err(auth_resp) => Ok(auth_resp),
     }
+    Ok(path)
 }
 
 #[tokio::main]
 async fn main() {
-    // Load configuration from environment variables.
-    let auth_token = env::var("AUTH_TOKEN").expect("AUTH_TOKEN must be set");
-    let upstream_str = env::var("UPSTREAM_URL").expect("UPSTREAM_URL must be set");
-    let upstream_base: Uri = upstream_str.parse().expect("Invalid UPSTREAM_URL");
-
-    // Server address – default to 127.0.0.1:3000 if not provided.
-    let addr: SocketAddr = env::var("BIND_ADDR")
-        .unwrap_or_else(|_| "127.0.0.1:3000".to_string())
-        .parse()
-        .expect("Invalid bind address");
-
-    // Build a service that clones the needed config for each request.
-    let make_svc = make_service_fn(move |_conn| {
-        let auth_token = auth_token.clone();
-        let upstream_base = upstream_base.clone();
-        async move {
-            Ok::<_, Infallible>(service_fn(move |req| {
-                let auth_token = auth_token.clone();
-                let upstream_base = upstream_base.clone();
-                handle(req, auth_token, upstream_base)
-            }))
-        }
+    // Load configuration from the optional config file and the environment.
+    // Logging depends on it, so an invalid configuration is reported once
+    // logging is up, without the access log file.
+    let loaded = config_path_from_args(env::args().skip(1)).and_then(|path| {
+        let config = ProxyConfig::load_from(path.as_deref()).map_err(|e| e.to_string())?;
+        Ok((path, config))
     });
+    let otel = init_logging(loaded.as_ref().ok().map(|(_, config)| config));
+    let (config_path, config) = match loaded {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            error!("configuration error: {}", e);
+            process::exit(1);
+        }
+    };
+    let proxy = match Proxy::builder(config).build() {
+        Ok(proxy) => proxy,
+        Err(e) => {
+            error!("{}", e);
+            process::exit(1);
+        }
+    };
+    tokio::spawn(reload_on_sighup(proxy.clone(), config_path));
+    if let Err(e) = proxy.serve().await {
+        error!("{}", e);
+        process::exit(1);
+    }
+    if otel {
+        telemetry::shutdown();
+    }
+}
 
-    // Build server with Tower middleware (currently only ServiceBuilder placeholder).
-    let service = ServiceBuilder::new().service(make_svc);
-
-    let server = Server::bind(&addr).serve(service);
-    println!("Listening on http://{}", addr);
-
-    if let Err(e) = server.await {
-        eprintln!("server error: {}", e);
+// Reload the auth settings (tokens, Basic credentials, JWT key), error pages
+// and maintenance mode from the config file (`config_path`, or else
+// `CONFIG_FILE`) and environment on every SIGHUP.
+// An invalid new configuration is logged and the current settings stay in
+// place. Other settings still need a restart.
+async fn reload_on_sighup(proxy: Proxy, config_path: Option<PathBuf>) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            error!("cannot install SIGHUP handler, auth reload disabled: {}", e);
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        let reloaded = ProxyConfig::load_from(config_path.as_deref())
+            .map_err(|e| e.to_string())
+            .and_then(|config| {
+                proxy.reload_auth(&config)?;
+                proxy.reload_error_pages(&config)?;
+                proxy.set_maintenance(config.maintenance_mode);
+                Ok(())
+            });
+        match reloaded {
+            Ok(()) => info!("Reloaded configuration"),
+            Err(e) => error!("reload failed, keeping current settings: {}", e),
+        }
     }
 }
@@ -0,0 +1,42 @@
+// Maintenance mode (`MAINTENANCE_MODE`): while on, the proxy answers
+// requests itself with the configured status and body instead of forwarding
+// them. The switch can be flipped at runtime, through SIGHUP or the admin
+// endpoint, without a restart.
+
+use crate::error::text_response;
+use hyper::body::Bytes;
+use hyper::{Body, Response, StatusCode};
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing::warn;
+
+pub struct Maintenance {
+    enabled: AtomicBool,
+    status: StatusCode,
+    body: Bytes,
+}
+
+impl Maintenance {
+    pub fn new(enabled: bool, status: StatusCode, body: String) -> Maintenance {
+        Maintenance {
+            enabled: AtomicBool::new(enabled),
+            status,
+            body: Bytes::from(body),
+        }
+    }
+
+    pub fn is_on(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    // Turn maintenance mode on or off, logging when that changes anything.
+    pub fn set(&self, on: bool) {
+        if self.enabled.swap(on, Ordering::Relaxed) != on {
+            warn!("maintenance mode {}", if on { "on" } else { "off" });
+        }
+    }
+
+    // The maintenance response while maintenance mode is on.
+    pub fn response(&self) -> Option<Response<Body>> {
+        self.is_on().then(|| text_response(self.status, self.body.clone()))
+    }
+}
@@ -0,0 +1,199 @@
+// Prometheus metrics for the proxy.
+//
+// Metrics are recorded by the request handler and exposed in the Prometheus
+// text format on a separate listener (`METRICS_ADDR`), which never requires
+// the auth token and is not reachable through the proxy port.
+
+use crate::error::text_response;
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server, StatusCode};
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::error;
+
+pub struct Metrics {
+    registry: Registry,
+    requests_total: IntCounterVec,
+    upstream_duration: Histogram,
+    slo_total: IntCounterVec,
+    upstream_errors: IntCounterVec,
+    active_connections: IntGauge,
+    upstream_in_flight: IntGauge,
+    accept_errors: IntCounter,
+}
+
+// Keeps a gauge raised for as long as it's alive, e.g. while a connection is
+// open; dropping it, however that ended, takes it off the gauge.
+pub struct GaugeGuard(IntGauge);
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        let registry = Registry::new();
+
+        let requests_total = IntCounterVec::new(
+            Opts::new("proxy_requests_total", "Requests handled by the proxy, by response status."),
+            &["status"],
+        )
+        .unwrap();
+        let upstream_duration = Histogram::with_opts(HistogramOpts::new(
+            "proxy_upstream_duration_seconds",
+            "Time spent waiting for upstream response headers.",
+        ))
+        .unwrap();
+        let slo_total = IntCounterVec::new(
+            Opts::new(
+                "proxy_slo_requests_total",
+                "Upstream requests by whether their latency met the SLO threshold.",
+            ),
+            &["slo"],
+        )
+        .unwrap();
+        let upstream_errors = IntCounterVec::new(
+            Opts::new(
+                "proxy_upstream_errors_total",
+                "Failed upstream requests, by kind (dns, connect, timeout, protocol, body).",
+            ),
+            &["kind"],
+        )
+        .unwrap();
+        let active_connections =
+            IntGauge::new("proxy_active_connections", "Client connections currently open.").unwrap();
+        let upstream_in_flight = IntGauge::new(
+            "proxy_upstream_requests_in_flight",
+            "Requests sent upstream and still waiting for response headers.",
+        )
+        .unwrap();
+        let accept_errors =
+            IntCounter::new("proxy_accept_errors_total", "Failures accepting client connections.").unwrap();
+
+        registry.register(Box::new(requests_total.clone())).unwrap();
+        registry.register(Box::new(upstream_duration.clone())).unwrap();
+        registry.register(Box::new(slo_total.clone())).unwrap();
+        registry.register(Box::new(upstream_errors.clone())).unwrap();
+        registry.register(Box::new(active_connections.clone())).unwrap();
+        registry.register(Box::new(upstream_in_flight.clone())).unwrap();
+        registry.register(Box::new(accept_errors.clone())).unwrap();
+
+        Metrics {
+            registry,
+            requests_total,
+            upstream_duration,
+            slo_total,
+            upstream_errors,
+            active_connections,
+            upstream_in_flight,
+            accept_errors,
+        }
+    }
+
+    // Count a finished request under its response status code.
+    pub fn observe_request(&self, status: u16) {
+        self.requests_total
+            .with_label_values(&[&status.to_string()])
+            .inc();
+    }
+
+    pub fn observe_upstream(&self, latency: Duration) {
+        self.upstream_duration.observe(latency.as_secs_f64());
+    }
+
+    // Count a request under its SLO tag, `met` or `violated`.
+    pub fn observe_slo(&self, slo: &str) {
+        self.slo_total.with_label_values(&[slo]).inc();
+    }
+
+    // Count a failed upstream request under its kind, e.g. `connect`.
+    pub fn observe_upstream_error(&self, kind: &str) {
+        self.upstream_errors.with_label_values(&[kind]).inc();
+    }
+
+    // Count a newly accepted connection until the returned guard is dropped.
+    pub fn connection_opened(&self) -> GaugeGuard {
+        self.active_connections.inc();
+        GaugeGuard(self.active_connections.clone())
+    }
+
+    // Count a request as outstanding upstream until the guard is dropped.
+    pub fn upstream_request_started(&self) -> GaugeGuard {
+        self.upstream_in_flight.inc();
+        GaugeGuard(self.upstream_in_flight.clone())
+    }
+
+    pub fn observe_accept_error(&self) {
+        self.accept_errors.inc();
+    }
+
+    // Render every registered metric in the Prometheus text format.
+    fn render(&self) -> prometheus::Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buf)?;
+        Ok(buf)
+    }
+}
+
+async fn handle(req: Request<Body>, metrics: Arc<Metrics>) -> Result<Response<Body>, Infallible> {
+    if req.uri().path() != "/metrics" {
+        return Ok(text_response(StatusCode::NOT_FOUND, "Not Found"));
+    }
+    let resp = match metrics.render() {
+        Ok(buf) => {
+            let mut resp = text_response(StatusCode::OK, buf);
+            resp.headers_mut().insert(
+                CONTENT_TYPE,
+                HeaderValue::from_static(prometheus::TEXT_FORMAT),
+            );
+            resp
+        }
+        Err(e) => {
+            error!("cannot render metrics: {}", e);
+            text_response(StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error")
+        }
+    };
+    Ok(resp)
+}
+
+// Serve `/metrics` on its own address until the process exits.
+pub async fn serve(addr: SocketAddr, metrics: Arc<Metrics>) -> Result<(), hyper::Error> {
+    let make_svc = make_service_fn(move |_conn| {
+        let metrics = metrics.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |req| handle(req, metrics.clone()))) }
+    });
+    Server::try_bind(&addr)?.serve(make_svc).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn scrape(metrics: &Arc<Metrics>) -> String {
+        let req = Request::get("/metrics").body(Body::empty()).unwrap();
+        let resp = handle(req, metrics.clone()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn request_counter_increments() {
+        let metrics = Arc::new(Metrics::new());
+        metrics.observe_request(200);
+        assert!(scrape(&metrics).await.contains(r#"proxy_requests_total{status="200"} 1"#));
+        metrics.observe_request(200);
+        metrics.observe_request(502);
+        let text = scrape(&metrics).await;
+        assert!(text.contains(r#"proxy_requests_total{status="200"} 2"#));
+        assert!(text.contains(r#"proxy_requests_total{status="502"} 1"#));
+    }
+}
@@ -0,0 +1,58 @@
+// Tower middleware supplied by embedders (`ProxyBuilder::layer`).
+//
+// The request pipeline (auth, routing, forwarding) is a Tower service,
+// `ProxyService`, and embedders can wrap it in layers of their own, e.g. for
+// extra auth checks or logging. Their layers see every request before the
+// proxy does, health probes included, and every response after it. Layers
+// added first are outermost, as with `ServiceBuilder`.
+
+use crate::{handle, ConnInfo, ProxyState};
+use hyper::service::service_fn;
+use hyper::{Body, Request, Response};
+use std::convert::Infallible;
+use std::sync::Arc;
+use tower::util::BoxCloneService;
+use tower::{Layer, Service};
+
+// The pipeline as seen by embedder layers: requests in, responses out. It
+// never fails; errors are already responses.
+pub type ProxyService = BoxCloneService<Request<Body>, Response<Body>, Infallible>;
+
+// The embedder's layers, applied to the pipeline of each connection.
+#[derive(Clone)]
+pub struct Middleware(Arc<dyn Fn(ProxyService) -> ProxyService + Send + Sync>);
+
+impl Middleware {
+    pub fn new<L>(layer: L) -> Middleware
+    where
+        L: Layer<ProxyService> + Send + Sync + 'static,
+        L::Service:
+            Service<Request<Body>, Response = Response<Body>, Error = Infallible> + Clone + Send + 'static,
+        <L::Service as Service<Request<Body>>>::Future: Send + 'static,
+    {
+        Middleware(Arc::new(move |inner| BoxCloneService::new(layer.layer(inner))))
+    }
+
+    // These layers with `layer` added inside them, next to the pipeline.
+    pub fn then<L>(self, layer: L) -> Middleware
+    where
+        L: Layer<ProxyService> + Send + Sync + 'static,
+        L::Service:
+            Service<Request<Body>, Response = Response<Body>, Error = Infallible> + Clone + Send + 'static,
+        <L::Service as Service<Request<Body>>>::Future: Send + 'static,
+    {
+        let inner = Middleware::new(layer);
+        Middleware(Arc::new(move |service| (self.0)((inner.0)(service))))
+    }
+}
+
+// The pipeline for requests arriving on `conn`, wrapped in the embedder's
+// layers, if any.
+pub fn service(state: Arc<ProxyState>, conn: ConnInfo) -> ProxyService {
+    let middleware = state.middleware.clone();
+    let pipeline = BoxCloneService::new(service_fn(move |req| handle(req, state.clone(), conn)));
+    match middleware {
+        Some(middleware) => (middleware.0)(pipeline),
+        None => pipeline,
+    }
+}
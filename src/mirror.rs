@@ -0,0 +1,97 @@
+// Request mirroring (`MIRROR_URL`), for trying a new backend on real
+// traffic.
+//
+// A sampled share of proxied requests is also sent to the mirror upstream,
+// with the same method, path, query and headers, except `Authorization`:
+// whether it is the client's or `UPSTREAM_AUTH_HEADER`'s, the credential is
+// for the primary and isn't shared with the mirror. The copy goes out in a
+// background task once the primary upstream has read the whole request
+// body, and the mirror's response or failure is discarded, so the client
+// only ever sees the primary's answer. Bodies are copied in memory as they
+// stream to the primary; requests with bodies over the size limit aren't
+// mirrored.
+
+use crate::balancer::{draw, roll};
+use crate::{body, point_at, HttpClient};
+use hyper::body::{Bytes, HttpBody};
+use hyper::http::request::Parts;
+use hyper::header::AUTHORIZATION;
+use hyper::http::uri::PathAndQuery;
+use hyper::{Body, Request, Uri};
+use std::future::Future;
+use std::time::Duration;
+use tracing::debug;
+
+pub struct Mirror {
+    upstream: Uri,
+    // Share of requests mirrored, 0–100.
+    percent: f64,
+    max_body_bytes: u64,
+    timeout: Duration,
+}
+
+impl Mirror {
+    pub fn new(upstream: Uri, percent: f64, max_body_bytes: u64, timeout: Duration) -> Mirror {
+        Mirror {
+            upstream,
+            percent,
+            max_body_bytes,
+            timeout,
+        }
+    }
+
+    // If this request is picked for mirroring, start a background task
+    // sending a copy of it, and return the body to forward to the primary in
+    // place of `body`. `parts` must be ready to send apart from the URI and
+    // Host, which are pointed at the mirror.
+    pub fn tap(
+        &self,
+        parts: &Parts,
+        path_and_query: &PathAndQuery,
+        body: Body,
+        client: &HttpClient,
+    ) -> Body {
+        if !draw(self.percent, roll()) {
+            return body;
+        }
+        let mut copy = Request::new(()).into_parts().0;
+        copy.method = parts.method.clone();
+        copy.version = parts.version;
+        copy.headers = parts.headers.clone();
+        copy.headers.remove(AUTHORIZATION);
+        if let Err(e) = point_at(&mut copy, &self.upstream, path_and_query) {
+            debug!(error = %e, "cannot mirror request");
+            return body;
+        }
+        if body.is_end_stream() {
+            self.spawn(copy, async { Some(Bytes::new()) }, client);
+            return body;
+        }
+        let (body, copied) = body::tee(body, self.max_body_bytes);
+        self.spawn(copy, async { copied.await.ok() }, client);
+        body
+    }
+
+    fn spawn(
+        &self,
+        parts: Parts,
+        body: impl Future<Output = Option<Bytes>> + Send + 'static,
+        client: &HttpClient,
+    ) {
+        let client = client.clone();
+        let timeout = self.timeout;
+        tokio::spawn(async move {
+            // No body means it was too large, or the client never sent it all.
+            let Some(body) = body.await else {
+                return;
+            };
+            let uri = parts.uri.clone();
+            let req = Request::from_parts(parts, Body::from(body));
+            match tokio::time::timeout(timeout, client.request(req)).await {
+                Ok(Ok(resp)) => debug!(%uri, status = resp.status().as_u16(), "mirrored request"),
+                Ok(Err(e)) => debug!(%uri, error = %e, "mirror request failed"),
+                Err(_) => debug!(%uri, "mirror request timed out"),
+            }
+        });
+    }
+}
@@ -0,0 +1,77 @@
+// Request path normalization (`NORMALIZE_PATH`).
+//
+// Duplicate slashes are collapsed and `.`/`..` segments resolved, the way
+// RFC 3986 section 5.2.4 resolves dot segments, so `/api//users` and
+// `/api/v1/../users` both become `/api/users`. Percent-encoded dots
+// (`%2e`) count as dots, otherwise they would slip a traversal past the
+// check. A path whose `..` segments climb above the root is refused rather
+// than clamped. Other percent-encoding, `%2f` included, is left as sent.
+
+use hyper::Uri;
+
+#[derive(Clone, Copy, Debug)]
+pub struct PathNormalizer {
+    // Keep a trailing `/` (`/docs/` stays `/docs/`); otherwise it's dropped.
+    pub preserve_trailing_slash: bool,
+}
+
+impl PathNormalizer {
+    // `uri` with its path normalized and the query untouched, or `None` if
+    // the path escapes the root.
+    pub fn apply(&self, uri: &Uri) -> Option<Uri> {
+        let path = self.normalize(uri.path())?;
+        if path == uri.path() {
+            return Some(uri.clone());
+        }
+        let path_and_query = match uri.query() {
+            Some(query) => format!("{}?{}", path, query),
+            None => path,
+        };
+        let mut parts = uri.clone().into_parts();
+        // Only valid path characters were kept, so the URI stays valid.
+        parts.path_and_query = Some(path_and_query.parse().expect("normalized path is valid"));
+        Some(Uri::from_parts(parts).expect("normalized URI is valid"))
+    }
+
+    // The normalized form of an origin-form `path`, or `None` if it escapes
+    // the root. Anything not starting with `/` (`OPTIONS *`) is kept.
+    fn normalize(&self, path: &str) -> Option<String> {
+        if !path.starts_with('/') {
+            return Some(path.to_string());
+        }
+        let mut segments: Vec<&str> = Vec::new();
+        // A path ending in a dot segment names a directory, like `/a/b/..`
+        // meaning `/a/`.
+        let mut trailing_slash = false;
+        for segment in path[1..].split('/') {
+            trailing_slash = true;
+            if is_dot(segment, 1) || segment.is_empty() {
+                continue;
+            }
+            if is_dot(segment, 2) {
+                segments.pop()?;
+                continue;
+            }
+            segments.push(segment);
+            trailing_slash = false;
+        }
+        let mut normalized = format!("/{}", segments.join("/"));
+        if trailing_slash && self.preserve_trailing_slash && !segments.is_empty() {
+            normalized.push('/');
+        }
+        Some(normalized)
+    }
+}
+
+// Whether `segment` is `count` dots, each written as `.` or `%2e`.
+fn is_dot(segment: &str, count: usize) -> bool {
+    let mut rest = segment;
+    for _ in 0..count {
+        rest = match rest.strip_prefix('.') {
+            Some(rest) => rest,
+            None if rest.len() >= 3 && rest[..3].eq_ignore_ascii_case("%2e") => &rest[3..],
+            None => return false,
+        };
+    }
+    rest.is_empty()
+}
@@ -0,0 +1,127 @@
+// PROXY protocol (`PROXY_PROTOCOL=v1|v2`), as sent by L4 load balancers
+// such as HAProxy or an AWS NLB.
+//
+// The balancer opens the TCP connection, so its address is the socket peer;
+// the real client's address arrives in a header it writes before any client
+// bytes (and before the TLS handshake). Only the header is read, so what
+// follows is served as usual. A header saying the connection is the
+// balancer's own (v1 `UNKNOWN`, v2 `LOCAL`, e.g. health checks) leaves the
+// peer address in place.
+
+use serde::Deserialize;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+// The v2 header's fixed signature.
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+// Longest v1 header allowed by the spec, CRLF included.
+const V1_MAX_LEN: usize = 107;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyProtocol {
+    // The human-readable header (`PROXY TCP4 ...\r\n`).
+    V1,
+    // The binary header.
+    V2,
+}
+
+impl FromStr for ProxyProtocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ProxyProtocol, String> {
+        if s.eq_ignore_ascii_case("v1") {
+            Ok(ProxyProtocol::V1)
+        } else if s.eq_ignore_ascii_case("v2") {
+            Ok(ProxyProtocol::V2)
+        } else {
+            Err(format!("unknown PROXY protocol version `{}` (expected `v1` or `v2`)", s))
+        }
+    }
+}
+
+// Read the header at the start of `stream` and return the client address it
+// carries, or `None` for the balancer's own connections. Anything that isn't
+// a valid header of the expected version is an `InvalidData` error.
+pub async fn read_header<S>(stream: &mut S, version: ProxyProtocol) -> io::Result<Option<SocketAddr>>
+where
+    S: AsyncRead + Unpin,
+{
+    match version {
+        ProxyProtocol::V1 => read_v1(stream).await,
+        ProxyProtocol::V2 => read_v2(stream).await,
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+// `PROXY TCP4 <src> <dst> <sport> <dport>\r\n`. Read a byte at a time so
+// nothing past the CRLF is consumed.
+async fn read_v1<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<SocketAddr>> {
+    let mut line = Vec::with_capacity(V1_MAX_LEN);
+    while !line.ends_with(b"\r\n") {
+        if line.len() == V1_MAX_LEN {
+            return Err(invalid("v1 header too long"));
+        }
+        line.push(stream.read_u8().await?);
+        if !b"PROXY ".starts_with(&line[..line.len().min(6)]) {
+            return Err(invalid("not a v1 header"));
+        }
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2]).map_err(|_| invalid("v1 header is not ASCII"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", family @ ("TCP4" | "TCP6"), src, _dst, sport, _dport] => {
+            let ip: IpAddr = src.parse().map_err(|_| invalid("bad v1 source address"))?;
+            if ip.is_ipv4() != (family == "TCP4") {
+                return Err(invalid("v1 source address doesn't match its family"));
+            }
+            let port = sport.parse().map_err(|_| invalid("bad v1 source port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("malformed v1 header")),
+    }
+}
+
+// A 16-byte preamble (signature, version and command, family, length), then
+// that many bytes of addresses and optional TLVs, which are skipped.
+async fn read_v2<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<SocketAddr>> {
+    let mut preamble = [0; 16];
+    stream.read_exact(&mut preamble).await?;
+    if &preamble[..12] != V2_SIGNATURE || preamble[12] >> 4 != 2 {
+        return Err(invalid("not a v2 header"));
+    }
+    let command = preamble[12] & 0x0f;
+    let family = preamble[13];
+    let len = usize::from(u16::from_be_bytes([preamble[14], preamble[15]]));
+    let mut rest = vec![0; len];
+    stream.read_exact(&mut rest).await?;
+    match command {
+        // LOCAL: the balancer's own connection.
+        0 => return Ok(None),
+        1 => {}
+        _ => return Err(invalid("unknown v2 command")),
+    }
+    // The high nibble is the address family, the low one the transport.
+    match family >> 4 {
+        1 if rest.len() >= 12 => {
+            let ip = Ipv4Addr::new(rest[0], rest[1], rest[2], rest[3]);
+            let port = u16::from_be_bytes([rest[8], rest[9]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        2 if rest.len() >= 36 => {
+            let octets: [u8; 16] = rest[..16].try_into().unwrap();
+            let port = u16::from_be_bytes([rest[32], rest[33]]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(octets).into(), port)))
+        }
+        1 | 2 => Err(invalid("v2 address block too short")),
+        // Unspecified or Unix addresses say nothing about an IP client.
+        _ => Ok(None),
+    }
+}
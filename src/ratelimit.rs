@@ -0,0 +1,73 @@
+// Per-client-IP rate limiting with token buckets.
+//
+// Each client IP gets a bucket holding up to `burst` tokens, refilled at
+// `rate` tokens per second; a request spends one token. Buckets live in a
+// shared map and are swept periodically once they have refilled completely,
+// since a full bucket behaves exactly like a missing one.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: f64, burst: u32) -> RateLimiter {
+        RateLimiter {
+            rate,
+            burst: f64::from(burst.max(1)),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Take a token for `ip`. When the bucket is empty, returns how long
+    // until the next token is available, for the `Retry-After` header.
+    pub fn check(&self, ip: IpAddr) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: self.burst,
+            last: now,
+        });
+        let refill = now.duration_since(bucket.last).as_secs_f64() * self.rate;
+        bucket.tokens = (bucket.tokens + refill).min(self.burst);
+        bucket.last = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+        }
+    }
+
+    // Forget buckets that would be full by now.
+    fn sweep(&self) {
+        let now = Instant::now();
+        let full_after = Duration::from_secs_f64(self.burst / self.rate);
+        self.buckets
+            .lock()
+            .unwrap()
+            .retain(|_, b| now.duration_since(b.last) < full_after);
+    }
+}
+
+// Sweep idle buckets every `interval` for the life of the process.
+pub fn spawn_sweeper(limiter: Arc<RateLimiter>, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            limiter.sweep();
+        }
+    });
+}
@@ -0,0 +1,181 @@
+// Path-prefix routing to multiple upstreams.
+//
+// Each route maps a path prefix to an upstream. A request goes to the route
+// with the longest matching prefix, matched on whole path segments so `/api`
+// covers `/api` and `/api/users` but not `/apix`. Requests matching no route
+// go to the default upstream, if one is configured.
+//
+// The path and query are passed through exactly as the client sent them,
+// percent-encoding included; only a stripped prefix is ever removed.
+//
+// Embedders can choose upstreams in code with an `UpstreamSelector`, which
+// is asked first; requests it passes on are routed as above.
+
+use crate::balancer::Balancer;
+use hyper::http::uri::{InvalidUri, PathAndQuery};
+use hyper::{Body, Request, Uri};
+use std::time::Duration;
+
+// Picks the upstream for a request, e.g. by tenant header. Only the scheme
+// and authority of the returned URI are used; the request keeps its own
+// path and query. `None` leaves the request to the configured routes and
+// default upstream. Any `Fn(&Request<Body>) -> Option<Uri>` closure is one.
+pub trait UpstreamSelector: Send + Sync {
+    fn select(&self, req: &Request<Body>) -> Option<Uri>;
+}
+
+impl<F> UpstreamSelector for F
+where
+    F: Fn(&Request<Body>) -> Option<Uri> + Send + Sync,
+{
+    fn select(&self, req: &Request<Body>) -> Option<Uri> {
+        self(req)
+    }
+}
+
+#[derive(Debug)]
+pub struct Route {
+    pub prefix: String,
+    pub upstream: Balancer,
+    // Remove the matched prefix from the path before forwarding.
+    pub strip_prefix: bool,
+    // Overrides the global upstream timeout.
+    pub timeout: Option<Duration>,
+}
+
+pub struct Router {
+    // Sorted longest prefix first, so the first match is the most specific.
+    routes: Vec<Route>,
+    default: Option<Balancer>,
+}
+
+// Where a request should be sent, and the path-and-query to send it with.
+pub struct Target<'a> {
+    // Prefix of the matched route; `None` for the default upstream.
+    pub route: Option<&'a str>,
+    pub upstream: &'a Balancer,
+    pub path_and_query: PathAndQuery,
+    // The matched route's upstream timeout, if it has its own.
+    pub timeout: Option<Duration>,
+    // Whether this is the configured default upstream (or its SRV
+    // replicas), the only one whose traffic the canary shares.
+    pub default_upstream: bool,
+}
+
+impl Target<'_> {
+    // Put `prefix` (e.g. `/service/v1`) in front of the path, keeping the
+    // query. A trailing slash on the prefix is ignored, so `/users` becomes
+    // `/service/v1/users` either way. `*` is left alone.
+    pub fn prepend(&mut self, prefix: &str) -> Result<(), InvalidUri> {
+        let prefix = prefix.trim_end_matches('/');
+        if prefix.is_empty() || self.path_and_query.as_str() == "*" {
+            return Ok(());
+        }
+        self.path_and_query = format!("{}{}", prefix, self.path_and_query.as_str()).parse()?;
+        Ok(())
+    }
+}
+
+impl Router {
+    pub fn new(mut routes: Vec<Route>, default: Option<Balancer>) -> Router {
+        routes.sort_by_key(|r| std::cmp::Reverse(r.prefix.len()));
+        Router { routes, default }
+    }
+
+    // Pick the upstream for a request URI, or `None` when nothing matches.
+    // The rebuilt path-and-query only fails to parse on input Hyper would
+    // have rejected already, but is reported rather than assumed away.
+    pub fn select(&self, uri: &Uri) -> Option<Result<Target<'_>, InvalidUri>> {
+        let path = uri.path();
+        let original = match original_path_and_query(uri) {
+            Ok(pq) => pq,
+            Err(e) => return Some(Err(e)),
+        };
+
+        match self.routes.iter().find(|r| matches_prefix(path, &r.prefix)) {
+            Some(route) if route.strip_prefix => {
+                let rest = strip_prefix(path, &route.prefix);
+                let path_and_query = match uri.query() {
+                    Some(query) => format!("{}?{}", rest, query),
+                    None => rest,
+                };
+                Some(path_and_query.parse().map(|path_and_query| Target {
+                    route: Some(&route.prefix),
+                    upstream: &route.upstream,
+                    path_and_query,
+                    timeout: route.timeout,
+                    default_upstream: false,
+                }))
+            }
+            Some(route) => Some(Ok(Target {
+                route: Some(&route.prefix),
+                upstream: &route.upstream,
+                path_and_query: original,
+                timeout: route.timeout,
+                default_upstream: false,
+            })),
+            None => self.default.as_ref().map(|upstream| Ok(default_target(original, upstream))),
+        }
+    }
+
+    // The upstream probed by readiness checks: the default if configured,
+    // otherwise the first route's.
+    pub fn primary(&self) -> Option<&Balancer> {
+        self.default
+            .as_ref()
+            .or_else(|| self.routes.first().map(|r| &r.upstream))
+    }
+}
+
+// Send a request for the default upstream to `upstream`, which may live
+// outside the router (e.g. replicas discovered per request).
+pub fn fallback<'a>(uri: &Uri, upstream: &'a Balancer) -> Result<Target<'a>, InvalidUri> {
+    original_path_and_query(uri).map(|original| default_target(original, upstream))
+}
+
+// Send a request to an upstream picked for it alone, by an embedder's
+// selector or from an absolute-form URI. Like `fallback`, but the canary
+// gets none of it.
+pub fn chosen<'a>(uri: &Uri, upstream: &'a Balancer) -> Result<Target<'a>, InvalidUri> {
+    fallback(uri, upstream).map(|target| Target {
+        default_upstream: false,
+        ..target
+    })
+}
+
+fn default_target(path_and_query: PathAndQuery, upstream: &Balancer) -> Target<'_> {
+    Target {
+        route: None,
+        upstream,
+        path_and_query,
+        timeout: None,
+        default_upstream: true,
+    }
+}
+
+// The request's path and query, untouched. An absolute-form URI without a
+// path (`http://host` or `http://host?q`) gets `/` in front.
+fn original_path_and_query(uri: &Uri) -> Result<PathAndQuery, InvalidUri> {
+    match uri.path_and_query() {
+        Some(pq) if pq.as_str().starts_with('/') || pq.as_str() == "*" => Ok(pq.clone()),
+        Some(pq) => format!("/{}", pq.as_str()).parse(),
+        None => Ok(PathAndQuery::from_static("/")),
+    }
+}
+
+fn matches_prefix(path: &str, prefix: &str) -> bool {
+    match path.strip_prefix(prefix) {
+        Some(rest) => prefix.ends_with('/') || rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+// The path with `prefix` removed, always starting with `/`.
+fn strip_prefix(path: &str, prefix: &str) -> String {
+    let rest = &path[prefix.len()..];
+    if rest.starts_with('/') {
+        rest.to_string()
+    } else {
+        format!("/{}", rest)
+    }
+}
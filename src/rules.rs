@@ -0,0 +1,94 @@
+// Config-driven header rewriting.
+//
+// A rule list is applied in order to a header map. In the config file each
+// rule is a table with an `op`:
+//
+//   { op = "add", name = "x-frame-options", value = "DENY" }   # append
+//   { op = "set", name = "cache-control", value = "no-store" } # replace all
+//   { op = "remove", name = "server" }
+//   { op = "replace", name = "set-cookie", from = "Domain=internal", to = "Domain=example.com" }
+//
+// `replace` substitutes text inside every value of the header. Names and
+// values are checked when the config is loaded.
+
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use serde::{Deserialize, Deserializer};
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase", deny_unknown_fields)]
+pub enum HeaderRule {
+    Add {
+        #[serde(deserialize_with = "header_name")]
+        name: HeaderName,
+        #[serde(deserialize_with = "header_value")]
+        value: HeaderValue,
+    },
+    Set {
+        #[serde(deserialize_with = "header_name")]
+        name: HeaderName,
+        #[serde(deserialize_with = "header_value")]
+        value: HeaderValue,
+    },
+    Remove {
+        #[serde(deserialize_with = "header_name")]
+        name: HeaderName,
+    },
+    Replace {
+        #[serde(deserialize_with = "header_name")]
+        name: HeaderName,
+        from: String,
+        to: String,
+    },
+}
+
+// Apply `rules` to `headers` in order.
+pub fn apply(rules: &[HeaderRule], headers: &mut HeaderMap) {
+    for rule in rules {
+        match rule {
+            HeaderRule::Add { name, value } => {
+                headers.append(name.clone(), value.clone());
+            }
+            HeaderRule::Set { name, value } => {
+                headers.insert(name.clone(), value.clone());
+            }
+            HeaderRule::Remove { name } => {
+                headers.remove(name);
+            }
+            HeaderRule::Replace { name, from, to } => replace(headers, name, from, to),
+        }
+    }
+}
+
+// Rewrite text inside each value of `name`. Values that aren't text, or that
+// would stop being valid after the rewrite, are left as they were.
+fn replace(headers: &mut HeaderMap, name: &HeaderName, from: &str, to: &str) {
+    let rewritten: Vec<HeaderValue> = headers
+        .get_all(name)
+        .iter()
+        .map(|value| match value.to_str() {
+            Ok(text) if text.contains(from) => {
+                HeaderValue::from_str(&text.replace(from, to)).unwrap_or_else(|_| value.clone())
+            }
+            _ => value.clone(),
+        })
+        .collect();
+    if rewritten.is_empty() {
+        return;
+    }
+    headers.remove(name);
+    for value in rewritten {
+        headers.append(name.clone(), value);
+    }
+}
+
+fn header_name<'de, D: Deserializer<'de>>(deserializer: D) -> Result<HeaderName, D::Error> {
+    let name = String::deserialize(deserializer)?;
+    HeaderName::from_bytes(name.as_bytes())
+        .map_err(|_| serde::de::Error::custom(format!("invalid header name `{}`", name)))
+}
+
+fn header_value<'de, D: Deserializer<'de>>(deserializer: D) -> Result<HeaderValue, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    HeaderValue::from_str(&value)
+        .map_err(|_| serde::de::Error::custom(format!("invalid header value {:?}", value)))
+}
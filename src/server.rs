@@ -0,0 +1,427 @@
+// Accepting and serving client connections.
+//
+// Connections are accepted in our own loop rather than by `hyper::Server`, so
+// each one can be prepared (e.g. wrapped in TLS) before Hyper serves it.
+// When shutdown is signalled the loop stops accepting, every open connection
+// is asked to finish its in-flight requests and close, and `run` returns once
+// they all have.
+//
+// The listener is TCP or a Unix domain socket. Unix clients have no IP, so
+// they are treated as connecting from 127.0.0.1 (for `X-Forwarded-For` and
+// rate limiting). TCP listeners are built by hand so the backlog and socket
+// options can be tuned; accepted connections get TCP_NODELAY and keepalive
+// as configured.
+//
+// With PROXY protocol on, the client address is read from the header the
+// load balancer sends first, before any TLS handshake; a connection without
+// a valid header is dropped.
+//
+// With a per-IP connection limit, a connection from a client that already
+// has that many open is closed straight away. The client is identified
+// after the PROXY protocol header, if any, so balanced clients are told
+// apart.
+//
+// With an idle timeout, a connection is dropped once nothing has arrived on
+// it for that long while no request is being answered: a client that
+// connects and never sends a request, or a kept-alive connection left
+// unused.
+
+use crate::config::{BindAddr, SocketMode};
+use crate::middleware::{self, ProxyService};
+use crate::proxyproto;
+use crate::{body, ConnInfo, ProxyState};
+use futures_util::future;
+use hyper::header::{HeaderValue, CONNECTION};
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, Method, Request, Response, StatusCode, Version};
+use socket2::{SockRef, TcpKeepalive};
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpSocket, TcpStream, UnixListener, UnixStream};
+use tokio::sync::{mpsc, watch};
+use tokio_rustls::TlsAcceptor;
+use tower::util::BoxCloneService;
+use tower::ServiceExt;
+use tracing::{debug, error};
+
+// Peer address reported for connections over a Unix socket.
+const UNIX_PEER: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+
+// How long a new connection has to send its PROXY protocol header.
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Socket settings for the TCP listener and the connections it accepts.
+#[derive(Clone, Copy, Debug)]
+pub struct SocketOptions {
+    pub reuse_addr: bool,
+    pub backlog: u32,
+    pub nodelay: bool,
+    // Idle time before keepalive probes start; off when `None`.
+    pub keepalive: Option<Duration>,
+}
+
+impl SocketOptions {
+    fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        stream.set_nodelay(self.nodelay)?;
+        if let Some(idle) = self.keepalive {
+            SockRef::from(stream).set_tcp_keepalive(&TcpKeepalive::new().with_time(idle))?;
+        }
+        Ok(())
+    }
+}
+
+pub enum Listener {
+    Tcp(TcpListener, SocketOptions),
+    Unix(UnixListener),
+}
+
+enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Listener {
+    async fn accept(&self) -> io::Result<(Stream, SocketAddr)> {
+        match self {
+            Listener::Tcp(listener, options) => {
+                let (stream, remote_addr) = listener.accept().await?;
+                if let Err(e) = options.apply(&stream) {
+                    debug!(%remote_addr, "cannot set socket options: {}", e);
+                }
+                Ok((Stream::Tcp(stream), remote_addr))
+            }
+            Listener::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Stream::Unix(stream), UNIX_PEER))
+            }
+        }
+    }
+}
+
+// Bind the listener. For a Unix socket, a stale socket file left by an
+// earlier run is removed first (anything else at the path is an error), and
+// `mode` is applied to the new one; `options` only affect TCP.
+pub async fn bind(addr: &BindAddr, mode: Option<SocketMode>, options: SocketOptions) -> io::Result<Listener> {
+    match addr {
+        BindAddr::Tcp(addr) => {
+            let socket = match addr {
+                SocketAddr::V4(_) => TcpSocket::new_v4()?,
+                SocketAddr::V6(_) => TcpSocket::new_v6()?,
+            };
+            socket.set_reuseaddr(options.reuse_addr)?;
+            socket.bind(*addr)?;
+            Ok(Listener::Tcp(socket.listen(options.backlog)?, options))
+        }
+        BindAddr::Unix(path) => {
+            match fs::symlink_metadata(path) {
+                Ok(meta) if meta.file_type().is_socket() => fs::remove_file(path)?,
+                Ok(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AlreadyExists,
+                        "path exists and is not a socket",
+                    ));
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+            let listener = UnixListener::bind(path)?;
+            if let Some(SocketMode(mode)) = mode {
+                fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+            }
+            Ok(Listener::Unix(listener))
+        }
+    }
+}
+
+// `http` carries the protocol settings every connection is served with.
+pub async fn run(
+    listener: Listener,
+    http: Http,
+    tls: Option<TlsAcceptor>,
+    state: Arc<ProxyState>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    // Each connection task holds a sender; `recv` returns `None` once every
+    // task has finished and dropped its copy.
+    let (done_tx, mut done_rx) = mpsc::channel::<()>(1);
+
+    loop {
+        let (accepted, remote_addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    // Typically fd exhaustion; back off instead of spinning.
+                    error!("accept error: {}", e);
+                    state.metrics.observe_accept_error();
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            },
+            _ = shutdown.changed() => break,
+        };
+        let (http, tls, state) = (http.clone(), tls.clone(), state.clone());
+        let (shutdown, done) = (shutdown.clone(), done_tx.clone());
+        match accepted {
+            Stream::Tcp(stream) => {
+                tokio::spawn(serve_connection(stream, remote_addr, http, tls, state, shutdown, done));
+            }
+            Stream::Unix(stream) => {
+                tokio::spawn(serve_connection(stream, remote_addr, http, tls, state, shutdown, done));
+            }
+        }
+    }
+
+    drop(done_tx);
+    let _ = done_rx.recv().await;
+}
+
+async fn serve_connection<S>(
+    mut stream: S,
+    peer_addr: SocketAddr,
+    http: Http,
+    tls: Option<TlsAcceptor>,
+    state: Arc<ProxyState>,
+    shutdown: watch::Receiver<bool>,
+    _done: mpsc::Sender<()>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let _active = state.metrics.connection_opened();
+    let remote_addr = match state.proxy_protocol {
+        Some(version) => {
+            let header = proxyproto::read_header(&mut stream, version);
+            match tokio::time::timeout(PROXY_HEADER_TIMEOUT, header).await {
+                Ok(Ok(client)) => client.unwrap_or(peer_addr),
+                Ok(Err(e)) => {
+                    debug!(%peer_addr, "invalid PROXY protocol header: {}", e);
+                    return;
+                }
+                Err(_) => {
+                    debug!(%peer_addr, "no PROXY protocol header in time");
+                    return;
+                }
+            }
+        }
+        None => peer_addr,
+    };
+    let _slot = match &state.conn_limiter {
+        Some(limiter) => match limiter.acquire(remote_addr.ip()) {
+            Some(permit) => Some(permit),
+            None => {
+                debug!(%remote_addr, "too many connections from client, closing");
+                return;
+            }
+        },
+        None => None,
+    };
+    let conn = ConnInfo {
+        remote_addr,
+        tls: tls.is_some(),
+    };
+    match tls {
+        Some(acceptor) => {
+            // A client that never finishes the handshake is as idle as one
+            // that never sends a request.
+            let handshake = acceptor.accept(stream);
+            let accepted = match state.client_idle_timeout {
+                Some(timeout) => tokio::time::timeout(timeout, handshake).await,
+                None => Ok(handshake.await),
+            };
+            match accepted {
+                Ok(Ok(stream)) => serve_http(stream, http, conn, state, shutdown).await,
+                Ok(Err(e)) => debug!(%remote_addr, "TLS handshake failed: {}", e),
+                Err(_) => debug!(%remote_addr, "TLS handshake timed out"),
+            }
+        }
+        None => serve_http(stream, http, conn, state, shutdown).await,
+    }
+}
+
+// Serve HTTP on an established connection until the client closes it or
+// shutdown asks it to wind down.
+async fn serve_http<I>(
+    io: I,
+    http: Http,
+    conn: ConnInfo,
+    state: Arc<ProxyState>,
+    mut shutdown: watch::Receiver<bool>,
+) where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let max_requests = state.max_requests_per_conn;
+    let idle_timeout = state.client_idle_timeout;
+    let mut service = middleware::service(state, conn);
+    if let Some(max) = max_requests {
+        service = limit_requests(service, max);
+    }
+    let activity = idle_timeout.map(|_| Arc::new(Activity::new()));
+    if let Some(activity) = &activity {
+        service = track_activity(service, activity.clone());
+    }
+    let io = Watched {
+        io,
+        activity: activity.clone(),
+    };
+    let connection = http.serve_connection(io, service).with_upgrades();
+    tokio::pin!(connection);
+    let idle = async {
+        match (&activity, idle_timeout) {
+            (Some(activity), Some(timeout)) => activity.idle(timeout).await,
+            _ => future::pending().await,
+        }
+    };
+
+    let result = tokio::select! {
+        result = connection.as_mut() => result,
+        _ = shutdown.changed() => {
+            connection.as_mut().graceful_shutdown();
+            connection.await
+        }
+        _ = idle => {
+            debug!(remote_addr = %conn.remote_addr, "closing idle connection");
+            return;
+        }
+    };
+    if let Err(e) = result {
+        debug!(remote_addr = %conn.remote_addr, "connection error: {}", e);
+    }
+}
+
+// Close the connection after its `max`th request: that response carries
+// `Connection: close`, and Hyper ends an HTTP/1.1 connection once it is
+// sent. HTTP/2 connections, which can't be closed that way, and upgrades,
+// CONNECT tunnels included, are left alone.
+fn limit_requests(service: ProxyService, max: u64) -> ProxyService {
+    let served = Arc::new(AtomicU64::new(0));
+    BoxCloneService::new(service_fn(move |req: Request<Body>| {
+        let last = req.version() < Version::HTTP_2
+            && req.method() != Method::CONNECT
+            && served.fetch_add(1, Ordering::Relaxed) + 1 >= max;
+        let response = service.clone().oneshot(req);
+        async move {
+            let mut resp = response.await?;
+            if last && resp.status() != StatusCode::SWITCHING_PROTOCOLS {
+                resp.headers_mut().insert(CONNECTION, HeaderValue::from_static("close"));
+            }
+            Ok(resp)
+        }
+    }))
+}
+
+// Signs of life on a client connection, for the idle timeout: when request
+// bytes last arrived or a response last finished, and how many requests are
+// being answered right now.
+struct Activity {
+    epoch: Instant,
+    // Milliseconds since `epoch`.
+    last: AtomicU64,
+    busy: AtomicUsize,
+}
+
+impl Activity {
+    fn new() -> Activity {
+        Activity {
+            epoch: Instant::now(),
+            last: AtomicU64::new(0),
+            busy: AtomicUsize::new(0),
+        }
+    }
+
+    fn touch(&self) {
+        self.last.store(self.epoch.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    // Resolves once the connection has been quiet for `timeout` with no
+    // request in progress.
+    async fn idle(&self, timeout: Duration) {
+        loop {
+            let last = Duration::from_millis(self.last.load(Ordering::Relaxed));
+            let quiet = self.epoch.elapsed().saturating_sub(last);
+            let wait = if self.busy.load(Ordering::Relaxed) > 0 {
+                timeout
+            } else if quiet >= timeout {
+                return;
+            } else {
+                timeout - quiet
+            };
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+// A request being answered; the connection counts as active again from
+// when it's dropped, along with the response body.
+struct Busy(Arc<Activity>);
+
+impl Drop for Busy {
+    fn drop(&mut self) {
+        self.0.touch();
+        self.0.busy.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Mark the connection busy from each request's arrival until its response
+// body has been sent.
+fn track_activity(service: ProxyService, activity: Arc<Activity>) -> ProxyService {
+    BoxCloneService::new(service_fn(move |req: Request<Body>| {
+        activity.busy.fetch_add(1, Ordering::Relaxed);
+        let busy = Busy(activity.clone());
+        let response = service.clone().oneshot(req);
+        async move {
+            let (parts, resp_body) = response.await?.into_parts();
+            Ok(Response::from_parts(parts, body::hold_until_end(resp_body, busy)))
+        }
+    }))
+}
+
+// A client connection's IO, noting in `activity` whenever bytes arrive.
+struct Watched<I> {
+    io: I,
+    activity: Option<Arc<Activity>>,
+}
+
+impl<I: AsyncRead + Unpin> AsyncRead for Watched<I> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.io).poll_read(cx, buf);
+        if let Some(activity) = self.activity.as_ref().filter(|_| buf.filled().len() > before) {
+            activity.touch();
+        }
+        result
+    }
+}
+
+impl<I: AsyncWrite + Unpin> AsyncWrite for Watched<I> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.io.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,116 @@
+// Spooling request bodies to disk so they can be replayed (`MAX_SPOOL_BYTES`).
+//
+// Bodies too big to buffer in memory for retries are written to a temporary
+// file in `SPOOL_DIR` as they arrive, and every attempt streams that file
+// from the start. The file is removed once nothing reads it any more, when
+// the request completes, fails or is abandoned; the removal runs on Tokio's
+// blocking pool so it never stalls a runtime thread. A body that turns out to be
+// larger than the cap isn't spooled after all: what was written so far is
+// sent, followed by the rest of the client's body, as a single attempt.
+
+use futures_util::{stream, StreamExt};
+use hyper::body::{Bytes, HttpBody};
+use hyper::Body;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio_util::io::ReaderStream;
+use tracing::warn;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+// A spooled body on disk, deleted when dropped.
+pub struct SpoolFile {
+    path: PathBuf,
+}
+
+impl Drop for SpoolFile {
+    fn drop(&mut self) {
+        let path = std::mem::take(&mut self.path);
+        let remove = move || {
+            if let Err(e) = fs::remove_file(&path) {
+                warn!(path = %path.display(), "cannot remove spool file: {}", e);
+            }
+        };
+        // Outside a runtime (e.g. during shutdown) there's nothing to block.
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => drop(runtime.spawn_blocking(remove)),
+            Err(_) => remove(),
+        }
+    }
+}
+
+// A request body that can be sent any number of times.
+#[derive(Clone)]
+pub enum ReplayBody {
+    Memory(Bytes),
+    Spooled(Arc<SpoolFile>),
+}
+
+impl ReplayBody {
+    // A fresh copy of the body for one attempt.
+    pub fn body(&self) -> Body {
+        match self {
+            ReplayBody::Memory(bytes) => Body::from(bytes.clone()),
+            ReplayBody::Spooled(file) => Body::wrap_stream(read(file.clone())),
+        }
+    }
+}
+
+// What became of a body given to `spool`.
+pub enum Spooled {
+    Complete(Arc<SpoolFile>),
+    // Over the cap; the body to send once instead.
+    TooLarge(Body),
+}
+
+// Why a body couldn't be spooled.
+pub enum SpoolError {
+    // The client's body failed, e.g. the client went away.
+    Body(hyper::Error),
+    // The spool file couldn't be written.
+    Io(io::Error),
+}
+
+// Write `body` to a new file in `dir`, up to `max` bytes.
+pub async fn spool(mut body: Body, dir: &Path, max: u64) -> Result<Spooled, SpoolError> {
+    let path = dir.join(format!("simple_proxy-spool-{}", uuid::Uuid::new_v4().simple()));
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&path)
+        .await
+        .map_err(SpoolError::Io)?;
+    let spooled = Arc::new(SpoolFile { path });
+    let mut written = 0;
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(SpoolError::Body)?;
+        written += chunk.len() as u64;
+        if written > max {
+            file.flush().await.map_err(SpoolError::Io)?;
+            let rest = body.map(|chunk| chunk.map_err(BoxError::from));
+            let rest = stream::once(async { Ok(chunk) }).chain(rest);
+            return Ok(Spooled::TooLarge(Body::wrap_stream(read(spooled).chain(rest))));
+        }
+        file.write_all(&chunk).await.map_err(SpoolError::Io)?;
+    }
+    file.flush().await.map_err(SpoolError::Io)?;
+    Ok(Spooled::Complete(spooled))
+}
+
+// Stream the file's contents, keeping it on disk until the stream is dropped.
+fn read(file: Arc<SpoolFile>) -> impl futures_util::Stream<Item = Result<Bytes, BoxError>> + Send {
+    let opened = stream::once(tokio::fs::File::open(file.path.clone()));
+    let chunks = opened
+        .map(|reader| match reader {
+            Ok(reader) => ReaderStream::new(reader).left_stream(),
+            Err(e) => stream::once(async { Err(e) }).right_stream(),
+        })
+        .flatten();
+    chunks.map(move |chunk| {
+        let _keep = &file;
+        chunk.map_err(BoxError::from)
+    })
+}
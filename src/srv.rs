@@ -0,0 +1,175 @@
+// Upstream discovery through DNS SRV records.
+//
+// With `upstream_srv` set, the default upstream's replicas are the targets
+// of an SRV record (`_http._tcp.backend.svc`) instead of a static list. The
+// record is resolved at startup and again every refresh interval; a failed
+// refresh keeps the last good targets. Each request starts at a target
+// picked at random, weighted by `weight`, among those with the lowest
+// `priority` (RFC 2782); the others follow as failover candidates.
+
+use crate::balancer::{roll, Balancer};
+use hickory_resolver::TokioAsyncResolver;
+use hyper::Uri;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tracing::{info, warn};
+
+// One target of the SRV record.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SrvTarget {
+    pub priority: u16,
+    pub weight: u16,
+    pub uri: Uri,
+}
+
+pub struct SrvUpstream {
+    name: String,
+    // `https` for `_https.` services, `http` otherwise.
+    scheme: &'static str,
+    resolver: TokioAsyncResolver,
+    targets: RwLock<Arc<Vec<SrvTarget>>>,
+}
+
+impl SrvUpstream {
+    pub fn new(name: String) -> Result<SrvUpstream, String> {
+        let resolver = TokioAsyncResolver::tokio_from_system_conf().map_err(|e| e.to_string())?;
+        let scheme = if name.starts_with("_https.") { "https" } else { "http" };
+        Ok(SrvUpstream {
+            name,
+            scheme,
+            resolver,
+            targets: RwLock::new(Arc::new(Vec::new())),
+        })
+    }
+
+    // A balancer over the current targets, in the order this request should
+    // try them, or `None` when nothing has resolved yet.
+    pub fn replicas(&self) -> Option<Balancer> {
+        let targets = self.targets.read().unwrap().clone();
+        let order = order(&targets, roll());
+        let weights = vec![1; order.len()];
+        (!order.is_empty()).then(|| Balancer::new(order, weights, None))
+    }
+
+    // Look the record up again, replacing the targets on success.
+    pub async fn refresh(&self) {
+        match self.lookup().await {
+            Ok(targets) if targets.is_empty() => {
+                warn!(name = %self.name, "SRV record has no targets, keeping previous ones");
+            }
+            Ok(targets) => {
+                let mut current = self.targets.write().unwrap();
+                if **current != targets {
+                    info!(name = %self.name, targets = targets.len(), "SRV targets updated");
+                    *current = Arc::new(targets);
+                }
+            }
+            Err(e) => warn!(name = %self.name, error = %e, "SRV lookup failed, keeping previous targets"),
+        }
+    }
+
+    async fn lookup(&self) -> Result<Vec<SrvTarget>, String> {
+        let lookup = self.resolver.srv_lookup(self.name.as_str()).await.map_err(|e| e.to_string())?;
+        lookup
+            .iter()
+            .map(|srv| {
+                let host = srv.target().to_utf8();
+                let host = host.trim_end_matches('.');
+                let uri = format!("{}://{}:{}", self.scheme, host, srv.port())
+                    .parse()
+                    .map_err(|e| format!("{}: {}", host, e))?;
+                Ok(SrvTarget {
+                    priority: srv.priority(),
+                    weight: srv.weight(),
+                    uri,
+                })
+            })
+            .collect()
+    }
+}
+
+// Resolve now, then every `interval` for the life of the process.
+pub fn spawn_refresher(upstream: Arc<SrvUpstream>, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            upstream.refresh().await;
+        }
+    });
+}
+
+// Targets ordered for one request: a weighted pick among the lowest
+// priority, then the rest of that priority, then the higher priorities.
+// `roll` is a random number choosing the pick.
+fn order(targets: &[SrvTarget], roll: u64) -> Vec<Uri> {
+    let mut sorted: Vec<&SrvTarget> = targets.iter().collect();
+    sorted.sort_by_key(|t| t.priority);
+    let Some(first) = sorted.first() else {
+        return Vec::new();
+    };
+    let group = sorted.iter().take_while(|t| t.priority == first.priority).count();
+    let picked = pick_weighted(&sorted[..group], roll);
+    sorted[..group].rotate_left(picked);
+    sorted.into_iter().map(|t| t.uri.clone()).collect()
+}
+
+// Index of the target `roll` lands on, each chance proportional to its
+// weight. When every weight is zero the choice is uniform.
+fn pick_weighted(group: &[&SrvTarget], roll: u64) -> usize {
+    let total: u64 = group.iter().map(|t| u64::from(t.weight)).sum();
+    if total == 0 {
+        return (roll % group.len() as u64) as usize;
+    }
+    let mut point = roll % total;
+    for (i, target) in group.iter().enumerate() {
+        let weight = u64::from(target.weight);
+        if point < weight {
+            return i;
+        }
+        point -= weight;
+    }
+    unreachable!("point is below the total weight")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(host: &str, priority: u16, weight: u16) -> SrvTarget {
+        SrvTarget {
+            priority,
+            weight,
+            uri: format!("http://{}:80", host).parse().unwrap(),
+        }
+    }
+
+    fn hosts(order: Vec<Uri>) -> Vec<String> {
+        order.iter().map(|uri| uri.host().unwrap().to_string()).collect()
+    }
+
+    #[test]
+    fn first_pick_follows_the_weights() {
+        let targets = [target("a", 10, 1), target("b", 10, 3), target("backup", 20, 100)];
+        // Rolls cover the total weight of the lowest priority (4) evenly.
+        let firsts: Vec<String> = (0..400).map(|roll| hosts(order(&targets, roll)).remove(0)).collect();
+        assert_eq!(firsts.iter().filter(|h| *h == "a").count(), 100);
+        assert_eq!(firsts.iter().filter(|h| *h == "b").count(), 300);
+
+        // The rest of the priority follows, then higher priorities, whatever
+        // their weight.
+        assert_eq!(hosts(order(&targets, 0)), ["a", "b", "backup"]);
+        assert_eq!(hosts(order(&targets, 1)), ["b", "a", "backup"]);
+    }
+
+    #[test]
+    fn zero_weights_are_picked_uniformly() {
+        let targets = [target("a", 0, 0), target("b", 0, 0)];
+        assert_eq!(hosts(order(&targets, 0)), ["a", "b"]);
+        assert_eq!(hosts(order(&targets, 1)), ["b", "a"]);
+        // A zero weight beside others is never picked first.
+        let targets = [target("a", 0, 0), target("b", 0, 5)];
+        assert!((0..50).all(|roll| hosts(order(&targets, roll))[0] == "b"));
+        assert!(order(&[], 7).is_empty());
+    }
+}
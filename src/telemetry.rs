@@ -0,0 +1,91 @@
+// OpenTelemetry tracing (`OTEL_EXPORTER_OTLP_ENDPOINT`).
+//
+// When an OTLP endpoint is configured, every request gets a server span
+// covering auth and upstream forwarding, exported over gRPC in batches. An
+// incoming W3C `traceparent` makes the span part of the caller's trace, and
+// the span's own context is passed on to the upstream the same way.
+
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use hyper::{Body, Request};
+use opentelemetry::propagation::{Extractor, Injector};
+use opentelemetry::trace::TraceError;
+use opentelemetry::{global, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{self, Tracer};
+use opentelemetry_sdk::{runtime, Resource};
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing::{field, info_span, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+// Set once the exporter is running; requests only get spans then.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+// Start the exporter and install W3C trace-context propagation. Must run
+// inside the Tokio runtime, which drives the batch exporter.
+pub fn init(endpoint: &str) -> Result<Tracer, TraceError> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(opentelemetry_otlp::new_exporter().tonic().with_endpoint(endpoint))
+        .with_trace_config(
+            trace::config().with_resource(Resource::new([KeyValue::new("service.name", "ezproxy")])),
+        )
+        .install_batch(runtime::Tokio)?;
+    ENABLED.store(true, Ordering::Relaxed);
+    Ok(tracer)
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+// Flush spans still waiting in the batch exporter.
+pub fn shutdown() {
+    global::shutdown_tracer_provider();
+}
+
+// The span for one request, continuing the trace in its `traceparent`, if
+// any. Status and upstream latency are recorded once they are known.
+pub fn request_span(req: &Request<Body>) -> Span {
+    let span = info_span!(
+        "proxy_request",
+        otel.kind = "server",
+        http.method = %req.method(),
+        http.target = req.uri().path(),
+        http.status_code = field::Empty,
+        upstream_ms = field::Empty,
+    );
+    let parent = global::get_text_map_propagator(|p| p.extract(&HeaderExtractor(req.headers())));
+    span.set_parent(parent);
+    span
+}
+
+// Replace the request's trace-context headers with the current span's, so
+// the upstream's spans nest under the proxy's.
+pub fn inject(headers: &mut HeaderMap) {
+    let context = Span::current().context();
+    global::get_text_map_propagator(|p| p.inject_context(&context, &mut HeaderInjector(headers)));
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(HeaderName::as_str).collect()
+    }
+}
+
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (HeaderName::from_bytes(key.as_bytes()), HeaderValue::from_str(&value)) {
+            self.0.insert(name, value);
+        }
+    }
+}
@@ -0,0 +1,166 @@
+// TLS settings: the client side for HTTPS upstreams, and the server side for
+// terminating TLS on the proxy's own listener.
+//
+// Upstream certificates are verified against the platform's trust store.
+// Verification can be switched off for self-signed backends, which should
+// only ever be done on trusted networks.
+//
+// The listener uses rustls' safe defaults: TLS 1.2 and 1.3 only, with the
+// cipher suites
+//   TLS13_AES_256_GCM_SHA384, TLS13_AES_128_GCM_SHA256,
+//   TLS13_CHACHA20_POLY1305_SHA256,
+//   TLS_ECDHE_{ECDSA,RSA}_WITH_AES_256_GCM_SHA384,
+//   TLS_ECDHE_{ECDSA,RSA}_WITH_AES_128_GCM_SHA256,
+//   TLS_ECDHE_{ECDSA,RSA}_WITH_CHACHA20_POLY1305_SHA256.
+
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::{
+    Certificate, ClientConfig, ClientConnection, Connection, PrivateKey, RootCertStore,
+    ServerConfig, ServerConnection, ServerName,
+};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tracing::warn;
+
+// Build the rustls client config used by the upstream connector.
+pub fn client_config(insecure_skip_verify: bool) -> ClientConfig {
+    let mut config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(native_roots())
+        .with_no_client_auth();
+    if insecure_skip_verify {
+        warn!("upstream TLS certificate verification is disabled");
+        config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(NoVerification));
+    }
+    config
+}
+
+// Load the platform's root certificates. Unusable entries are skipped rather
+// than failing startup, since plain-HTTP upstreams don't need any roots.
+fn native_roots() -> RootCertStore {
+    let mut roots = RootCertStore::empty();
+    match rustls_native_certs::load_native_certs() {
+        Ok(certs) => {
+            for cert in certs {
+                if let Err(e) = roots.add(&Certificate(cert.0)) {
+                    warn!("skipping unusable root certificate: {}", e);
+                }
+            }
+        }
+        Err(e) => warn!("could not load platform root certificates: {}", e),
+    }
+    roots
+}
+
+// Accepts any server certificate (`UPSTREAM_INSECURE_SKIP_VERIFY`).
+struct NoVerification;
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+// Load the listener's certificate chain and private key (both PEM) and
+// build the server config, offering HTTP/2 via ALPN when `http2` is set.
+// Errors are descriptive strings for startup.
+pub fn server_config(cert_path: &Path, key_path: &Path, http2: bool) -> Result<Arc<ServerConfig>, String> {
+    let certs = load_certs(cert_path)?;
+    let key = load_key(key_path)?;
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| format!("invalid certificate or key: {}", e))?;
+    config.alpn_protocols = if http2 {
+        vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+    } else {
+        vec![b"http/1.1".to_vec()]
+    };
+    let config = Arc::new(config);
+    check_key_matches(&config)?;
+    Ok(config)
+}
+
+fn load_certs(path: &Path) -> Result<Vec<Certificate>, String> {
+    let file = File::open(path).map_err(|e| format!("cannot open {}: {}", path.display(), e))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .map_err(|e| format!("cannot parse {}: {}", path.display(), e))?;
+    if certs.is_empty() {
+        return Err(format!("no certificates found in {}", path.display()));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+// Accepts PKCS#8, PKCS#1 (RSA) and SEC1 (EC) keys; the first one wins.
+fn load_key(path: &Path) -> Result<PrivateKey, String> {
+    let file = File::open(path).map_err(|e| format!("cannot open {}: {}", path.display(), e))?;
+    let mut reader = BufReader::new(file);
+    loop {
+        let item = rustls_pemfile::read_one(&mut reader)
+            .map_err(|e| format!("cannot parse {}: {}", path.display(), e))?;
+        match item {
+            Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => return Ok(PrivateKey(key)),
+            Some(_) => continue,
+            None => return Err(format!("no private key found in {}", path.display())),
+        }
+    }
+}
+
+// rustls accepts any well-formed key alongside any certificate, so run an
+// in-memory handshake against the config: the client side verifies the
+// server's handshake signature with the certificate's public key, which
+// fails if the key belongs to a different certificate.
+fn check_key_matches(config: &Arc<ServerConfig>) -> Result<(), String> {
+    let client_config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(NoVerification))
+        .with_no_client_auth();
+    let name = ServerName::try_from("localhost").expect("valid server name");
+    let setup_err = |e: rustls::Error| format!("TLS self-check failed: {}", e);
+    let mut client: Connection = ClientConnection::new(Arc::new(client_config), name)
+        .map_err(setup_err)?
+        .into();
+    let mut server: Connection = ServerConnection::new(config.clone()).map_err(setup_err)?.into();
+
+    let mismatch = |e: String| format!("certificate and private key do not match ({})", e);
+    // A full handshake needs only a few round trips; the bound guards against
+    // looping forever if the state machine stalls.
+    for _ in 0..10 {
+        if !client.is_handshaking() && !server.is_handshaking() {
+            return Ok(());
+        }
+        transfer(&mut client, &mut server).map_err(mismatch)?;
+        transfer(&mut server, &mut client).map_err(mismatch)?;
+    }
+    Err("TLS self-check did not complete".to_string())
+}
+
+// Move pending TLS records from one in-memory connection to the other.
+fn transfer(from: &mut Connection, to: &mut Connection) -> Result<(), String> {
+    let mut buf = Vec::new();
+    while from.wants_write() {
+        from.write_tls(&mut buf).map_err(|e| e.to_string())?;
+    }
+    let mut records = &buf[..];
+    while !records.is_empty() {
+        to.read_tls(&mut records).map_err(|e| e.to_string())?;
+        to.process_new_packets().map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
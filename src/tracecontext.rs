@@ -0,0 +1,78 @@
+// W3C trace context (`TRACE_SAMPLE_RATE`), without an OpenTelemetry
+// exporter.
+//
+// A request arriving with a valid `traceparent` keeps it, together with its
+// `tracestate` and the caller's sampling decision. Otherwise the request is
+// sampled at the configured rate, and a sampled request gets a fresh
+// `traceparent` with the sampled flag set. The header travels upstream with
+// the request, and the trace ID and decision go into the access log.
+
+use crate::balancer::{draw, roll};
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use uuid::Uuid;
+
+pub const TRACEPARENT: HeaderName = HeaderName::from_static("traceparent");
+
+// The trace a request belongs to, as logged.
+pub struct Trace {
+    // `None` for an unsampled request that arrived without a trace.
+    pub id: Option<String>,
+    pub sampled: bool,
+}
+
+pub struct Sampler {
+    // Share of new traces sampled, 0–100.
+    percent: f64,
+}
+
+impl Sampler {
+    // `rate` is the fraction of requests sampled, 0.0–1.0.
+    pub fn new(rate: f64) -> Sampler {
+        Sampler { percent: rate * 100.0 }
+    }
+
+    // Continue the request's trace, or start one if it is sampled.
+    pub fn apply(&self, headers: &mut HeaderMap) -> Trace {
+        let incoming = headers.get(TRACEPARENT).and_then(|v| v.to_str().ok()).and_then(parse);
+        if let Some(trace) = incoming {
+            return trace;
+        }
+        // An invalid header is dropped; its `tracestate` belongs to it.
+        headers.remove(TRACEPARENT);
+        headers.remove("tracestate");
+        if !draw(self.percent, roll()) {
+            return Trace { id: None, sampled: false };
+        }
+        let trace_id = Uuid::new_v4().simple().to_string();
+        let parent_id = &Uuid::new_v4().simple().to_string()[..16];
+        let value = format!("00-{}-{}-01", trace_id, parent_id);
+        headers.insert(TRACEPARENT, HeaderValue::from_str(&value).expect("hex digits are a valid header value"));
+        Trace { id: Some(trace_id), sampled: true }
+    }
+}
+
+// A `version-traceid-parentid-flags` header. Later versions may append
+// fields, which are ignored; version `ff` and all-zero IDs are invalid.
+fn parse(value: &str) -> Option<Trace> {
+    let mut fields = value.trim().split('-');
+    let (version, trace_id, parent_id, flags) = (fields.next()?, fields.next()?, fields.next()?, fields.next()?);
+    if !is_hex(version, 2) || version == "ff" || (version == "00" && fields.next().is_some()) {
+        return None;
+    }
+    if !is_hex(trace_id, 32) || !is_hex(parent_id, 16) || !is_hex(flags, 2) {
+        return None;
+    }
+    if trace_id.bytes().all(|b| b == b'0') || parent_id.bytes().all(|b| b == b'0') {
+        return None;
+    }
+    let flags = u8::from_str_radix(flags, 16).ok()?;
+    Some(Trace {
+        id: Some(trace_id.to_string()),
+        sampled: flags & 1 == 1,
+    })
+}
+
+// `len` lowercase hex digits.
+fn is_hex(s: &str, len: usize) -> bool {
+    s.len() == len && s.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
@@ -0,0 +1,53 @@
+// Response body transforms set by embedders
+// (`ProxyBuilder::transform_response`).
+//
+// A transform sees each response from the upstream (or the cache) that has a
+// body, after any gzip/deflate encoding has been decoded and before the
+// proxy's own compression, and returns the body to send instead. It gets the
+// body as a stream, so a transform working chunk by chunk keeps large
+// responses streaming; one that needs the whole body can collect it first.
+// Responses in encodings other than gzip and deflate are left alone.
+
+use crate::compress;
+use hyper::header::{CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::{Body, Response};
+
+// Rewrites response bodies. Any `Fn(Option<&str>, Body) -> Body` closure is
+// one, applied to every response; implement the trait to pick responses by
+// content type with `applies`, leaving the rest untouched.
+pub trait ResponseTransform: Send + Sync {
+    // Whether responses of this content type are transformed at all.
+    fn applies(&self, _content_type: Option<&str>) -> bool {
+        true
+    }
+
+    fn transform(&self, content_type: Option<&str>, body: Body) -> Body;
+}
+
+impl<F> ResponseTransform for F
+where
+    F: Fn(Option<&str>, Body) -> Body + Send + Sync,
+{
+    fn transform(&self, content_type: Option<&str>, body: Body) -> Body {
+        self(content_type, body)
+    }
+}
+
+// Run `resp`'s body through `transform`. The new body's length is unknown,
+// and it no longer matches a strong `ETag`.
+pub fn apply(transform: &dyn ResponseTransform, resp: &mut Response<Body>) {
+    let content_type = resp.headers().get(CONTENT_TYPE).and_then(|v| v.to_str().ok());
+    if !transform.applies(content_type) || !compress::decode(resp) {
+        return;
+    }
+    let content_type = resp
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+    let body = std::mem::take(resp.body_mut());
+    *resp.body_mut() = transform.transform(content_type.as_deref(), body);
+    let headers = resp.headers_mut();
+    headers.remove(CONTENT_LENGTH);
+    compress::weaken_etag(headers);
+}
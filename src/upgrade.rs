@@ -0,0 +1,52 @@
+// WebSocket passthrough.
+//
+// A handshake request is authorized and forwarded like any other, except
+// that its `Upgrade` and `Connection` headers survive hop-by-hop stripping.
+// When the upstream answers 101, both connections are taken over from Hyper
+// and bytes are copied between them until either side closes.
+
+use hyper::header::{HeaderMap, HeaderValue, CONNECTION, UPGRADE};
+use hyper::upgrade::OnUpgrade;
+use hyper::{Body, Response, StatusCode};
+use tracing::debug;
+
+// The requested protocol, if this is a WebSocket handshake.
+pub fn websocket_protocol(headers: &HeaderMap) -> Option<HeaderValue> {
+    let connection_upgrade = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|token| token.trim().eq_ignore_ascii_case("upgrade"));
+    let upgrade = headers.get(UPGRADE)?;
+    let websocket = upgrade
+        .to_str()
+        .is_ok_and(|v| v.split(',').any(|p| p.trim().eq_ignore_ascii_case("websocket")));
+    (connection_upgrade && websocket).then(|| upgrade.clone())
+}
+
+// Put back the upgrade headers removed with the other hop-by-hop headers.
+pub fn restore_headers(headers: &mut HeaderMap, protocol: HeaderValue) {
+    headers.insert(CONNECTION, HeaderValue::from_static("upgrade"));
+    headers.insert(UPGRADE, protocol);
+}
+
+// If the upstream switched protocols, splice the client connection onto the
+// upstream one in a background task. Returns false when it declined.
+pub fn tunnel(client: OnUpgrade, resp: &mut Response<Body>) -> bool {
+    if resp.status() != StatusCode::SWITCHING_PROTOCOLS {
+        return false;
+    }
+    let upstream = hyper::upgrade::on(&mut *resp);
+    tokio::spawn(async move {
+        match tokio::try_join!(client, upstream) {
+            Ok((mut client, mut upstream)) => {
+                if let Err(e) = tokio::io::copy_bidirectional(&mut client, &mut upstream).await {
+                    debug!("upgraded connection closed with error: {}", e);
+                }
+            }
+            Err(e) => debug!("connection upgrade failed: {}", e),
+        }
+    });
+    true
+}
@@ -0,0 +1,111 @@
+// Requests refused before auth: by client address and by method.
+
+mod common;
+
+use common::{client_addr, config, get, proxy, request, upstream};
+use hyper::header::{ALLOW, AUTHORIZATION};
+use hyper::{Body, Method, Response};
+use simple_proxy::{Proxy, ProxyConfig};
+use std::net::SocketAddr;
+
+async fn ok_upstream() -> String {
+    upstream(|_| async { Response::new(Body::from("ok")) }).await
+}
+
+async fn status_from(proxy: &Proxy, ip: &str) -> u16 {
+    let addr = SocketAddr::new(ip.parse().unwrap(), 40000);
+    proxy.handle(get("/"), addr).await.status().as_u16()
+}
+
+#[tokio::test]
+async fn ip_lists_pick_who_gets_in() {
+    let upstream = ok_upstream().await;
+    let proxy = proxy(ProxyConfig {
+        ip_allow: "10.0.0.0/8, 192.168.1.7, 2001:db8::/32".parse().unwrap(),
+        ip_deny: "10.1.0.0/16, 2001:db8:bad::/48".parse().unwrap(),
+        ..config(&upstream)
+    });
+
+    // Allowed: inside a range, or an exact address.
+    assert_eq!(status_from(&proxy, "10.2.3.4").await, 200);
+    assert_eq!(status_from(&proxy, "192.168.1.7").await, 200);
+    assert_eq!(status_from(&proxy, "2001:db8:1::1").await, 200);
+    // Outside the allow list.
+    assert_eq!(status_from(&proxy, "192.168.1.8").await, 403);
+    assert_eq!(status_from(&proxy, "2001:db9::1").await, 403);
+    // Deny wins over allow.
+    assert_eq!(status_from(&proxy, "10.1.2.3").await, 403);
+    assert_eq!(status_from(&proxy, "2001:db8:bad::1").await, 403);
+
+    // Checked before auth: a denied client learns nothing about credentials.
+    let mut req = get("/");
+    req.headers_mut().remove(AUTHORIZATION);
+    assert_eq!(proxy.handle(req, "10.1.2.3:40000".parse().unwrap()).await.status(), 403);
+    let mut req = get("/");
+    req.headers_mut().remove(AUTHORIZATION);
+    assert_eq!(proxy.handle(req, client_addr()).await.status(), 403);
+}
+
+#[tokio::test]
+async fn deny_list_alone_lets_others_in() {
+    let upstream = ok_upstream().await;
+    let proxy = proxy(ProxyConfig {
+        ip_deny: "203.0.113.0/24".parse().unwrap(),
+        ..config(&upstream)
+    });
+    assert_eq!(status_from(&proxy, "203.0.113.9").await, 403);
+    assert_eq!(status_from(&proxy, "198.51.100.1").await, 200);
+    assert_eq!(status_from(&proxy, "::1").await, 200);
+}
+
+#[tokio::test]
+async fn methods_outside_the_list_get_405() {
+    let upstream = ok_upstream().await;
+    let proxy = proxy(ProxyConfig {
+        allowed_methods: vec!["GET".to_string(), "HEAD".to_string()],
+        ..config(&upstream)
+    });
+
+    let resp = proxy.handle(request(Method::POST, "/", "data"), client_addr()).await;
+    assert_eq!(resp.status(), 405);
+    assert_eq!(resp.headers()[ALLOW], "GET, HEAD");
+    let resp = proxy.handle(request(Method::DELETE, "/", ""), client_addr()).await;
+    assert_eq!(resp.status(), 405);
+    assert_eq!(proxy.handle(get("/"), client_addr()).await.status(), 200);
+    assert_eq!(proxy.handle(request(Method::HEAD, "/", ""), client_addr()).await.status(), 200);
+
+    // All are allowed by default.
+    let proxy = common::proxy(config(&upstream));
+    let resp = proxy.handle(request(Method::POST, "/", "data"), client_addr()).await;
+    assert_eq!(resp.status(), 200);
+}
+
+async fn status_of(proxy: &Proxy, method: Method) -> u16 {
+    proxy.handle(request(method, "/", ""), client_addr()).await.status().as_u16()
+}
+
+#[tokio::test]
+async fn trace_and_connect_are_blocked_by_default() {
+    let upstream = ok_upstream().await;
+    let track = Method::from_bytes(b"TRACK").unwrap();
+    let proxy = proxy(config(&upstream));
+    for method in [Method::TRACE, track.clone(), Method::CONNECT] {
+        assert_eq!(status_of(&proxy, method.clone()).await, 405, "{}", method);
+    }
+
+    // Even when the allow-list names it, and it stays out of `Allow`.
+    let proxy = common::proxy(ProxyConfig {
+        allowed_methods: vec!["GET".to_string(), "TRACE".to_string()],
+        ..config(&upstream)
+    });
+    let resp = proxy.handle(request(Method::TRACE, "/", ""), client_addr()).await;
+    assert_eq!(resp.status(), 405);
+    assert_eq!(resp.headers()[ALLOW], "GET");
+
+    let proxy = common::proxy(ProxyConfig {
+        block_trace: false,
+        ..config(&upstream)
+    });
+    assert_eq!(status_of(&proxy, Method::TRACE).await, 200);
+    assert_eq!(status_of(&proxy, track).await, 200);
+}
@@ -0,0 +1,90 @@
+// The access log written to a rotating file.
+
+mod common;
+
+use common::{client_addr, config, get, proxy, upstream};
+use hyper::{Body, Request, Response};
+use simple_proxy::logfile::RotatingFile;
+use simple_proxy::ProxyConfig;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::layer::SubscriberExt;
+
+// A fresh directory under the system temp dir.
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("simple-proxy-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[tokio::test]
+async fn access_lines_go_to_the_configured_file() {
+    let dir = temp_dir("access-log");
+    let upstream = upstream(|_: Request<Body>| async { Response::new(Body::from("ok")) }).await;
+    let config = ProxyConfig {
+        access_log_file: Some(dir.join("access.log")),
+        access_log_max_bytes: 1024,
+        access_log_max_files: 1,
+        ..config(&upstream)
+    };
+    // Set up as the binary does, writing access lines only.
+    let path = config.access_log_file.clone().unwrap();
+    let file = RotatingFile::open(&path, config.access_log_max_bytes, config.access_log_max_files).unwrap();
+    let subscriber = tracing_subscriber::registry()
+        .with(Targets::new().with_target("access", tracing::Level::INFO))
+        .with(tracing_subscriber::fmt::layer().with_ansi(false).with_writer(Mutex::new(file)));
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let proxy = proxy(config);
+    let resp = proxy.handle(get("/first"), client_addr()).await;
+    assert_eq!(resp.status(), 200);
+    let log = fs::read_to_string(&path).unwrap();
+    assert_eq!(log.lines().count(), 1, "{}", log);
+    assert!(log.contains("path=\"/first\"") && log.contains("status=200"), "{}", log);
+    let request_id = resp.headers()["x-request-id"].to_str().unwrap();
+    assert!(log.contains(request_id), "{}", log);
+
+    // Past 1 KiB the file is rotated, keeping one old copy.
+    for _ in 0..20 {
+        proxy.handle(get("/more"), client_addr()).await;
+    }
+    let rotated = fs::read_to_string(dir.join("access.log.1")).unwrap();
+    assert!(rotated.contains("path=\"/more\""), "{}", rotated);
+    assert!(!dir.join("access.log.2").exists());
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn access_lines_are_tagged_against_the_slo() {
+    let dir = temp_dir("slo-log");
+    let path = dir.join("access.log");
+    let upstream = upstream(|req: Request<Body>| async move {
+        if req.uri().path() == "/slow" {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        }
+        Response::new(Body::from("ok"))
+    })
+    .await;
+    let file = RotatingFile::open(&path, 1024 * 1024, 1).unwrap();
+    let subscriber = tracing_subscriber::registry()
+        .with(Targets::new().with_target("access", tracing::Level::INFO))
+        .with(tracing_subscriber::fmt::layer().with_ansi(false).with_writer(Mutex::new(file)));
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let proxy = proxy(ProxyConfig {
+        slo_threshold_ms: Some(100),
+        ..config(&upstream)
+    });
+    proxy.handle(get("/fast"), client_addr()).await;
+    proxy.handle(get("/slow"), client_addr()).await;
+    let log = fs::read_to_string(&path).unwrap();
+    let lines: Vec<&str> = log.lines().collect();
+    assert_eq!(lines.len(), 2, "{}", log);
+    assert!(lines[0].contains("path=\"/fast\"") && lines[0].contains("slo=\"met\""), "{}", log);
+    assert!(lines[1].contains("path=\"/slow\"") && lines[1].contains("slo=\"violated\""), "{}", log);
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,339 @@
+// Credentials checks in each auth mode.
+
+mod common;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use common::{body_string, client_addr, config, get, upstream};
+use hyper::header::{AUTHORIZATION, WWW_AUTHENTICATE};
+use hyper::{Body, Request, Response, StatusCode};
+use jsonwebtoken::{encode, EncodingKey, Header};
+use simple_proxy::auth::AuthMode;
+use simple_proxy::config::RouteConfig;
+use simple_proxy::{Proxy, ProxyConfig};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const JWT_SECRET: &str = "jwt-secret";
+
+async fn ok_upstream() -> String {
+    upstream(|_: Request<Body>| async { Response::new(Body::from("ok")) }).await
+}
+
+async fn with_auth(proxy: &Proxy, authorization: &str) -> Response<Body> {
+    let req = Request::get("/").header(AUTHORIZATION, authorization).body(Body::empty()).unwrap();
+    proxy.handle(req, client_addr()).await
+}
+
+fn basic(credentials: &str) -> String {
+    format!("Basic {}", BASE64.encode(credentials))
+}
+
+// Basic auth for alice, password s3cret.
+fn basic_proxy(upstream: &str) -> Proxy {
+    common::proxy(ProxyConfig {
+        auth_mode: AuthMode::Basic,
+        basic_auth_user: Some("alice".to_string()),
+        basic_auth_pass: Some("s3cret".to_string()),
+        upstream_url: Some(upstream.parse().unwrap()),
+        ..ProxyConfig::default()
+    })
+}
+
+#[tokio::test]
+async fn basic_auth() {
+    let proxy = basic_proxy(&ok_upstream().await);
+
+    assert_eq!(with_auth(&proxy, &basic("alice:s3cret")).await.status(), 200);
+    // The scheme name is case-insensitive.
+    assert_eq!(with_auth(&proxy, &basic("alice:s3cret").replace("Basic", "basic")).await.status(), 200);
+
+    let refused = [
+        basic("alice:wrong"),
+        basic("bob:s3cret"),
+        basic("alice"),
+        "Basic !!not-base64!!".to_string(),
+    ];
+    for refused in refused {
+        let resp = with_auth(&proxy, &refused).await;
+        assert_eq!(resp.status(), 401, "{}", refused);
+        assert_eq!(resp.headers()[WWW_AUTHENTICATE], r#"Basic realm="proxy""#);
+    }
+}
+
+// An HS256 token for `sub` expiring `expires_in` seconds from now.
+fn jwt(secret: &str, sub: &str, expires_in: i64) -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+    let claims = serde_json::json!({ "sub": sub, "exp": now + expires_in });
+    encode(&Header::default(), &claims, &EncodingKey::from_secret(secret.as_bytes())).unwrap()
+}
+
+// JWT auth with `JWT_SECRET`, passing the subject on.
+fn jwt_proxy(upstream: &str) -> Proxy {
+    common::proxy(ProxyConfig {
+        auth_mode: AuthMode::Jwt,
+        jwt_secret: Some(JWT_SECRET.to_string()),
+        jwt_forward_subject: true,
+        upstream_url: Some(upstream.parse().unwrap()),
+        ..ProxyConfig::default()
+    })
+}
+
+#[tokio::test]
+async fn jwt_auth() {
+    let upstream = upstream(|req: Request<Body>| async move {
+        let subject = req.headers().get("x-auth-subject").map_or("-", |s| s.to_str().unwrap());
+        Response::new(Body::from(subject.to_string()))
+    })
+    .await;
+    let proxy = jwt_proxy(&upstream);
+
+    let resp = with_auth(&proxy, &format!("Bearer {}", jwt(JWT_SECRET, "alice", 3600))).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(body_string(resp).await, "alice");
+
+    // Well past the default leeway of a minute.
+    let expired = jwt(JWT_SECRET, "alice", -3600);
+    assert_eq!(with_auth(&proxy, &format!("Bearer {}", expired)).await.status(), 401);
+
+    let forged = jwt("another-secret", "alice", 3600);
+    assert_eq!(with_auth(&proxy, &format!("Bearer {}", forged)).await.status(), 401);
+}
+
+// The `X-Auth-Error` code of a refused request.
+async fn auth_error(proxy: &Proxy, authorization: Option<&str>) -> String {
+    let mut req = get("/");
+    req.headers_mut().remove(AUTHORIZATION);
+    if let Some(authorization) = authorization {
+        req.headers_mut().insert(AUTHORIZATION, authorization.parse().unwrap());
+    }
+    let resp = proxy.handle(req, client_addr()).await;
+    assert!(resp.status().is_client_error(), "{:?}: {}", authorization, resp.status());
+    resp.headers()["x-auth-error"].to_str().unwrap().to_string()
+}
+
+#[tokio::test]
+async fn refusals_carry_a_reason_code() {
+    let upstream = ok_upstream().await;
+
+    let by_token = common::proxy(config(&upstream));
+    assert_eq!(auth_error(&by_token, None).await, "missing_header");
+    assert_eq!(auth_error(&by_token, Some("Token secret")).await, "malformed_header");
+    assert_eq!(auth_error(&by_token, Some("Bearer wrong")).await, "invalid_token");
+
+    let by_basic = basic_proxy(&upstream);
+    assert_eq!(auth_error(&by_basic, Some(&basic("alice:wrong"))).await, "invalid_credentials");
+
+    let by_jwt = jwt_proxy(&upstream);
+    let expired = format!("Bearer {}", jwt(JWT_SECRET, "alice", -3600));
+    assert_eq!(auth_error(&by_jwt, Some(&expired)).await, "expired_jwt");
+    let forged = format!("Bearer {}", jwt("another-secret", "alice", 3600));
+    assert_eq!(auth_error(&by_jwt, Some(&forged)).await, "bad_signature");
+    assert_eq!(auth_error(&by_jwt, Some("Bearer not-a-jwt")).await, "invalid_jwt");
+
+    // Nothing listens on the introspection URL.
+    let by_introspect = common::proxy(ProxyConfig {
+        auth_mode: AuthMode::Introspect,
+        introspect_url: Some("http://127.0.0.1:1/introspect".to_string()),
+        upstream_url: Some(upstream.parse().unwrap()),
+        ..ProxyConfig::default()
+    });
+    assert_eq!(auth_error(&by_introspect, Some("Bearer anything")).await, "auth_unavailable");
+}
+
+// A route to `upstream` for `prefix` taking `tokens`, or public.
+fn route(prefix: &str, upstream: &str, tokens: &[&str], public: bool) -> RouteConfig {
+    RouteConfig {
+        prefix: prefix.to_string(),
+        upstream: upstream.parse().unwrap(),
+        strip_prefix: false,
+        auth_tokens: tokens.iter().map(|t| t.to_string()).collect(),
+        public,
+        timeout_ms: None,
+    }
+}
+
+async fn status_at(proxy: &Proxy, path: &str, token: Option<&str>) -> u16 {
+    let mut req = get(path);
+    req.headers_mut().remove(AUTHORIZATION);
+    if let Some(token) = token {
+        req.headers_mut().insert(AUTHORIZATION, format!("Bearer {}", token).parse().unwrap());
+    }
+    proxy.handle(req, client_addr()).await.status().as_u16()
+}
+
+#[tokio::test]
+async fn routes_take_their_own_tokens() {
+    let upstream = ok_upstream().await;
+    let proxy = common::proxy(ProxyConfig {
+        routes: vec![
+            route("/admin", &upstream, &["admin-token", "admin-spare"], false),
+            route("/public", &upstream, &[], true),
+        ],
+        ..config(&upstream)
+    });
+
+    // The global token works for unrouted paths but not on /admin.
+    assert_eq!(status_at(&proxy, "/other", Some(common::TOKEN)).await, 200);
+    assert_eq!(status_at(&proxy, "/admin/users", Some(common::TOKEN)).await, 401);
+    assert_eq!(status_at(&proxy, "/admin/users", Some("admin-token")).await, 200);
+    assert_eq!(status_at(&proxy, "/admin/users", Some("admin-spare")).await, 200);
+    assert_eq!(status_at(&proxy, "/other", Some("admin-token")).await, 401);
+
+    // Public routes need nothing.
+    assert_eq!(status_at(&proxy, "/public/page", None).await, 200);
+    assert_eq!(status_at(&proxy, "/admin/users", None).await, 401);
+}
+
+#[tokio::test]
+async fn credentials_can_come_from_a_custom_header() {
+    let upstream = ok_upstream().await;
+    let proxy = common::proxy(ProxyConfig {
+        auth_header: "X-Api-Key".to_string(),
+        ..config(&upstream)
+    });
+    let with_key = |value: &str| Request::get("/").header("x-api-key", value).body(Body::empty()).unwrap();
+    let token = common::TOKEN;
+    assert_eq!(proxy.handle(with_key(token), client_addr()).await.status(), 200);
+    assert_eq!(proxy.handle(with_key(&format!("Bearer {}", token)), client_addr()).await.status(), 200);
+    assert_eq!(proxy.handle(with_key("wrong"), client_addr()).await.status(), 401);
+    // Authorization is no longer looked at.
+    assert_eq!(with_auth(&proxy, &format!("Bearer {}", token)).await.status(), 401);
+
+    // By default it is.
+    let proxy = common::proxy(config(&upstream));
+    assert_eq!(with_auth(&proxy, &format!("Bearer {}", token)).await.status(), 200);
+    assert_eq!(proxy.handle(with_key(token), client_addr()).await.status(), 401);
+}
+
+#[tokio::test]
+async fn stealth_paths_hide_the_401() {
+    let upstream = ok_upstream().await;
+    let proxy = common::proxy(ProxyConfig {
+        stealth_mode: true,
+        ..config(&upstream)
+    });
+
+    for path in ["/", "/favicon.ico"] {
+        let mut req = get(path);
+        req.headers_mut().remove(AUTHORIZATION);
+        let resp = proxy.handle(req, client_addr()).await;
+        assert_eq!(resp.status(), 404, "{}", path);
+        assert!(resp.headers().get(WWW_AUTHENTICATE).is_none());
+        assert_eq!(body_string(resp).await, "");
+    }
+    // Elsewhere, and with bad credentials on a stealth path too.
+    assert_eq!(status_at(&proxy, "/api", None).await, 401);
+    assert_eq!(status_at(&proxy, "/", Some("wrong")).await, 404);
+    // Authenticated clients are served as usual.
+    assert_eq!(status_at(&proxy, "/", Some(common::TOKEN)).await, 200);
+
+    // The paths and response are configurable.
+    let proxy = common::proxy(ProxyConfig {
+        stealth_mode: true,
+        stealth_paths: vec!["/wp-login.php".to_string()],
+        stealth_status: 403,
+        stealth_body: "nope".to_string(),
+        ..config(&upstream)
+    });
+    let mut req = get("/wp-login.php");
+    req.headers_mut().remove(AUTHORIZATION);
+    let resp = proxy.handle(req, client_addr()).await;
+    assert_eq!(resp.status(), 403);
+    assert_eq!(body_string(resp).await, "nope");
+    assert_eq!(status_at(&proxy, "/", None).await, 401);
+
+    // Off by default.
+    let proxy = common::proxy(config(&upstream));
+    assert_eq!(status_at(&proxy, "/", None).await, 401);
+}
+
+// Introspect mode against `service`, which approves `Bearer good`.
+fn introspect_proxy(upstream: &str, service: &str, configure: impl FnOnce(&mut ProxyConfig)) -> Proxy {
+    let mut config = ProxyConfig {
+        auth_mode: AuthMode::Introspect,
+        introspect_url: Some(service.to_string()),
+        upstream_url: Some(upstream.parse().unwrap()),
+        ..ProxyConfig::default()
+    };
+    configure(&mut config);
+    common::proxy(config)
+}
+
+#[tokio::test]
+async fn introspection_service_decides() {
+    let asked = Arc::new(AtomicUsize::new(0));
+    let counter = asked.clone();
+    let service = upstream(move |req: Request<Body>| {
+        counter.fetch_add(1, Ordering::SeqCst);
+        async move {
+            let approved = req.headers().get(AUTHORIZATION).is_some_and(|v| v == "Bearer good");
+            let mut resp = Response::new(Body::empty());
+            if !approved {
+                *resp.status_mut() = StatusCode::UNAUTHORIZED;
+            }
+            resp
+        }
+    })
+    .await;
+    let upstream = ok_upstream().await;
+    let proxy = introspect_proxy(&upstream, &service, |config| config.introspect_cache_secs = Some(60));
+
+    assert_eq!(with_auth(&proxy, "Bearer good").await.status(), 200);
+    assert_eq!(with_auth(&proxy, "Bearer bad").await.status(), 401);
+    assert_eq!(asked.load(Ordering::SeqCst), 2);
+    // The approval is cached; the refusal is not.
+    assert_eq!(with_auth(&proxy, "Bearer good").await.status(), 200);
+    assert_eq!(with_auth(&proxy, "Bearer bad").await.status(), 401);
+    assert_eq!(asked.load(Ordering::SeqCst), 3);
+}
+
+#[tokio::test]
+async fn introspection_outage_fails_closed() {
+    let failing = upstream(|_: Request<Body>| async {
+        let mut resp = Response::new(Body::empty());
+        *resp.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+        resp
+    })
+    .await;
+    let upstream = ok_upstream().await;
+    for service in ["http://127.0.0.1:1", failing.as_str()] {
+        let proxy = introspect_proxy(&upstream, service, |_| {});
+        assert_eq!(with_auth(&proxy, "Bearer good").await.status(), 401, "{}", service);
+        let proxy = introspect_proxy(&upstream, service, |config| config.introspect_failure_status = 503);
+        assert_eq!(with_auth(&proxy, "Bearer good").await.status(), 503, "{}", service);
+    }
+}
+
+#[tokio::test]
+async fn denial_status_and_body_are_configurable() {
+    let upstream = ok_upstream().await;
+    let deny_with = |status: u16, body: &str| {
+        common::proxy(ProxyConfig {
+            auth_mode: AuthMode::Basic,
+            basic_auth_user: Some("alice".to_string()),
+            basic_auth_pass: Some("s3cret".to_string()),
+            auth_deny_status: status,
+            auth_deny_body: Some(body.to_string()),
+            upstream_url: Some(upstream.parse().unwrap()),
+            ..ProxyConfig::default()
+        })
+    };
+
+    // A 403 is no invitation to retry, so there's no challenge.
+    let resp = with_auth(&deny_with(403, "Access denied"), &basic("alice:wrong")).await;
+    assert_eq!(resp.status(), 403);
+    assert!(resp.headers().get(WWW_AUTHENTICATE).is_none());
+    assert_eq!(body_string(resp).await, "Access denied");
+
+    let resp = with_auth(&deny_with(401, ""), &basic("alice:wrong")).await;
+    assert_eq!(resp.status(), 401);
+    assert_eq!(resp.headers()[WWW_AUTHENTICATE], r#"Basic realm="proxy""#);
+    assert_eq!(body_string(resp).await, "");
+
+    // The default names the problem.
+    let resp = with_auth(&basic_proxy(&upstream), &basic("alice:wrong")).await;
+    assert_eq!(resp.status(), 401);
+    assert!(!body_string(resp).await.is_empty());
+}
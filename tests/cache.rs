@@ -0,0 +1,172 @@
+// The in-memory response cache.
+
+mod common;
+
+use common::{body_string, client_addr, config, get, proxy, upstream};
+use hyper::header::{CACHE_CONTROL, ETAG, IF_NONE_MATCH};
+use hyper::{Body, Request, Response, StatusCode};
+use simple_proxy::{Proxy, ProxyConfig};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// Answers `<path> #<n>` for its n-th request, with `cache_control`.
+async fn counting_upstream(cache_control: &'static str) -> (String, Arc<AtomicUsize>) {
+    let count = Arc::new(AtomicUsize::new(0));
+    let counter = count.clone();
+    let upstream = upstream(move |req: Request<Body>| {
+        let n = counter.fetch_add(1, Ordering::SeqCst) + 1;
+        async move {
+            let mut resp = Response::new(Body::from(format!("{} #{}", req.uri().path(), n)));
+            resp.headers_mut().insert(CACHE_CONTROL, cache_control.parse().unwrap());
+            resp
+        }
+    })
+    .await;
+    (upstream, count)
+}
+
+fn caching_proxy(upstream: &str) -> Proxy {
+    proxy(ProxyConfig {
+        cache_max_entries: Some(100),
+        ..config(upstream)
+    })
+}
+
+// The response body and its `X-Cache` header.
+async fn fetch(proxy: &Proxy, path: &str) -> (String, String) {
+    let resp = proxy.handle(get(path), client_addr()).await;
+    assert_eq!(resp.status(), 200);
+    let x_cache = resp.headers()["x-cache"].to_str().unwrap().to_string();
+    (body_string(resp).await, x_cache)
+}
+
+#[tokio::test]
+async fn miss_then_hit() {
+    let (upstream, count) = counting_upstream("public, max-age=60").await;
+    let proxy = caching_proxy(&upstream);
+    assert_eq!(fetch(&proxy, "/a").await, ("/a #1".to_string(), "MISS".to_string()));
+    assert_eq!(fetch(&proxy, "/a").await, ("/a #1".to_string(), "HIT".to_string()));
+    // Another path is another entry.
+    assert_eq!(fetch(&proxy, "/b").await, ("/b #2".to_string(), "MISS".to_string()));
+    assert_eq!(count.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn uncacheable_responses_are_not_stored() {
+    let (upstream, count) = counting_upstream("no-store").await;
+    let proxy = caching_proxy(&upstream);
+    assert_eq!(fetch(&proxy, "/a").await.1, "MISS");
+    assert_eq!(fetch(&proxy, "/a").await.1, "MISS");
+    assert_eq!(count.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn entry_expires_after_max_age() {
+    let (upstream, count) = counting_upstream("public, max-age=1").await;
+    let proxy = caching_proxy(&upstream);
+    assert_eq!(fetch(&proxy, "/a").await.1, "MISS");
+    assert_eq!(fetch(&proxy, "/a").await.1, "HIT");
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    assert_eq!(fetch(&proxy, "/a").await, ("/a #2".to_string(), "MISS".to_string()));
+    assert_eq!(count.load(Ordering::SeqCst), 2);
+}
+
+// Serves `body` with ETag `"v1"` for a second, answering a matching
+// `If-None-Match` with 304. Records each request's `If-None-Match`.
+async fn etag_upstream() -> (String, Arc<Mutex<Vec<String>>>) {
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let log = seen.clone();
+    let upstream = upstream(move |req: Request<Body>| {
+        let if_none_match = req.headers().get(IF_NONE_MATCH).map(|v| v.to_str().unwrap().to_string());
+        log.lock().unwrap().push(if_none_match.clone().unwrap_or_default());
+        async move {
+            let mut resp = match if_none_match.as_deref() {
+                Some("\"v1\"") => Response::builder().status(304).body(Body::empty()).unwrap(),
+                _ => Response::new(Body::from("body")),
+            };
+            resp.headers_mut().insert(CACHE_CONTROL, "public, max-age=1".parse().unwrap());
+            resp.headers_mut().insert(ETAG, "\"v1\"".parse().unwrap());
+            resp
+        }
+    })
+    .await;
+    (upstream, seen)
+}
+
+#[tokio::test]
+async fn matching_if_none_match_gets_304_from_cache() {
+    let (upstream, seen) = etag_upstream().await;
+    let proxy = caching_proxy(&upstream);
+    assert_eq!(fetch(&proxy, "/a").await.1, "MISS");
+
+    let mut req = get("/a");
+    req.headers_mut().insert(IF_NONE_MATCH, "\"v1\"".parse().unwrap());
+    let resp = proxy.handle(req, client_addr()).await;
+    assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(resp.headers()["x-cache"], "HIT");
+    assert_eq!(resp.headers()[ETAG], "\"v1\"");
+    assert_eq!(body_string(resp).await, "");
+
+    // A tag that doesn't match gets the full entry.
+    let mut req = get("/a");
+    req.headers_mut().insert(IF_NONE_MATCH, "\"v0\"".parse().unwrap());
+    let resp = proxy.handle(req, client_addr()).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(body_string(resp).await, "body");
+    assert_eq!(seen.lock().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn stale_entry_is_revalidated_by_upstream_304() {
+    let (upstream, seen) = etag_upstream().await;
+    let proxy = caching_proxy(&upstream);
+    assert_eq!(fetch(&proxy, "/a").await, ("body".to_string(), "MISS".to_string()));
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+
+    // The stale entry's ETag goes upstream; its 304 refreshes the entry,
+    // whose body is served.
+    assert_eq!(fetch(&proxy, "/a").await, ("body".to_string(), "REVALIDATED".to_string()));
+    assert_eq!(*seen.lock().unwrap(), ["", "\"v1\""]);
+    assert_eq!(fetch(&proxy, "/a").await, ("body".to_string(), "HIT".to_string()));
+    assert_eq!(seen.lock().unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn custom_auth_header_counts_as_credentials() {
+    // Each client is answered with the key it sent, which is passed on.
+    let upstream = upstream(|req: Request<Body>| async move {
+        let key = req.headers()["x-api-key"].to_str().unwrap().to_string();
+        let cache_control = if req.uri().path() == "/shared" { "public, max-age=60" } else { "max-age=60" };
+        let mut resp = Response::new(Body::from(key));
+        resp.headers_mut().insert(CACHE_CONTROL, cache_control.parse().unwrap());
+        resp
+    })
+    .await;
+    let proxy = proxy(ProxyConfig {
+        auth_header: "X-Api-Key".to_string(),
+        strip_auth_header: false,
+        auth_tokens: vec!["key-a".to_string(), "key-b".to_string()],
+        cache_max_entries: Some(100),
+        ..config(&upstream)
+    });
+    let fetch_with = |key: &'static str, path: &'static str| {
+        let proxy = proxy.clone();
+        async move {
+            let req = Request::get(path).header("x-api-key", key).body(Body::empty()).unwrap();
+            let resp = proxy.handle(req, client_addr()).await;
+            assert_eq!(resp.status(), 200);
+            let x_cache = resp.headers()["x-cache"].to_str().unwrap().to_string();
+            (body_string(resp).await, x_cache)
+        }
+    };
+
+    // Not marked public, so never stored for credentialed requests.
+    assert_eq!(fetch_with("key-a", "/private").await, ("key-a".to_string(), "MISS".to_string()));
+    assert_eq!(fetch_with("key-b", "/private").await, ("key-b".to_string(), "MISS".to_string()));
+    assert_eq!(fetch_with("key-a", "/private").await, ("key-a".to_string(), "MISS".to_string()));
+
+    // Marked public, it may be shared.
+    assert_eq!(fetch_with("key-a", "/shared").await, ("key-a".to_string(), "MISS".to_string()));
+    assert_eq!(fetch_with("key-b", "/shared").await, ("key-a".to_string(), "HIT".to_string()));
+}
@@ -0,0 +1,131 @@
+// Helpers shared by the integration tests: proxies configured in code, and
+// mock upstreams serving from closures on random local ports.
+#![allow(dead_code)]
+
+use hyper::header::AUTHORIZATION;
+use hyper::server::conn::Http;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server};
+use simple_proxy::{Proxy, ProxyConfig};
+use std::convert::Infallible;
+use std::fs::File;
+use std::future::Future;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+pub const TOKEN: &str = "secret";
+
+// A self-signed certificate for `localhost` and 127.0.0.1, and its key.
+pub const TLS_CERT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/cert.pem");
+pub const TLS_KEY: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/key.pem");
+
+// Where requests passed to `Proxy::handle` claim to come from.
+pub fn client_addr() -> SocketAddr {
+    ([127, 0, 0, 1], 40000).into()
+}
+
+// Token auth with `TOKEN`, forwarding to `upstream`.
+pub fn config(upstream: &str) -> ProxyConfig {
+    ProxyConfig {
+        auth_token: Some(TOKEN.to_string()),
+        upstream_url: Some(upstream.parse().expect("valid upstream URL")),
+        ..ProxyConfig::default()
+    }
+}
+
+pub fn proxy(config: ProxyConfig) -> Proxy {
+    Proxy::builder(config).build().expect("valid config")
+}
+
+// An authorized request with `body`.
+pub fn request(method: Method, path: &str, body: impl Into<Body>) -> Request<Body> {
+    Request::builder()
+        .method(method)
+        .uri(path)
+        .header(AUTHORIZATION, format!("Bearer {}", TOKEN))
+        .body(body.into())
+        .unwrap()
+}
+
+pub fn get(path: &str) -> Request<Body> {
+    request(Method::GET, path, Body::empty())
+}
+
+pub async fn body_string(resp: Response<Body>) -> String {
+    let bytes = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    String::from_utf8(bytes.to_vec()).unwrap()
+}
+
+// Serve `handler` on a random local port. Returns the upstream's base URL.
+pub async fn upstream<F, Fut>(handler: F) -> String
+where
+    F: Fn(Request<Body>) -> Fut + Clone + Send + Sync + 'static,
+    Fut: Future<Output = Response<Body>> + Send + 'static,
+{
+    let make_service = make_service_fn(move |_| {
+        let handler = handler.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let response = handler(req);
+                async move { Ok::<_, Infallible>(response.await) }
+            }))
+        }
+    });
+    let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+    let addr = server.local_addr();
+    tokio::spawn(server);
+    format!("http://{}", addr)
+}
+
+// Like `upstream`, but over HTTPS with the `TLS_CERT` certificate.
+pub async fn https_upstream<F, Fut>(handler: F) -> String
+where
+    F: Fn(Request<Body>) -> Fut + Clone + Send + Sync + 'static,
+    Fut: Future<Output = Response<Body>> + Send + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let acceptor = TlsAcceptor::from(tls_server_config());
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let (acceptor, handler) = (acceptor.clone(), handler.clone());
+            tokio::spawn(async move {
+                let Ok(stream) = acceptor.accept(stream).await else { return };
+                let service = service_fn(move |req| {
+                    let response = handler(req);
+                    async move { Ok::<_, Infallible>(response.await) }
+                });
+                let _ = Http::new().serve_connection(stream, service).await;
+            });
+        }
+    });
+    format!("https://{}", addr)
+}
+
+pub fn tls_server_config() -> Arc<ServerConfig> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(TLS_CERT).unwrap())).unwrap();
+    let key = rustls_pemfile::pkcs8_private_keys(&mut BufReader::new(File::open(TLS_KEY).unwrap())).unwrap();
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs.into_iter().map(Certificate).collect(), PrivateKey(key[0].clone()))
+        .unwrap();
+    Arc::new(config)
+}
+
+// Serve the proxy on a random local port, for tests that need a real client
+// connection (tunnels, upgrades).
+pub async fn serve(proxy: Proxy) -> SocketAddr {
+    let make_service = make_service_fn(move |conn: &hyper::server::conn::AddrStream| {
+        let service = proxy.service(conn.remote_addr());
+        async move { Ok::<_, Infallible>(service) }
+    });
+    let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+    let addr = server.local_addr();
+    tokio::spawn(server);
+    addr
+}
@@ -0,0 +1,99 @@
+// Compressing responses for clients that accept it, and decoding them for
+// clients that don't.
+
+mod common;
+
+use async_compression::tokio::bufread::{GzipDecoder, GzipEncoder};
+use common::{client_addr, config, get, proxy, upstream};
+use hyper::header::{ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::{Body, Request, Response};
+use simple_proxy::ProxyConfig;
+use tokio::io::AsyncReadExt;
+
+// A JSON document well over the compression threshold.
+fn large_json() -> String {
+    let items: Vec<String> = (0..200).map(|i| format!("{{\"id\":{},\"name\":\"item {}\"}}", i, i)).collect();
+    format!("[{}]", items.join(","))
+}
+
+async fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    GzipEncoder::new(data).read_to_end(&mut out).await.unwrap();
+    out
+}
+
+async fn gunzip(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    GzipDecoder::new(data).read_to_end(&mut out).await.unwrap();
+    out
+}
+
+async fn body_bytes(resp: Response<Body>) -> Vec<u8> {
+    hyper::body::to_bytes(resp.into_body()).await.unwrap().to_vec()
+}
+
+fn get_accepting(path: &str, accept_encoding: &str) -> Request<Body> {
+    let mut req = get(path);
+    req.headers_mut().insert(ACCEPT_ENCODING, accept_encoding.parse().unwrap());
+    req
+}
+
+#[tokio::test]
+async fn large_json_is_gzipped_when_accepted() {
+    let upstream = upstream(|req: Request<Body>| async move {
+        let body = if req.uri().path() == "/tiny" { "{}".to_string() } else { large_json() };
+        Response::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .header(CONTENT_LENGTH, body.len())
+            .body(Body::from(body))
+            .unwrap()
+    })
+    .await;
+    let proxy = proxy(ProxyConfig {
+        enable_compression: true,
+        ..config(&upstream)
+    });
+
+    let resp = proxy.handle(get_accepting("/", "br, gzip"), client_addr()).await;
+    assert_eq!(resp.headers()[CONTENT_ENCODING], "gzip");
+    assert!(resp.headers().get(CONTENT_LENGTH).is_none());
+    let compressed = body_bytes(resp).await;
+    assert!(compressed.len() < large_json().len());
+    assert_eq!(gunzip(&compressed).await, large_json().as_bytes());
+
+    // Not without Accept-Encoding, nor under the size threshold.
+    let resp = proxy.handle(get("/"), client_addr()).await;
+    assert!(resp.headers().get(CONTENT_ENCODING).is_none());
+    assert_eq!(body_bytes(resp).await, large_json().as_bytes());
+    let resp = proxy.handle(get_accepting("/tiny", "gzip"), client_addr()).await;
+    assert!(resp.headers().get(CONTENT_ENCODING).is_none());
+    assert_eq!(body_bytes(resp).await, b"{}");
+}
+
+#[tokio::test]
+async fn gzip_is_decoded_for_clients_that_cannot_take_it() {
+    let upstream = upstream(|_| async {
+        let body = gzip(large_json().as_bytes()).await;
+        Response::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .header(CONTENT_ENCODING, "gzip")
+            .header(CONTENT_LENGTH, body.len())
+            .body(Body::from(body))
+            .unwrap()
+    })
+    .await;
+    let proxy = proxy(ProxyConfig {
+        enable_decompression: true,
+        ..config(&upstream)
+    });
+
+    let resp = proxy.handle(get("/"), client_addr()).await;
+    assert!(resp.headers().get(CONTENT_ENCODING).is_none());
+    assert!(resp.headers().get(CONTENT_LENGTH).is_none());
+    assert_eq!(body_bytes(resp).await, large_json().as_bytes());
+
+    // A client accepting gzip gets it as sent.
+    let resp = proxy.handle(get_accepting("/", "gzip"), client_addr()).await;
+    assert_eq!(resp.headers()[CONTENT_ENCODING], "gzip");
+    assert_eq!(gunzip(&body_bytes(resp).await).await, large_json().as_bytes());
+}
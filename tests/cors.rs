@@ -0,0 +1,77 @@
+// CORS for browser clients calling the proxy directly.
+
+mod common;
+
+use common::{client_addr, config, get, proxy, upstream};
+use hyper::header::{
+    ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN,
+    ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_METHOD, ORIGIN, VARY,
+};
+use hyper::{Body, Method, Request, Response};
+use simple_proxy::{Proxy, ProxyConfig};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+const APP: &str = "https://app.example.com";
+
+async fn cors_proxy() -> (Proxy, Arc<AtomicUsize>) {
+    let hits = Arc::new(AtomicUsize::new(0));
+    let counter = hits.clone();
+    let upstream = upstream(move |_: Request<Body>| {
+        counter.fetch_add(1, Ordering::SeqCst);
+        async { Response::new(Body::from("ok")) }
+    })
+    .await;
+    let proxy = proxy(ProxyConfig {
+        cors_allowed_origins: vec![APP.to_string()],
+        cors_max_age_secs: Some(600),
+        ..config(&upstream)
+    });
+    (proxy, hits)
+}
+
+// A preflight from `origin`, without credentials as browsers send it.
+fn preflight(origin: &str) -> Request<Body> {
+    Request::builder()
+        .method(Method::OPTIONS)
+        .uri("/api/items")
+        .header(ORIGIN, origin)
+        .header(ACCESS_CONTROL_REQUEST_METHOD, "PUT")
+        .body(Body::empty())
+        .unwrap()
+}
+
+#[tokio::test]
+async fn preflight_is_answered_by_the_proxy() {
+    let (proxy, hits) = cors_proxy().await;
+    let resp = proxy.handle(preflight(APP), client_addr()).await;
+    assert_eq!(resp.status(), 204);
+    let headers = resp.headers();
+    assert_eq!(headers[ACCESS_CONTROL_ALLOW_ORIGIN], APP);
+    assert_eq!(headers[ACCESS_CONTROL_ALLOW_METHODS], "GET, HEAD, POST, PUT, PATCH, DELETE");
+    assert_eq!(headers[ACCESS_CONTROL_ALLOW_HEADERS], "authorization, content-type");
+    assert_eq!(headers[ACCESS_CONTROL_MAX_AGE], "600");
+    assert_eq!(headers[VARY], "Origin");
+
+    let resp = proxy.handle(preflight("https://evil.example.com"), client_addr()).await;
+    assert_eq!(resp.status(), 403);
+    assert!(!resp.headers().contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+    assert_eq!(hits.load(Ordering::SeqCst), 0);
+}
+
+#[tokio::test]
+async fn simple_get_gets_allow_origin() {
+    let (proxy, hits) = cors_proxy().await;
+    let mut req = get("/api/items");
+    req.headers_mut().insert(ORIGIN, APP.parse().unwrap());
+    let resp = proxy.handle(req, client_addr()).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers()[ACCESS_CONTROL_ALLOW_ORIGIN], APP);
+
+    let mut req = get("/api/items");
+    req.headers_mut().insert(ORIGIN, "https://evil.example.com".parse().unwrap());
+    let resp = proxy.handle(req, client_addr()).await;
+    assert_eq!(resp.status(), 200);
+    assert!(!resp.headers().contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+    assert_eq!(hits.load(Ordering::SeqCst), 2);
+}
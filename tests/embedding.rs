@@ -0,0 +1,683 @@
+// Embedding the proxy: a config built in code, served on real listeners.
+
+mod common;
+
+use common::{body_string, config, upstream, TLS_CERT, TLS_KEY, TOKEN};
+use hyper::header::AUTHORIZATION;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Client, Method, Request, Response, Server};
+use simple_proxy::config::{BindAddr, BindAddrs, RouteConfig, SocketMode};
+use simple_proxy::{Proxy, ProxyConfig, StartupError};
+use std::convert::Infallible;
+use std::fs;
+use std::net::{SocketAddr, TcpListener};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixListener;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+use tokio::sync::oneshot;
+
+// A local address nothing is listening on.
+fn free_addr() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+}
+
+#[tokio::test]
+async fn config_built_in_code_serves_requests() {
+    let upstream = upstream(|req: Request<Body>| async move {
+        Response::new(Body::from(format!("upstream saw {}", req.uri())))
+    })
+    .await;
+    let addr = free_addr();
+    let proxy = Proxy::builder(ProxyConfig {
+        bind_addr: BindAddrs(vec![BindAddr::Tcp(addr)]),
+        metrics_addr: free_addr(),
+        ..config(&upstream)
+    })
+    .build()
+    .unwrap();
+
+    let (stop, stopped) = oneshot::channel::<()>();
+    let server = tokio::spawn(async move {
+        proxy
+            .serve_with_shutdown(async {
+                let _ = stopped.await;
+            })
+            .await
+    });
+
+    let client = Client::new();
+    let mut attempts = 0;
+    let resp = loop {
+        let req = Request::get(format!("http://{}/hello?x=1", addr))
+            .header(AUTHORIZATION, format!("Bearer {}", TOKEN))
+            .body(Body::empty())
+            .unwrap();
+        match client.request(req).await {
+            Ok(resp) => break resp,
+            // Not listening yet.
+            Err(_) if attempts < 50 => {
+                attempts += 1;
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+            Err(e) => panic!("proxy never came up: {}", e),
+        }
+    };
+    assert_eq!(resp.status(), 200);
+    assert_eq!(body_string(resp).await, "upstream saw /hello?x=1");
+
+    stop.send(()).unwrap();
+    server.await.unwrap().expect("clean shutdown");
+}
+
+#[tokio::test]
+async fn unix_socket_listener_serves_requests() {
+    let upstream = upstream(|req: Request<Body>| async move {
+        Response::new(Body::from(format!("upstream saw {}", req.uri())))
+    })
+    .await;
+    // A socket file left behind by an earlier run is replaced.
+    let path = std::env::temp_dir().join(format!("simple-proxy-{}.sock", std::process::id()));
+    let _ = fs::remove_file(&path);
+    drop(UnixListener::bind(&path).unwrap());
+    let proxy = Proxy::builder(ProxyConfig {
+        bind_addr: BindAddrs(vec![BindAddr::Unix(path.clone())]),
+        unix_socket_mode: Some(SocketMode(0o600)),
+        metrics_addr: free_addr(),
+        ..config(&upstream)
+    })
+    .build()
+    .unwrap();
+
+    let (stop, stopped) = oneshot::channel::<()>();
+    let server = tokio::spawn(async move {
+        proxy
+            .serve_with_shutdown(async {
+                let _ = stopped.await;
+            })
+            .await
+    });
+
+    let mut attempts = 0;
+    let stream = loop {
+        match UnixStream::connect(&path).await {
+            Ok(stream) => break stream,
+            // The stale socket is still there, or nothing is yet.
+            Err(_) if attempts < 50 => {
+                attempts += 1;
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+            Err(e) => panic!("proxy never came up: {}", e),
+        }
+    };
+    assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+    let (mut sender, conn) = hyper::client::conn::handshake(stream).await.unwrap();
+    tokio::spawn(conn);
+    let req = Request::get("/over-unix")
+        .header(AUTHORIZATION, format!("Bearer {}", TOKEN))
+        .body(Body::empty())
+        .unwrap();
+    let resp = sender.send_request(req).await.unwrap();
+    assert_eq!(resp.status(), 200);
+    assert_eq!(body_string(resp).await, "upstream saw /over-unix");
+
+    stop.send(()).unwrap();
+    server.await.unwrap().expect("clean shutdown");
+    let _ = fs::remove_file(&path);
+}
+
+// POST /admin/shutdown with `token`, retrying until the proxy is listening.
+async fn post_shutdown(addr: SocketAddr, token: &str) -> u16 {
+    for _ in 0..50 {
+        let req = Request::post(format!("http://{}/admin/shutdown", addr))
+            .header(AUTHORIZATION, format!("Bearer {}", token))
+            .body(Body::empty())
+            .unwrap();
+        match Client::new().request(req).await {
+            Ok(resp) => return resp.status().as_u16(),
+            Err(_) => tokio::time::sleep(Duration::from_millis(20)).await,
+        }
+    }
+    panic!("proxy never came up");
+}
+
+#[tokio::test]
+async fn admin_shutdown_needs_the_admin_token() {
+    let upstream = upstream(|_| async { Response::new(Body::from("ok")) }).await;
+    let addr = free_addr();
+    let proxy = Proxy::builder(ProxyConfig {
+        bind_addr: BindAddrs(vec![BindAddr::Tcp(addr)]),
+        metrics_addr: free_addr(),
+        admin_enabled: true,
+        admin_token: Some("admin-secret".to_string()),
+        ..config(&upstream)
+    })
+    .build()
+    .unwrap();
+    let server = tokio::spawn(async move { proxy.serve_with_shutdown(std::future::pending()).await });
+
+    // The proxy's own token is not enough.
+    assert_eq!(post_shutdown(addr, "wrong").await, 401);
+    assert_eq!(post_shutdown(addr, TOKEN).await, 401);
+    assert!(!server.is_finished());
+
+    assert_eq!(post_shutdown(addr, "admin-secret").await, 202);
+    tokio::time::timeout(Duration::from_secs(5), server)
+        .await
+        .expect("proxy drained and stopped")
+        .unwrap()
+        .expect("clean shutdown");
+}
+
+#[tokio::test]
+async fn admin_paths_are_proxied_when_disabled() {
+    let upstream = upstream(|req: Request<Body>| async move {
+        Response::new(Body::from(format!("upstream saw {} {}", req.method(), req.uri())))
+    })
+    .await;
+    let proxy = common::proxy(config(&upstream));
+    let req = common::request(Method::POST, "/admin/shutdown", "");
+    let resp = proxy.handle(req, common::client_addr()).await;
+    assert_eq!(body_string(resp).await, "upstream saw POST /admin/shutdown");
+}
+
+// GET `path` from the proxy at `addr`, retrying until it is listening.
+async fn fetch_when_up(addr: SocketAddr, path: &str) -> String {
+    for _ in 0..50 {
+        let req = Request::get(format!("http://{}{}", addr, path))
+            .header(AUTHORIZATION, format!("Bearer {}", TOKEN))
+            .body(Body::empty())
+            .unwrap();
+        match Client::new().request(req).await {
+            Ok(resp) => return body_string(resp).await,
+            Err(_) => tokio::time::sleep(Duration::from_millis(20)).await,
+        }
+    }
+    panic!("proxy never came up");
+}
+
+#[tokio::test]
+async fn tuned_listener_serves_and_rebinds() {
+    let upstream = upstream(|req: Request<Body>| async move {
+        Response::new(Body::from(format!("upstream saw {}", req.uri())))
+    })
+    .await;
+    let addr = free_addr();
+    let tuned = || {
+        Proxy::builder(ProxyConfig {
+            bind_addr: BindAddrs(vec![BindAddr::Tcp(addr)]),
+            metrics_addr: free_addr(),
+            reuse_addr: true,
+            listen_backlog: 16,
+            tcp_nodelay: true,
+            tcp_keepalive_secs: Some(30),
+            ..config(&upstream)
+        })
+        .build()
+        .unwrap()
+    };
+
+    // With SO_REUSEADDR, a restart can bind the same address straight away,
+    // even with the earlier connections still in TIME_WAIT.
+    for _ in 0..2 {
+        let proxy = tuned();
+        let (stop, stopped) = oneshot::channel::<()>();
+        let server = tokio::spawn(async move {
+            proxy
+                .serve_with_shutdown(async {
+                    let _ = stopped.await;
+                })
+                .await
+        });
+        assert_eq!(fetch_when_up(addr, "/tuned").await, "upstream saw /tuned");
+        stop.send(()).unwrap();
+        server.await.unwrap().expect("clean shutdown");
+    }
+}
+
+#[tokio::test]
+async fn every_bind_address_serves() {
+    let upstream = upstream(|req: Request<Body>| async move {
+        Response::new(Body::from(format!("upstream saw {}", req.uri())))
+    })
+    .await;
+    let (first, second) = (free_addr(), free_addr());
+    let proxy = Proxy::builder(ProxyConfig {
+        bind_addr: BindAddrs(vec![BindAddr::Tcp(first), BindAddr::Tcp(second)]),
+        metrics_addr: free_addr(),
+        ..config(&upstream)
+    })
+    .build()
+    .unwrap();
+
+    let (stop, stopped) = oneshot::channel::<()>();
+    let server = tokio::spawn(async move {
+        proxy
+            .serve_with_shutdown(async {
+                let _ = stopped.await;
+            })
+            .await
+    });
+    assert_eq!(fetch_when_up(first, "/one").await, "upstream saw /one");
+    assert_eq!(fetch_when_up(second, "/two").await, "upstream saw /two");
+    // Both are still up after each has served a request.
+    assert_eq!(fetch_when_up(first, "/again").await, "upstream saw /again");
+
+    stop.send(()).unwrap();
+    server.await.unwrap().expect("clean shutdown");
+}
+
+// How many requests one connection to `addr` carries before the proxy
+// closes it, trying up to `attempts`.
+async fn requests_per_connection(addr: SocketAddr, attempts: usize) -> usize {
+    let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+    let (mut sender, conn) = hyper::client::conn::handshake(stream).await.unwrap();
+    let conn = tokio::spawn(conn);
+    let mut served = 0;
+    for _ in 0..attempts {
+        let req = Request::get("/")
+            .header(AUTHORIZATION, format!("Bearer {}", TOKEN))
+            .body(Body::empty())
+            .unwrap();
+        let Ok(resp) = sender.send_request(req).await else {
+            break;
+        };
+        assert_eq!(resp.status(), 200);
+        body_string(resp).await;
+        served += 1;
+    }
+    // Closed by the proxy, not left open for more.
+    tokio::time::timeout(Duration::from_secs(2), conn).await.expect("connection closed").unwrap().unwrap();
+    served
+}
+
+#[tokio::test]
+async fn connections_close_after_the_request_limit() {
+    let upstream = upstream(|_: Request<Body>| async { Response::new(Body::from("ok")) }).await;
+    for (keep_alive, max_requests, expected) in [(true, Some(3), 3), (false, None, 1)] {
+        let addr = free_addr();
+        let proxy = Proxy::builder(ProxyConfig {
+            bind_addr: BindAddrs(vec![BindAddr::Tcp(addr)]),
+            metrics_addr: free_addr(),
+            keep_alive,
+            max_requests_per_conn: max_requests,
+            ..config(&upstream)
+        })
+        .build()
+        .unwrap();
+
+        let (stop, stopped) = oneshot::channel::<()>();
+        let server = tokio::spawn(async move {
+            proxy
+                .serve_with_shutdown(async {
+                    let _ = stopped.await;
+                })
+                .await
+        });
+        fetch_when_up(addr, "/").await;
+        assert_eq!(requests_per_connection(addr, 5).await, expected);
+
+        stop.send(()).unwrap();
+        server.await.unwrap().expect("clean shutdown");
+    }
+}
+
+// A TCP connection to `addr`, retrying until the proxy is listening.
+async fn connect_when_up(addr: SocketAddr) -> tokio::net::TcpStream {
+    for _ in 0..50 {
+        match tokio::net::TcpStream::connect(addr).await {
+            Ok(stream) => return stream,
+            Err(_) => tokio::time::sleep(Duration::from_millis(20)).await,
+        }
+    }
+    panic!("proxy never came up");
+}
+
+#[tokio::test]
+async fn silent_connections_are_closed_after_the_idle_timeout() {
+    let upstream = upstream(|_: Request<Body>| async { Response::new(Body::from("ok")) }).await;
+    for tls in [false, true] {
+        let addr = free_addr();
+        let proxy = Proxy::builder(ProxyConfig {
+            bind_addr: BindAddrs(vec![BindAddr::Tcp(addr)]),
+            metrics_addr: free_addr(),
+            client_idle_timeout_secs: Some(1),
+            tls_cert_path: tls.then(|| TLS_CERT.into()),
+            tls_key_path: tls.then(|| TLS_KEY.into()),
+            ..config(&upstream)
+        })
+        .build()
+        .unwrap();
+        let (stop, stopped) = oneshot::channel::<()>();
+        let server = tokio::spawn(async move {
+            proxy
+                .serve_with_shutdown(async {
+                    let _ = stopped.await;
+                })
+                .await
+        });
+
+        let mut silent = connect_when_up(addr).await;
+        // Not even a TLS ClientHello is sent.
+        let started = std::time::Instant::now();
+        let mut buf = [0; 64];
+        let read = tokio::time::timeout(Duration::from_secs(5), silent.read(&mut buf)).await;
+        assert!(matches!(read, Ok(Ok(0) | Err(_))), "tls {}: {:?}", tls, read);
+        assert!(started.elapsed() >= Duration::from_millis(900), "tls {}: closed at once", tls);
+
+        stop.send(()).unwrap();
+        server.await.unwrap().expect("clean shutdown");
+    }
+}
+
+// Send `head` and then a GET with `Connection: close` on a fresh connection
+// to `addr`; returns everything the proxy writes back.
+async fn raw_get_after(addr: SocketAddr, head: &[u8]) -> String {
+    let mut stream = connect_when_up(addr).await;
+    let request = format!(
+        "GET / HTTP/1.1\r\nhost: proxy\r\nauthorization: Bearer {}\r\nconnection: close\r\n\r\n",
+        TOKEN
+    );
+    stream.write_all(&[head, request.as_bytes()].concat()).await.unwrap();
+    let mut resp = Vec::new();
+    let _ = stream.read_to_end(&mut resp).await;
+    String::from_utf8_lossy(&resp).into_owned()
+}
+
+#[tokio::test]
+async fn proxy_protocol_header_gives_the_client_address() {
+    let upstream = upstream(|req: Request<Body>| async move {
+        let forwarded_for = req.headers().get("x-forwarded-for").unwrap().to_str().unwrap().to_string();
+        Response::new(Body::from(forwarded_for))
+    })
+    .await;
+    let addr = free_addr();
+    let proxy = Proxy::builder(ProxyConfig {
+        bind_addr: BindAddrs(vec![BindAddr::Tcp(addr)]),
+        metrics_addr: free_addr(),
+        proxy_protocol: Some("v1".parse().unwrap()),
+        ..config(&upstream)
+    })
+    .build()
+    .unwrap();
+    let (stop, stopped) = oneshot::channel::<()>();
+    let server = tokio::spawn(async move {
+        proxy
+            .serve_with_shutdown(async {
+                let _ = stopped.await;
+            })
+            .await
+    });
+    let resp = raw_get_after(addr, b"PROXY TCP4 203.0.113.7 10.0.0.1 56324 443\r\n").await;
+    assert!(resp.starts_with("HTTP/1.1 200"), "{}", resp);
+    assert!(resp.ends_with("\r\n\r\n203.0.113.7"), "{}", resp);
+
+    // Without the header the connection is dropped unanswered.
+    assert_eq!(raw_get_after(addr, b"").await, "");
+
+    stop.send(()).unwrap();
+    server.await.unwrap().expect("clean shutdown");
+}
+
+// A keep-alive connection to `addr`, opened with `head` and proven by one
+// request. It closes when the returned sender is dropped.
+async fn held_connection(addr: SocketAddr, head: &[u8]) -> hyper::client::conn::SendRequest<Body> {
+    let mut stream = connect_when_up(addr).await;
+    stream.write_all(head).await.unwrap();
+    let (mut sender, conn) = hyper::client::conn::handshake(stream).await.unwrap();
+    tokio::spawn(conn);
+    let req = Request::get("/")
+        .header(AUTHORIZATION, format!("Bearer {}", TOKEN))
+        .body(Body::empty())
+        .unwrap();
+    assert_eq!(sender.send_request(req).await.unwrap().status(), 200);
+    sender
+}
+
+#[tokio::test]
+async fn connections_per_client_ip_are_capped() {
+    let upstream = upstream(|_: Request<Body>| async { Response::new(Body::from("ok")) }).await;
+    for proxy_protocol in [false, true] {
+        let addr = free_addr();
+        let proxy = Proxy::builder(ProxyConfig {
+            bind_addr: BindAddrs(vec![BindAddr::Tcp(addr)]),
+            metrics_addr: free_addr(),
+            max_conns_per_ip: Some(2),
+            proxy_protocol: proxy_protocol.then(|| "v1".parse().unwrap()),
+            ..config(&upstream)
+        })
+        .build()
+        .unwrap();
+        let (stop, stopped) = oneshot::channel::<()>();
+        let server = tokio::spawn(async move {
+            proxy
+                .serve_with_shutdown(async {
+                    let _ = stopped.await;
+                })
+                .await
+        });
+        // Behind a balancer every connection comes from its address; the
+        // limit applies to the client named in the PROXY header.
+        let from = |ip: &str| {
+            if proxy_protocol {
+                format!("PROXY TCP4 {} 10.0.0.1 56324 443\r\n", ip).into_bytes()
+            } else {
+                Vec::new()
+            }
+        };
+
+        let first = held_connection(addr, &from("203.0.113.7")).await;
+        let _second = held_connection(addr, &from("203.0.113.7")).await;
+        assert_eq!(raw_get_after(addr, &from("203.0.113.7")).await, "", "proxy protocol {}", proxy_protocol);
+        if proxy_protocol {
+            let resp = raw_get_after(addr, &from("203.0.113.8")).await;
+            assert!(resp.starts_with("HTTP/1.1 200"), "{}", resp);
+        }
+
+        // Closing one frees its slot.
+        drop(first);
+        let mut resp = String::new();
+        for _ in 0..50 {
+            resp = raw_get_after(addr, &from("203.0.113.7")).await;
+            if !resp.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(resp.starts_with("HTTP/1.1 200"), "{}", resp);
+
+        stop.send(()).unwrap();
+        server.await.unwrap().expect("clean shutdown");
+    }
+}
+
+// The value of `name` in the metrics served at `addr`.
+async fn metric(addr: SocketAddr, name: &str) -> Option<String> {
+    let resp = Client::new().get(format!("http://{}/metrics", addr).parse().unwrap()).await.ok()?;
+    let text = body_string(resp).await;
+    let line = text.lines().find(|line| line.split(' ').next() == Some(name))?;
+    Some(line.split(' ').nth(1)?.to_string())
+}
+
+// Poll until `name` reads `expected`.
+async fn wait_for_metric(addr: SocketAddr, name: &str, expected: &str) {
+    for _ in 0..100 {
+        if metric(addr, name).await.as_deref() == Some(expected) {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("{} never read {}, last {:?}", name, expected, metric(addr, name).await);
+}
+
+#[tokio::test]
+async fn active_connections_gauge_follows_clients() {
+    let upstream = upstream(|_| async { Response::new(Body::from("ok")) }).await;
+    let (addr, metrics_addr) = (free_addr(), free_addr());
+    let proxy = Proxy::builder(ProxyConfig {
+        bind_addr: BindAddrs(vec![BindAddr::Tcp(addr)]),
+        metrics_addr,
+        ..config(&upstream)
+    })
+    .build()
+    .unwrap();
+    tokio::spawn(async move { proxy.serve_with_shutdown(std::future::pending()).await });
+    assert_eq!(fetch_when_up(addr, "/").await, "ok");
+    wait_for_metric(metrics_addr, "proxy_active_connections", "0").await;
+
+    // Idle connections count too, including one that never sends a byte.
+    let mut open = Vec::new();
+    for _ in 0..3 {
+        open.push(tokio::net::TcpStream::connect(addr).await.unwrap());
+    }
+    wait_for_metric(metrics_addr, "proxy_active_connections", "3").await;
+    open.truncate(1);
+    wait_for_metric(metrics_addr, "proxy_active_connections", "1").await;
+    drop(open);
+    wait_for_metric(metrics_addr, "proxy_active_connections", "0").await;
+    assert_eq!(metric(metrics_addr, "proxy_accept_errors_total").await.as_deref(), Some("0"));
+}
+
+#[tokio::test]
+async fn requests_are_counted_against_the_slo() {
+    let upstream = upstream(|req: Request<Body>| async move {
+        if req.uri().path() == "/slow" {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        }
+        Response::new(Body::from(format!("upstream saw {}", req.uri())))
+    })
+    .await;
+    let (addr, metrics_addr) = (free_addr(), free_addr());
+    let proxy = Proxy::builder(ProxyConfig {
+        bind_addr: BindAddrs(vec![BindAddr::Tcp(addr)]),
+        metrics_addr,
+        slo_threshold_ms: Some(100),
+        ..config(&upstream)
+    })
+    .build()
+    .unwrap();
+
+    let (stop, stopped) = oneshot::channel::<()>();
+    let server = tokio::spawn(async move {
+        proxy
+            .serve_with_shutdown(async {
+                let _ = stopped.await;
+            })
+            .await
+    });
+    fetch_when_up(addr, "/fast").await;
+    fetch_when_up(addr, "/fast").await;
+    fetch_when_up(addr, "/slow").await;
+    wait_for_metric(metrics_addr, r#"proxy_slo_requests_total{slo="met"}"#, "2").await;
+    wait_for_metric(metrics_addr, r#"proxy_slo_requests_total{slo="violated"}"#, "1").await;
+
+    stop.send(()).unwrap();
+    server.await.unwrap().expect("clean shutdown");
+}
+
+// Accepts connections and answers each with something that isn't HTTP.
+async fn garbage_upstream() -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut buf = [0; 4096];
+                let _ = stream.read(&mut buf).await;
+                let _ = stream.write_all(b"this is not http\r\n\r\n").await;
+            });
+        }
+    });
+    format!("http://{}", addr)
+}
+
+#[tokio::test]
+async fn upstream_failures_are_counted_by_kind() {
+    let slow = upstream(|_: Request<Body>| async {
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        Response::new(Body::empty())
+    })
+    .await;
+    let route = |prefix: &str, upstream: &str| RouteConfig {
+        prefix: prefix.to_string(),
+        upstream: upstream.parse().unwrap(),
+        strip_prefix: false,
+        auth_tokens: Vec::new(),
+        public: false,
+        timeout_ms: None,
+    };
+    let (addr, metrics_addr) = (free_addr(), free_addr());
+    let proxy = Proxy::builder(ProxyConfig {
+        bind_addr: BindAddrs(vec![BindAddr::Tcp(addr)]),
+        metrics_addr,
+        upstream_timeout_ms: 200,
+        routes: vec![route("/refused", "http://127.0.0.1:1"), route("/broken", &garbage_upstream().await)],
+        ..config(&slow)
+    })
+    .build()
+    .unwrap();
+
+    let (stop, stopped) = oneshot::channel::<()>();
+    let server = tokio::spawn(async move {
+        proxy
+            .serve_with_shutdown(async {
+                let _ = stopped.await;
+            })
+            .await
+    });
+    fetch_when_up(addr, "/refused").await;
+    fetch_when_up(addr, "/broken").await;
+    fetch_when_up(addr, "/slow").await;
+    for kind in ["connect", "protocol", "timeout"] {
+        let name = format!("proxy_upstream_errors_total{{kind=\"{}\"}}", kind);
+        wait_for_metric(metrics_addr, &name, "1").await;
+    }
+
+    stop.send(()).unwrap();
+    server.await.unwrap().expect("clean shutdown");
+}
+
+#[tokio::test]
+async fn listening_waits_for_the_upstream() {
+    // The upstream comes up on this address a little after the proxy starts.
+    let upstream_addr = free_addr();
+    let (addr, upstream) = (free_addr(), format!("http://{}", upstream_addr));
+    let proxy = Proxy::builder(ProxyConfig {
+        bind_addr: BindAddrs(vec![BindAddr::Tcp(addr)]),
+        metrics_addr: free_addr(),
+        wait_for_upstream: true,
+        wait_for_upstream_secs: 10,
+        ..config(&upstream)
+    })
+    .build()
+    .unwrap();
+    tokio::spawn(async move { proxy.serve_with_shutdown(std::future::pending()).await });
+
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert!(tokio::net::TcpStream::connect(addr).await.is_err(), "listening before the upstream is up");
+
+    let make_service = make_service_fn(|_| async {
+        Ok::<_, Infallible>(service_fn(|_| async { Ok::<_, Infallible>(Response::new(Body::from("ok"))) }))
+    });
+    tokio::spawn(Server::bind(&upstream_addr).serve(make_service));
+    assert_eq!(fetch_when_up(addr, "/").await, "ok");
+}
+
+#[tokio::test]
+async fn startup_fails_when_the_upstream_never_comes_up() {
+    let proxy = Proxy::builder(ProxyConfig {
+        bind_addr: BindAddrs(vec![BindAddr::Tcp(free_addr())]),
+        metrics_addr: free_addr(),
+        wait_for_upstream: true,
+        wait_for_upstream_secs: 1,
+        ..config(&format!("http://{}", free_addr()))
+    })
+    .build()
+    .unwrap();
+    let err = proxy.serve_with_shutdown(std::future::pending()).await.unwrap_err();
+    assert!(matches!(err, StartupError::UpstreamNotReady { .. }), "{}", err);
+    assert!(err.to_string().contains("upstream not ready"), "{}", err);
+}
@@ -0,0 +1,94 @@
+// Custom pages for the proxy's own error responses.
+
+mod common;
+
+use common::{body_string, client_addr, config, get, proxy, upstream};
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Request, Response};
+use simple_proxy::ProxyConfig;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Nothing listens here, so every request gets the proxy's own 502.
+const DEAD_UPSTREAM: &str = "http://127.0.0.1:1";
+
+// Forwarding to `upstream`, with a page for 502s.
+fn with_502_page(upstream: &str, page: &Path) -> ProxyConfig {
+    ProxyConfig {
+        error_pages: BTreeMap::from([("502".to_string(), page.to_path_buf())]),
+        ..config(upstream)
+    }
+}
+
+fn temp_page(name: &str, contents: &str) -> PathBuf {
+    let page = std::env::temp_dir().join(format!("simple-proxy-{}-{}.html", name, std::process::id()));
+    fs::write(&page, contents).unwrap();
+    page
+}
+
+#[tokio::test]
+async fn configured_502_page_is_served_on_upstream_failure() {
+    let page = temp_page("502", "<h1>Back soon</h1>");
+    let proxy = proxy(with_502_page(DEAD_UPSTREAM, &page));
+
+    let resp = proxy.handle(get("/"), client_addr()).await;
+    assert_eq!(resp.status(), 502);
+    assert_eq!(resp.headers()[CONTENT_TYPE], "text/html; charset=utf-8");
+    assert_eq!(body_string(resp).await, "<h1>Back soon</h1>");
+
+    // A reload picks up the file's new contents.
+    fs::write(&page, "<h1>Back later</h1>").unwrap();
+    proxy.reload_error_pages(&with_502_page(DEAD_UPSTREAM, &page)).unwrap();
+    let resp = proxy.handle(get("/"), client_addr()).await;
+    assert_eq!(body_string(resp).await, "<h1>Back later</h1>");
+    fs::remove_file(&page).unwrap();
+
+    // Statuses without a page keep the default body.
+    let req = Request::get("/").body(Body::empty()).unwrap();
+    let resp = proxy.handle(req, client_addr()).await;
+    assert_eq!(resp.status(), 401);
+    assert!(!body_string(resp).await.contains("<h1>"));
+}
+
+#[tokio::test]
+async fn upstream_502_passes_through() {
+    let upstream = upstream(|_: Request<Body>| async {
+        Response::builder().status(502).body(Body::from("upstream's own")).unwrap()
+    })
+    .await;
+    let page = temp_page("502-passthrough", "<h1>Back soon</h1>");
+    let proxy = proxy(with_502_page(&upstream, &page));
+    fs::remove_file(&page).unwrap();
+
+    let resp = proxy.handle(get("/"), client_addr()).await;
+    assert_eq!(resp.status(), 502);
+    assert_eq!(body_string(resp).await, "upstream's own");
+}
+
+#[tokio::test]
+async fn json_errors_share_one_schema() {
+    let proxy = proxy(ProxyConfig {
+        error_format: "json".parse().unwrap(),
+        ..config(DEAD_UPSTREAM)
+    });
+
+    let mut unauthenticated = get("/");
+    unauthenticated.headers_mut().remove(hyper::header::AUTHORIZATION);
+    unauthenticated.headers_mut().insert("x-request-id", "req-401".parse().unwrap());
+    for (req, status, error, request_id) in [
+        (unauthenticated, 401, "unauthorized", Some("req-401")),
+        (get("/"), 502, "bad_gateway", None),
+    ] {
+        let resp = proxy.handle(req, client_addr()).await;
+        assert_eq!(resp.status(), status);
+        assert_eq!(resp.headers()[CONTENT_TYPE], "application/json");
+        let echoed_id = resp.headers()["x-request-id"].to_str().unwrap().to_string();
+        let body: serde_json::Value = serde_json::from_str(&body_string(resp).await).unwrap();
+        let fields = body.as_object().unwrap();
+        assert_eq!(fields.len(), 3, "{}", body);
+        assert_eq!(body["error"], error);
+        assert!(body["message"].as_str().is_some_and(|m| !m.is_empty()), "{}", body);
+        assert_eq!(body["request_id"], request_id.unwrap_or(&echoed_id));
+    }
+}
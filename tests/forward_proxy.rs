@@ -0,0 +1,88 @@
+// Forward-proxy mode: CONNECT tunnels and absolute-form request URIs.
+
+mod common;
+
+use common::{body_string, client_addr, config, get, serve, upstream, TOKEN};
+use hyper::header::CACHE_CONTROL;
+use hyper::{Body, Request, Response};
+use simple_proxy::{Proxy, ProxyConfig};
+use std::net::SocketAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+fn forward_proxy(upstream: &str) -> Proxy {
+    Proxy::builder(ProxyConfig {
+        forward_proxy_mode: true,
+        cache_max_entries: Some(100),
+        ..config(upstream)
+    })
+    .build()
+    .unwrap()
+}
+
+// A TCP server echoing back whatever it receives.
+async fn echo_server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut read, mut write) = stream.split();
+                let _ = tokio::io::copy(&mut read, &mut write).await;
+            });
+        }
+    });
+    addr
+}
+
+// Send a CONNECT for `target` and return the response head.
+async fn connect(proxy: SocketAddr, target: SocketAddr, authorization: &str) -> (TcpStream, String) {
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    let request = format!(
+        "CONNECT {target} HTTP/1.1\r\nHost: {target}\r\nAuthorization: {authorization}\r\n\r\n",
+    );
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        let mut byte = [0];
+        assert_eq!(stream.read(&mut byte).await.unwrap(), 1, "connection closed mid-response");
+        head.push(byte[0]);
+    }
+    (stream, String::from_utf8(head).unwrap())
+}
+
+#[tokio::test]
+async fn connect_opens_a_tunnel() {
+    let target = echo_server().await;
+    let proxy = serve(forward_proxy("http://127.0.0.1:1")).await;
+
+    let (mut tunnel, head) = connect(proxy, target, &format!("Bearer {}", TOKEN)).await;
+    assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+    tunnel.write_all(b"ping").await.unwrap();
+    let mut echoed = [0; 4];
+    tunnel.read_exact(&mut echoed).await.unwrap();
+    assert_eq!(&echoed, b"ping");
+
+    let (_, head) = connect(proxy, target, "Bearer wrong").await;
+    assert!(head.starts_with("HTTP/1.1 401"), "{}", head);
+}
+
+#[tokio::test]
+async fn absolute_form_hosts_are_cached_apart() {
+    let cacheable = |name: &'static str| {
+        upstream(move |_: Request<Body>| async move {
+            let mut resp = Response::new(Body::from(name));
+            resp.headers_mut().insert(CACHE_CONTROL, "public, max-age=60".parse().unwrap());
+            resp
+        })
+    };
+    let (a, b) = (cacheable("a").await, cacheable("b").await);
+    let proxy = forward_proxy(&a);
+
+    let fetches = [(&a, "a", "MISS"), (&b, "b", "MISS"), (&a, "a", "HIT"), (&b, "b", "HIT")];
+    for (host, body, x_cache) in fetches {
+        let resp = proxy.handle(get(&format!("{}/page", host)), client_addr()).await;
+        assert_eq!(resp.headers()["x-cache"], x_cache);
+        assert_eq!(body_string(resp).await, body);
+    }
+}
@@ -0,0 +1,586 @@
+// How requests and responses are rewritten on their way through the proxy.
+
+mod common;
+
+use common::{body_string, client_addr, config, get, https_upstream, proxy, serve, upstream, TOKEN};
+use futures_util::TryStreamExt;
+use hyper::body::Bytes;
+use hyper::header::{HeaderValue, CONTENT_LENGTH, CONTENT_TYPE, ETAG, HOST, LOCATION, SERVER, VIA};
+use hyper::{Body, Request, Response};
+use simple_proxy::{Proxy, ProxyConfig, ResponseTransform};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tower::ServiceBuilder;
+
+// Answers with the named request headers, one line each, `-` for those
+// missing.
+async fn echo_headers(names: &'static [&'static str]) -> String {
+    upstream(move |req: Request<Body>| async move {
+        let values: Vec<&str> = names
+            .iter()
+            .map(|name| req.headers().get(*name).map_or("-", |v| v.to_str().unwrap()))
+            .collect();
+        Response::new(Body::from(values.join("\n")))
+    })
+    .await
+}
+
+#[tokio::test]
+async fn host_is_upstream_authority_with_port() {
+    let upstream = echo_headers(&["host"]).await;
+    let proxy = proxy(config(&upstream));
+    let mut req = get("/path");
+    req.headers_mut().insert(HOST, "proxy.example.com".parse().unwrap());
+    let resp = proxy.handle(req, client_addr()).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(body_string(resp).await, upstream.strip_prefix("http://").unwrap());
+}
+
+#[tokio::test]
+async fn forwarded_headers_describe_the_client() {
+    let upstream = echo_headers(&["x-forwarded-for", "x-forwarded-proto", "x-forwarded-host"]).await;
+    let proxy = proxy(config(&upstream));
+
+    let mut req = get("/");
+    req.headers_mut().insert(HOST, "proxy.example.com".parse().unwrap());
+    let resp = proxy.handle(req, client_addr()).await;
+    assert_eq!(body_string(resp).await, "127.0.0.1\nhttp\nproxy.example.com");
+
+    // An existing chain is extended, not replaced.
+    let mut req = get("/");
+    req.headers_mut().insert("x-forwarded-for", "203.0.113.7".parse().unwrap());
+    let resp = proxy.handle(req, client_addr()).await;
+    assert_eq!(body_string(resp).await, "203.0.113.7, 127.0.0.1\nhttp\n-");
+}
+
+// The Via the upstream received, and the response's Via and Server, for a
+// request that already went through one proxy.
+async fn via_and_server(proxy: &Proxy) -> (String, Option<String>, Option<String>) {
+    let mut req = get("/");
+    req.headers_mut().insert(VIA, "1.0 edge".parse().unwrap());
+    let resp = proxy.handle(req, client_addr()).await;
+    let header = |name| resp.headers().get(name).map(|v: &HeaderValue| v.to_str().unwrap().to_string());
+    let (via, server) = (header(VIA), header(SERVER));
+    (body_string(resp).await, via, server)
+}
+
+#[tokio::test]
+async fn via_is_appended_and_server_stripped() {
+    // Reports the request's Via, and answers as an upstream behind a CDN.
+    let upstream = upstream(|req: Request<Body>| async move {
+        let via = req.headers().get(VIA).map_or("-", |v| v.to_str().unwrap()).to_string();
+        Response::builder()
+            .header(SERVER, "nginx/1.25.3")
+            .header(VIA, "1.1 cdn")
+            .body(Body::from(via))
+            .unwrap()
+    })
+    .await;
+    let nginx = Some("nginx/1.25.3".to_string());
+
+    let both = proxy(ProxyConfig {
+        add_via_header: true,
+        strip_server_header: true,
+        ..config(&upstream)
+    });
+    let (forwarded, via, server) = via_and_server(&both).await;
+    assert_eq!(forwarded, "1.0 edge, 1.1 ezproxy");
+    assert_eq!(via.as_deref(), Some("1.1 cdn, 1.1 ezproxy"));
+    assert_eq!(server, None);
+
+    // Each can be turned on without the other.
+    let via_only = proxy(ProxyConfig {
+        add_via_header: true,
+        ..config(&upstream)
+    });
+    let (forwarded, via, server) = via_and_server(&via_only).await;
+    assert_eq!(forwarded, "1.0 edge, 1.1 ezproxy");
+    assert_eq!(via.as_deref(), Some("1.1 cdn, 1.1 ezproxy"));
+    assert_eq!(server, nginx);
+
+    let strip_only = proxy(ProxyConfig {
+        strip_server_header: true,
+        ..config(&upstream)
+    });
+    let (forwarded, via, server) = via_and_server(&strip_only).await;
+    assert_eq!(forwarded, "1.0 edge");
+    assert_eq!(via.as_deref(), Some("1.1 cdn"));
+    assert_eq!(server, None);
+
+    let neither = proxy(config(&upstream));
+    assert_eq!(via_and_server(&neither).await.2, nginx);
+}
+
+#[tokio::test]
+async fn https_upstream_is_reached_over_tls() {
+    let upstream = https_upstream(|req: Request<Body>| async move {
+        Response::new(Body::from(format!("tls {}", req.uri())))
+    })
+    .await;
+
+    // The certificate is self-signed, so it only passes with verification
+    // off.
+    let insecure = proxy(ProxyConfig {
+        upstream_insecure_skip_verify: true,
+        ..config(&upstream)
+    });
+    let resp = insecure.handle(get("/secure?q=1"), client_addr()).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(body_string(resp).await, "tls /secure?q=1");
+
+    let verifying = proxy(config(&upstream));
+    assert_eq!(verifying.handle(get("/"), client_addr()).await.status(), 502);
+}
+
+#[tokio::test]
+async fn hop_by_hop_headers_are_stripped_both_ways() {
+    let upstream = upstream(|req: Request<Body>| async move {
+        let names = ["connection", "x-session", "keep-alive", "proxy-authorization", "x-kept"];
+        let values: Vec<&str> = names
+            .iter()
+            .map(|name| req.headers().get(*name).map_or("-", |v| v.to_str().unwrap()))
+            .collect();
+        Response::builder()
+            .header("connection", "x-internal")
+            .header("x-internal", "secret")
+            .header("keep-alive", "timeout=5")
+            .header("x-public", "yes")
+            .body(Body::from(values.join("\n")))
+            .unwrap()
+    })
+    .await;
+    let proxy = proxy(config(&upstream));
+
+    let mut req = get("/");
+    let headers = req.headers_mut();
+    headers.insert("connection", "close, x-session".parse().unwrap());
+    headers.insert("x-session", "abc".parse().unwrap());
+    headers.insert("keep-alive", "timeout=5".parse().unwrap());
+    headers.insert("proxy-authorization", "Basic Zm9vOmJhcg==".parse().unwrap());
+    headers.insert("x-kept", "yes".parse().unwrap());
+    let resp = proxy.handle(req, client_addr()).await;
+    for name in ["connection", "x-internal", "keep-alive"] {
+        assert!(!resp.headers().contains_key(name), "{} reached the client", name);
+    }
+    assert_eq!(resp.headers()["x-public"], "yes");
+    assert_eq!(body_string(resp).await, "-\n-\n-\n-\nyes");
+}
+
+#[tokio::test]
+async fn client_credentials_are_stripped_kept_or_replaced() {
+    let upstream = echo_headers(&["authorization"]).await;
+    let forwarded = |config: ProxyConfig| async move {
+        let resp = proxy(config).handle(get("/"), client_addr()).await;
+        body_string(resp).await
+    };
+
+    assert_eq!(forwarded(config(&upstream)).await, "-");
+    let keep = ProxyConfig {
+        strip_auth_header: false,
+        ..config(&upstream)
+    };
+    assert_eq!(forwarded(keep).await, format!("Bearer {}", TOKEN));
+    let replace = ProxyConfig {
+        upstream_auth_header: Some("Bearer for-the-upstream".to_string()),
+        ..config(&upstream)
+    };
+    assert_eq!(forwarded(replace).await, "Bearer for-the-upstream");
+}
+
+#[tokio::test]
+async fn new_traces_are_sampled_at_the_configured_rate() {
+    const REQUESTS: usize = 400;
+    let upstream = echo_headers(&["traceparent"]).await;
+    let proxy = proxy(ProxyConfig {
+        trace_sample_rate: Some(0.25),
+        ..config(&upstream)
+    });
+    let mut sampled = 0;
+    for _ in 0..REQUESTS {
+        let traceparent = body_string(proxy.handle(get("/"), client_addr()).await).await;
+        if traceparent != "-" {
+            let fields: Vec<&str> = traceparent.split('-').collect();
+            let lengths: Vec<usize> = fields.iter().map(|f| f.len()).collect();
+            assert_eq!((fields[0], fields[3], lengths), ("00", "01", vec![2, 32, 16, 2]), "{}", traceparent);
+            sampled += 1;
+        }
+    }
+    assert!((60..=140).contains(&sampled), "sampled {} of {}", sampled, REQUESTS);
+}
+
+#[tokio::test]
+async fn incoming_traceparent_is_kept() {
+    let upstream = echo_headers(&["traceparent", "tracestate"]).await;
+    let traceparent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00";
+    for rate in [0.0, 1.0] {
+        let proxy = proxy(ProxyConfig {
+            trace_sample_rate: Some(rate),
+            ..config(&upstream)
+        });
+        let mut req = get("/");
+        req.headers_mut().insert("traceparent", traceparent.parse().unwrap());
+        req.headers_mut().insert("tracestate", "vendor=abc".parse().unwrap());
+        let resp = proxy.handle(req, client_addr()).await;
+        assert_eq!(body_string(resp).await, format!("{}\nvendor=abc", traceparent), "rate {}", rate);
+
+        // A malformed one is dropped along with its state.
+        let mut req = get("/");
+        req.headers_mut().insert("traceparent", "00-not-a-trace-01".parse().unwrap());
+        req.headers_mut().insert("tracestate", "vendor=abc".parse().unwrap());
+        let echoed = body_string(proxy.handle(req, client_addr()).await).await;
+        let (sent, state) = echoed.split_once('\n').unwrap();
+        assert_eq!(state, "-");
+        // Replaced by a fresh trace when sampled.
+        assert!(sent != "00-not-a-trace-01" && (sent == "-") == (rate == 0.0), "{}", sent);
+    }
+}
+
+// Rewrites `Hello` in HTML bodies, one chunk at a time.
+struct Greeting;
+
+impl ResponseTransform for Greeting {
+    fn applies(&self, content_type: Option<&str>) -> bool {
+        content_type.is_some_and(|t| t.starts_with("text/html"))
+    }
+
+    fn transform(&self, _content_type: Option<&str>, body: Body) -> Body {
+        Body::wrap_stream(body.map_ok(|chunk| {
+            Bytes::from(String::from_utf8_lossy(&chunk).replace("Hello", "Howdy"))
+        }))
+    }
+}
+
+#[tokio::test]
+async fn transform_rewrites_html_responses_only() {
+    let upstream = upstream(|req: Request<Body>| async move {
+        let html = req.uri().path() == "/page";
+        let body = if html { "<p>Hello</p>" } else { r#"{"greeting":"Hello"}"# };
+        Response::builder()
+            .header(CONTENT_TYPE, if html { "text/html; charset=utf-8" } else { "application/json" })
+            .header(ETAG, "\"v1\"")
+            .body(Body::from(body))
+            .unwrap()
+    })
+    .await;
+    let proxy = Proxy::builder(config(&upstream)).transform_response(Greeting).build().unwrap();
+
+    let resp = proxy.handle(get("/page"), client_addr()).await;
+    assert!(resp.headers().get(CONTENT_LENGTH).is_none());
+    assert_eq!(resp.headers()[ETAG], "W/\"v1\"");
+    assert_eq!(body_string(resp).await, "<p>Howdy</p>");
+
+    let resp = proxy.handle(get("/data"), client_addr()).await;
+    assert_eq!(resp.headers()[ETAG], "\"v1\"");
+    assert_eq!(body_string(resp).await, r#"{"greeting":"Hello"}"#);
+}
+
+#[tokio::test]
+async fn injected_layers_run_in_order_before_forwarding() {
+    let upstream = echo_headers(&["x-layers"]).await;
+    let append = |name: &'static str| {
+        ServiceBuilder::new().map_request(move |mut req: Request<Body>| {
+            let value = match req.headers().get("x-layers") {
+                Some(seen) => format!("{},{}", seen.to_str().unwrap(), name),
+                None => name.to_string(),
+            };
+            req.headers_mut().insert("x-layers", value.parse().unwrap());
+            req
+        })
+    };
+    let proxy = Proxy::builder(config(&upstream))
+        .layer(append("first"))
+        .layer(append("second"))
+        .build()
+        .unwrap();
+
+    let resp = proxy.handle(get("/"), client_addr()).await;
+    assert_eq!(body_string(resp).await, "first,second");
+}
+
+#[tokio::test]
+async fn unusable_upstream_is_a_502_not_a_panic() {
+    let upstream = upstream(|_| async { Response::new(Body::from("ok")) }).await;
+    // A selector naming a URI without scheme or authority, which cannot be
+    // forwarded to.
+    let proxy = Proxy::builder(config(&upstream))
+        .select_upstream(|req: &Request<Body>| {
+            req.headers().get("x-broken").map(|_| "/elsewhere".parse().unwrap())
+        })
+        .build()
+        .unwrap();
+
+    let mut req = get("/");
+    req.headers_mut().insert("x-broken", "1".parse().unwrap());
+    let resp = proxy.handle(req, client_addr()).await;
+    assert_eq!(resp.status(), 502);
+
+    // The proxy carries on serving.
+    let resp = proxy.handle(get("/"), client_addr()).await;
+    assert_eq!(body_string(resp).await, "ok");
+}
+
+#[tokio::test]
+async fn garbled_request_is_a_400_and_serving_continues() {
+    let upstream = upstream(|_| async { Response::new(Body::from("ok")) }).await;
+    let addr = serve(proxy(config(&upstream))).await;
+
+    for garbage in ["GET /a b c HTTP/1.1\r\n\r\n", "GET / HTTP/1.1\r\nHost: a\r\nbad header\r\n\r\n"] {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(garbage.as_bytes()).await.unwrap();
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).await.unwrap();
+        assert!(resp.starts_with("HTTP/1.1 400"), "{:?} got {:?}", garbage, resp);
+    }
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let req = format!(
+        "GET / HTTP/1.1\r\nHost: a\r\nAuthorization: Bearer {}\r\nConnection: close\r\n\r\n",
+        TOKEN
+    );
+    stream.write_all(req.as_bytes()).await.unwrap();
+    let mut resp = String::new();
+    stream.read_to_string(&mut resp).await.unwrap();
+    assert!(resp.starts_with("HTTP/1.1 200") && resp.ends_with("ok"), "{:?}", resp);
+}
+
+#[tokio::test]
+async fn path_and_query_reach_the_upstream_byte_for_byte() {
+    let upstream = upstream(|req: Request<Body>| async move {
+        Response::new(Body::from(req.uri().to_string()))
+    })
+    .await;
+    let proxy = proxy(config(&upstream));
+
+    for path in ["/search?q=a%20b&x=1", "/a%2Fb/c?q=%25&empty=&=v&x=1+2", "/caf%C3%A9?", "/p?a=[1]|{2}"] {
+        let resp = proxy.handle(get(path), client_addr()).await;
+        assert_eq!(body_string(resp).await, path);
+    }
+
+    // An absolute-form URI without a path gets `/`.
+    let resp = proxy.handle(get("http://proxy.example.com?q=a%20b"), client_addr()).await;
+    assert_eq!(body_string(resp).await, "/?q=a%20b");
+}
+
+#[tokio::test]
+async fn request_id_is_generated_or_passed_through() {
+    let upstream = echo_headers(&["x-request-id"]).await;
+    let proxy = proxy(config(&upstream));
+
+    let resp = proxy.handle(get("/"), client_addr()).await;
+    let echoed = resp.headers()["x-request-id"].to_str().unwrap().to_string();
+    assert!(uuid::Uuid::parse_str(&echoed).is_ok(), "{}", echoed);
+    assert_eq!(body_string(resp).await, echoed);
+
+    // Each request gets its own.
+    let resp = proxy.handle(get("/"), client_addr()).await;
+    assert_ne!(resp.headers()["x-request-id"], echoed.as_str());
+
+    let mut req = get("/");
+    req.headers_mut().insert("x-request-id", "from-the-edge".parse().unwrap());
+    let resp = proxy.handle(req, client_addr()).await;
+    assert_eq!(resp.headers()["x-request-id"], "from-the-edge");
+    assert_eq!(body_string(resp).await, "from-the-edge");
+}
+
+// `config(upstream)`, with settings from a TOML snippet.
+fn config_with(upstream: &str, toml: &str) -> ProxyConfig {
+    let settings: ProxyConfig = toml::from_str(toml).unwrap();
+    ProxyConfig {
+        auth_token: Some(TOKEN.to_string()),
+        upstream_url: Some(upstream.parse().unwrap()),
+        ..settings
+    }
+}
+
+#[tokio::test]
+async fn response_header_rules_are_applied() {
+    let upstream = upstream(|_| async {
+        Response::builder()
+            .header(SERVER, "leaky/1.0")
+            .header("set-cookie", "session=abc; Domain=internal.local; Path=/")
+            .header("set-cookie", "theme=dark; Domain=internal.local")
+            .body(Body::empty())
+            .unwrap()
+    })
+    .await;
+    let proxy = proxy(config_with(
+        &upstream,
+        r#"
+        [[response_headers]]
+        op = "remove"
+        name = "server"
+
+        [[response_headers]]
+        op = "replace"
+        name = "set-cookie"
+        from = "Domain=internal.local"
+        to = "Domain=example.com"
+
+        [[response_headers]]
+        op = "add"
+        name = "x-frame-options"
+        value = "DENY"
+        "#,
+    ));
+
+    let resp = proxy.handle(get("/"), client_addr()).await;
+    assert!(resp.headers().get(SERVER).is_none());
+    let cookies: Vec<_> = resp.headers().get_all("set-cookie").iter().collect();
+    assert_eq!(
+        cookies,
+        ["session=abc; Domain=example.com; Path=/", "theme=dark; Domain=example.com"]
+    );
+    assert_eq!(resp.headers()["x-frame-options"], "DENY");
+}
+
+#[tokio::test]
+async fn request_header_rules_are_applied() {
+    let upstream = echo_headers(&["x-internal-service", "x-api-version", "x-debug"]).await;
+    let proxy = proxy(config_with(
+        &upstream,
+        r#"
+        request_headers = [
+            { op = "set", name = "x-internal-service", value = "proxy" },
+            { op = "set", name = "x-api-version", value = "2" },
+            { op = "remove", name = "x-debug" },
+        ]
+        "#,
+    ));
+
+    let mut req = get("/");
+    req.headers_mut().insert("x-debug", "1".parse().unwrap());
+    // Set rules override what the client sent.
+    req.headers_mut().insert("x-internal-service", "spoofed".parse().unwrap());
+    let resp = proxy.handle(req, client_addr()).await;
+    assert_eq!(body_string(resp).await, "proxy\n2\n-");
+}
+
+#[tokio::test]
+async fn redirects_to_the_upstream_point_back_at_the_proxy() {
+    // Redirects to wherever the `to` query parameter says, with `{self}`
+    // standing for the upstream's own address.
+    let upstream = upstream(|req: Request<Body>| async move {
+        let query = req.uri().query().unwrap_or("");
+        let to = query.strip_prefix("to=").unwrap_or("/");
+        let host = req.headers()[HOST].to_str().unwrap();
+        Response::builder()
+            .status(302)
+            .header(LOCATION, to.replace("{self}", &format!("http://{}", host)))
+            .body(Body::empty())
+            .unwrap()
+    })
+    .await;
+    let proxy = proxy(ProxyConfig {
+        public_url: Some("https://api.example.com".to_string()),
+        ..config(&upstream)
+    });
+    let location = |to: &'static str| {
+        let proxy = proxy.clone();
+        async move {
+            let resp = proxy.handle(get(&format!("/go?to={}", to)), client_addr()).await;
+            assert_eq!(resp.status(), 302);
+            resp.headers()[LOCATION].to_str().unwrap().to_string()
+        }
+    };
+
+    assert_eq!(location("{self}/login?next=/a").await, "https://api.example.com/login?next=/a");
+    assert_eq!(location("{self}").await, "https://api.example.com");
+    // Relative and external redirects are left alone.
+    assert_eq!(location("/login").await, "/login");
+    assert_eq!(location("https://sso.example.org/auth").await, "https://sso.example.org/auth");
+}
+
+// Answers every request with `head` (status line and headers) and `ok`.
+async fn raw_upstream(head: &'static [u8]) -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut buf = [0; 4096];
+                let _ = stream.read(&mut buf).await;
+                let _ = stream.write_all(head).await;
+                let _ = stream.write_all(b"content-length: 2\r\nconnection: close\r\n\r\nok").await;
+            });
+        }
+    });
+    url
+}
+
+#[tokio::test]
+async fn odd_but_legal_upstream_headers_pass_through() {
+    // Non-UTF-8 (obs-text) values in headers the proxy looks at, an empty
+    // value and a repeated Cache-Control.
+    let upstream = raw_upstream(
+        b"HTTP/1.1 200 OK\r\n\
+          location: http://backend/caf\xe9\r\n\
+          set-cookie: name=caf\xe9; Domain=internal\r\n\
+          content-type: text/plain; charset=\xff\r\n\
+          vary: x-caf\xe9\r\n\
+          cache-control: public\r\n\
+          cache-control: max-age=\xe960\r\n\
+          x-empty: \r\n",
+    )
+    .await;
+    let proxy = proxy(ProxyConfig {
+        public_url: Some("https://api.example.com".to_string()),
+        enable_compression: true,
+        compression_min_bytes: 0,
+        cache_max_entries: Some(10),
+        ..config_with(
+            &upstream,
+            r#"
+            [[response_headers]]
+            op = "replace"
+            name = "set-cookie"
+            from = "Domain=internal"
+            to = "Domain=example.com"
+            "#,
+        )
+    });
+
+    for _ in 0..2 {
+        let mut req = get("/");
+        req.headers_mut().insert("accept-encoding", "gzip".parse().unwrap());
+        let resp = proxy.handle(req, client_addr()).await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.headers()[LOCATION].as_bytes(), b"http://backend/caf\xe9");
+        assert_eq!(resp.headers()["set-cookie"].as_bytes(), b"name=caf\xe9; Domain=internal");
+        assert_eq!(resp.headers()["x-empty"], "");
+    }
+}
+
+#[tokio::test]
+async fn echo_mode_describes_the_rewritten_request() {
+    // Nothing listens upstream; echo mode never goes there.
+    let proxy = proxy(ProxyConfig {
+        echo_mode: true,
+        upstream_path_prefix: Some("/v1".to_string()),
+        ..config_with(
+            "http://127.0.0.1:1",
+            r#"
+            request_headers = [{ op = "set", name = "x-internal-service", value = "proxy" }]
+            "#,
+        )
+    });
+
+    let mut req = common::request(hyper::Method::POST, "/orders?id=7", "0123456789");
+    req.headers_mut().insert("x-forwarded-for", "203.0.113.7".parse().unwrap());
+    req.headers_mut().insert("connection", "keep-alive".parse().unwrap());
+    let resp = proxy.handle(req, client_addr()).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers()["content-type"], "application/json");
+    let echo: serde_json::Value = serde_json::from_str(&body_string(resp).await).unwrap();
+
+    assert_eq!(echo["method"], "POST");
+    assert_eq!(echo["uri"], "http://127.0.0.1:1/v1/orders?id=7");
+    assert_eq!(echo["body_bytes"], 10);
+    let headers = &echo["headers"];
+    assert_eq!(headers["host"], serde_json::json!(["127.0.0.1:1"]));
+    assert_eq!(headers["x-forwarded-for"], serde_json::json!(["203.0.113.7, 127.0.0.1"]));
+    assert_eq!(headers["x-internal-service"], serde_json::json!(["proxy"]));
+    // Hop-by-hop headers and the client's credentials are gone.
+    assert!(headers.get("connection").is_none(), "{}", headers);
+    assert!(headers.get("authorization").is_none(), "{}", headers);
+}
@@ -0,0 +1,227 @@
+// Limits on what clients may send.
+
+mod common;
+
+use common::{client_addr, config, get, proxy, request, upstream};
+use futures_util::stream;
+use hyper::body::Bytes;
+use hyper::header::{HeaderName, HeaderValue, RETRY_AFTER};
+use hyper::{Body, Method, Request, Response};
+use simple_proxy::ProxyConfig;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, Semaphore};
+
+// Reads the whole request body before answering.
+async fn draining_upstream() -> String {
+    upstream(|req: Request<Body>| async move {
+        match hyper::body::to_bytes(req.into_body()).await {
+            Ok(body) => Response::new(Body::from(body.len().to_string())),
+            Err(_) => Response::new(Body::empty()),
+        }
+    })
+    .await
+}
+
+#[tokio::test]
+async fn oversized_body_gets_413() {
+    let upstream = draining_upstream().await;
+    let proxy = proxy(ProxyConfig {
+        max_body_bytes: Some(1024),
+        ..config(&upstream)
+    });
+
+    // Declared too large: refused without reading it.
+    let resp = proxy.handle(request(Method::POST, "/", vec![0; 2048]), client_addr()).await;
+    assert_eq!(resp.status(), 413);
+
+    // Streamed without a length: cut off once it passes the limit.
+    let chunks = (0..4).map(|_| Ok::<_, std::io::Error>(Bytes::from(vec![0; 512])));
+    let body = Body::wrap_stream(stream::iter(chunks));
+    let resp = proxy.handle(request(Method::POST, "/", body), client_addr()).await;
+    assert_eq!(resp.status(), 413);
+
+    let resp = proxy.handle(request(Method::POST, "/", vec![0; 1024]), client_addr()).await;
+    assert_eq!(resp.status(), 200);
+}
+
+#[tokio::test]
+async fn too_many_or_too_large_headers_get_431() {
+    let upstream = draining_upstream().await;
+    let proxy = proxy(ProxyConfig {
+        max_headers: Some(10),
+        max_header_bytes: Some(1024),
+        ..config(&upstream)
+    });
+    let with_headers = |count: usize, value: &str| {
+        let mut req = get("/");
+        for i in 0..count {
+            let name = HeaderName::try_from(format!("x-extra-{}", i)).unwrap();
+            req.headers_mut().insert(name, HeaderValue::from_str(value).unwrap());
+        }
+        req
+    };
+
+    let resp = proxy.handle(with_headers(5, "small"), client_addr()).await;
+    assert_eq!(resp.status(), 200);
+    let resp = proxy.handle(with_headers(20, "small"), client_addr()).await;
+    assert_eq!(resp.status(), 431);
+    let resp = proxy.handle(with_headers(2, &"x".repeat(2048)), client_addr()).await;
+    assert_eq!(resp.status(), 431);
+}
+
+#[tokio::test]
+async fn slow_body_gets_408() {
+    let upstream = draining_upstream().await;
+    let proxy = proxy(ProxyConfig {
+        request_body_timeout_ms: Some(100),
+        ..config(&upstream)
+    });
+
+    // The first chunk arrives at once, the rest never does.
+    let (mut sender, body) = Body::channel();
+    let trickle = tokio::spawn(async move {
+        sender.send_data(Bytes::from("first")).await.unwrap();
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        drop(sender);
+    });
+    let started = Instant::now();
+    let resp = proxy.handle(request(Method::POST, "/", body), client_addr()).await;
+    assert_eq!(resp.status(), 408);
+    assert!(started.elapsed() < Duration::from_secs(2));
+    trickle.abort();
+
+    let resp = proxy.handle(request(Method::POST, "/", "prompt"), client_addr()).await;
+    assert_eq!(resp.status(), 200);
+}
+
+#[tokio::test]
+async fn burst_over_rate_limit_gets_429() {
+    let upstream = upstream(|_: Request<Body>| async { Response::new(Body::empty()) }).await;
+    let proxy = proxy(ProxyConfig {
+        rate_limit_rps: Some(1.0),
+        rate_limit_burst: Some(2),
+        ..config(&upstream)
+    });
+    let mut statuses = Vec::new();
+    for _ in 0..4 {
+        statuses.push(proxy.handle(get("/"), client_addr()).await.status().as_u16());
+    }
+    assert_eq!(statuses, [200, 200, 429, 429]);
+    let resp = proxy.handle(get("/"), client_addr()).await;
+    assert_eq!(resp.headers()[RETRY_AFTER], "1");
+
+    // Health probes aren't counted or limited.
+    let req = Request::get("/healthz").body(Body::empty()).unwrap();
+    assert_eq!(proxy.handle(req, client_addr()).await.status(), 200);
+    // Other clients have their own buckets.
+    let other = ([127, 0, 0, 2], 40000).into();
+    assert_eq!(proxy.handle(get("/"), other).await.status(), 200);
+}
+
+// Requests reaching the upstream report in on the channel, then hang until
+// a permit is added to the semaphore.
+async fn gated_upstream() -> (String, mpsc::UnboundedReceiver<()>, Arc<Semaphore>) {
+    let (arrived_tx, arrived) = mpsc::unbounded_channel();
+    let release = Arc::new(Semaphore::new(0));
+    let gate = release.clone();
+    let upstream = upstream(move |_: Request<Body>| {
+        let (arrived, gate) = (arrived_tx.clone(), gate.clone());
+        async move {
+            arrived.send(()).unwrap();
+            gate.acquire().await.unwrap().forget();
+            Response::new(Body::from("ok"))
+        }
+    })
+    .await;
+    (upstream, arrived, release)
+}
+
+#[tokio::test]
+async fn saturated_upstream_sheds_with_503() {
+    let (upstream, mut arrived, release) = gated_upstream().await;
+    let proxy = proxy(ProxyConfig {
+        max_outstanding_upstream: Some(2),
+        ..config(&upstream)
+    });
+
+    let outstanding: Vec<_> = (0..2)
+        .map(|_| {
+            let proxy = proxy.clone();
+            tokio::spawn(async move { proxy.handle(get("/"), client_addr()).await.status() })
+        })
+        .collect();
+    arrived.recv().await.unwrap();
+    arrived.recv().await.unwrap();
+
+    // Over the threshold: shed without reaching the upstream.
+    for _ in 0..3 {
+        assert_eq!(proxy.handle(get("/"), client_addr()).await.status(), 503);
+    }
+    assert!(arrived.try_recv().is_err());
+
+    release.add_permits(2);
+    for request in outstanding {
+        assert_eq!(request.await.unwrap(), 200);
+    }
+    release.add_permits(1);
+    assert_eq!(proxy.handle(get("/"), client_addr()).await.status(), 200);
+}
+
+#[tokio::test]
+async fn concurrency_cap_gives_503_while_slots_are_held() {
+    let (upstream, mut arrived, release) = gated_upstream().await;
+    let proxy = proxy(ProxyConfig {
+        max_concurrent_requests: Some(2),
+        ..config(&upstream)
+    });
+
+    let held: Vec<_> = (0..2)
+        .map(|_| {
+            let proxy = proxy.clone();
+            tokio::spawn(async move { proxy.handle(get("/"), client_addr()).await.status() })
+        })
+        .collect();
+    arrived.recv().await.unwrap();
+    arrived.recv().await.unwrap();
+
+    assert_eq!(proxy.handle(get("/"), client_addr()).await.status(), 503);
+    assert!(arrived.try_recv().is_err());
+    // Health checks don't need a slot.
+    let health = Request::get("/healthz").body(Body::empty()).unwrap();
+    assert_eq!(proxy.handle(health, client_addr()).await.status(), 200);
+
+    // Slots come back once the responses are done.
+    release.add_permits(2);
+    for request in held {
+        assert_eq!(request.await.unwrap(), 200);
+    }
+    release.add_permits(1);
+    assert_eq!(proxy.handle(get("/"), client_addr()).await.status(), 200);
+}
+
+#[tokio::test]
+async fn queued_request_waits_for_a_slot() {
+    let (upstream, mut arrived, release) = gated_upstream().await;
+    let proxy = proxy(ProxyConfig {
+        max_concurrent_requests: Some(1),
+        concurrency_queue_ms: Some(5_000),
+        ..config(&upstream)
+    });
+
+    let first = tokio::spawn({
+        let proxy = proxy.clone();
+        async move { proxy.handle(get("/"), client_addr()).await.status() }
+    });
+    arrived.recv().await.unwrap();
+    let queued = tokio::spawn({
+        let proxy = proxy.clone();
+        async move { proxy.handle(get("/"), client_addr()).await.status() }
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(arrived.try_recv().is_err(), "queued request reached the upstream early");
+
+    release.add_permits(2);
+    assert_eq!(first.await.unwrap(), 200);
+    assert_eq!(queued.await.unwrap(), 200);
+}
@@ -0,0 +1,112 @@
+// Shadow traffic: copies of requests sent to a mirror upstream.
+
+mod common;
+
+use common::{body_string, client_addr, config, proxy, request, upstream};
+use hyper::header::AUTHORIZATION;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use simple_proxy::ProxyConfig;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+// What an upstream saw of a request: path, Authorization and body.
+type Seen = (String, Option<String>, String);
+
+// An upstream answering `name` and reporting each request it gets.
+async fn recording_upstream(name: &'static str) -> (String, mpsc::UnboundedReceiver<Seen>) {
+    let (seen_tx, seen) = mpsc::unbounded_channel();
+    let url = upstream(move |req: Request<Body>| {
+        let seen_tx = seen_tx.clone();
+        async move {
+            let path = req.uri().path().to_string();
+            let authorization = req.headers().get(AUTHORIZATION).map(|v| v.to_str().unwrap().to_string());
+            let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+            let _ = seen_tx.send((path, authorization, String::from_utf8(body.to_vec()).unwrap()));
+            Response::new(Body::from(name))
+        }
+    })
+    .await;
+    (url, seen)
+}
+
+// Collect what arrives within a short wait.
+async fn drain(seen: &mut mpsc::UnboundedReceiver<Seen>) -> Vec<Seen> {
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let mut all = Vec::new();
+    while let Ok(next) = seen.try_recv() {
+        all.push(next);
+    }
+    all
+}
+
+#[tokio::test]
+async fn mirror_gets_a_copy_without_credentials() {
+    let (primary, mut primary_seen) = recording_upstream("primary").await;
+    let (mirror, mut mirror_seen) = recording_upstream("mirror").await;
+    let proxy = proxy(ProxyConfig {
+        mirror_url: Some(mirror),
+        upstream_auth_header: Some("Bearer upstream-only".to_string()),
+        ..config(&primary)
+    });
+
+    let resp = proxy.handle(request(Method::POST, "/orders", "payload"), client_addr()).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(body_string(resp).await, "primary");
+
+    let expected = |authorization: Option<&str>| {
+        vec![("/orders".to_string(), authorization.map(str::to_string), "payload".to_string())]
+    };
+    assert_eq!(drain(&mut primary_seen).await, expected(Some("Bearer upstream-only")));
+    assert_eq!(drain(&mut mirror_seen).await, expected(None));
+}
+
+#[tokio::test]
+async fn only_the_sampled_share_is_mirrored() {
+    const REQUESTS: usize = 200;
+    let (primary, _primary_seen) = recording_upstream("primary").await;
+    for (percent, range) in [(0.0, 0..=0), (100.0, REQUESTS..=REQUESTS), (25.0, 25..=75)] {
+        let (mirror, mut mirror_seen) = recording_upstream("mirror").await;
+        let proxy = proxy(ProxyConfig {
+            mirror_url: Some(mirror),
+            mirror_percent: percent,
+            ..config(&primary)
+        });
+        for _ in 0..REQUESTS {
+            let resp = proxy.handle(request(Method::GET, "/", Body::empty()), client_addr()).await;
+            assert_eq!(body_string(resp).await, "primary");
+        }
+        let mirrored = drain(&mut mirror_seen).await.len();
+        assert!(range.contains(&mirrored), "{}% mirrored {} of {}", percent, mirrored, REQUESTS);
+    }
+}
+
+#[tokio::test]
+async fn failing_mirror_leaves_the_primary_alone() {
+    let (primary, _primary_seen) = recording_upstream("primary").await;
+    let erroring = upstream(|_: Request<Body>| async {
+        let mut resp = Response::new(Body::from("mirror broke"));
+        *resp.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+        resp
+    })
+    .await;
+    let hanging = upstream(|_: Request<Body>| async {
+        tokio::time::sleep(Duration::from_secs(30)).await;
+        Response::new(Body::empty())
+    })
+    .await;
+
+    for mirror in ["http://127.0.0.1:1".to_string(), erroring, hanging] {
+        let proxy = proxy(ProxyConfig {
+            mirror_url: Some(mirror.clone()),
+            ..config(&primary)
+        });
+        let resp = tokio::time::timeout(
+            Duration::from_secs(5),
+            proxy.handle(request(Method::POST, "/", "data"), client_addr()),
+        )
+        .await
+        .expect("primary answered");
+        assert_eq!(resp.status(), 200, "{}", mirror);
+        assert_eq!(body_string(resp).await, "primary");
+    }
+}
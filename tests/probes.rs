@@ -0,0 +1,77 @@
+// Endpoints the proxy answers itself.
+
+mod common;
+
+use common::{body_string, client_addr, config, get, proxy, upstream};
+use hyper::header::AUTHORIZATION;
+use hyper::{Body, Method, Request, Response};
+use simple_proxy::ProxyConfig;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+// Nothing listens here, so any attempt to reach it fails.
+const DEAD_UPSTREAM: &str = "http://127.0.0.1:1";
+
+#[tokio::test]
+async fn healthz_needs_no_auth_or_upstream() {
+    let proxy = proxy(config(DEAD_UPSTREAM));
+    let req = Request::get("/healthz").body(Body::empty()).unwrap();
+    let resp = proxy.handle(req, client_addr()).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(body_string(resp).await, r#"{"status":"ok"}"#);
+
+    let req = Request::get("/other").body(Body::empty()).unwrap();
+    assert_eq!(proxy.handle(req, client_addr()).await.status(), 401);
+}
+
+#[tokio::test]
+async fn version_reports_the_build() {
+    let proxy = proxy(config(DEAD_UPSTREAM));
+    let req = Request::get("/version").body(Body::empty()).unwrap();
+    let resp = proxy.handle(req, client_addr()).await;
+    assert_eq!(resp.status(), 200);
+    let info: serde_json::Value = serde_json::from_str(&body_string(resp).await).unwrap();
+    assert_eq!(info["version"], env!("CARGO_PKG_VERSION"));
+    assert_eq!(info["git_hash"], env!("GIT_HASH"));
+    assert!(info["build_time"].is_string());
+}
+
+#[tokio::test]
+async fn maintenance_mode_keeps_traffic_off_the_upstream() {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let counter = calls.clone();
+    let upstream = upstream(move |_: Request<Body>| {
+        counter.fetch_add(1, Ordering::SeqCst);
+        async { Response::new(Body::from("ok")) }
+    })
+    .await;
+    let proxy = proxy(ProxyConfig {
+        maintenance_mode: true,
+        admin_enabled: true,
+        admin_token: Some("admin-secret".to_string()),
+        ..config(&upstream)
+    });
+    let admin = |method: Method| {
+        Request::builder()
+            .method(method)
+            .uri("/admin/maintenance")
+            .header(AUTHORIZATION, "Bearer admin-secret")
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    let resp = proxy.handle(get("/"), client_addr()).await;
+    assert_eq!(resp.status(), 503);
+    assert_eq!(body_string(resp).await, "Service Unavailable: down for maintenance");
+    let req = Request::get("/healthz").body(Body::empty()).unwrap();
+    assert_eq!(proxy.handle(req, client_addr()).await.status(), 200);
+    assert_eq!(calls.load(Ordering::SeqCst), 0);
+
+    // Switched off through the admin endpoint, and back on in code.
+    assert!(proxy.handle(admin(Method::DELETE), client_addr()).await.status().is_success());
+    assert_eq!(proxy.handle(get("/"), client_addr()).await.status(), 200);
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+    proxy.set_maintenance(true);
+    assert_eq!(proxy.handle(get("/"), client_addr()).await.status(), 503);
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+}
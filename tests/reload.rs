@@ -0,0 +1,127 @@
+// The binary: its command line, and reloading its auth settings on SIGHUP.
+
+mod common;
+
+use common::upstream;
+use hyper::header::AUTHORIZATION;
+use hyper::{Body, Client, Request, Response, StatusCode};
+use std::fs;
+use std::net::{SocketAddr, TcpListener};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+// A local address nothing is listening on.
+fn free_addr() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+}
+
+// The proxy binary, killed when dropped so a failed test leaves nothing
+// running.
+struct Running(Child);
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn write_config(path: &Path, upstream: &str, addr: SocketAddr, token: &str) {
+    let config = format!(
+        "upstream_url = \"{}\"\nbind_addr = \"{}\"\nmetrics_addr = \"{}\"\nauth_token = \"{}\"\n",
+        upstream,
+        addr,
+        free_addr(),
+        token
+    );
+    fs::write(path, config).unwrap();
+}
+
+async fn status(addr: SocketAddr, path: &str, token: &str) -> Option<StatusCode> {
+    let req = Request::get(format!("http://{}{}", addr, path))
+        .header(AUTHORIZATION, format!("Bearer {}", token))
+        .body(Body::empty())
+        .unwrap();
+    Client::new().request(req).await.ok().map(|resp| resp.status())
+}
+
+// Poll until a request with `token` gets `expected`.
+async fn wait_for(addr: SocketAddr, token: &str, expected: StatusCode) {
+    for _ in 0..100 {
+        if status(addr, "/", token).await == Some(expected) {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("{} never got {}", token, expected);
+}
+
+#[tokio::test]
+async fn sighup_swaps_the_token_without_dropping_requests() {
+    let upstream = upstream(|req: Request<Body>| async move {
+        if req.uri().path() == "/slow" {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+        }
+        Response::new(Body::from("ok"))
+    })
+    .await;
+    let addr = free_addr();
+    let config = std::env::temp_dir().join(format!("simple-proxy-reload-{}.toml", std::process::id()));
+    write_config(&config, &upstream, addr, "old-token");
+    let child = Command::new(env!("CARGO_BIN_EXE_simple_proxy"))
+        .env_clear()
+        .env("CONFIG_FILE", &config)
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    let proxy = Running(child);
+    wait_for(addr, "old-token", StatusCode::OK).await;
+    assert_eq!(status(addr, "/", "new-token").await, Some(StatusCode::UNAUTHORIZED));
+
+    // A request already in flight finishes under the old settings.
+    let in_flight = tokio::spawn(async move { status(addr, "/slow", "old-token").await });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    write_config(&config, &upstream, addr, "new-token");
+    let hup = Command::new("kill").arg("-HUP").arg(proxy.0.id().to_string()).status().unwrap();
+    assert!(hup.success());
+
+    wait_for(addr, "new-token", StatusCode::OK).await;
+    assert_eq!(status(addr, "/", "old-token").await, Some(StatusCode::UNAUTHORIZED));
+    assert_eq!(in_flight.await.unwrap(), Some(StatusCode::OK));
+    fs::remove_file(&config).unwrap();
+}
+
+#[tokio::test]
+async fn file_from_the_command_line_is_reloaded() {
+    let upstream = upstream(|_: Request<Body>| async { Response::new(Body::from("ok")) }).await;
+    let addr = free_addr();
+    let config = std::env::temp_dir().join(format!("simple-proxy-reload-arg-{}.toml", std::process::id()));
+    write_config(&config, &upstream, addr, "old-token");
+    let child = Command::new(env!("CARGO_BIN_EXE_simple_proxy"))
+        .env_clear()
+        .arg("--config")
+        .arg(&config)
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    let proxy = Running(child);
+    wait_for(addr, "old-token", StatusCode::OK).await;
+
+    write_config(&config, &upstream, addr, "new-token");
+    let hup = Command::new("kill").arg("-HUP").arg(proxy.0.id().to_string()).status().unwrap();
+    assert!(hup.success());
+    wait_for(addr, "new-token", StatusCode::OK).await;
+    fs::remove_file(&config).unwrap();
+}
+
+#[test]
+fn unknown_argument_stops_the_binary() {
+    let status = Command::new(env!("CARGO_BIN_EXE_simple_proxy"))
+        .env_clear()
+        .arg("--verbose")
+        .stdout(Stdio::null())
+        .status()
+        .unwrap();
+    assert!(!status.success());
+}
@@ -0,0 +1,296 @@
+// Slow and failing upstreams: timeouts and retries.
+
+mod common;
+
+use common::{body_string, client_addr, config, get, proxy, request, upstream};
+use hyper::header::CONTENT_LENGTH;
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, Method, Request, Response};
+use simple_proxy::{Proxy, ProxyConfig};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+
+// An upstream that drops its first `failures` connections once the request
+// has arrived, then answers `ok`. Also returns the number of connections.
+async fn flaky_upstream(failures: usize) -> (String, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let connections = Arc::new(AtomicUsize::new(0));
+    let count = connections.clone();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let n = count.fetch_add(1, Ordering::SeqCst);
+            let mut buf = [0; 4096];
+            let _ = stream.read(&mut buf).await;
+            if n >= failures {
+                let resp = b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\nconnection: close\r\n\r\nok";
+                let _ = stream.write_all(resp).await;
+            }
+        }
+    });
+    (url, connections)
+}
+
+#[tokio::test]
+async fn slow_upstream_times_out_with_504() {
+    let upstream = upstream(|_: Request<Body>| async {
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        Response::new(Body::from("late"))
+    })
+    .await;
+    let proxy = proxy(ProxyConfig {
+        upstream_timeout_ms: 100,
+        ..config(&upstream)
+    });
+    let resp = proxy.handle(get("/"), client_addr()).await;
+    assert_eq!(resp.status(), 504);
+}
+
+#[tokio::test]
+async fn get_is_retried_after_a_dropped_connection() {
+    let (upstream, connections) = flaky_upstream(1).await;
+    let proxy = proxy(ProxyConfig {
+        upstream_max_retries: 2,
+        upstream_retry_backoff_ms: 1,
+        ..config(&upstream)
+    });
+    let resp = proxy.handle(get("/"), client_addr()).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(body_string(resp).await, "ok");
+    assert_eq!(connections.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn post_is_never_retried() {
+    let (upstream, connections) = flaky_upstream(1).await;
+    let proxy = proxy(ProxyConfig {
+        upstream_max_retries: 2,
+        upstream_retry_backoff_ms: 1,
+        ..config(&upstream)
+    });
+    let resp = proxy.handle(request(Method::POST, "/", "data"), client_addr()).await;
+    assert_eq!(resp.status(), 502);
+    assert_eq!(connections.load(Ordering::SeqCst), 1);
+}
+
+// Retrying, with bodies up to 1 KiB buffered for a replay.
+fn buffering_proxy(upstream: &str) -> Proxy {
+    proxy(ProxyConfig {
+        upstream_max_retries: 2,
+        upstream_retry_backoff_ms: 1,
+        retry_buffer_bytes: Some(1024),
+        ..config(upstream)
+    })
+}
+
+// A POST of `size` bytes, declaring its length as clients do.
+fn post_of(size: usize) -> Request<Body> {
+    let mut req = request(Method::POST, "/", vec![7; size]);
+    req.headers_mut().insert(CONTENT_LENGTH, size.into());
+    req
+}
+
+#[tokio::test]
+async fn small_post_is_buffered_and_retried() {
+    let (upstream, connections) = flaky_upstream(1).await;
+    let resp = buffering_proxy(&upstream)
+        .handle(post_of(512), client_addr())
+        .await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(connections.load(Ordering::SeqCst), 2);
+
+    // Over the buffer size, with no spooling, the body isn't kept for a
+    // replay.
+    let (upstream, connections) = flaky_upstream(1).await;
+    let resp = buffering_proxy(&upstream)
+        .handle(post_of(4096), client_addr())
+        .await;
+    assert_eq!(resp.status(), 502);
+    assert_eq!(connections.load(Ordering::SeqCst), 1);
+}
+
+// Drops its first connection partway through the request body. Later ones
+// are answered with the number of body bytes received.
+async fn upload_dropping_upstream() -> (String, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let connections = Arc::new(AtomicUsize::new(0));
+    let count = connections.clone();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            if count.fetch_add(1, Ordering::SeqCst) == 0 {
+                let mut buf = [0; 64 * 1024];
+                let _ = stream.read(&mut buf).await;
+                continue;
+            }
+            let count_body = service_fn(|req: Request<Body>| async move {
+                let body = hyper::body::to_bytes(req.into_body()).await?;
+                Ok::<_, hyper::Error>(Response::new(Body::from(body.len().to_string())))
+            });
+            tokio::spawn(Http::new().serve_connection(stream, count_body));
+        }
+    });
+    (url, connections)
+}
+
+// Remove the spool directory `dir` once the proxy has deleted every file in
+// it, which happens in the background shortly after a request is done.
+async fn remove_emptied(dir: &Path) {
+    for _ in 0..100 {
+        if std::fs::read_dir(dir).unwrap().count() == 0 {
+            std::fs::remove_dir(dir).unwrap();
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("spool files left in {}", dir.display());
+}
+
+#[tokio::test]
+async fn spooled_post_is_replayed_after_a_dropped_connection() {
+    const SIZE: usize = 4 * 1024 * 1024;
+    let (upstream, connections) = upload_dropping_upstream().await;
+    let spool_dir = std::env::temp_dir().join(format!("simple-proxy-spool-{}", std::process::id()));
+    std::fs::create_dir_all(&spool_dir).unwrap();
+    let proxy = proxy(ProxyConfig {
+        upstream_max_retries: 2,
+        upstream_retry_backoff_ms: 1,
+        retry_buffer_bytes: Some(1024),
+        max_spool_bytes: Some(2 * SIZE as u64),
+        spool_dir: Some(spool_dir.clone()),
+        ..config(&upstream)
+    });
+
+    let resp = proxy.handle(request(Method::POST, "/", vec![7; SIZE]), client_addr()).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(body_string(resp).await, SIZE.to_string());
+    assert_eq!(connections.load(Ordering::SeqCst), 2);
+    // The spool file is gone once the request is done.
+    remove_emptied(&spool_dir).await;
+}
+
+// A listener whose accept queue is full and never drained, so new
+// connections to it hang in the handshake. Returns its URL and the listener
+// and queued connections, which must be kept alive.
+async fn unaccepting_upstream() -> (String, TcpListener, Vec<TcpStream>) {
+    let socket = TcpSocket::new_v4().unwrap();
+    socket.bind(([127, 0, 0, 1], 0).into()).unwrap();
+    let listener = socket.listen(1).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut queued = Vec::new();
+    let connect = || tokio::time::timeout(Duration::from_millis(200), TcpStream::connect(addr));
+    while let Ok(Ok(stream)) = connect().await {
+        queued.push(stream);
+    }
+    (format!("http://{}", addr), listener, queued)
+}
+
+#[tokio::test]
+async fn connect_timeout_fails_fast() {
+    let (upstream, _listener, _queued) = unaccepting_upstream().await;
+    let proxy = proxy(ProxyConfig {
+        connect_timeout_ms: Some(300),
+        upstream_timeout_ms: 30_000,
+        upstream_max_retries: 0,
+        ..config(&upstream)
+    });
+    let started = Instant::now();
+    let resp = proxy.handle(get("/"), client_addr()).await;
+    let elapsed = started.elapsed();
+    assert!(elapsed >= Duration::from_millis(300) && elapsed < Duration::from_secs(5), "took {:?}", elapsed);
+    assert_eq!(resp.status(), 502);
+}
+
+#[tokio::test]
+async fn body_over_the_spool_limit_is_not_replayed() {
+    const SIZE: usize = 64 * 1024;
+    let spool_dir = std::env::temp_dir().join(format!("simple-proxy-spool-limit-{}", std::process::id()));
+    std::fs::create_dir_all(&spool_dir).unwrap();
+    let spooling = |upstream: &str| {
+        proxy(ProxyConfig {
+            upstream_max_retries: 2,
+            upstream_retry_backoff_ms: 1,
+            max_spool_bytes: Some(SIZE as u64 / 2),
+            spool_dir: Some(spool_dir.clone()),
+            ..config(upstream)
+        })
+    };
+
+    let (upstream, connections) = upload_dropping_upstream().await;
+    let resp = spooling(&upstream).handle(post_of(SIZE), client_addr()).await;
+    assert_eq!(resp.status(), 502);
+    assert_eq!(connections.load(Ordering::SeqCst), 1);
+
+    // Without a declared length, the part spooled before it passed the
+    // limit goes out first, then the rest streams; nothing is replayed.
+    let (upstream, connections) = upload_dropping_upstream().await;
+    let req = request(Method::POST, "/", vec![7; SIZE]);
+    let resp = spooling(&upstream).handle(req, client_addr()).await;
+    assert_eq!(resp.status(), 502);
+    assert_eq!(connections.load(Ordering::SeqCst), 1);
+    remove_emptied(&spool_dir).await;
+}
+
+#[tokio::test]
+async fn client_abort_does_not_trip_the_breaker() {
+    let upstream = upstream(|req: Request<Body>| async move {
+        let _ = hyper::body::to_bytes(req.into_body()).await;
+        Response::new(Body::from("ok"))
+    })
+    .await;
+    let proxy = proxy(ProxyConfig {
+        cb_failure_threshold: Some(1),
+        cb_open_secs: 60,
+        ..config(&upstream)
+    });
+    let addr = common::serve(proxy.clone()).await;
+
+    // Promise a body, send part of it and hang up while it streams upstream.
+    for _ in 0..3 {
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let head = format!(
+            "POST /upload HTTP/1.1\r\nhost: proxy\r\nauthorization: Bearer {}\r\n\
+             content-length: 100000\r\n\r\n",
+            common::TOKEN
+        );
+        client.write_all(head.as_bytes()).await.unwrap();
+        client.write_all(&[7; 1000]).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        drop(client);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    let resp = proxy.handle(get("/"), client_addr()).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(body_string(resp).await, "ok");
+}
+
+#[tokio::test]
+async fn retry_budget_runs_dry_under_sustained_failures() {
+    let (upstream, connections) = flaky_upstream(usize::MAX).await;
+    let proxy = proxy(ProxyConfig {
+        upstream_max_retries: 2,
+        upstream_retry_backoff_ms: 1,
+        retry_budget_ratio: Some(0.2),
+        ..config(&upstream)
+    });
+    for _ in 0..20 {
+        let resp = proxy.handle(get("/"), client_addr()).await;
+        assert_eq!(resp.status(), 502);
+    }
+    // Without a budget that would have been 60 attempts; the reserve covers
+    // a few retries, then failures stop being retried.
+    let attempts = connections.load(Ordering::SeqCst);
+    assert!(attempts > 20 && attempts < 40, "{} attempts", attempts);
+
+    let resp = proxy.handle(get("/"), client_addr()).await;
+    assert_eq!(resp.status(), 502);
+    assert_eq!(connections.load(Ordering::SeqCst), attempts + 1);
+}
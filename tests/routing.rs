@@ -0,0 +1,379 @@
+// Choosing the upstream for each request.
+
+mod common;
+
+use common::{body_string, client_addr, config, get, proxy, upstream, TOKEN};
+use hyper::header::{CACHE_CONTROL, CONTENT_TYPE, COOKIE};
+use hyper::{Body, Request, Response, Uri};
+use simple_proxy::config::RouteConfig;
+use simple_proxy::{Proxy, ProxyConfig};
+use std::time::Duration;
+
+// Answers with its name and the path and query it was sent.
+async fn named(name: &'static str) -> String {
+    upstream(move |req: Request<Body>| async move {
+        Response::new(Body::from(format!("{} {}", name, req.uri())))
+    })
+    .await
+}
+
+fn route(prefix: &str, upstream: &str, strip_prefix: bool) -> RouteConfig {
+    RouteConfig {
+        prefix: prefix.to_string(),
+        upstream: upstream.parse().unwrap(),
+        strip_prefix,
+        auth_tokens: Vec::new(),
+        public: false,
+        timeout_ms: None,
+    }
+}
+
+async fn fetch(proxy: &Proxy, path: &str) -> (u16, String) {
+    fetch_as(proxy, path, None).await
+}
+
+// `fetch`, with an `X-Tenant` header when `tenant` is given.
+async fn fetch_as(proxy: &Proxy, path: &str, tenant: Option<&str>) -> (u16, String) {
+    let mut req = get(path);
+    if let Some(tenant) = tenant {
+        req.headers_mut().insert("x-tenant", tenant.parse().unwrap());
+    }
+    let resp = proxy.handle(req, client_addr()).await;
+    (resp.status().as_u16(), body_string(resp).await)
+}
+
+#[tokio::test]
+async fn longest_prefix_wins() {
+    let (api, api_v2, default) = (named("api").await, named("api-v2").await, named("default").await);
+    let proxy = proxy(ProxyConfig {
+        routes: vec![route("/api", &api, false), route("/api/v2", &api_v2, false)],
+        ..config(&default)
+    });
+    assert_eq!(fetch(&proxy, "/api/users").await, (200, "api /api/users".to_string()));
+    assert_eq!(fetch(&proxy, "/api/v2/users").await, (200, "api-v2 /api/v2/users".to_string()));
+    assert_eq!(fetch(&proxy, "/other").await, (200, "default /other".to_string()));
+    // Prefixes match whole path segments only.
+    assert_eq!(fetch(&proxy, "/apis").await, (200, "default /apis".to_string()));
+}
+
+#[tokio::test]
+async fn matched_prefix_is_stripped_when_asked() {
+    let (auth, api) = (named("auth").await, named("api").await);
+    let proxy = proxy(ProxyConfig {
+        auth_token: Some(TOKEN.to_string()),
+        routes: vec![route("/auth", &auth, true), route("/api", &api, false)],
+        ..ProxyConfig::default()
+    });
+    assert_eq!(fetch(&proxy, "/auth/login?next=/").await, (200, "auth /login?next=/".to_string()));
+    assert_eq!(fetch(&proxy, "/auth").await, (200, "auth /".to_string()));
+    assert_eq!(fetch(&proxy, "/api/users").await, (200, "api /api/users".to_string()));
+    // No route and no default upstream.
+    assert_eq!(fetch(&proxy, "/other").await.0, 404);
+}
+
+#[tokio::test]
+async fn selector_routes_on_tenant_header() {
+    // Cacheable, so a shared cache entry across tenants would show.
+    let cacheable = |name: &'static str| {
+        upstream(move |req: Request<Body>| async move {
+            let mut resp = Response::new(Body::from(format!("{} {}", name, req.uri())));
+            resp.headers_mut().insert(CACHE_CONTROL, "public, max-age=60".parse().unwrap());
+            resp
+        })
+    };
+    let (acme, globex) = (cacheable("acme").await, cacheable("globex").await);
+    let (default, canary) = (named("default").await, named("canary").await);
+    let tenants: Vec<(&'static str, Uri)> =
+        vec![("acme", acme.parse().unwrap()), ("globex", globex.parse().unwrap())];
+    let proxy = Proxy::builder(ProxyConfig {
+        cache_max_entries: Some(100),
+        canary_url: Some(canary.parse().unwrap()),
+        canary_percent: 100.0,
+        ..config(&default)
+    })
+    .select_upstream(move |req: &Request<Body>| {
+        let tenant = req.headers().get("x-tenant")?;
+        tenants.iter().find(|(name, _)| tenant == name).map(|(_, uri)| uri.clone())
+    })
+    .build()
+    .unwrap();
+
+    assert_eq!(fetch_as(&proxy, "/x", Some("acme")).await, (200, "acme /x".to_string()));
+    assert_eq!(fetch_as(&proxy, "/x", Some("globex")).await, (200, "globex /x".to_string()));
+    assert_eq!(fetch_as(&proxy, "/x", Some("acme")).await, (200, "acme /x".to_string()));
+    // Unknown tenants fall back to the default upstream, which alone
+    // shares its traffic with the canary.
+    assert_eq!(fetch_as(&proxy, "/x", Some("initech")).await, (200, "canary /x".to_string()));
+    assert_eq!(fetch(&proxy, "/x").await, (200, "canary /x".to_string()));
+}
+
+#[tokio::test]
+async fn requests_rotate_across_replicas() {
+    let (a, b) = (named("a").await, named("b").await);
+    let proxy = proxy(config(&format!("{},{}", a, b)));
+    let mut served = Vec::new();
+    for _ in 0..4 {
+        served.push(fetch(&proxy, "/").await.1);
+    }
+    served.sort();
+    assert_eq!(served, ["a /", "a /", "b /", "b /"]);
+}
+
+#[tokio::test]
+async fn unreachable_replica_fails_over() {
+    // Nothing listens on the second replica.
+    let a = named("a").await;
+    let proxy = proxy(config(&format!("{},http://127.0.0.1:1", a)));
+    for _ in 0..4 {
+        assert_eq!(fetch(&proxy, "/").await, (200, "a /".to_string()));
+    }
+}
+
+// Which replica answers a client at `ip`, sending `cookie` if given.
+async fn served_to(proxy: &Proxy, ip: [u8; 4], cookie: Option<&str>) -> String {
+    let mut req = get("/");
+    if let Some(cookie) = cookie {
+        req.headers_mut().insert(COOKIE, cookie.parse().unwrap());
+    }
+    let resp = proxy.handle(req, (ip, 40000).into()).await;
+    assert_eq!(resp.status(), 200);
+    body_string(resp).await
+}
+
+#[tokio::test]
+async fn sticky_sessions_pin_clients_to_a_replica() {
+    let (a, b, c) = (named("a").await, named("b").await, named("c").await);
+    let replicas = format!("{},{},{}", a, b, c);
+
+    let by_ip = proxy(ProxyConfig {
+        sticky_sessions: Some("ip".parse().unwrap()),
+        ..config(&replicas)
+    });
+    let mut seen = Vec::new();
+    for last in 1..=20 {
+        let ip = [10, 0, 0, last];
+        let first = served_to(&by_ip, ip, None).await;
+        for _ in 0..4 {
+            assert_eq!(served_to(&by_ip, ip, None).await, first);
+        }
+        seen.push(first);
+    }
+    // Different clients still spread out.
+    seen.sort();
+    seen.dedup();
+    assert!(seen.len() > 1, "{:?}", seen);
+
+    // By cookie, the client IP doesn't matter.
+    let by_cookie = proxy(ProxyConfig {
+        sticky_sessions: Some("cookie".parse().unwrap()),
+        sticky_cookie: "sid".to_string(),
+        ..config(&replicas)
+    });
+    for session in ["sid=alpha", "theme=dark; sid=beta", "sid=gamma"] {
+        let first = served_to(&by_cookie, [10, 0, 0, 1], Some(session)).await;
+        for last in 2..6 {
+            assert_eq!(served_to(&by_cookie, [10, 0, 0, last], Some(session)).await, first);
+        }
+    }
+}
+
+#[tokio::test]
+async fn sticky_client_moves_when_its_replica_is_down() {
+    // Nothing listens on the second replica; clients pinned to it go on to
+    // the live one.
+    let a = named("a").await;
+    let proxy = proxy(ProxyConfig {
+        sticky_sessions: Some("ip".parse().unwrap()),
+        ..config(&format!("{},http://127.0.0.1:1", a))
+    });
+    for last in 1..=10 {
+        assert_eq!(served_to(&proxy, [10, 0, 0, last], None).await, "a /");
+    }
+}
+
+#[tokio::test]
+async fn paths_are_normalized_before_routing() {
+    let (api, default) = (named("api").await, named("default").await);
+    let with = |normalize_path, preserve_trailing_slash| {
+        proxy(ProxyConfig {
+            normalize_path,
+            preserve_trailing_slash,
+            routes: vec![route("/api", &api, false)],
+            ..config(&default)
+        })
+    };
+
+    let proxy = with(true, false);
+    assert_eq!(fetch(&proxy, "/api//users").await, (200, "api /api/users".to_string()));
+    assert_eq!(fetch(&proxy, "/api/./v1/../users?q=a//b").await, (200, "api /api/users?q=a//b".to_string()));
+    // Resolved before routing, so this never reaches the /api upstream.
+    assert_eq!(fetch(&proxy, "/api/../etc/passwd").await, (200, "default /etc/passwd".to_string()));
+    assert_eq!(fetch(&proxy, "/api/users/").await, (200, "api /api/users".to_string()));
+    // Climbing above the root is refused.
+    assert_eq!(fetch(&proxy, "/api/../../etc/passwd").await.0, 400);
+    assert_eq!(fetch(&proxy, "/..").await.0, 400);
+
+    let proxy = with(true, true);
+    assert_eq!(fetch(&proxy, "/api//users//").await, (200, "api /api/users/".to_string()));
+
+    // Off by default.
+    let proxy = with(false, false);
+    assert_eq!(fetch(&proxy, "/api//users").await, (200, "api /api//users".to_string()));
+}
+
+#[tokio::test]
+async fn unresolved_srv_upstream_gets_503() {
+    let proxy = proxy(ProxyConfig {
+        upstream_url: None,
+        upstream_srv: Some("_http._tcp.backend.invalid".to_string()),
+        ..config("http://unused")
+    });
+    let (status, body) = fetch(&proxy, "/").await;
+    assert_eq!(status, 503);
+    assert_eq!(body, "No upstream available");
+}
+
+// A proxy sending `percent` of default-upstream traffic to a canary.
+async fn canary_proxy(percent: f64) -> Proxy {
+    let (primary, canary) = (named("primary").await, named("canary").await);
+    proxy(ProxyConfig {
+        canary_url: Some(canary.parse().unwrap()),
+        canary_percent: percent,
+        ..config(&primary)
+    })
+}
+
+// How many of `n` requests the canary served.
+async fn canary_share(proxy: &Proxy, n: usize) -> usize {
+    let mut served = 0;
+    for _ in 0..n {
+        let (status, body) = fetch(proxy, "/").await;
+        assert_eq!(status, 200);
+        if body.starts_with("canary") {
+            served += 1;
+        }
+    }
+    served
+}
+
+#[tokio::test]
+async fn canary_gets_its_share() {
+    assert_eq!(canary_share(&canary_proxy(0.0).await, 200).await, 0);
+    assert_eq!(canary_share(&canary_proxy(100.0).await, 200).await, 200);
+    // 10% of 2000 is 200, with a standard deviation near 13; six of them
+    // either way is vanishingly unlikely.
+    let served = canary_share(&canary_proxy(10.0).await, 2000).await;
+    assert!((120..=280).contains(&served), "canary served {} of 2000", served);
+}
+
+#[tokio::test]
+async fn canary_header_forces_the_canary() {
+    let proxy = canary_proxy(0.0).await;
+    for value in ["true", "TRUE", " true "] {
+        let mut req = get("/");
+        req.headers_mut().insert("x-canary", value.parse().unwrap());
+        let resp = proxy.handle(req, client_addr()).await;
+        assert_eq!(body_string(resp).await, "canary /", "{:?}", value);
+    }
+    let mut req = get("/");
+    req.headers_mut().insert("x-canary", "false".parse().unwrap());
+    let resp = proxy.handle(req, client_addr()).await;
+    assert_eq!(body_string(resp).await, "primary /");
+}
+
+#[tokio::test]
+async fn upstream_path_prefix_is_prepended() {
+    let (auth, default) = (named("auth").await, named("default").await);
+    for prefix in ["/service/v1", "/service/v1/"] {
+        let proxy = proxy(ProxyConfig {
+            upstream_path_prefix: Some(prefix.to_string()),
+            routes: vec![route("/auth", &auth, true)],
+            ..config(&default)
+        });
+        assert_eq!(fetch(&proxy, "/users").await, (200, "default /service/v1/users".to_string()));
+        let expected = "default /service/v1/users/?q=a%20b".to_string();
+        assert_eq!(fetch(&proxy, "/users/?q=a%20b").await, (200, expected));
+        assert_eq!(fetch(&proxy, "/").await, (200, "default /service/v1/".to_string()));
+        // Applied after a route strips its own prefix.
+        assert_eq!(fetch(&proxy, "/auth/login?x=1").await, (200, "auth /service/v1/login?x=1".to_string()));
+    }
+}
+
+#[tokio::test]
+async fn routes_override_the_upstream_timeout() {
+    let slow = upstream(|req: Request<Body>| async move {
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        Response::new(Body::from(format!("slow {}", req.uri())))
+    })
+    .await;
+    let with_timeout = |prefix: &str, timeout_ms: u64| RouteConfig {
+        timeout_ms: Some(timeout_ms),
+        ..route(prefix, &slow, false)
+    };
+    let proxy = proxy(ProxyConfig {
+        upstream_timeout_ms: 100,
+        routes: vec![with_timeout("/reports", 2000), with_timeout("/api", 50)],
+        ..config(&slow)
+    });
+    assert_eq!(fetch(&proxy, "/reports/daily").await, (200, "slow /reports/daily".to_string()));
+    assert_eq!(fetch(&proxy, "/api/orders").await.0, 504);
+    assert_eq!(fetch(&proxy, "/other").await.0, 504);
+}
+
+#[tokio::test]
+async fn unrouted_requests_get_the_custom_404_or_the_catch_all() {
+    let (api, catch_all) = (named("api").await, named("catch-all").await);
+    let routes_only = ProxyConfig {
+        auth_token: Some(TOKEN.to_string()),
+        routes: vec![route("/api", &api, false)],
+        no_route_body: Some("<h1>Nothing here</h1>".to_string()),
+        no_route_content_type: "text/html".to_string(),
+        ..ProxyConfig::default()
+    };
+    let proxy = proxy(routes_only);
+    let resp = proxy.handle(get("/unknown"), client_addr()).await;
+    assert_eq!(resp.status(), 404);
+    assert_eq!(resp.headers()[CONTENT_TYPE], "text/html");
+    assert_eq!(body_string(resp).await, "<h1>Nothing here</h1>");
+    assert_eq!(fetch(&proxy, "/api/users").await, (200, "api /api/users".to_string()));
+
+    let proxy = common::proxy(ProxyConfig {
+        routes: vec![route("/api", &api, false)],
+        ..config(&catch_all)
+    });
+    assert_eq!(fetch(&proxy, "/unknown").await, (200, "catch-all /unknown".to_string()));
+}
+
+#[tokio::test]
+async fn replicas_get_traffic_in_proportion_to_weight() {
+    const REQUESTS: usize = 400;
+    let (big, small, drained) = (named("big").await, named("small").await, named("drained").await);
+    let replicas = format!("{};weight=3,{};weight=1,{};weight=0", big, small, drained);
+    let proxy = proxy(ProxyConfig {
+        upstream_url: Some(replicas.parse().unwrap()),
+        ..config(&big)
+    });
+    let mut to_big = 0;
+    for _ in 0..REQUESTS {
+        let (status, body) = fetch(&proxy, "/").await;
+        assert_eq!(status, 200);
+        match body.split(' ').next().unwrap() {
+            "big" => to_big += 1,
+            "small" => {}
+            other => panic!("{} got a request", other),
+        }
+    }
+    // 300 expected; this is more than four standard deviations either way.
+    assert!((260..=340).contains(&to_big), "big got {} of {}", to_big, REQUESTS);
+
+    // A heavy replica that's down fails over to the others, never the
+    // drained one.
+    let replicas = format!("http://127.0.0.1:1;weight=3,{},{};weight=0", small, drained);
+    let proxy = common::proxy(ProxyConfig {
+        upstream_url: Some(replicas.parse().unwrap()),
+        ..config(&big)
+    });
+    for _ in 0..20 {
+        assert_eq!(fetch(&proxy, "/").await, (200, "small /".to_string()));
+    }
+}
@@ -0,0 +1,194 @@
+// Bodies stream through the proxy chunk by chunk instead of being buffered.
+
+mod common;
+
+use common::{body_string, client_addr, config, get, proxy, request, serve, upstream, TOKEN};
+use hyper::body::{Bytes, HttpBody};
+use hyper::header::HeaderMap;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server};
+use simple_proxy::ProxyConfig;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::Notify;
+use tokio::time::timeout;
+
+const CHUNK: usize = 64 * 1024;
+
+// Long enough for anything but a proxy waiting on the whole body.
+const PATIENCE: Duration = Duration::from_secs(10);
+
+#[tokio::test]
+async fn large_response_streams_through() {
+    const TOTAL: usize = 50 * 1024 * 1024;
+    // The upstream holds back everything after the first chunk until the
+    // client has seen it, which it can't if the proxy buffers the body.
+    let first_seen = Arc::new(Notify::new());
+    let notify = first_seen.clone();
+    let upstream = upstream(move |_: Request<Body>| {
+        let first_seen = notify.clone();
+        async move {
+            let (mut sender, body) = Body::channel();
+            tokio::spawn(async move {
+                sender.send_data(Bytes::from(vec![1; CHUNK])).await.unwrap();
+                first_seen.notified().await;
+                for _ in 1..TOTAL / CHUNK {
+                    sender.send_data(Bytes::from(vec![1; CHUNK])).await.unwrap();
+                }
+            });
+            Response::new(body)
+        }
+    })
+    .await;
+    let proxy = proxy(config(&upstream));
+
+    let resp = timeout(PATIENCE, proxy.handle(get("/"), client_addr())).await.unwrap();
+    assert_eq!(resp.status(), 200);
+    let mut body = resp.into_body();
+    let first = timeout(PATIENCE, body.data()).await.expect("first chunk before the rest is sent");
+    let mut received = first.unwrap().unwrap().len();
+    first_seen.notify_one();
+    while let Some(chunk) = timeout(PATIENCE, body.data()).await.unwrap() {
+        received += chunk.unwrap().len();
+    }
+    assert_eq!(received, TOTAL);
+}
+
+#[tokio::test]
+async fn large_upload_streams_through() {
+    const TOTAL: usize = 100 * 1024 * 1024;
+    // Mirror image of the above: the client holds back the rest of the
+    // upload until the upstream has seen the first chunk.
+    let first_seen = Arc::new(Notify::new());
+    let notify = first_seen.clone();
+    let upstream = upstream(move |req: Request<Body>| {
+        let first_seen = notify.clone();
+        async move {
+            let mut body = req.into_body();
+            let mut received = 0;
+            while let Some(chunk) = body.data().await {
+                received += chunk.unwrap().len();
+                first_seen.notify_one();
+            }
+            Response::new(Body::from(received.to_string()))
+        }
+    })
+    .await;
+    let proxy = proxy(config(&upstream));
+
+    let (mut sender, body) = Body::channel();
+    let upload = tokio::spawn(async move {
+        sender.send_data(Bytes::from(vec![1; CHUNK])).await.unwrap();
+        timeout(PATIENCE, first_seen.notified()).await.expect("first chunk before the rest is sent");
+        for _ in 1..TOTAL / CHUNK {
+            sender.send_data(Bytes::from(vec![1; CHUNK])).await.unwrap();
+        }
+    });
+    let resp = timeout(PATIENCE, proxy.handle(request(Method::POST, "/", body), client_addr()))
+        .await
+        .unwrap();
+    upload.await.unwrap();
+    assert_eq!(resp.status(), 200);
+    assert_eq!(body_string(resp).await, TOTAL.to_string());
+}
+
+#[tokio::test]
+async fn trailers_pass_through_over_http2() {
+    // Trailers need HTTP/2 on both hops; Hyper drops them over HTTP/1.1.
+    let make_service = make_service_fn(|_| async {
+        Ok::<_, Infallible>(service_fn(|_: Request<Body>| async {
+            let (mut sender, body) = Body::channel();
+            tokio::spawn(async move {
+                sender.send_data(Bytes::from("payload")).await.unwrap();
+                let mut trailers = HeaderMap::new();
+                trailers.insert("grpc-status", "0".parse().unwrap());
+                sender.send_trailers(trailers).await.unwrap();
+            });
+            Ok::<_, Infallible>(Response::new(body))
+        }))
+    });
+    let server = Server::bind(&([127, 0, 0, 1], 0).into()).http2_only(true).serve(make_service);
+    let upstream = format!("http://{}", server.local_addr());
+    tokio::spawn(server);
+    let proxy = proxy(ProxyConfig {
+        upstream_http2: true,
+        ..config(&upstream)
+    });
+
+    let mut body = proxy.handle(get("/"), client_addr()).await.into_body();
+    assert_eq!(body.data().await.unwrap().unwrap(), "payload");
+    let trailers = body.trailers().await.unwrap().expect("trailers received");
+    assert_eq!(trailers["grpc-status"], "0");
+}
+
+// Read one response head (up to the blank line) from `stream`.
+async fn read_head(stream: &mut TcpStream) -> String {
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        let mut byte = [0];
+        assert_eq!(stream.read(&mut byte).await.unwrap(), 1, "connection closed mid-response");
+        head.push(byte[0]);
+    }
+    String::from_utf8(head).unwrap()
+}
+
+// Send the head of a 10-byte upload that waits for 100 Continue.
+async fn start_upload(proxy: SocketAddr) -> TcpStream {
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    let head = format!(
+        "POST /upload HTTP/1.1\r\nHost: proxy\r\nAuthorization: Bearer {}\r\n\
+         Content-Length: 10\r\nExpect: 100-continue\r\n\r\n",
+        TOKEN
+    );
+    stream.write_all(head.as_bytes()).await.unwrap();
+    stream
+}
+
+#[tokio::test]
+async fn expect_continue_upload_goes_through() {
+    let upstream = upstream(|req: Request<Body>| async move {
+        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+        Response::new(Body::from(format!("got {}", String::from_utf8_lossy(&body))))
+    })
+    .await;
+    let proxy = serve(proxy(config(&upstream))).await;
+
+    let mut stream = start_upload(proxy).await;
+    // The go-ahead comes straight away.
+    let interim = timeout(Duration::from_millis(500), read_head(&mut stream)).await.expect("no 100 Continue");
+    assert!(interim.starts_with("HTTP/1.1 100"), "{}", interim);
+    stream.write_all(b"0123456789").await.unwrap();
+    let head = read_head(&mut stream).await;
+    assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+    let mut body = [0; 14];
+    stream.read_exact(&mut body).await.unwrap();
+    assert_eq!(&body, b"got 0123456789");
+}
+
+#[tokio::test]
+async fn upstream_refusal_reaches_the_client_before_the_body() {
+    // Refuses every upload without reading it.
+    let upstream = upstream(|_| async {
+        Response::builder().status(417).body(Body::from("no uploads")).unwrap()
+    })
+    .await;
+    let proxy = serve(proxy(ProxyConfig {
+        expect_continue_timeout_ms: Some(5_000),
+        ..config(&upstream)
+    }))
+    .await;
+
+    let mut stream = start_upload(proxy).await;
+    // The final status comes instead of a 100, well before the hold-back
+    // ends, and the connection is closed rather than reading a body.
+    let head = timeout(Duration::from_secs(2), read_head(&mut stream)).await.expect("no response");
+    assert!(head.starts_with("HTTP/1.1 417"), "{}", head);
+    assert!(head.to_lowercase().contains("connection: close"), "{}", head);
+    let mut rest = String::new();
+    stream.read_to_string(&mut rest).await.unwrap();
+    assert_eq!(rest, "no uploads");
+}
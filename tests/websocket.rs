@@ -0,0 +1,111 @@
+// WebSocket handshakes and the upgraded connection.
+
+mod common;
+
+use common::{config, serve, TOKEN};
+use hyper::header::{CONNECTION, UPGRADE};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server, StatusCode};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+// A WebSocket server answering each text frame with `echo: <text>`.
+async fn echo_server() -> String {
+    let make_service = make_service_fn(|_| async {
+        Ok::<_, Infallible>(service_fn(|mut req: Request<Body>| async move {
+            let upgraded = hyper::upgrade::on(&mut req);
+            tokio::spawn(async move {
+                let mut socket = upgraded.await.unwrap();
+                while let Some(text) = read_frame(&mut socket).await {
+                    write_frame(&mut socket, &format!("echo: {}", text), None).await;
+                }
+            });
+            let resp = Response::builder()
+                .status(StatusCode::SWITCHING_PROTOCOLS)
+                .header(UPGRADE, "websocket")
+                .header(CONNECTION, "upgrade")
+                .header("sec-websocket-accept", "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=")
+                .body(Body::empty())
+                .unwrap();
+            Ok::<_, Infallible>(resp)
+        }))
+    });
+    let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+    let url = format!("http://{}", server.local_addr());
+    tokio::spawn(server);
+    url
+}
+
+// Write a short text frame, masked when sent by a client.
+async fn write_frame(socket: &mut (impl AsyncWrite + Unpin), text: &str, mask: Option<[u8; 4]>) {
+    let payload = text.as_bytes();
+    assert!(payload.len() < 126);
+    let mut frame = vec![0x81, payload.len() as u8];
+    match mask {
+        Some(mask) => {
+            frame[1] |= 0x80;
+            frame.extend_from_slice(&mask);
+            frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        }
+        None => frame.extend_from_slice(payload),
+    }
+    socket.write_all(&frame).await.unwrap();
+}
+
+// Read a short text frame, or `None` once the connection is closed.
+async fn read_frame(socket: &mut (impl AsyncRead + Unpin)) -> Option<String> {
+    let mut head = [0; 2];
+    socket.read_exact(&mut head).await.ok()?;
+    let len = (head[1] & 0x7f) as usize;
+    let mut mask = [0; 4];
+    if head[1] & 0x80 != 0 {
+        socket.read_exact(&mut mask).await.ok()?;
+    }
+    let mut payload = vec![0; len];
+    socket.read_exact(&mut payload).await.ok()?;
+    let text = payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]).collect();
+    Some(String::from_utf8(text).unwrap())
+}
+
+// Send a handshake with `authorization` and return the connection and the
+// response head.
+async fn handshake(proxy: SocketAddr, authorization: &str) -> (TcpStream, String) {
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    let request = format!(
+        "GET /chat HTTP/1.1\r\nHost: {}\r\nAuthorization: {}\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\
+         Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+        proxy, authorization
+    );
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        let mut byte = [0];
+        assert_eq!(stream.read(&mut byte).await.unwrap(), 1, "connection closed mid-response");
+        head.push(byte[0]);
+    }
+    (stream, String::from_utf8(head).unwrap())
+}
+
+#[tokio::test]
+async fn messages_pass_through_the_upgraded_connection() {
+    let upstream = echo_server().await;
+    let proxy = serve(common::proxy(config(&upstream))).await;
+
+    let (mut socket, head) = handshake(proxy, &format!("Bearer {}", TOKEN)).await;
+    assert!(head.starts_with("HTTP/1.1 101"), "{}", head);
+    assert!(head.to_lowercase().contains("upgrade: websocket"), "{}", head);
+    write_frame(&mut socket, "hello", Some([1, 2, 3, 4])).await;
+    assert_eq!(read_frame(&mut socket).await.as_deref(), Some("echo: hello"));
+    write_frame(&mut socket, "again", Some([5, 6, 7, 8])).await;
+    assert_eq!(read_frame(&mut socket).await.as_deref(), Some("echo: again"));
+}
+
+#[tokio::test]
+async fn handshake_needs_auth() {
+    let upstream = echo_server().await;
+    let proxy = serve(common::proxy(config(&upstream))).await;
+    let (_, head) = handshake(proxy, "Bearer wrong").await;
+    assert!(head.starts_with("HTTP/1.1 401"), "{}", head);
+}